@@ -0,0 +1,61 @@
+//! Compares the throughput of the [`ThreadPool`]'s schedulers under many tiny jobs,
+//! where the cost of handing out jobs dominates that of running them.
+//!
+//! Run with `cargo bench`; being a plain binary, it takes no arguments.
+
+use chap_20_rust_web_server::{Scheduler, ThreadPool, ThreadPoolConfig};
+
+use std::{
+    hint,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+const JOBS: u64 = 200_000;
+const ROUNDS: u32 = 5;
+
+/// Time running `JOBS` jobs, each adding to a shared counter, on a fresh pool.
+fn run(scheduler: Scheduler, size: usize) -> Duration {
+    let pool = ThreadPool::build_with(ThreadPoolConfig {
+        scheduler,
+        ..ThreadPoolConfig::new(size)
+    })
+    .unwrap();
+    let counter = Arc::new(AtomicU64::new(0));
+
+    let start = Instant::now();
+    for n in 0..JOBS {
+        let counter = Arc::clone(&counter);
+        pool.execute(move || {
+            counter.fetch_add(hint::black_box(n), Ordering::Relaxed);
+            Ok(())
+        })
+        .unwrap();
+    }
+    let report = pool.shutdown(Duration::from_secs(60));
+    let elapsed = start.elapsed();
+
+    assert!(report.busy.is_empty());
+    assert_eq!(counter.load(Ordering::Relaxed), JOBS * (JOBS - 1) / 2);
+    elapsed
+}
+
+fn main() {
+    println!("{JOBS} tiny jobs, best of {ROUNDS} rounds");
+    for size in [1, 2, 4, 8] {
+        for scheduler in [Scheduler::Channel, Scheduler::WorkStealing] {
+            let best = (0..ROUNDS).map(|_| run(scheduler, size)).min().unwrap();
+            let jobs_per_sec = JOBS as f64 / best.as_secs_f64();
+            println!(
+                "{:>2} workers, {:<14} {:>8.1} ms {:>12.0} jobs/s",
+                size,
+                format!("{scheduler:?}:"),
+                best.as_secs_f64() * 1000.0,
+                jobs_per_sec
+            );
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! This module contains [`AccessLog`], which records every request the server answers,
+//! one line per request, separately from the diagnostic log set up by
+//! `util::init_logging_infrastructure`.
+//!
+//! Lines are written in one of the [`AccessLogFormat`]s: the Common and Combined Log
+//! Formats understood by most log analyzers, or JSON lines, which also carry how long
+//! the request took to serve, and the ID of the [`Worker`](crate::Worker) that served it.
+
+use std::{
+    fs,
+    io::{self, Write},
+    net,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{
+    date::DateTime,
+    http::{Method, StatusCode, Version},
+    lock,
+};
+
+/// The ways an [`AccessLog`] can format its lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// The Common Log Format, e.g.
+    /// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 2326`.
+    Common,
+    /// The Common Log Format, followed by the `Referer` and `User-Agent` fields of the
+    /// request, quoted, as Apache's and nginx's `combined` format.
+    #[default]
+    Combined,
+    /// A JSON object per line, with every field of an [`AccessLogEntry`].
+    Json,
+}
+
+impl AccessLogFormat {
+    /// Name of the format, as in a server configuration, e.g. `combined`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessLogFormat::Common => "common",
+            AccessLogFormat::Combined => "combined",
+            AccessLogFormat::Json => "json",
+        }
+    }
+}
+
+/// What is recorded about a request in an [`AccessLog`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogEntry {
+    /// Address of the client, if known.
+    pub peer_addr: Option<net::SocketAddr>,
+    /// When the request was received.
+    pub time: DateTime,
+    /// The request's method, target and version, or `None` for a request that could
+    /// not be parsed.
+    pub request_line: Option<(Method, String, Version)>,
+    pub status: StatusCode,
+    /// Size of the response's body as sent, i.e. `0` for a response to `HEAD`.
+    pub bytes: u64,
+    /// Time from the request being received to its response being sent.
+    pub duration: Duration,
+    /// ID of the [`Worker`](crate::Worker) that served the request, if any.
+    pub worker_id: Option<usize>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl AccessLogEntry {
+    /// Format the entry as a line of the given format, without its line terminator.
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => self.common(),
+            AccessLogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                self.common(),
+                self.referer
+                    .as_deref()
+                    .map_or("-".to_string(), escape_quoted),
+                self.user_agent
+                    .as_deref()
+                    .map_or("-".to_string(), escape_quoted),
+            ),
+            AccessLogFormat::Json => self.json(),
+        }
+    }
+
+    fn common(&self) -> String {
+        let host = self
+            .peer_addr
+            .map_or("-".to_string(), |addr| addr.ip().to_string());
+        let request = match &self.request_line {
+            Some((method, target, version)) => {
+                escape_quoted(&format!("{method} {target} {}", version.as_str()))
+            }
+            None => "-".to_string(),
+        };
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        format!(
+            "{host} - - [{}] \"{request}\" {} {bytes}",
+            self.time.to_clf(),
+            self.status.as_u16()
+        )
+    }
+
+    fn json(&self) -> String {
+        let string_or_null = |s: Option<&str>| s.map_or("null".to_string(), json_string);
+        let (method, target, protocol) = match &self.request_line {
+            Some((method, target, version)) => (
+                Some(method.as_str()),
+                Some(target.as_str()),
+                Some(version.as_str()),
+            ),
+            None => (None, None, None),
+        };
+        format!(
+            "{{\"time\":\"{}\",\"remote_addr\":{},\"method\":{},\"target\":{},\"protocol\":{},\
+             \"status\":{},\"bytes\":{},\"duration_ms\":{:.3},\"worker\":{},\"referer\":{},\
+             \"user_agent\":{}}}",
+            self.time.to_rfc3339(),
+            string_or_null(self.peer_addr.map(|addr| addr.ip().to_string()).as_deref()),
+            string_or_null(method),
+            string_or_null(target),
+            string_or_null(protocol),
+            self.status.as_u16(),
+            self.bytes,
+            self.duration.as_secs_f64() * 1000.0,
+            self.worker_id
+                .map_or("null".to_string(), |id| id.to_string()),
+            string_or_null(self.referer.as_deref()),
+            string_or_null(self.user_agent.as_deref()),
+        )
+    }
+}
+
+/// Escape `"` and `\` in a quoted field of the Common or Combined Log Format, and
+/// control characters, so that a client can't forge log lines.
+fn escape_quoted(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quote and escape `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Writes an [`AccessLogEntry`] per request into a file, or any other writer.
+///
+/// It is shared by every worker through the [`Service`](crate::service::Service), so
+/// lines are written whole, one at a time.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    /// Create an access log writing lines of the given format into `writer`.
+    pub fn new(writer: impl Write + Send + 'static, format: AccessLogFormat) -> AccessLog {
+        AccessLog {
+            format,
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Create an access log appending lines of the given format to the file at `path`,
+    /// which is created if it doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// If the file can't be opened.
+    pub fn open(path: impl AsRef<Path>, format: AccessLogFormat) -> io::Result<AccessLog> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(AccessLog::new(io::LineWriter::new(file), format))
+    }
+
+    pub fn format(&self) -> AccessLogFormat {
+        self.format
+    }
+
+    /// Write `entry` as a line. Failing to do so is only logged, as it is no reason
+    /// to fail the request.
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        let mut writer = lock(&self.writer);
+        if let Err(err) = writer
+            .write_all(line.as_bytes())
+            .and_then(|()| writer.flush())
+        {
+            simplelog::warn!("Could not write to access log: {:?}", err);
+        }
+    }
+}
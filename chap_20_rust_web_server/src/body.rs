@@ -0,0 +1,584 @@
+//! This module contains what handlers need to make sense of request bodies:
+//! * [`Form`], the fields of an `application/x-www-form-urlencoded` body, or of a
+//!   query string;
+//! * [`Multipart`], the fields and uploaded files of a `multipart/form-data` body, the
+//!   files being streamed to disk as they are parsed, within [`UploadLimits`]; and
+//! * JSON bodies, deserialized into any type implementing `serde::Deserialize`.
+//!
+//! They are exposed as accessors on [`Request`]: `Request::form`, `Request::multipart`
+//! and `Request::json`, each of which checks the request's `Content-Type` first. Their
+//! [`BodyError`]s map to the status code the client should be answered with.
+//!
+//! The body itself is read by `Request::read_from`, within the service's
+//! [`RequestLimits`](crate::http::RequestLimits), except for `multipart/form-data`
+//! bodies, which the server leaves on the connection for `Request::multipart` to read
+//! through the request's [`BodyStream`](crate::http::BodyStream), within the
+//! handler's `UploadLimits` alone.
+
+use std::{
+    env, fmt, fs,
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::de::DeserializeOwned;
+
+use crate::http::{is_timeout, percent_decode, Request, StatusCode};
+
+/// Size of the chunks a multipart body is read in.
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Maximum size in bytes of the header fields of a part of a multipart body.
+const MAX_PART_HEADER_BYTES: usize = 8 * 1024;
+
+/// Longest boundary allowed by RFC 2046.
+const MAX_BOUNDARY_LEN: usize = 70;
+
+/// Enum representing the ways making sense of a request body can fail.
+#[derive(Debug)]
+pub enum BodyError {
+    /// The body's `Content-Type` isn't the one the accessor parses, or is missing.
+    UnsupportedMediaTypeError(Option<String>),
+    /// An `application/x-www-form-urlencoded` body has a malformed escape, or
+    /// decodes to invalid UTF-8.
+    MalformedFormError,
+    /// A `multipart/form-data` body, or its `Content-Type`, isn't well-formed, and why.
+    MalformedMultipartError(&'static str),
+    /// A JSON body isn't valid JSON, or doesn't fit the type it is deserialized into.
+    MalformedJsonError(serde_json::Error),
+    /// A multipart body has more parts than `UploadLimits::max_parts`.
+    TooManyPartsError,
+    /// A multipart field larger than `UploadLimits::max_field_bytes`, and its name.
+    FieldTooLargeError(String),
+    /// An uploaded file larger than `UploadLimits::max_file_bytes`, and its field's name.
+    FileTooLargeError(String),
+    /// Storing an uploaded file failed.
+    UploadIoError(io::Error),
+    /// Reading a multipart body failed, e.g. as the client took longer than the read
+    /// timeout to send some of it, or as its chunked framing is malformed.
+    BodyReadError(io::Error),
+}
+
+impl BodyError {
+    /// The status code the client should be answered with: `415 Unsupported Media
+    /// Type`, `413 Payload Too Large`, `400 Bad Request`, `408 Request Timeout` if the
+    /// client was too slow to send the body, or, if the server failed to store an
+    /// upload, `500 Internal Server Error`.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            BodyError::UnsupportedMediaTypeError(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            BodyError::MalformedFormError
+            | BodyError::MalformedMultipartError(_)
+            | BodyError::MalformedJsonError(_) => StatusCode::BAD_REQUEST,
+            BodyError::TooManyPartsError
+            | BodyError::FieldTooLargeError(_)
+            | BodyError::FileTooLargeError(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            BodyError::UploadIoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            BodyError::BodyReadError(err) if is_timeout(err) => StatusCode::REQUEST_TIMEOUT,
+            BodyError::BodyReadError(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyError::UnsupportedMediaTypeError(Some(content_type)) => {
+                write!(f, "unsupported content type {content_type:?}")
+            }
+            BodyError::UnsupportedMediaTypeError(None) => write!(f, "missing content type"),
+            BodyError::MalformedFormError => write!(f, "malformed form data"),
+            BodyError::MalformedMultipartError(reason) => {
+                write!(f, "malformed multipart body: {reason}")
+            }
+            BodyError::MalformedJsonError(err) => write!(f, "malformed JSON body: {err}"),
+            BodyError::TooManyPartsError => write!(f, "too many parts in multipart body"),
+            BodyError::FieldTooLargeError(name) => write!(f, "field {name:?} is too large"),
+            BodyError::FileTooLargeError(name) => write!(f, "file uploaded as {name:?} is too large"),
+            BodyError::UploadIoError(err) => write!(f, "could not store uploaded file: {err}"),
+            BodyError::BodyReadError(err) => write!(f, "could not read body: {err}"),
+        }
+    }
+}
+
+impl From<io::Error> for BodyError {
+    fn from(err: io::Error) -> Self {
+        BodyError::UploadIoError(err)
+    }
+}
+
+/// The fields of a form, in the order they were sent; a name may appear several times,
+/// e.g. for checkboxes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+}
+
+impl Form {
+    /// Parse `urlencoded`, e.g. `name=Ferris&likes=rust+%26+crabs`, where `+` stands
+    /// for a space, and `%XX` escapes are decoded.
+    ///
+    /// # Errors
+    ///
+    /// `MalformedFormError` if an escape is malformed, or decodes to invalid UTF-8.
+    pub fn parse(urlencoded: &str) -> Result<Form, BodyError> {
+        let decode = |s: &str| percent_decode(&s.replace('+', " ")).ok_or(BodyError::MalformedFormError);
+        let mut fields = Vec::new();
+        for pair in urlencoded.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            fields.push((decode(name)?, decode(value)?));
+        }
+        Ok(Form { fields })
+    }
+
+    /// The first value of the field `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of the field `name`, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every field, as `(name, value)` pairs, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Add a field, after the others.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.fields.push((name.into(), value.into()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// Limits applied while parsing a `multipart/form-data` body, and where uploaded files
+/// are stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadLimits {
+    /// Directory uploaded files are written to, which must exist.
+    pub dir: PathBuf,
+    /// Maximum size in bytes of each uploaded file.
+    pub max_file_bytes: u64,
+    /// Maximum size in bytes of each field that isn't a file.
+    pub max_field_bytes: usize,
+    /// Maximum number of parts, fields and files together.
+    pub max_parts: usize,
+}
+
+impl Default for UploadLimits {
+    /// Files of up to 16 MiB, written to the OS's temporary directory, fields of up to
+    /// 64 KiB, and up to 64 parts.
+    fn default() -> UploadLimits {
+        UploadLimits {
+            dir: env::temp_dir(),
+            max_file_bytes: 16 * 1024 * 1024,
+            max_field_bytes: 64 * 1024,
+            max_parts: 64,
+        }
+    }
+}
+
+/// A file uploaded in a `multipart/form-data` body, stored in `UploadLimits::dir`.
+///
+/// The file is deleted once this is dropped, unless it was moved elsewhere with
+/// `UploadedFile::persist`.
+#[derive(Debug)]
+pub struct UploadedFile {
+    /// Name of the form field the file was uploaded as.
+    pub field: String,
+    /// Name of the file on the client's side, if it gave one. It is not to be trusted
+    /// as a path, e.g. it may contain `..`.
+    pub file_name: Option<String>,
+    /// `Content-Type` of the file, as the client gave it.
+    pub content_type: Option<String>,
+    /// Size of the file in bytes.
+    pub size: u64,
+    path: PathBuf,
+}
+
+impl UploadedFile {
+    /// Where the file is stored, until it is dropped.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the file to `destination`, where it is kept.
+    ///
+    /// # Errors
+    ///
+    /// If the file can't be moved, in which case it is left where it was, and deleted
+    /// along with this.
+    pub fn persist(mut self, destination: impl AsRef<Path>) -> io::Result<()> {
+        let destination = destination.as_ref();
+        if fs::rename(&self.path, destination).is_err() {
+            // E.g. across file systems.
+            fs::copy(&self.path, destination)?;
+            let _ = fs::remove_file(&self.path);
+        }
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The fields and files of a `multipart/form-data` body, as described in RFC 7578.
+#[derive(Debug, Default)]
+pub struct Multipart {
+    /// The parts that aren't files, which must be valid UTF-8.
+    pub fields: Form,
+    /// The parts that are files, i.e. those with a `filename` parameter, in order.
+    pub files: Vec<UploadedFile>,
+}
+
+impl Multipart {
+    /// The first file uploaded as the field `name`.
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|file| file.field == name)
+    }
+
+    /// Parse the multipart body read from `reader`, whose parts are delimited by
+    /// `boundary`, as given in its `Content-Type`.
+    ///
+    /// The body is read in chunks, and files are written to `limits.dir` as they
+    /// arrive, so they're never held in memory whole.
+    ///
+    /// # Errors
+    ///
+    /// * `MalformedMultipartError` if the body isn't well-formed, or ends before its
+    ///   closing delimiter;
+    /// * `TooManyPartsError`, `FieldTooLargeError` or `FileTooLargeError` if the body
+    ///   exceeds `limits`;
+    /// * `BodyReadError` if reading the body fails, and `UploadIoError` if storing a
+    ///   file does.
+    ///
+    /// Files stored before the error are deleted.
+    pub fn read_from<R: Read>(reader: R, boundary: &str, limits: &UploadLimits) -> Result<Multipart, BodyError> {
+        if boundary.is_empty() || boundary.len() > MAX_BOUNDARY_LEN {
+            return Err(BodyError::MalformedMultipartError("invalid boundary"));
+        }
+        let delimiter = format!("\r\n--{boundary}").into_bytes();
+        // The first delimiter may be at the very start, without a line break before it.
+        let mut scanner = Scanner { reader, buffer: b"\r\n".to_vec() };
+        let mut multipart = Multipart::default();
+
+        // Anything before the first delimiter is a preamble, to be ignored.
+        scanner.copy_until(&delimiter, |_| Ok(()))?;
+        let mut parts = 0;
+        loop {
+            if scanner.starts_with(b"--")? {
+                // The closing delimiter; anything after it is an epilogue.
+                return Ok(multipart);
+            }
+            // Whitespace may follow a delimiter before its line break.
+            if !scanner.read_line()?.iter().all(|byte| *byte == b' ' || *byte == b'\t') {
+                return Err(BodyError::MalformedMultipartError("junk after delimiter"));
+            }
+            parts += 1;
+            if parts > limits.max_parts {
+                return Err(BodyError::TooManyPartsError);
+            }
+
+            let mut disposition = None;
+            let mut content_type = None;
+            let mut header_bytes = 0;
+            loop {
+                let line = scanner.read_line()?;
+                header_bytes += line.len() + 2;
+                if header_bytes > MAX_PART_HEADER_BYTES {
+                    return Err(BodyError::MalformedMultipartError("part headers too large"));
+                }
+                if line.is_empty() {
+                    break;
+                }
+                let line = String::from_utf8(line)
+                    .map_err(|_| BodyError::MalformedMultipartError("part header isn't UTF-8"))?;
+                let Some((name, value)) = line.split_once(':') else {
+                    return Err(BodyError::MalformedMultipartError("malformed part header"));
+                };
+                if name.trim().eq_ignore_ascii_case("Content-Disposition") {
+                    disposition = Some(value.trim().to_string());
+                } else if name.trim().eq_ignore_ascii_case("Content-Type") {
+                    content_type = Some(value.trim().to_string());
+                }
+            }
+            let disposition = disposition
+                .ok_or(BodyError::MalformedMultipartError("part without Content-Disposition"))?;
+            if !media_type(&disposition).eq_ignore_ascii_case("form-data") {
+                return Err(BodyError::MalformedMultipartError("part isn't form-data"));
+            }
+            let name = parameter(&disposition, "name")
+                .ok_or(BodyError::MalformedMultipartError("part without a name"))?;
+
+            match parameter(&disposition, "filename") {
+                Some(file_name) => {
+                    let (file, path) = create_upload_file(&limits.dir)?;
+                    // Deletes the file, should the upload fail.
+                    let mut upload = UploadedFile {
+                        field: name,
+                        file_name: Some(file_name).filter(|file_name| !file_name.is_empty()),
+                        content_type,
+                        size: 0,
+                        path,
+                    };
+                    let mut writer = BufWriter::new(file);
+                    scanner.copy_until(&delimiter, |chunk| {
+                        upload.size += chunk.len() as u64;
+                        if upload.size > limits.max_file_bytes {
+                            return Err(BodyError::FileTooLargeError(upload.field.clone()));
+                        }
+                        Ok(writer.write_all(chunk)?)
+                    })?;
+                    writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+                    multipart.files.push(upload);
+                }
+                None => {
+                    let mut value = Vec::new();
+                    scanner.copy_until(&delimiter, |chunk| {
+                        if value.len() + chunk.len() > limits.max_field_bytes {
+                            return Err(BodyError::FieldTooLargeError(name.clone()));
+                        }
+                        value.extend_from_slice(chunk);
+                        Ok(())
+                    })?;
+                    let value = String::from_utf8(value)
+                        .map_err(|_| BodyError::MalformedMultipartError("field isn't UTF-8"))?;
+                    multipart.fields.append(name, value);
+                }
+            }
+        }
+    }
+}
+
+/// Create a file to store an upload in, under `dir`, with a name no other upload has.
+fn create_upload_file(dir: &Path) -> io::Result<(fs::File, PathBuf)> {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    loop {
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("rust_web_server_upload_{}_{count}", process::id()));
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            // Left behind by a process that had the same id.
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Reads a multipart body through a buffer, in which delimiters are looked for.
+struct Scanner<R> {
+    reader: R,
+    /// Bytes read, but not consumed yet.
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> Scanner<R> {
+    /// Read another chunk into the buffer, returning `false` at the end of the body.
+    fn fill(&mut self) -> Result<bool, BodyError> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let n = loop {
+            match self.reader.read(&mut chunk) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                read => break read.map_err(BodyError::BodyReadError)?,
+            }
+        };
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+
+    /// Whether what comes next is `prefix`, which is consumed if so.
+    fn starts_with(&mut self, prefix: &[u8]) -> Result<bool, BodyError> {
+        while self.buffer.len() < prefix.len() {
+            if !self.fill()? {
+                return Err(BodyError::MalformedMultipartError("unexpected end of body"));
+            }
+        }
+        let found = self.buffer.starts_with(prefix);
+        if found {
+            self.buffer.drain(..prefix.len());
+        }
+        Ok(found)
+    }
+
+    /// Consume a line, returning it without its `\r\n`.
+    fn read_line(&mut self) -> Result<Vec<u8>, BodyError> {
+        loop {
+            if let Some(end) = find(&self.buffer, b"\r\n") {
+                let line = self.buffer[..end].to_vec();
+                self.buffer.drain(..end + 2);
+                return Ok(line);
+            }
+            if self.buffer.len() > MAX_PART_HEADER_BYTES {
+                return Err(BodyError::MalformedMultipartError("part headers too large"));
+            }
+            if !self.fill()? {
+                return Err(BodyError::MalformedMultipartError("unexpected end of body"));
+            }
+        }
+    }
+
+    /// Consume everything up to and including `delimiter`, passing what comes before
+    /// it to `emit`, chunk by chunk.
+    fn copy_until<F>(&mut self, delimiter: &[u8], mut emit: F) -> Result<(), BodyError>
+    where
+        F: FnMut(&[u8]) -> Result<(), BodyError>,
+    {
+        loop {
+            if let Some(start) = find(&self.buffer, delimiter) {
+                emit(&self.buffer[..start])?;
+                self.buffer.drain(..start + delimiter.len());
+                return Ok(());
+            }
+            // The end of the buffer could be the start of the delimiter, so it's kept.
+            let safe = self.buffer.len().saturating_sub(delimiter.len() - 1);
+            if safe > 0 {
+                emit(&self.buffer[..safe])?;
+                self.buffer.drain(..safe);
+            }
+            if !self.fill()? {
+                return Err(BodyError::MalformedMultipartError("unexpected end of body"));
+            }
+        }
+    }
+}
+
+/// Position of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// The media type of a header field value with parameters, e.g. `text/html` for
+/// `text/html; charset=utf-8`, or `form-data` for `form-data; name="file"`.
+fn media_type(value: &str) -> &str {
+    value.split(';').next().unwrap_or_default().trim()
+}
+
+/// The value of the parameter `name` of a header field value such as
+/// `form-data; name="file"; filename="a.txt"`, unquoted.
+fn parameter(value: &str, name: &str) -> Option<String> {
+    // Parameters are split by hand, as quoted values may contain `;`.
+    let mut rest = value.split_once(';')?.1;
+    loop {
+        let (key, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let (parsed, remainder) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut parsed = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (i, '"') => break i + 1,
+                        (_, '\\') => parsed.push(chars.next()?.1),
+                        (_, c) => parsed.push(c),
+                    }
+                };
+                let remainder = &quoted[end..];
+                (parsed, remainder.split_once(';').map_or("", |(_, remainder)| remainder))
+            }
+            None => match after.split_once(';') {
+                Some((token, remainder)) => (token.trim().to_string(), remainder),
+                None => (after.trim().to_string(), ""),
+            },
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(parsed);
+        }
+        rest = remainder;
+    }
+}
+
+/// Accessors for the request's body, according to its `Content-Type`.
+impl Request {
+    /// The media type of the body, e.g. `application/json`, parameters aside.
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type").map(media_type)
+    }
+
+    /// The fields of an `application/x-www-form-urlencoded` body, as sent by HTML
+    /// forms by default. For those of the query string, see `Form::parse`.
+    ///
+    /// # Errors
+    ///
+    /// `UnsupportedMediaTypeError` if the body is of another type, and
+    /// `MalformedFormError` if it can't be decoded.
+    pub fn form(&self) -> Result<Form, BodyError> {
+        self.expect_content_type(|media_type| {
+            media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded")
+        })?;
+        let body = std::str::from_utf8(&self.body).map_err(|_| BodyError::MalformedFormError)?;
+        Form::parse(body)
+    }
+
+    /// The fields and files of a `multipart/form-data` body, as sent by HTML forms
+    /// with `enctype="multipart/form-data"`, the files being stored according to
+    /// `limits`; see `Multipart::read_from`.
+    ///
+    /// The body is read from the request's `body_stream` if it has one, i.e. from the
+    /// connection, as the server leaves it, and from `Request::body` otherwise. A
+    /// streamed body can only be read once.
+    ///
+    /// # Errors
+    ///
+    /// `UnsupportedMediaTypeError` if the body is of another type, and
+    /// `MalformedMultipartError` if its `Content-Type` has no boundary, as well as those
+    /// of `Multipart::read_from`.
+    pub fn multipart(&self, limits: &UploadLimits) -> Result<Multipart, BodyError> {
+        self.expect_content_type(|media_type| media_type.eq_ignore_ascii_case("multipart/form-data"))?;
+        let content_type = self.headers.get("Content-Type").unwrap_or_default();
+        let boundary = parameter(content_type, "boundary")
+            .ok_or(BodyError::MalformedMultipartError("missing boundary"))?;
+        match &self.body_stream {
+            Some(body_stream) => Multipart::read_from(body_stream.clone(), &boundary, limits),
+            None => Multipart::read_from(self.body.as_slice(), &boundary, limits),
+        }
+    }
+
+    /// The JSON body, deserialized into a `T`, e.g. a struct deriving
+    /// `serde::Deserialize`, or a `serde_json::Value` for any JSON.
+    ///
+    /// Bodies of type `application/json`, or of any type with the `+json` suffix,
+    /// e.g. `application/merge-patch+json`, are accepted.
+    ///
+    /// # Errors
+    ///
+    /// `UnsupportedMediaTypeError` if the body is of another type, and
+    /// `MalformedJsonError` if it isn't valid JSON, or doesn't fit a `T`.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, BodyError> {
+        self.expect_content_type(|media_type| {
+            let media_type = media_type.to_ascii_lowercase();
+            media_type == "application/json" || media_type.ends_with("+json")
+        })?;
+        serde_json::from_slice(&self.body).map_err(BodyError::MalformedJsonError)
+    }
+
+    /// Check the body's media type is one `accepts`.
+    fn expect_content_type(&self, accepts: impl Fn(&str) -> bool) -> Result<(), BodyError> {
+        match self.content_type() {
+            Some(media_type) if accepts(media_type) => Ok(()),
+            media_type => Err(BodyError::UnsupportedMediaTypeError(media_type.map(str::to_string))),
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! This module contains what the server needs for HTTP caching, as described in
+//! RFC 9110 and RFC 9111:
+//! * [`Validators`], the `ETag` and `Last-Modified` of a file, which clients send back
+//!   in conditional requests to check whether their copy is still fresh;
+//! * [`Conditions`], the `If-None-Match` and `If-Modified-Since` preconditions of such
+//!   a request, which `util::handle_connection` evaluates against the response, so as
+//!   to answer `304 Not Modified` rather than send the same body again;
+//! * [`FileCache`], which keeps small files in memory until they are modified; and
+//! * `with_cache_control`, which sets `Cache-Control` on the responses of a route,
+//!   telling clients for how long they may reuse them without asking.
+
+use std::{
+    collections::HashMap,
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    date::DateTime,
+    http::{Method, Request, Response, StatusCode},
+    lock,
+};
+
+/// Header fields of a `200 OK` response repeated in the `304 Not Modified` replacing it,
+/// as listed in RFC 9110, section 15.4.5.
+const NOT_MODIFIED_FIELDS: [&str; 6] =
+    ["Cache-Control", "Content-Location", "Date", "ETag", "Expires", "Vary"];
+
+/// What identifies a version of a file, for clients to validate their copy of it with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validators {
+    /// A strong entity tag, quotes included, made of the file's size and modification
+    /// time, so that it changes whenever the file does, short of the modification time
+    /// being restored along with the size.
+    pub etag: String,
+    /// When the file was last modified, if the platform tells.
+    pub last_modified: Option<DateTime>,
+}
+
+impl Validators {
+    /// The validators of the file `metadata` was read from.
+    pub fn from_metadata(metadata: &fs::Metadata) -> Validators {
+        let modified = metadata.modified().ok();
+        let nanos = modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_nanos());
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", metadata.len(), nanos),
+            last_modified: modified.map(DateTime::from_system_time),
+        }
+    }
+
+    /// The validators of a body generated rather than read from a file, e.g. a
+    /// rendered page: a strong entity tag made of its size and a hash of `contents`,
+    /// and no `Last-Modified`, there being no telling when it last changed.
+    pub fn from_contents(contents: &[u8]) -> Validators {
+        let mut hasher = DefaultHasher::new();
+        contents.hash(&mut hasher);
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", contents.len(), hasher.finish()),
+            last_modified: None,
+        }
+    }
+
+    /// Set `ETag` and, if known, `Last-Modified` on `response`.
+    pub fn apply(&self, response: Response) -> Response {
+        let response = response.with_header("ETag", &self.etag);
+        match self.last_modified {
+            Some(last_modified) => response.with_header("Last-Modified", last_modified.to_http_date()),
+            None => response,
+        }
+    }
+}
+
+/// The entity tag of a variant of the representation tagged `etag`, e.g. its gzipped
+/// variant with `suffix` being `gzip`, as a strong tag must differ between variants.
+///
+/// Weak tags only promise equivalent content, so they are left as they are.
+pub fn variant_etag(etag: &str, suffix: &str) -> String {
+    match etag.strip_suffix('"') {
+        Some(opaque) if !etag.starts_with("W/") => format!("{opaque}-{suffix}\""),
+        _ => etag.to_string(),
+    }
+}
+
+/// Whether two entity tags are the same, weak tags being equal to strong ones with the
+/// same opaque tag, as `If-None-Match` calls for.
+fn weak_eq(a: &str, b: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    opaque(a) == opaque(b)
+}
+
+/// The preconditions of a conditional `GET` or `HEAD` request, under which the server
+/// may answer `304 Not Modified`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Conditions {
+    /// Entity tags of the copies the client has, as the comma-separated list of
+    /// `If-None-Match`, or `*` for any copy.
+    pub if_none_match: Option<String>,
+    /// When the client's copy was last modified, as it was told by `Last-Modified`.
+    pub if_modified_since: Option<DateTime>,
+}
+
+impl Conditions {
+    /// The preconditions of `request`; requests other than `GET` and `HEAD` have none,
+    /// as `304 Not Modified` only applies to those.
+    ///
+    /// An `If-Modified-Since` that isn't a valid HTTP date is ignored.
+    pub fn from_request(request: &Request) -> Conditions {
+        if !matches!(request.method, Method::Get | Method::Head) {
+            return Conditions::default();
+        }
+        let if_none_match: Vec<&str> = request.headers.get_all("If-None-Match").collect();
+        Conditions {
+            if_none_match: (!if_none_match.is_empty()).then(|| if_none_match.join(", ")),
+            if_modified_since: request
+                .headers
+                .get("If-Modified-Since")
+                .and_then(DateTime::parse_http_date),
+        }
+    }
+
+    /// Whether the client's copy of `response` is as fresh as `response` itself,
+    /// according to RFC 9110, section 13.2.2.
+    ///
+    /// Only `200 OK` and `206 Partial Content` responses are considered, the latter
+    /// because a `Range` only applies once preconditions are met. `If-None-Match`
+    /// takes precedence over `If-Modified-Since`, which is ignored when given, as is
+    /// an `If-Modified-Since` in the future.
+    pub fn is_fresh(&self, response: &Response) -> bool {
+        if response.status != StatusCode::OK && response.status != StatusCode::PARTIAL_CONTENT {
+            return false;
+        }
+        if let Some(if_none_match) = &self.if_none_match {
+            let Some(etag) = response.headers.get("ETag") else {
+                return false;
+            };
+            return if_none_match
+                .split(',')
+                .any(|tag| tag.trim() == "*" || weak_eq(tag, etag));
+        }
+        match (self.if_modified_since, response.headers.get("Last-Modified")) {
+            (Some(if_modified_since), Some(last_modified)) => {
+                if_modified_since <= DateTime::now()
+                    && DateTime::parse_http_date(last_modified)
+                        .is_some_and(|last_modified| last_modified <= if_modified_since)
+            }
+            _ => false,
+        }
+    }
+
+    /// Replace `response` with a `304 Not Modified` if the client's copy of it is
+    /// fresh, keeping the header fields RFC 9110 says to, and `Last-Modified` if
+    /// there is no `ETag` to go by.
+    pub fn apply(&self, response: Response) -> Response {
+        if !self.is_fresh(&response) {
+            return response;
+        }
+        let mut not_modified = Response::new(StatusCode::NOT_MODIFIED);
+        for (name, value) in response.headers.iter() {
+            let kept = NOT_MODIFIED_FIELDS.iter().any(|field| name.eq_ignore_ascii_case(field))
+                || (name.eq_ignore_ascii_case("Last-Modified") && !response.headers.contains("ETag"));
+            if kept {
+                not_modified.headers.append(name, value);
+            }
+        }
+        not_modified
+    }
+}
+
+/// Wrap `handler` so that its responses carry `Cache-Control: <cache_control>`, e.g.
+/// `public, max-age=3600`, unless they have one already.
+///
+/// Error responses are left alone, as they usually shouldn't be cached for as long.
+pub fn with_cache_control<H>(
+    cache_control: impl Into<String>,
+    handler: H,
+) -> impl Fn(&Request) -> io::Result<Response> + Send + Sync + 'static
+where
+    H: Fn(&Request) -> io::Result<Response> + Send + Sync + 'static,
+{
+    let cache_control = cache_control.into();
+    move |request| {
+        let response = handler(request)?;
+        if response.status.as_u16() >= 400 || response.headers.contains("Cache-Control") {
+            return Ok(response);
+        }
+        Ok(response.with_header("Cache-Control", &cache_control))
+    }
+}
+
+/// A file as read by a [`FileCache`].
+#[derive(Debug, Clone)]
+pub struct CachedFile {
+    pub contents: Arc<[u8]>,
+    pub validators: Validators,
+    /// When the file was modified, as it was read, to tell whether it was since.
+    modified: Option<SystemTime>,
+}
+
+/// Keeps the contents of small files in memory, rereading them only once they are
+/// modified, which is checked on every read.
+///
+/// It is cheap to clone, every clone sharing the same files.
+#[derive(Debug, Clone)]
+pub struct FileCache {
+    state: Arc<FileCacheState>,
+}
+
+#[derive(Debug)]
+struct FileCacheState {
+    max_file_bytes: u64,
+    max_total_bytes: usize,
+    files: Mutex<CachedFiles>,
+}
+
+#[derive(Debug, Default)]
+struct CachedFiles {
+    files: HashMap<PathBuf, CachedFile>,
+    /// Total size of the files' contents, in bytes.
+    size: usize,
+}
+
+impl Default for FileCache {
+    /// A cache of files of up to 1 MiB, up to 32 MiB in total.
+    fn default() -> FileCache {
+        FileCache::new(1024 * 1024, 32 * 1024 * 1024)
+    }
+}
+
+impl FileCache {
+    /// Create a cache of files of up to `max_file_bytes`, which stops caching more
+    /// once they make up `max_total_bytes`.
+    pub fn new(max_file_bytes: u64, max_total_bytes: usize) -> FileCache {
+        FileCache {
+            state: Arc::new(FileCacheState {
+                max_file_bytes,
+                max_total_bytes,
+                files: Mutex::new(CachedFiles::default()),
+            }),
+        }
+    }
+
+    /// Size of the largest file cached, in bytes.
+    pub fn max_file_bytes(&self) -> u64 {
+        self.state.max_file_bytes
+    }
+
+    /// Read the file at `path`, from memory if it wasn't modified since it was last
+    /// read. Files too large to be cached, or once the cache is full, are read from
+    /// disk every time.
+    ///
+    /// # Errors
+    ///
+    /// If the file's metadata or contents can't be read.
+    pub fn read(&self, path: impl AsRef<Path>) -> io::Result<CachedFile> {
+        let path = path.as_ref();
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified().ok();
+        if let Some(cached) = lock(&self.state.files).files.get(path) {
+            if modified.is_some()
+                && cached.modified == modified
+                && cached.contents.len() as u64 == metadata.len()
+            {
+                return Ok(cached.clone());
+            }
+        }
+
+        let contents: Arc<[u8]> = fs::read(path)?.into();
+        let file = CachedFile {
+            contents,
+            validators: Validators::from_metadata(&metadata),
+            modified,
+        };
+        // Modified while being read, or without modification times to tell staleness.
+        if file.contents.len() as u64 != metadata.len() || modified.is_none() {
+            return Ok(CachedFile {
+                validators: Validators::from_metadata(&fs::metadata(path)?),
+                ..file
+            });
+        }
+
+        if metadata.len() <= self.state.max_file_bytes {
+            let mut cache = lock(&self.state.files);
+            let replaced = cache.files.get(path).map_or(0, |cached| cached.contents.len());
+            let size = cache.size - replaced + file.contents.len();
+            if size <= self.state.max_total_bytes {
+                cache.size = size;
+                cache.files.insert(path.to_path_buf(), file.clone());
+            }
+        }
+        Ok(file)
+    }
+}
@@ -0,0 +1,116 @@
+//! This module contains [`CancellationToken`], through which request handlers are told
+//! to give up on a request.
+//!
+//! A worker can't be interrupted in the middle of a handler, so cancellation is
+//! cooperative: `util::handle_connection` gives every request a token expiring after
+//! the service's handler timeout, and it is up to handlers doing lengthy work to check
+//! it every so often, or to wait with `CancellationToken::sleep` rather than
+//! `thread::sleep`, and to give up with `CancellationToken::check`'s error once it is
+//! cancelled.
+
+use std::{
+    io,
+    sync::{Arc, Condvar, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::lock;
+
+/// Shared state behind every clone of a [`CancellationToken`].
+#[derive(Debug, Default)]
+struct TokenState {
+    cancelled: Mutex<bool>,
+    /// Notified when the token is cancelled, to wake up `CancellationToken::sleep`.
+    on_cancel: Condvar,
+    /// Once past this, the token counts as cancelled.
+    deadline: Option<Instant>,
+}
+
+/// A cloneable flag telling a handler to stop working on a request, either because
+/// `CancellationToken::cancel` was called, or because its deadline passed.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    state: Arc<TokenState>,
+}
+
+impl CancellationToken {
+    /// Create a token that is only cancelled by `CancellationToken::cancel`.
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Create a token that is cancelled once `timeout` has passed.
+    pub fn with_timeout(timeout: Duration) -> CancellationToken {
+        CancellationToken {
+            state: Arc::new(TokenState {
+                deadline: Instant::now().checked_add(timeout),
+                ..TokenState::default()
+            }),
+        }
+    }
+
+    /// Cancel the token, and every clone of it.
+    pub fn cancel(&self) {
+        *lock(&self.state.cancelled) = true;
+        self.state.on_cancel.notify_all();
+    }
+
+    /// Whether the token was cancelled, or its deadline has passed.
+    pub fn is_cancelled(&self) -> bool {
+        *lock(&self.state.cancelled) || self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// When the token expires, if it does.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.state.deadline
+    }
+
+    /// Time left until the token expires, if it does.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.state
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Give up if the token is cancelled, with `?`.
+    ///
+    /// # Errors
+    ///
+    /// An `io::ErrorKind::TimedOut` error if the token is cancelled, which, returned
+    /// from a handler, has the client answered with `503 Service Unavailable`.
+    pub fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "request cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Block for `duration`, or until the token is cancelled, whichever comes first.
+    ///
+    /// Returns whether the whole `duration` elapsed, i.e. `false` if cancelled.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let wake_at = Instant::now() + duration;
+        let end = match self.state.deadline {
+            Some(deadline) if deadline < wake_at => deadline,
+            _ => wake_at,
+        };
+
+        let mut cancelled = lock(&self.state.cancelled);
+        loop {
+            let now = Instant::now();
+            if *cancelled || now >= end {
+                break;
+            }
+            cancelled = self
+                .state
+                .on_cancel
+                .wait_timeout(cancelled, end - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        drop(cancelled);
+
+        !self.is_cancelled()
+    }
+}
@@ -0,0 +1,340 @@
+//! This module contains [`Compression`], the policy by which responses are compressed,
+//! and the [`Encoder`]s doing so.
+//!
+//! The encoding of a response is negotiated with the client through its
+//! `Accept-Encoding` header field, as described in RFC 9110, section 12.5.3: of the
+//! [`Encoding`]s the server offers, the one the client prefers is used, ties going to
+//! the server's preference. Responses are compressed by `util::handle_connection`
+//! once their handler produced them, unless the handler already did so, as
+//! [`StaticFiles`](crate::static_files::StaticFiles) does to cache the compressed
+//! variants of files.
+//!
+//! Responses that could have been compressed carry `Vary: Accept-Encoding`, whether
+//! they were or not, so that caches don't serve a compressed variant to a client that
+//! can't decode it, or vice versa.
+
+use std::io::{self, Write};
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::{
+    cache,
+    http::{Body, Response, StatusCode},
+};
+
+/// Size of the buffer brotli compresses into before writing out.
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// Base-2 logarithm of brotli's window size, the default of the `brotli` tool.
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+/// A content coding responses may be compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Gzip,
+    /// The zlib format, which is what `deflate` means in HTTP, despite the name.
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    /// The encoding's name, as in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    /// Whether `coding`, as named by a client, is this encoding. `x-gzip` is an alias of
+    /// `gzip`.
+    fn matches(&self, coding: &str) -> bool {
+        coding.eq_ignore_ascii_case(self.as_str())
+            || (*self == Encoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"))
+    }
+
+    /// Compress `bytes` with this encoding, at `level`.
+    pub fn encode(&self, bytes: &[u8], level: Level) -> io::Result<Vec<u8>> {
+        let mut encoder = Encoder::new(*self, Vec::new(), level);
+        encoder.write_all(bytes)?;
+        encoder.finish()
+    }
+}
+
+/// How hard an [`Encoder`] tries to make its output small, at the expense of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// A compromise suited to compressing each response as it is sent.
+    Default,
+    /// The smallest output, suited to content compressed once and sent many times.
+    Best,
+}
+
+/// Compresses everything written through it into the writer it wraps, with an
+/// [`Encoding`].
+///
+/// The compressed stream must be ended with `Encoder::finish`; dropping the encoder
+/// without doing so leaves it truncated, or ends it while ignoring errors.
+pub enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Deflate(ZlibEncoder<W>),
+    Brotli(Box<brotli::CompressorWriter<ErrorKeeper<W>>>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Create an encoder compressing into `writer` with `encoding`, at `level`.
+    pub fn new(encoding: Encoding, writer: W, level: Level) -> Encoder<W> {
+        let flate_level = match level {
+            Level::Default => flate2::Compression::default(),
+            Level::Best => flate2::Compression::best(),
+        };
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(writer, flate_level)),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(writer, flate_level)),
+            Encoding::Brotli => {
+                // Brotli's highest qualities are too slow for on-the-fly compression.
+                let quality = match level {
+                    Level::Default => 5,
+                    Level::Best => 11,
+                };
+                let keeper = ErrorKeeper { inner: writer, error: None };
+                Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                    keeper,
+                    BROTLI_BUFFER_SIZE,
+                    quality,
+                    BROTLI_WINDOW_BITS,
+                )))
+            }
+        }
+    }
+
+    /// End the compressed stream, and return the writer it was written to.
+    ///
+    /// # Errors
+    ///
+    /// If writing the end of the stream fails.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+            Encoder::Brotli(encoder) => {
+                let ErrorKeeper { inner, error } = encoder.into_inner();
+                match error {
+                    Some(err) => Err(err),
+                    None => Ok(inner),
+                }
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Deflate(encoder) => encoder.write(buf),
+            Encoder::Brotli(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Deflate(encoder) => encoder.flush(),
+            Encoder::Brotli(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Wraps the writer of a brotli encoder, keeping the first error it returns, as the
+/// encoder ignores those that happen while ending the stream.
+pub struct ErrorKeeper<W: Write> {
+    inner: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Write for ErrorKeeper<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf).inspect_err(|err| {
+            if self.error.is_none() {
+                self.error = Some(io::Error::new(err.kind(), err.to_string()));
+            }
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Which responses are compressed, and how.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compression {
+    /// Encodings offered to clients, the one preferred when the client has no
+    /// preference first.
+    pub encodings: Vec<Encoding>,
+    /// Bodies smaller than this many bytes are sent as they are, as compressing them
+    /// gains little. Bodies of unknown length are compressed whatever their size.
+    pub min_size: u64,
+    /// Media types of the bodies compressed, e.g. `application/json`, or `text/*` for
+    /// every text type. Others, e.g. most images, are compressed already.
+    pub mime_types: Vec<String>,
+}
+
+impl Default for Compression {
+    /// Brotli, gzip and deflate, in that order, for text, JSON, JavaScript, XML, SVG
+    /// and WebAssembly bodies of at least 1 KiB.
+    fn default() -> Compression {
+        Compression {
+            encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+            min_size: 1024,
+            mime_types: [
+                "text/*",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "application/wasm",
+                "image/svg+xml",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+        }
+    }
+}
+
+impl Compression {
+    /// Whether bodies with the `Content-Type` `content_type` are compressed, parameters
+    /// such as `charset` aside.
+    pub fn is_compressible(&self, content_type: &str) -> bool {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        self.mime_types.iter().any(|mime_type| match mime_type.strip_suffix('*') {
+            Some(prefix) => essence
+                .get(..prefix.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+            None => essence.eq_ignore_ascii_case(mime_type),
+        })
+    }
+
+    /// Whether a body of `len` bytes, or of unknown length, is large enough to be
+    /// compressed.
+    pub fn is_large_enough(&self, len: Option<u64>) -> bool {
+        len.is_none_or(|len| len >= self.min_size)
+    }
+
+    /// The encoding to compress a response with, given the value of the request's
+    /// `Accept-Encoding` header field, or `None` to send it as it is.
+    ///
+    /// Codings are weighed by their `q` parameter, `1` by default, and those the client
+    /// doesn't name by that of `*`, if given; a weight of `0` means "not acceptable".
+    /// Without an `Accept-Encoding` header field, responses aren't compressed.
+    pub fn negotiate(&self, accept_encoding: Option<&str>) -> Option<Encoding> {
+        let accept_encoding = accept_encoding?;
+        let mut preferences = Vec::new();
+        for element in accept_encoding.split(',') {
+            let mut parts = element.split(';');
+            let coding = parts.next().unwrap_or_default().trim();
+            if coding.is_empty() {
+                continue;
+            }
+            let weight = parts
+                .filter_map(|param| param.trim().split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .map_or(Some(1.0), |(_, value)| value.trim().parse::<f32>().ok());
+            // A malformed weight makes the element meaningless.
+            if let Some(weight) = weight {
+                preferences.push((coding, weight));
+            }
+        }
+
+        let weight_of = |encoding: Encoding| {
+            let named = preferences.iter().find(|(coding, _)| encoding.matches(coding));
+            let any = preferences.iter().find(|(coding, _)| *coding == "*");
+            named.or(any).map_or(0.0, |(_, weight)| *weight)
+        };
+        let mut best: Option<(Encoding, f32)> = None;
+        for &encoding in &self.encodings {
+            let weight = weight_of(encoding);
+            // Strictly greater, so that ties go to the server's preference.
+            if weight > 0.0 && best.is_none_or(|(_, best_weight)| weight > best_weight) {
+                best = Some((encoding, weight));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    /// Compress `response` as negotiated with a request whose `Accept-Encoding` header
+    /// field is `accept_encoding`, if its status, `Content-Type` and size call for it.
+    ///
+    /// Bodies held in memory are compressed right away, and are sent as they are if
+    /// that doesn't make them smaller; others are compressed as they are written, and
+    /// sent chunked.
+    ///
+    /// A strong `ETag` is suffixed with the encoding, e.g. `"abc-gzip"`, as the
+    /// compressed variant is a different representation. `206 Partial Content` isn't
+    /// compressed, its ranges being of the uncompressed body, and `Accept-Ranges` is
+    /// removed from compressed responses, as their ranges couldn't be served.
+    ///
+    /// # Errors
+    ///
+    /// If compressing a body held in memory fails, which it only does if out of memory.
+    pub fn apply(&self, accept_encoding: Option<&str>, mut response: Response) -> io::Result<Response> {
+        let compressible = matches!(response.status.as_u16(), 200..=299)
+            && response.status != StatusCode::NO_CONTENT
+            && response.status != StatusCode::PARTIAL_CONTENT
+            && !response.headers.contains("Content-Encoding")
+            && response
+                .headers
+                .get("Content-Type")
+                .is_some_and(|content_type| self.is_compressible(content_type))
+            && self.is_large_enough(response.body.content_length());
+        if !compressible {
+            return Ok(response);
+        }
+        add_vary(&mut response);
+        let Some(encoding) = self.negotiate(accept_encoding) else {
+            return Ok(response);
+        };
+
+        let body = std::mem::replace(&mut response.body, Body::Bytes(Vec::new()));
+        response.body = match body {
+            Body::Bytes(bytes) => {
+                let compressed = encoding.encode(&bytes, Level::Default)?;
+                if compressed.len() >= bytes.len() {
+                    response.body = Body::Bytes(bytes);
+                    return Ok(response);
+                }
+                Body::Bytes(compressed)
+            }
+            body => Body::Chunked(Box::new(move |writer| {
+                let mut encoder = Encoder::new(encoding, writer, Level::Default);
+                body.copy_to(&mut encoder)?;
+                encoder.finish()?;
+                Ok(())
+            })),
+        };
+        if let Some(etag) = response.headers.get("ETag") {
+            let etag = cache::variant_etag(etag, encoding.as_str());
+            response.headers.insert("ETag", etag);
+        }
+        response.headers.remove("Accept-Ranges");
+        Ok(response.with_header("Content-Encoding", encoding.as_str()))
+    }
+}
+
+/// Add `Accept-Encoding` to the response's `Vary` header field, unless it is there
+/// already.
+pub fn add_vary(response: &mut Response) {
+    let vary = response.headers.get("Vary").unwrap_or_default();
+    let varies = vary.split(',').any(|name| {
+        let name = name.trim();
+        name == "*" || name.eq_ignore_ascii_case("Accept-Encoding")
+    });
+    if !varies {
+        let vary = match vary.trim() {
+            "" => "Accept-Encoding".to_string(),
+            vary => format!("{vary}, Accept-Encoding"),
+        };
+        response.headers.insert("Vary", vary);
+    }
+}
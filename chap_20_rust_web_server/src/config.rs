@@ -0,0 +1,656 @@
+//! This module contains [`ServerConfig`], the settings of the server binary, and the
+//! [`CommandLine`] it is started with.
+//!
+//! Every setting has a default, which may be overridden, in increasing order of
+//! precedence:
+//! * in a TOML file, `server.toml` if it exists, or the one given with `--config`
+//!   or the `RUST_WEB_SERVER_CONFIG` environment variable, e.g. `pool_size = 8`;
+//! * by an environment variable, named after the setting, e.g.
+//!   `RUST_WEB_SERVER_POOL_SIZE=8`;
+//! * by a command-line flag, also named after the setting, e.g. `--pool-size 8`.
+//!
+//! Optional settings are unset with the value `none`. The resulting configuration is
+//! validated as a whole before the server starts, and may be printed as TOML with
+//! `--print-config`, e.g. as a starting point for a configuration file.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use log::LevelFilter;
+
+use crate::{
+    access_log::AccessLogFormat, compression::Compression, http::RequestLimits,
+    log_rotation::LogRotation, template::TEMPLATES_DIR, Scheduler, ThreadPoolBuildError,
+    ThreadPoolConfig,
+};
+
+/// Configuration file read if none is given, and if it exists.
+pub const DEFAULT_CONFIG_FILE: &str = "server.toml";
+
+/// Environment variable naming the configuration file, like `--config`.
+pub const CONFIG_FILE_ENV_VAR: &str = "RUST_WEB_SERVER_CONFIG";
+
+/// Prefix of the environment variables overriding settings, followed by the setting's
+/// name in upper case.
+pub const ENV_VAR_PREFIX: &str = "RUST_WEB_SERVER_";
+
+/// Names of every setting, as in a configuration file.
+pub const SETTINGS: [&str; 27] = [
+    "addr",
+    "io_mode",
+    "tls_addr",
+    "tls_cert_file",
+    "tls_key_file",
+    "pool_size",
+    "max_pool_size",
+    "worker_idle_timeout_secs",
+    "queue_capacity",
+    "drain_timeout_secs",
+    "max_body_size",
+    "log_file",
+    "log_level",
+    "log_max_size",
+    "log_max_age_secs",
+    "log_retention",
+    "log_compress",
+    "access_log",
+    "access_log_format",
+    "static_root",
+    "templates_dir",
+    "template_reload",
+    "page_cache_control",
+    "static_cache_control",
+    "compress_responses",
+    "compression_min_size",
+    "compression_mime_types",
+];
+
+/// How connections to the HTTP listener are waited on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoMode {
+    /// Each connection is handed to a worker, which blocks on it until it is closed,
+    /// with `util::accept_connections`.
+    Blocking,
+    /// A single thread waits on every connection at once, and workers are only handed
+    /// requests once they have fully arrived, with `event_loop::accept_connections`.
+    /// Suited to many slow or idle clients.
+    EventLoop,
+}
+
+impl IoMode {
+    /// The mode's name, as in a configuration file.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IoMode::Blocking => "blocking",
+            IoMode::EventLoop => "event-loop",
+        }
+    }
+}
+
+/// Settings of the server binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    /// Address to listen on, e.g. `127.0.0.1:7878`.
+    pub addr: String,
+    /// How connections to `addr` are waited on. HTTPS connections are always served
+    /// by blocking workers.
+    pub io_mode: IoMode,
+    /// Address to listen on for HTTPS, if at all, e.g. `127.0.0.1:7879`.
+    pub tls_addr: Option<String>,
+    /// PEM file with the certificate chain presented to HTTPS clients, leaf first.
+    pub tls_cert_file: Option<PathBuf>,
+    /// PEM file with the private key of the certificate.
+    pub tls_key_file: Option<PathBuf>,
+    /// Number of workers serving connections.
+    pub pool_size: usize,
+    /// Number of workers the pool may grow to under bursts of connections.
+    pub max_pool_size: Option<usize>,
+    /// How long the extra workers may be idle before they are retired.
+    pub worker_idle_timeout: Duration,
+    /// Number of connections that may wait for a worker, past which they are turned
+    /// away with `503 Service Unavailable`.
+    pub queue_capacity: Option<usize>,
+    /// How long connections already accepted get to be served, once shutdown is requested.
+    pub drain_timeout: Duration,
+    /// Largest request body accepted, in bytes, past which requests are answered with
+    /// `413 Payload Too Large`, as bodies are read into memory; `multipart/form-data`
+    /// bodies, whose uploads are streamed to disk, aren't bounded by it.
+    pub max_body_size: usize,
+    /// Where diagnostics are logged, besides the terminal.
+    pub log_file: Option<PathBuf>,
+    /// Most verbose level of diagnostics shown in the terminal.
+    pub log_level: LevelFilter,
+    /// How the log file is rotated.
+    pub log_rotation: LogRotation,
+    /// Where every request answered is recorded.
+    pub access_log: Option<PathBuf>,
+    pub access_log_format: AccessLogFormat,
+    /// Directory whose files are served at `/static/...`, if it exists.
+    pub static_root: PathBuf,
+    /// Directory the templates of the pages are loaded from.
+    pub templates_dir: PathBuf,
+    /// Whether templates are parsed again once modified, as is convenient in
+    /// development, rather than only once.
+    pub template_reload: bool,
+    /// `Cache-Control` of the pages served at `/` and `/sleep`, if any.
+    pub page_cache_control: Option<String>,
+    /// `Cache-Control` of the files served at `/static/...`, if any.
+    pub static_cache_control: Option<String>,
+    /// Whether responses are compressed, when clients accept it.
+    pub compress_responses: bool,
+    /// Which responses are compressed, if they are.
+    pub compression: Compression,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            addr: "127.0.0.1:7878".to_string(),
+            io_mode: IoMode::Blocking,
+            tls_addr: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            pool_size: 4,
+            max_pool_size: Some(16),
+            worker_idle_timeout: Duration::from_secs(30),
+            queue_capacity: Some(64),
+            drain_timeout: Duration::from_secs(10),
+            max_body_size: RequestLimits::default().max_body_bytes,
+            log_file: Some(PathBuf::from("rust_web_server.log")),
+            log_level: LevelFilter::Trace,
+            // Rotated daily, or sooner if it reaches 10 MiB, keeping a week's worth.
+            log_rotation: LogRotation {
+                max_size: Some(10 * 1024 * 1024),
+                max_age: Some(Duration::from_secs(24 * 60 * 60)),
+                retention: 7,
+                compress: true,
+            },
+            access_log: Some(PathBuf::from("access.log")),
+            access_log_format: AccessLogFormat::Combined,
+            static_root: PathBuf::from("static"),
+            templates_dir: PathBuf::from(TEMPLATES_DIR),
+            template_reload: false,
+            // Pages are revalidated on every use, static files reused for 5 minutes.
+            page_cache_control: Some("no-cache".to_string()),
+            static_cache_control: Some("public, max-age=300".to_string()),
+            compress_responses: true,
+            compression: Compression::default(),
+        }
+    }
+}
+
+/// Enum representing errors that can occur while loading a [`ServerConfig`], each of
+/// which reads as a message for whoever is starting the server.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    FileReadError(PathBuf, io::Error),
+    /// The configuration file is not valid TOML.
+    FileParseError(PathBuf, toml::de::Error),
+    /// A setting that doesn't exist, and where it was found.
+    UnknownSettingError { key: String, origin: String },
+    /// A setting's value that doesn't make sense, where it was found, and what was
+    /// expected instead.
+    InvalidValueError {
+        key: String,
+        value: String,
+        origin: String,
+        expected: &'static str,
+    },
+    /// A command-line argument that isn't a flag, or not a known one.
+    UnknownArgumentError(String),
+    /// A command-line flag given without its value.
+    MissingArgumentValueError(String),
+    /// A setting that must be set, as another one is.
+    MissingSettingError {
+        key: &'static str,
+        required_by: &'static str,
+    },
+    /// The settings of the thread pool are inconsistent with each other.
+    ThreadPoolError(ThreadPoolBuildError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::FileReadError(path, err) => {
+                write!(f, "could not read configuration file {path:?}: {err}")
+            }
+            ConfigError::FileParseError(path, err) => {
+                write!(f, "configuration file {path:?} is not valid TOML: {err}")
+            }
+            ConfigError::UnknownSettingError { key, origin } => {
+                write!(f, "unknown setting \"{key}\" in {origin}")
+            }
+            ConfigError::InvalidValueError { key, value, origin, expected } => write!(
+                f,
+                "invalid value \"{value}\" for \"{key}\" in {origin}; expected {expected}"
+            ),
+            ConfigError::UnknownArgumentError(arg) => write!(f, "unknown argument \"{arg}\""),
+            ConfigError::MissingArgumentValueError(flag) => {
+                write!(f, "missing value for \"{flag}\"")
+            }
+            ConfigError::MissingSettingError { key, required_by } => {
+                write!(f, "\"{key}\" must be set, as \"{required_by}\" is")
+            }
+            ConfigError::ThreadPoolError(err) => write!(f, "invalid thread pool settings: {err}"),
+        }
+    }
+}
+
+impl From<ThreadPoolBuildError> for ConfigError {
+    fn from(err: ThreadPoolBuildError) -> ConfigError {
+        ConfigError::ThreadPoolError(err)
+    }
+}
+
+impl ServerConfig {
+    /// Load the configuration as described in this module's documentation, from the
+    /// configuration file and settings of `command_line`, and the environment
+    /// variables looked up with `env`, e.g. `|name| std::env::var(name).ok()`.
+    ///
+    /// # Errors
+    ///
+    /// * `FileReadError` if the configuration file given can't be read, or if
+    ///   [`DEFAULT_CONFIG_FILE`] exists, but can't be read;
+    /// * `FileParseError`, `UnknownSettingError` or `InvalidValueError` if a setting
+    ///   is wrong wherever it is found;
+    /// * `ThreadPoolError` if the configuration fails `ServerConfig::validate`.
+    pub fn load(
+        command_line: &CommandLine,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<ServerConfig, ConfigError> {
+        let mut config = ServerConfig::default();
+
+        let config_file = command_line
+            .config_file
+            .clone()
+            .or_else(|| env(CONFIG_FILE_ENV_VAR).map(PathBuf::from));
+        match config_file {
+            Some(path) => config.merge_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                config.merge_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => {}
+        }
+
+        for key in SETTINGS {
+            let var = format!("{ENV_VAR_PREFIX}{}", key.to_uppercase());
+            if let Some(value) = env(&var) {
+                config.set(key, &value, &format!("environment variable {var}"))?;
+            }
+        }
+
+        for (key, value) in &command_line.settings {
+            let origin = format!("command-line flag --{}", key.replace('_', "-"));
+            config.set(key, value, &origin)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Override settings with those of the TOML file at `path`.
+    ///
+    /// Values may be TOML strings, integers or booleans, as appropriate for the
+    /// setting, or a string in any case, e.g. `pool_size = "8"`.
+    ///
+    /// # Errors
+    ///
+    /// `FileReadError`, `FileParseError`, or any of `ServerConfig::set`'s.
+    pub fn merge_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let table: toml::Table = fs::read_to_string(path)
+            .map_err(|err| ConfigError::FileReadError(path.to_path_buf(), err))?
+            .parse()
+            .map_err(|err| ConfigError::FileParseError(path.to_path_buf(), err))?;
+
+        let origin = format!("configuration file {path:?}");
+        for (key, value) in &table {
+            let value = match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Integer(_) | toml::Value::Boolean(_) => value.to_string(),
+                _ => {
+                    return Err(ConfigError::InvalidValueError {
+                        key: key.clone(),
+                        value: value.to_string(),
+                        origin,
+                        expected: "a string, integer or boolean",
+                    })
+                }
+            };
+            self.set(key, &value, &origin)?;
+        }
+        Ok(())
+    }
+
+    /// Set the setting named `key` from its textual `value`, found in `origin`, as
+    /// reported in errors.
+    ///
+    /// # Errors
+    ///
+    /// * `UnknownSettingError` if there is no such setting;
+    /// * `InvalidValueError` if `value` can't be parsed as the setting's type, or is out
+    ///   of its range, e.g. a rotation size of `0`.
+    pub fn set(&mut self, key: &str, value: &str, origin: &str) -> Result<(), ConfigError> {
+        let invalid = |expected| ConfigError::InvalidValueError {
+            key: key.to_string(),
+            value: value.to_string(),
+            origin: origin.to_string(),
+            expected,
+        };
+        const COUNT: &str = "a non-negative integer";
+        const OPTIONAL_COUNT: &str = "a non-negative integer, or \"none\"";
+        const SECONDS: &str = "a number of seconds";
+        const POSITIVE: &str = "a positive integer, or \"none\"";
+        const ADDR: &str = "a host and port, e.g. 127.0.0.1:7878";
+
+        match key {
+            "addr" => {
+                if !is_addr(value) {
+                    return Err(invalid(ADDR));
+                }
+                self.addr = value.to_string();
+            }
+            "io_mode" => {
+                self.io_mode = match value {
+                    "blocking" => IoMode::Blocking,
+                    "event-loop" => IoMode::EventLoop,
+                    _ => return Err(invalid("blocking or event-loop")),
+                }
+            }
+            "tls_addr" => {
+                self.tls_addr = match value {
+                    "none" => None,
+                    value if is_addr(value) => Some(value.to_string()),
+                    _ => return Err(invalid("a host and port, e.g. 127.0.0.1:7879, or \"none\"")),
+                }
+            }
+            "tls_cert_file" => self.tls_cert_file = parse_path(value),
+            "tls_key_file" => self.tls_key_file = parse_path(value),
+            "pool_size" => self.pool_size = parse(value).ok_or_else(|| invalid(COUNT))?,
+            "max_pool_size" => {
+                self.max_pool_size = parse_optional(value).ok_or_else(|| invalid(OPTIONAL_COUNT))?
+            }
+            "worker_idle_timeout_secs" => {
+                self.worker_idle_timeout = parse_secs(value).ok_or_else(|| invalid(SECONDS))?
+            }
+            "queue_capacity" => {
+                self.queue_capacity = parse_optional(value)
+                    .filter(|capacity| *capacity != Some(0))
+                    .ok_or_else(|| invalid(POSITIVE))?
+            }
+            "drain_timeout_secs" => {
+                self.drain_timeout = parse_secs(value).ok_or_else(|| invalid(SECONDS))?
+            }
+            "max_body_size" => self.max_body_size = parse(value).ok_or_else(|| invalid(COUNT))?,
+            "log_file" => self.log_file = parse_path(value),
+            "log_level" => {
+                self.log_level = parse(value).ok_or_else(|| {
+                    invalid("one of off, error, warn, info, debug or trace")
+                })?
+            }
+            "log_max_size" => {
+                self.log_rotation.max_size = parse_optional(value)
+                    .filter(|max_size| *max_size != Some(0))
+                    .ok_or_else(|| invalid(POSITIVE))?
+            }
+            "log_max_age_secs" => {
+                self.log_rotation.max_age = parse_optional(value)
+                    .filter(|secs| *secs != Some(0))
+                    .ok_or_else(|| invalid(POSITIVE))?
+                    .map(Duration::from_secs)
+            }
+            "log_retention" => {
+                self.log_rotation.retention = parse(value).ok_or_else(|| invalid(COUNT))?
+            }
+            "log_compress" => {
+                self.log_rotation.compress = parse(value).ok_or_else(|| invalid("true or false"))?
+            }
+            "access_log" => self.access_log = parse_path(value),
+            "access_log_format" => {
+                self.access_log_format = match value {
+                    "common" => AccessLogFormat::Common,
+                    "combined" => AccessLogFormat::Combined,
+                    "json" => AccessLogFormat::Json,
+                    _ => return Err(invalid("one of common, combined or json")),
+                }
+            }
+            "static_root" => self.static_root = PathBuf::from(value),
+            "templates_dir" => self.templates_dir = PathBuf::from(value),
+            "template_reload" => {
+                self.template_reload = parse(value).ok_or_else(|| invalid("true or false"))?
+            }
+            "page_cache_control" => self.page_cache_control = parse_string(value),
+            "static_cache_control" => self.static_cache_control = parse_string(value),
+            "compress_responses" => {
+                self.compress_responses = parse(value).ok_or_else(|| invalid("true or false"))?
+            }
+            "compression_min_size" => {
+                self.compression.min_size = parse(value).ok_or_else(|| invalid(COUNT))?
+            }
+            "compression_mime_types" => {
+                self.compression.mime_types = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|mime_type| !mime_type.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }
+            _ => {
+                return Err(ConfigError::UnknownSettingError {
+                    key: key.to_string(),
+                    origin: origin.to_string(),
+                })
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the settings are consistent with each other, which `ServerConfig::set`
+    /// can't do one setting at a time.
+    ///
+    /// # Errors
+    ///
+    /// * `MissingSettingError` if `tls_addr` is set, but not `tls_cert_file` and
+    ///   `tls_key_file`;
+    /// * `ThreadPoolError` if `ServerConfig::thread_pool_config` fails
+    ///   `ThreadPoolConfig::validate`, e.g. with a pool size of `0`.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.tls_addr.is_some() {
+            for (key, file) in [
+                ("tls_cert_file", &self.tls_cert_file),
+                ("tls_key_file", &self.tls_key_file),
+            ] {
+                if file.is_none() {
+                    return Err(ConfigError::MissingSettingError { key, required_by: "tls_addr" });
+                }
+            }
+        }
+        self.thread_pool_config().validate()?;
+        Ok(())
+    }
+
+    /// The address to serve HTTPS on, and the certificate chain and private key files
+    /// to do so with, if it is to be served at all.
+    pub fn tls(&self) -> Option<(&str, &Path, &Path)> {
+        match (&self.tls_addr, &self.tls_cert_file, &self.tls_key_file) {
+            (Some(addr), Some(cert_file), Some(key_file)) => Some((addr, cert_file, key_file)),
+            _ => None,
+        }
+    }
+
+    /// Configuration of the thread pool serving connections.
+    pub fn thread_pool_config(&self) -> ThreadPoolConfig {
+        ThreadPoolConfig {
+            size: self.pool_size,
+            max_size: self.max_pool_size,
+            idle_timeout: self.worker_idle_timeout,
+            queue_capacity: self.queue_capacity,
+            // Jobs are whole connections, or requests with the event loop, too long-lived
+            // for work stealing to pay off.
+            scheduler: Scheduler::Channel,
+        }
+    }
+
+    /// The configuration as a TOML file, which loads back into the same configuration.
+    pub fn to_toml(&self) -> String {
+        let string = |s: &str| toml::Value::String(s.to_string()).to_string();
+        let path = |p: &Option<PathBuf>| match p {
+            Some(p) => string(&p.to_string_lossy()),
+            None => string("none"),
+        };
+        let optional = |n: Option<u64>| n.map_or(string("none"), |n| n.to_string());
+
+        let settings = [
+            ("addr", string(&self.addr)),
+            ("io_mode", string(self.io_mode.as_str())),
+            ("tls_addr", string(self.tls_addr.as_deref().unwrap_or("none"))),
+            ("tls_cert_file", path(&self.tls_cert_file)),
+            ("tls_key_file", path(&self.tls_key_file)),
+            ("pool_size", self.pool_size.to_string()),
+            ("max_pool_size", optional(self.max_pool_size.map(|n| n as u64))),
+            ("worker_idle_timeout_secs", self.worker_idle_timeout.as_secs().to_string()),
+            ("queue_capacity", optional(self.queue_capacity.map(|n| n as u64))),
+            ("drain_timeout_secs", self.drain_timeout.as_secs().to_string()),
+            ("max_body_size", self.max_body_size.to_string()),
+            ("log_file", path(&self.log_file)),
+            ("log_level", string(&self.log_level.as_str().to_lowercase())),
+            ("log_max_size", optional(self.log_rotation.max_size)),
+            ("log_max_age_secs", optional(self.log_rotation.max_age.map(|age| age.as_secs()))),
+            ("log_retention", self.log_rotation.retention.to_string()),
+            ("log_compress", self.log_rotation.compress.to_string()),
+            ("access_log", path(&self.access_log)),
+            ("access_log_format", string(self.access_log_format.as_str())),
+            ("static_root", string(&self.static_root.to_string_lossy())),
+            ("templates_dir", string(&self.templates_dir.to_string_lossy())),
+            ("template_reload", self.template_reload.to_string()),
+            ("page_cache_control", string(self.page_cache_control.as_deref().unwrap_or("none"))),
+            ("static_cache_control", string(self.static_cache_control.as_deref().unwrap_or("none"))),
+            ("compress_responses", self.compress_responses.to_string()),
+            ("compression_min_size", self.compression.min_size.to_string()),
+            ("compression_mime_types", string(&self.compression.mime_types.join(","))),
+        ];
+
+        let mut toml = String::new();
+        for (key, value) in settings {
+            toml.push_str(&format!("{key} = {value}\n"));
+        }
+        toml
+    }
+}
+
+/// Whether `value` looks like a host and port, to be bound to.
+fn is_addr(value: &str) -> bool {
+    value
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+}
+
+fn parse<T: FromStr>(value: &str) -> Option<T> {
+    value.parse().ok()
+}
+
+/// Parse a value that may be `none`, returning `None` if it is neither that nor a `T`.
+fn parse_optional<T: FromStr>(value: &str) -> Option<Option<T>> {
+    match value {
+        "none" => Some(None),
+        value => value.parse().ok().map(Some),
+    }
+}
+
+fn parse_secs(value: &str) -> Option<Duration> {
+    value.parse().ok().map(Duration::from_secs)
+}
+
+fn parse_path(value: &str) -> Option<PathBuf> {
+    match value {
+        "none" => None,
+        value => Some(PathBuf::from(value)),
+    }
+}
+
+fn parse_string(value: &str) -> Option<String> {
+    match value {
+        "none" => None,
+        value => Some(value.to_string()),
+    }
+}
+
+/// What the server binary was asked to do on its command line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandLine {
+    /// The configuration file given with `--config`.
+    pub config_file: Option<PathBuf>,
+    /// Settings given as flags, by name and value, in order.
+    pub settings: Vec<(String, String)>,
+    /// Whether to print the configuration, rather than start the server.
+    pub print_config: bool,
+    /// Whether to print `usage`, rather than start the server.
+    pub help: bool,
+}
+
+impl CommandLine {
+    /// Parse the arguments given to the server binary, without the binary's name.
+    ///
+    /// Flags take their value as the next argument, or after `=`, e.g. both
+    /// `--pool-size 8` and `--pool-size=8` work.
+    ///
+    /// # Errors
+    ///
+    /// * `UnknownArgumentError` for an argument that isn't a flag, or a flag for a
+    ///   setting that doesn't exist;
+    /// * `MissingArgumentValueError` for a flag at the end, without its value.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<CommandLine, ConfigError> {
+        let mut command_line = CommandLine::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--print-config" => command_line.print_config = true,
+                "-h" | "--help" => command_line.help = true,
+                _ => {
+                    let Some(flag) = arg.strip_prefix("--") else {
+                        return Err(ConfigError::UnknownArgumentError(arg));
+                    };
+                    let (name, value) = match flag.split_once('=') {
+                        Some((name, value)) => (name, Some(value.to_string())),
+                        None => (flag, None),
+                    };
+                    let key = name.replace('-', "_");
+                    if key != "config" && !SETTINGS.contains(&key.as_str()) {
+                        return Err(ConfigError::UnknownArgumentError(arg));
+                    }
+                    let Some(value) = value.or_else(|| args.next()) else {
+                        return Err(ConfigError::MissingArgumentValueError(arg));
+                    };
+                    if key == "config" {
+                        command_line.config_file = Some(PathBuf::from(value));
+                    } else {
+                        command_line.settings.push((key, value));
+                    }
+                }
+            }
+        }
+        Ok(command_line)
+    }
+}
+
+/// Help text describing the command line of the server binary, named `program`.
+pub fn usage(program: &str) -> String {
+    let mut usage = format!(
+        "Usage: {program} [--config <file>] [--print-config] [--<setting> <value>]...\n\n\
+         Settings are read from {DEFAULT_CONFIG_FILE}, or the file given with --config or\n\
+         {CONFIG_FILE_ENV_VAR}, then from {ENV_VAR_PREFIX}<SETTING> environment variables,\n\
+         then from flags. Optional ones are unset with \"none\". The settings, and their\n\
+         defaults, are:\n\n"
+    );
+    for line in ServerConfig::default().to_toml().lines() {
+        if let Some((key, value)) = line.split_once(" = ") {
+            usage.push_str(&format!("  --{} {value}\n", key.replace('_', "-")));
+        }
+    }
+    usage
+}
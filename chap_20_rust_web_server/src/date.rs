@@ -0,0 +1,204 @@
+//! This module contains [`DateTime`], a UTC calendar date and time, as needed to
+//! timestamp log lines and HTTP messages without depending on a date library.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Abbreviated month names, as used by the Common Log Format and in HTTP dates.
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Abbreviated day names, from Sunday, as used in HTTP dates.
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// A date and time in UTC, to the second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: i64,
+    /// From `1` for January to `12` for December.
+    pub month: u32,
+    /// From `1`.
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    /// The current date and time.
+    pub fn now() -> DateTime {
+        DateTime::from_system_time(SystemTime::now())
+    }
+
+    /// The date and time of `time`, truncated to the second.
+    pub fn from_system_time(time: SystemTime) -> DateTime {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs_f64().ceil() as i64),
+        };
+        DateTime::from_unix_seconds(secs)
+    }
+
+    /// The date and time `secs` seconds after the Unix epoch, which may be negative.
+    pub fn from_unix_seconds(secs: i64) -> DateTime {
+        let days = secs.div_euclid(86_400);
+        let secs_of_day = secs.rem_euclid(86_400) as u32;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: secs_of_day / 3600,
+            minute: secs_of_day / 60 % 60,
+            second: secs_of_day % 60,
+        }
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn unix_seconds(&self) -> i64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+        days * 86_400 + i64::from(self.hour * 3600 + self.minute * 60 + self.second)
+    }
+
+    /// The `SystemTime` of this date and time.
+    pub fn to_system_time(&self) -> SystemTime {
+        let secs = self.unix_seconds();
+        if secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(secs as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+        }
+    }
+
+    /// Abbreviated name of the month, e.g. `Oct`.
+    pub fn month_name(&self) -> &'static str {
+        MONTHS[(self.month as usize - 1) % 12]
+    }
+
+    /// Format as in the Common Log Format, e.g. `10/Oct/2000:13:55:36 +0000`.
+    pub fn to_clf(&self) -> String {
+        format!(
+            "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+            self.day,
+            self.month_name(),
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// Abbreviated name of the day of the week, e.g. `Tue`.
+    pub fn weekday_name(&self) -> &'static str {
+        // The Unix epoch was a Thursday.
+        let days = days_from_civil(self.year, self.month, self.day);
+        WEEKDAYS[(days + 4).rem_euclid(7) as usize]
+    }
+
+    /// Format as an HTTP date, in the IMF-fixdate format of RFC 9110, section 5.6.7,
+    /// e.g. `Tue, 10 Oct 2000 13:55:36 GMT`, as in `Last-Modified`.
+    pub fn to_http_date(&self) -> String {
+        format!(
+            "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+            self.weekday_name(),
+            self.day,
+            self.month_name(),
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// Parse an HTTP date, e.g. from `If-Modified-Since`, in any of the three formats
+    /// RFC 9110 requires recipients to accept:
+    /// * IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`;
+    /// * the obsolete RFC 850 format, e.g. `Sunday, 06-Nov-94 08:49:37 GMT`, whose
+    ///   two-digit year is taken to be within 50 years of now;
+    /// * the obsolete `asctime` format, e.g. `Sun Nov  6 08:49:37 1994`.
+    ///
+    /// The day of the week isn't checked against the date. Returns `None` if `date`
+    /// is in none of these formats, or isn't a valid date.
+    pub fn parse_http_date(date: &str) -> Option<DateTime> {
+        let (weekday, rest) = date.trim().split_once([',', ' '])?;
+        if !WEEKDAYS.iter().any(|day| weekday.starts_with(day)) {
+            return None;
+        }
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        let (year, month, day, time) = match fields[..] {
+            // IMF-fixdate.
+            [day, month, year, time, "GMT"] if year.len() == 4 => {
+                (year.parse().ok()?, month, day, time)
+            }
+            // RFC 850.
+            [date, time, "GMT"] => {
+                let mut parts = date.split('-');
+                let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+                if year.len() != 2 || parts.next().is_some() {
+                    return None;
+                }
+                let this_year = DateTime::now().year;
+                let mut year = this_year - this_year.rem_euclid(100) + year.parse::<i64>().ok()?;
+                if year > this_year + 50 {
+                    year -= 100;
+                }
+                (year, month, day, time)
+            }
+            // asctime.
+            [month, day, time, year] if year.len() == 4 => (year.parse().ok()?, month, day, time),
+            _ => return None,
+        };
+
+        let month = MONTHS.iter().position(|name| *name == month)? as u32 + 1;
+        let day: u32 = day.parse().ok()?;
+        let mut time = time.split(':').map(|part| part.parse::<u32>().ok());
+        let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+        if time.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+        let date_time = DateTime { year, month, day, hour, minute, second: second.min(59) };
+        // Rejects e.g. the 31st of April, which would otherwise roll over into May.
+        (day >= 1 && DateTime::from_unix_seconds(date_time.unix_seconds()) == date_time)
+            .then_some(date_time)
+    }
+
+    /// Format as in RFC 3339, e.g. `2000-10-10T13:55:36Z`.
+    pub fn to_rfc3339(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Convert days since the Unix epoch into a `(year, month, day)` date in the proleptic
+/// Gregorian calendar, as described in Howard Hinnant's `chrono`-compatible
+/// date algorithms.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// The inverse of `civil_from_days`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = i64::from((month + 9) % 12);
+    let day_of_year = (153 * shifted_month + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
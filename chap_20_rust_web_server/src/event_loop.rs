@@ -0,0 +1,533 @@
+//! This module contains an alternative to `util::accept_connections`, which doesn't
+//! tie a worker to every connection.
+//!
+//! With `util::accept_connections`, each connection is a [`Job`](crate::Job) of its
+//! own, so a worker is busy for as long as its client is: while the request trickles
+//! in, and while the connection is kept alive between requests. A handful of slow, or
+//! merely idle, clients are then enough to leave no worker for anyone else.
+//!
+//! Here, a single thread waits on every connection at once, with `mio` (i.e. `epoll`,
+//! `kqueue` or IOCP, depending on the OS), and reads requests as their bytes arrive.
+//! Only once a request was fully read is a job submitted to the
+//! [`ThreadPool`], which routes it and writes the response, as `util::handle_connection`
+//! would, and then hands the connection back to be waited on for the next request.
+//! Connections cost a buffer rather than a thread while idle, so thousands of them can
+//! be kept open by a pool of a few workers.
+//!
+//! `multipart/form-data` requests are the exception: they are handed to a worker once
+//! their header section was read, and their handler reads the body from the connection
+//! itself, as with `util::handle_connection`, so that uploads aren't held in memory.
+//!
+//! Responses are still written by the workers, with blocking writes, so a client slow
+//! to read a large response holds up a worker, within the service's write timeout.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    io::{self, BufRead, Read},
+    mem, net,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use mio::{
+    net::{TcpListener, TcpStream},
+    Events, Interest, Poll, Token, Waker,
+};
+
+use crate::{
+    http::{BodyStream, Framing, Request, RequestParseError, StatusCode},
+    lock,
+    priority::JobOptions,
+    service::Service,
+    util::{self, Outcome},
+    ThreadPool, ThreadPoolError,
+};
+
+/// Token of the listener, readable when connections are waiting to be accepted.
+const LISTENER: Token = Token(0);
+/// Token of the waker, woken by workers handing a connection back.
+const WAKER: Token = Token(1);
+/// Token of the first connection; each one gets a new token when it is registered.
+const FIRST_CONNECTION: usize = 2;
+
+/// Number of bytes read from a connection at once.
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Accept connections on `listener`, and wait on them for requests, submitting a
+/// [`Job`](crate::Job) answering each request to `pool` once it was fully read, until
+/// `service.shutdown` is triggered.
+///
+/// The same limits, keep-alive settings and timeouts as in `util::handle_connection`
+/// apply: a connection idle for longer than the keep-alive idle timeout is closed, and
+/// a client taking longer than the read timeout to send a request is answered with
+/// `408 Request Timeout`. Requests the pool has no room for are answered by
+/// `util::reject_connection`.
+///
+/// `listener` is put in non-blocking mode, as the OS makes it so for every handle to
+/// the same socket.
+///
+/// Once this returns, connections waiting for a request are closed, but the pool may
+/// still be answering some; `ThreadPool::shutdown` is how to wait for those. They are
+/// then closed too, rather than kept alive.
+///
+/// # Errors
+///
+/// If the listener's address can't be gotten, the OS facility waiting on connections
+/// can't be set up, or fails while waiting. As in `util::accept_connections`, failures
+/// to accept or read from connections are logged, and serving continues.
+pub fn accept_connections(
+    listener: &net::TcpListener,
+    pool: &ThreadPool,
+    service: &Arc<Service>,
+) -> io::Result<()> {
+    service.shutdown.watch_listener(listener.local_addr()?);
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener.try_clone()?);
+
+    let poll = Poll::new()?;
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    let (returned_sender, returned) = mpsc::channel();
+
+    let mut event_loop = EventLoop {
+        poll,
+        listener,
+        pool,
+        service,
+        waiting: HashMap::new(),
+        deadlines: BinaryHeap::new(),
+        next_token: FIRST_CONNECTION,
+        waker,
+        returned_sender,
+        returned,
+    };
+    event_loop.run()
+}
+
+/// What is known of a connection besides its stream, which travels along with it to
+/// the worker answering its request, and back.
+struct ConnectionState {
+    /// Bytes read but not yet parsed: the start of the next request, or all of it.
+    buffer: Vec<u8>,
+    peer_addr: Option<net::SocketAddr>,
+    /// Number of requests served on the connection so far.
+    served: usize,
+}
+
+/// A connection registered with the event loop, waiting for a request.
+struct WaitingConnection {
+    stream: TcpStream,
+    state: ConnectionState,
+    /// Whether some of the request has arrived, so that `deadline` is the read
+    /// timeout's rather than the keep-alive idle timeout's.
+    started: bool,
+    /// When the connection is given up on, if the request hasn't fully arrived.
+    deadline: Instant,
+    /// Whether the client closed its end of the connection.
+    closed: bool,
+}
+
+struct EventLoop<'a> {
+    poll: Poll,
+    listener: TcpListener,
+    pool: &'a ThreadPool,
+    service: &'a Arc<Service>,
+    waiting: HashMap<Token, WaitingConnection>,
+    /// Deadlines of waiting connections, earliest first. Deadlines that no longer are
+    /// their connection's, e.g. as the connection was handed to a worker since, are
+    /// only removed once they are reached.
+    deadlines: BinaryHeap<Reverse<(Instant, Token)>>,
+    next_token: usize,
+    /// Woken by workers once they've put a connection in `returned_sender`.
+    waker: Arc<Waker>,
+    returned_sender: mpsc::Sender<(net::TcpStream, ConnectionState)>,
+    /// Connections handed back by workers, to wait on for their next request.
+    returned: mpsc::Receiver<(net::TcpStream, ConnectionState)>,
+}
+
+impl EventLoop<'_> {
+    fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            let timeout = self
+                .deadlines
+                .peek()
+                .map(|Reverse((deadline, _))| deadline.saturating_duration_since(Instant::now()));
+            match self.poll.poll(&mut events, timeout) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+            if self.service.shutdown.is_triggered() {
+                simplelog::info!("No longer accepting connections");
+                return Ok(());
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => self.take_returned(),
+                    token => self.read(token),
+                }
+            }
+            self.expire();
+        }
+    }
+
+    /// Accept every connection waiting on the listener.
+    fn accept(&mut self) {
+        loop {
+            let (stream, peer_addr) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    simplelog::warn!("Could not accept connection. Error: {:?}", err);
+                    return;
+                }
+            };
+            self.service.metrics.record_connection();
+
+            let state = ConnectionState {
+                buffer: Vec::new(),
+                peer_addr: Some(peer_addr),
+                served: 0,
+            };
+            if let Err(err) = self.register(stream, state) {
+                simplelog::warn!("Could not wait on connection. Error: {:?}", err);
+            }
+        }
+    }
+
+    /// Start waiting on `stream` for a request, of which `state.buffer` may already
+    /// hold the start, under a new token, which is returned.
+    fn register(&mut self, mut stream: TcpStream, state: ConnectionState) -> io::Result<Token> {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        self.poll
+            .registry()
+            .register(&mut stream, token, Interest::READABLE)?;
+
+        let started = !state.buffer.is_empty();
+        let timeout = if started {
+            self.service.timeouts.read
+        } else {
+            self.service.keep_alive.idle_timeout
+        };
+        let deadline = Instant::now() + timeout;
+        self.deadlines.push(Reverse((deadline, token)));
+        self.waiting.insert(
+            token,
+            WaitingConnection { stream, state, started, deadline, closed: false },
+        );
+        Ok(token)
+    }
+
+    /// Wait again on the connections workers kept alive.
+    fn take_returned(&mut self) {
+        while let Ok((stream, state)) = self.returned.try_recv() {
+            match self.register(TcpStream::from_std(stream), state) {
+                // The next request may have arrived while the worker was busy, or even
+                // along with the previous one, in which case no event is coming.
+                Ok(token) => self.read(token),
+                Err(err) => simplelog::warn!("Could not wait on connection. Error: {:?}", err),
+            }
+        }
+    }
+
+    /// Read whatever arrived on the connection with `token`, and hand its request to
+    /// a worker if it is complete, or as soon as its header section is, if its body is
+    /// left for the handler to read.
+    fn read(&mut self, token: Token) {
+        // Events for connections handed to a worker since are ignored.
+        let Some(connection) = self.waiting.get_mut(&token) else {
+            return;
+        };
+        let limits = &self.service.limits;
+        // Past this, the request can only be too large; reading stops there, so that a
+        // client can't make the buffer grow without bounds.
+        let max_len = limits.max_header_bytes + limits.max_body_bytes + READ_CHUNK_SIZE;
+
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        // Whether the header section has arrived, or can't be parsed anyway, so that
+        // it isn't parsed again for every chunk of the body.
+        let mut head_read = false;
+        while connection.state.buffer.len() < max_len {
+            match connection.stream.read(&mut chunk) {
+                Ok(0) => {
+                    connection.closed = true;
+                    break;
+                }
+                Ok(read) => {
+                    connection.state.buffer.extend_from_slice(&chunk[..read]);
+                    // A body left for the handler to read is left in the connection,
+                    // rather than buffered here.
+                    if !head_read {
+                        match Request::read_head_from(&mut &connection.state.buffer[..], limits) {
+                            Ok((request, _)) if request.streams_body() => break,
+                            Err(err) if is_incomplete(&err) => {}
+                            _ => head_read = true,
+                        }
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    simplelog::warn!("Could not read from connection. Error: {:?}", err);
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+
+        if connection.state.buffer.is_empty() {
+            if connection.closed {
+                self.close(token);
+            }
+            return;
+        }
+        if !connection.started {
+            connection.started = true;
+            connection.deadline = Instant::now() + self.service.timeouts.read;
+            self.deadlines.push(Reverse((connection.deadline, token)));
+        }
+
+        // A body left for the handler to read only needs the header section to have
+        // arrived.
+        let mut unparsed = &connection.state.buffer[..];
+        let parsed = match Request::read_head_from(&mut unparsed, limits) {
+            Ok((request, framing)) if request.streams_body() => Ok((request, Some(framing))),
+            _ => {
+                unparsed = &connection.state.buffer[..];
+                Request::read_from(&mut unparsed, limits).map(|request| (request, None))
+            }
+        };
+        match parsed {
+            Ok((request, framing)) => {
+                let consumed = connection.state.buffer.len() - unparsed.len();
+                connection.state.buffer.drain(..consumed);
+                self.dispatch_with_body(token, Ok(request), framing);
+            }
+            Err(err) if is_incomplete(&err) => self.wait_for_rest(token, max_len),
+            Err(err) if err.status().is_some() => self.dispatch(token, Err(err)),
+            Err(_) => self.close(token),
+        }
+    }
+
+    /// Keep waiting for the rest of the request on the connection with `token`,
+    /// unless it can't come.
+    fn wait_for_rest(&mut self, token: Token, max_len: usize) {
+        let Some(connection) = self.waiting.get(&token) else {
+            return;
+        };
+        if connection.closed {
+            self.close(token);
+        } else if connection.state.buffer.len() >= max_len {
+            self.dispatch(token, Err(RequestParseError::PayloadTooLarge));
+        }
+    }
+
+    /// Close the connections whose deadline has passed: those idle since their last
+    /// request silently, and those in the middle of one with `408 Request Timeout`.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        while let Some(&Reverse((deadline, token))) = self.deadlines.peek() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop();
+            match self.waiting.get(&token) {
+                Some(connection) if connection.deadline == deadline => {
+                    if connection.started {
+                        self.dispatch(token, Err(RequestParseError::RequestTimeout));
+                    } else {
+                        self.close(token);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Stop waiting on the connection with `token`, and close it.
+    fn close(&mut self, token: Token) {
+        if let Some(mut connection) = self.waiting.remove(&token) {
+            // Dropping the stream would do, but mio asks for sources to be deregistered.
+            let _ = self.poll.registry().deregister(&mut connection.stream);
+        }
+    }
+
+    /// Submit a job answering `parsed`, the request read from the connection with
+    /// `token`, to the pool, which hands the connection back if it is kept alive.
+    ///
+    /// The job is submitted with the options the service's `job_policy` gives the
+    /// request, e.g. ahead of others; requests that couldn't be parsed, and those given
+    /// the default options, go straight into the pool's queue.
+    fn dispatch(&mut self, token: Token, parsed: Result<Request, RequestParseError>) {
+        self.dispatch_with_body(token, parsed, None);
+    }
+
+    /// Like `dispatch`, except that a request whose body, framed as `framing`, wasn't
+    /// read, is given a `body_stream` to read it from the connection with, starting
+    /// with what is left of the connection's buffer.
+    fn dispatch_with_body(
+        &mut self,
+        token: Token,
+        parsed: Result<Request, RequestParseError>,
+        framing: Option<Framing>,
+    ) {
+        let Some(WaitingConnection { mut stream, mut state, .. }) = self.waiting.remove(&token)
+        else {
+            return;
+        };
+        let received_at = Instant::now();
+        let stream = match self
+            .poll
+            .registry()
+            .deregister(&mut stream)
+            .map(|()| net::TcpStream::from(stream))
+            .and_then(|stream| blocking(stream, self.service.timeouts.write))
+        {
+            Ok(stream) => stream,
+            Err(err) => {
+                simplelog::warn!("Could not hand connection to a worker. Error: {:?}", err);
+                return;
+            }
+        };
+
+        // The job takes ownership of the stream, so a handle to it is kept to be
+        // able to answer the client if the job is rejected.
+        let rejection_stream = stream.try_clone();
+        let service = Arc::clone(self.service);
+        let waker = Arc::clone(&self.waker);
+        let returned_sender = self.returned_sender.clone();
+        let options = match &parsed {
+            Ok(request) => (self.service.job_policy)(request),
+            Err(_) => JobOptions::default(),
+        };
+        let job = move || {
+            let mut stream = stream;
+            let mut parsed = parsed;
+            let source = match (&mut parsed, framing) {
+                (Ok(request), Some(framing)) => {
+                    stream.set_read_timeout(Some(service.timeouts.read))?;
+                    let source = Arc::new(Mutex::new(BodySource {
+                        buffered: mem::take(&mut state.buffer),
+                        consumed: 0,
+                        stream: io::BufReader::new(stream.try_clone()?),
+                    }));
+                    request.body_stream = Some(BodyStream::new(source.clone(), framing));
+                    Some(source)
+                }
+                _ => None,
+            };
+            let outcome = util::respond(
+                parsed,
+                received_at,
+                &mut state.served,
+                state.peer_addr,
+                &mut stream,
+                &service,
+            )?;
+            if outcome == Outcome::KeepAlive {
+                if let Some(source) = source {
+                    // What was read past the body is the start of the next request.
+                    state.buffer = lock(&source).unread();
+                }
+                stream.set_nonblocking(true)?;
+                // The event loop is gone once shutting down, and the connection is then
+                // just closed.
+                if returned_sender.send((stream, state)).is_ok() {
+                    waker.wake()?;
+                }
+            }
+            Ok(())
+        };
+        let execution_res = if options == JobOptions::default() {
+            self.pool.try_execute(job)
+        } else {
+            self.pool.try_execute_with(options, job)
+        };
+
+        match execution_res {
+            Ok(()) => {}
+            Err(ThreadPoolError::JobQueueFullError) => {
+                simplelog::warn!("job queue is full; rejecting connection");
+                let rejection_res = rejection_stream
+                    .and_then(|s| util::reject_connection(s, self.service.retry_after));
+                match rejection_res {
+                    Ok(()) => self
+                        .service
+                        .metrics
+                        .record_response(StatusCode::SERVICE_UNAVAILABLE),
+                    Err(err) => simplelog::warn!("problem rejecting connection; {:?}", err),
+                }
+            }
+            Err(err) => simplelog::warn!("problem sending job to pool; {:?}", err),
+        }
+    }
+}
+
+/// Whether `err` only means that the request hasn't fully arrived yet: that only empty
+/// lines, or part of a request, have.
+fn is_incomplete(err: &RequestParseError) -> bool {
+    match err {
+        RequestParseError::ConnectionClosed => true,
+        RequestParseError::Io(err) => err.kind() == io::ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+/// What a worker reads the body of a request from, when it is left for the handler to
+/// read: the bytes the event loop read past the header section, and then the connection.
+struct BodySource {
+    buffered: Vec<u8>,
+    /// How many of the `buffered` bytes were read.
+    consumed: usize,
+    stream: io::BufReader<net::TcpStream>,
+}
+
+impl BodySource {
+    /// The bytes read from the connection, but not from this source, i.e. the start of
+    /// the next request, once the body was read.
+    fn unread(&self) -> Vec<u8> {
+        [&self.buffered[self.consumed..], self.stream.buffer()].concat()
+    }
+}
+
+impl Read for BodySource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.fill_buf()?.read(buf)?;
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for BodySource {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.consumed < self.buffered.len() {
+            Ok(&self.buffered[self.consumed..])
+        } else {
+            self.stream.fill_buf()
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if self.consumed < self.buffered.len() {
+            self.consumed += amt;
+        } else {
+            self.stream.consume(amt);
+        }
+    }
+}
+
+/// Make `stream` blocking, for a worker to write a response into it, within
+/// `write_timeout`.
+fn blocking(stream: net::TcpStream, write_timeout: Duration) -> io::Result<net::TcpStream> {
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(write_timeout))?;
+    Ok(stream)
+}
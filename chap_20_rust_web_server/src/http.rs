@@ -0,0 +1,1146 @@
+//! This module contains the HTTP/1.1 types the server works with.
+//!
+//! There's:
+//! * a [`Request`] type, parsed incrementally from anything implementing `io::BufRead`
+//!   (in the server's case, a `net::TcpStream` wrapped in an `io::BufReader`),
+//! * a [`RequestParseError`] describing why a request could not be parsed, which
+//!   maps onto the status code the client should receive, and
+//! * a [`BodyStream`], through which the handler of a `multipart/form-data` request
+//!   reads its body from the connection, rather than from memory, and
+//! * a [`Response`] type, which knows how to serialize itself into a socket, and whose
+//!   [`Body`] is either held in memory or streamed from e.g. a file.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, prelude::*},
+    net,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::{cancellation::CancellationToken, lock};
+
+/// HTTP request methods understood by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    /// The method's name, as it appears in a request line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = RequestParseError;
+
+    /// Method names are case-sensitive, so `get` is not the same as `GET`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "CONNECT" => Ok(Method::Connect),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "PATCH" => Ok(Method::Patch),
+            _ => Err(RequestParseError::UnknownMethod),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// HTTP protocol versions accepted in a request line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    /// The version, as it appears in request and status lines.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+/// An ordered collection of HTTP header fields.
+///
+/// Field names are compared case-insensitively, as per RFC 9110, but are stored
+/// as they were received or inserted. A `Vec` is used rather than a map because
+/// requests rarely carry more than a couple dozen fields, and some fields may
+/// legitimately appear more than once.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// Create an empty header collection.
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// Value of the first field with the given name, if any.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Values of every field with the given name, in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether at least one field with the given name is present.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Add a field, keeping any existing fields with the same name.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Set a field, replacing any existing fields with the same name.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    /// Remove every field with the given name.
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// Iterate over `(name, value)` pairs, in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Limits applied while parsing a [`Request`], so that a client cannot make a worker
+/// buffer arbitrary amounts of data.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    /// Maximum size in bytes of the request line plus every header line, including
+    /// line terminators. Exceeding it results in `RequestParseError::HeadersTooLarge`.
+    pub max_header_bytes: usize,
+    /// Maximum size in bytes of the request body. Exceeding it results in
+    /// `RequestParseError::PayloadTooLarge`.
+    ///
+    /// `multipart/form-data` bodies the server streams to their handler through
+    /// [`BodyStream`] aren't bound by it, but by the
+    /// [`UploadLimits`](crate::body::UploadLimits) they are parsed with.
+    pub max_body_bytes: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_header_bytes: 8 * 1024,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Enum representing the ways reading a [`Request`] from a stream can fail.
+///
+/// Apart from `ConnectionClosed`, `IdleTimeout` and `Io`, every variant is the
+/// client's fault, and `RequestParseError::status` gives the status code it should
+/// be answered with.
+#[derive(Debug)]
+pub enum RequestParseError {
+    /// The peer closed the connection before sending a single byte. This is not
+    /// really an error, and no response should be written.
+    ConnectionClosed,
+
+    /// The stream's read timeout expired before the first byte of a request was
+    /// received, e.g. on an idle persistent connection. As with `ConnectionClosed`,
+    /// the connection should just be closed.
+    IdleTimeout,
+
+    /// Reading from the underlying stream failed, or it ended in the middle of
+    /// a request (`io::ErrorKind::UnexpectedEof`).
+    Io(io::Error),
+
+    /// The request line isn't of the form `METHOD SP request-target SP HTTP-version`.
+    MalformedRequestLine,
+
+    /// The method in the request line isn't one of [`Method`]'s variants.
+    UnknownMethod,
+
+    /// The request line's version is neither `HTTP/1.0` nor `HTTP/1.1`.
+    UnsupportedVersion,
+
+    /// A header line has no colon, an empty or invalid field name, or is an
+    /// obsolete folded continuation line.
+    MalformedHeader,
+
+    /// The request line and headers together exceed `RequestLimits::max_header_bytes`.
+    HeadersTooLarge,
+
+    /// `Content-Length` is not a number, appears several times with different values,
+    /// or appears along with `Transfer-Encoding`, which RFC 9112 warns could be an
+    /// attempt at request smuggling.
+    InvalidContentLength,
+
+    /// The request uses a `Transfer-Encoding` other than `chunked`.
+    UnsupportedTransferEncoding,
+
+    /// A `chunked` body's chunk-size line isn't a hexadecimal number, or a chunk
+    /// isn't followed by a line terminator.
+    MalformedChunk,
+
+    /// The declared body is larger than `RequestLimits::max_body_bytes`.
+    PayloadTooLarge,
+
+    /// The stream's read timeout expired after the request had started arriving,
+    /// i.e. the client is too slow to send it, or stalled halfway.
+    RequestTimeout,
+}
+
+impl RequestParseError {
+    /// The status code the client should be answered with, or `None` if the
+    /// connection should simply be closed.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            RequestParseError::ConnectionClosed
+            | RequestParseError::IdleTimeout
+            | RequestParseError::Io(_) => None,
+            RequestParseError::MalformedRequestLine
+            | RequestParseError::UnknownMethod
+            | RequestParseError::MalformedHeader
+            | RequestParseError::InvalidContentLength
+            | RequestParseError::MalformedChunk => Some(StatusCode::BAD_REQUEST),
+            RequestParseError::UnsupportedVersion => Some(StatusCode::HTTP_VERSION_NOT_SUPPORTED),
+            RequestParseError::HeadersTooLarge => Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            RequestParseError::UnsupportedTransferEncoding => Some(StatusCode::NOT_IMPLEMENTED),
+            RequestParseError::PayloadTooLarge => Some(StatusCode::PAYLOAD_TOO_LARGE),
+            RequestParseError::RequestTimeout => Some(StatusCode::REQUEST_TIMEOUT),
+        }
+    }
+}
+
+/// Timeouts are told apart from other errors, as they call for a `408 Request Timeout`.
+impl From<io::Error> for RequestParseError {
+    fn from(err: io::Error) -> Self {
+        if is_timeout(&err) {
+            RequestParseError::RequestTimeout
+        } else {
+            RequestParseError::Io(err)
+        }
+    }
+}
+
+/// An HTTP request, as received from a client.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    /// The request target exactly as it appeared in the request line, e.g. `/users?id=3`.
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    /// The request body; empty unless the request carried a `Content-Length`, or
+    /// was chunked, and empty too if it is read through `body_stream` instead.
+    pub body: Vec<u8>,
+    /// The rest of the connection the request was read from, from which its body is
+    /// to be read, if it wasn't read into `body`. The server leaves
+    /// `multipart/form-data` bodies there; see `Request::multipart`.
+    pub body_stream: Option<BodyStream>,
+    /// Values captured from the path by the route pattern the request matched,
+    /// already percent-decoded. Filled in by `Router::handle`.
+    pub params: HashMap<String, String>,
+    /// Address of the client that sent the request, if known. Filled in by
+    /// `util::handle_connection`.
+    pub peer_addr: Option<net::SocketAddr>,
+    /// Cancelled once the handler serving the request should give up on it; see the
+    /// [`cancellation`](crate::cancellation) module. Set by `util::handle_connection`
+    /// to expire after the service's handler timeout, and never cancelled otherwise.
+    pub cancellation: CancellationToken,
+}
+
+impl Request {
+    /// Create an `HTTP/1.1` request with no headers and an empty body.
+    pub fn new(method: Method, target: impl Into<String>) -> Request {
+        Request {
+            method,
+            target: target.into(),
+            version: Version::Http11,
+            headers: Headers::new(),
+            body: Vec::new(),
+            body_stream: None,
+            params: HashMap::new(),
+            peer_addr: None,
+            cancellation: CancellationToken::new(),
+        }
+    }
+
+    /// Read a single request from `reader`.
+    ///
+    /// Parsing is incremental: the request line and headers are read line by line
+    /// until the empty line terminating them, and then exactly as many body bytes
+    /// as `Content-Length` declares, or, for `Transfer-Encoding: chunked` bodies, chunks
+    /// until the last one, which are decoded into `Request::body`. Trailer fields sent
+    /// after the last chunk are discarded. Any bytes following the request are left
+    /// unread in `reader`, so the same reader can be used to read the next request
+    /// on the connection.
+    ///
+    /// Empty lines preceding the request line are ignored, as RFC 9112 recommends.
+    ///
+    /// # Errors
+    ///
+    /// * `RequestParseError::ConnectionClosed` if `reader` is at EOF before the first byte;
+    /// * `RequestParseError::IdleTimeout` if reading times out before the first byte, and
+    ///   `RequestParseError::RequestTimeout` if it does afterwards;
+    /// * `RequestParseError::Io` if reading fails otherwise, or `reader` ends mid-request;
+    /// * any other variant if the request is malformed or exceeds `limits`.
+    ///
+    /// # Panics
+    ///
+    /// This function does not panic.
+    pub fn read_from<R: BufRead>(
+        reader: &mut R,
+        limits: &RequestLimits,
+    ) -> Result<Request, RequestParseError> {
+        let (mut request, framing) = Request::read_head_from(reader, limits)?;
+        request.body = read_body(reader, framing, limits)?;
+        Ok(request)
+    }
+
+    /// Like `read_from`, except that the body of a `multipart/form-data` request is
+    /// left in `source`, to be read by the handler through the request's `body_stream`,
+    /// and so isn't bound by `limits.max_body_bytes`. `source` is locked while the
+    /// rest of the request is read.
+    pub(crate) fn read_streaming_from<R: BufRead + Send + 'static>(
+        source: &Arc<Mutex<R>>,
+        limits: &RequestLimits,
+    ) -> Result<Request, RequestParseError> {
+        let mut reader = lock(source);
+        let (mut request, framing) = Request::read_head_from(&mut *reader, limits)?;
+        if request.streams_body() {
+            request.body_stream = Some(BodyStream::new(source.clone(), framing));
+        } else {
+            request.body = read_body(&mut *reader, framing, limits)?;
+        }
+        Ok(request)
+    }
+
+    /// Read the request line and headers of a request from `reader`, as `read_from`
+    /// does, leaving its body, framed as returned, unread.
+    pub(crate) fn read_head_from<R: BufRead>(
+        reader: &mut R,
+        limits: &RequestLimits,
+    ) -> Result<(Request, Framing), RequestParseError> {
+        let mut budget = limits.max_header_bytes;
+
+        let mut request_line = read_line(reader, &mut budget, true)?;
+        while request_line.is_empty() {
+            request_line = read_line(reader, &mut budget, true)?;
+        }
+        let (method, target, version) = parse_request_line(&request_line)?;
+
+        let mut headers = Headers::new();
+        loop {
+            let line = read_line(reader, &mut budget, false)?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = parse_header_line(&line)?;
+            headers.append(name, value);
+        }
+
+        let framing = if headers.contains("Transfer-Encoding") {
+            if !is_chunked(&headers) {
+                return Err(RequestParseError::UnsupportedTransferEncoding);
+            }
+            if headers.contains("Content-Length") {
+                return Err(RequestParseError::InvalidContentLength);
+            }
+            Framing::Chunked { header_budget: budget }
+        } else {
+            Framing::Length(content_length(&headers)?.unwrap_or(0))
+        };
+
+        let request = Request {
+            method,
+            target,
+            version,
+            headers,
+            body: Vec::new(),
+            body_stream: None,
+            params: HashMap::new(),
+            peer_addr: None,
+            cancellation: CancellationToken::new(),
+        };
+        Ok((request, framing))
+    }
+
+    /// Whether the request's body is left for its handler to read through a
+    /// [`BodyStream`] when read by `read_streaming_from`, which is the case of
+    /// `multipart/form-data` bodies, whose files are stored as they arrive.
+    pub(crate) fn streams_body(&self) -> bool {
+        self.headers
+            .get("Content-Type")
+            .and_then(|value| value.split(';').next())
+            .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("multipart/form-data"))
+    }
+
+    /// The path component of the request target, i.e. everything before the first `?`.
+    pub fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _)) => path,
+            None => &self.target,
+        }
+    }
+
+    /// The query component of the request target, i.e. everything after the first `?`.
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    /// Whether the client asked for the connection to be kept open after this request.
+    ///
+    /// Connections are persistent by default in HTTP/1.1, unless the client sends
+    /// `Connection: close`; in HTTP/1.0 it is the opposite, and the client must
+    /// send `Connection: keep-alive`.
+    pub fn wants_keep_alive(&self) -> bool {
+        let has_option = |option: &str| {
+            self.headers
+                .get_all("Connection")
+                .flat_map(|value| value.split(','))
+                .any(|token| token.trim().eq_ignore_ascii_case(option))
+        };
+
+        match self.version {
+            Version::Http11 => !has_option("close"),
+            Version::Http10 => has_option("keep-alive"),
+        }
+    }
+
+    /// Value of a parameter captured by the matched route pattern, e.g. `id`
+    /// for a request to `/users/7` matching `/users/:id`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+}
+
+/// Decode `%XX` escapes in a URL component.
+///
+/// Returns `None` if an escape is truncated, isn't hexadecimal, or the decoded
+/// bytes aren't valid UTF-8.
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Read one line, without its terminator, consuming at most `budget` bytes.
+///
+/// Both `\r\n` and a bare `\n` are accepted as terminators.
+fn read_line<R: BufRead>(
+    reader: &mut R,
+    budget: &mut usize,
+    first: bool,
+) -> Result<String, RequestParseError> {
+    let mut line = Vec::new();
+    // Reading one byte past the budget is how a line exceeding it is told apart
+    // from one that fits exactly.
+    let read = match reader
+        .by_ref()
+        .take(*budget as u64 + 1)
+        .read_until(b'\n', &mut line)
+    {
+        Err(err) if first && line.is_empty() && is_timeout(&err) => {
+            return Err(RequestParseError::IdleTimeout)
+        }
+        read => read?,
+    };
+
+    if read == 0 {
+        return Err(if first {
+            RequestParseError::ConnectionClosed
+        } else {
+            RequestParseError::Io(io::ErrorKind::UnexpectedEof.into())
+        });
+    }
+    if read > *budget {
+        return Err(RequestParseError::HeadersTooLarge);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(RequestParseError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    *budget -= read;
+
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| {
+        if first {
+            RequestParseError::MalformedRequestLine
+        } else {
+            RequestParseError::MalformedHeader
+        }
+    })
+}
+
+/// Whether `err` is what reading from a socket whose read timeout expired returns,
+/// which differs between platforms.
+pub fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, String, Version), RequestParseError> {
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) if !m.is_empty() && !t.is_empty() => (m, t, v),
+        _ => return Err(RequestParseError::MalformedRequestLine),
+    };
+
+    let method = method.parse()?;
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        v if v.starts_with("HTTP/") => return Err(RequestParseError::UnsupportedVersion),
+        _ => return Err(RequestParseError::MalformedRequestLine),
+    };
+
+    Ok((method, target.to_string(), version))
+}
+
+fn parse_header_line(line: &str) -> Result<(&str, &str), RequestParseError> {
+    // Lines starting with whitespace are obsolete line folding, which RFC 9112
+    // allows servers to reject.
+    if line.starts_with([' ', '\t']) {
+        return Err(RequestParseError::MalformedHeader);
+    }
+    let (name, value) = line
+        .split_once(':')
+        .ok_or(RequestParseError::MalformedHeader)?;
+    if name.is_empty() || !name.bytes().all(is_token_byte) {
+        return Err(RequestParseError::MalformedHeader);
+    }
+
+    Ok((name, value.trim_matches([' ', '\t'])))
+}
+
+/// Whether `b` may appear in a header field name, which RFC 9110 calls a `token`.
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Whether the request's transfer codings are exactly `chunked`, the only one supported.
+fn is_chunked(headers: &Headers) -> bool {
+    let mut codings = headers
+        .get_all("Transfer-Encoding")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty());
+
+    matches!(
+        (codings.next(), codings.next()),
+        (Some(coding), None) if coding.eq_ignore_ascii_case("chunked")
+    )
+}
+
+/// Maximum length of a chunk-size line, extensions included.
+const MAX_CHUNK_LINE_BYTES: usize = 1024;
+
+/// How the body following a request's header section is delimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    /// By its `Content-Length`, or lack thereof for an empty body.
+    Length(usize),
+    /// By `Transfer-Encoding: chunked`, what is left of the header budget then being
+    /// what trailer fields may take.
+    Chunked { header_budget: usize },
+}
+
+/// Read a body framed as `framing` from `reader`, within `limits.max_body_bytes`.
+fn read_body<R: BufRead>(
+    reader: &mut R,
+    framing: Framing,
+    limits: &RequestLimits,
+) -> Result<Vec<u8>, RequestParseError> {
+    match framing {
+        Framing::Length(content_length) => {
+            if content_length > limits.max_body_bytes {
+                return Err(RequestParseError::PayloadTooLarge);
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+            Ok(body)
+        }
+        Framing::Chunked { mut header_budget } => {
+            read_chunked_body(reader, &mut header_budget, limits)
+        }
+    }
+}
+
+/// Decode a `chunked` body, as described in RFC 9112, section 7.1.
+///
+/// Chunk extensions are ignored. Trailer fields count towards the header budget
+/// left over from the request's header section.
+fn read_chunked_body<R: BufRead>(
+    reader: &mut R,
+    header_budget: &mut usize,
+    limits: &RequestLimits,
+) -> Result<Vec<u8>, RequestParseError> {
+    let mut body = Vec::new();
+
+    loop {
+        let size = read_chunk_size(reader)?;
+        if size == 0 {
+            break;
+        }
+
+        if body.len().saturating_add(size) > limits.max_body_bytes {
+            return Err(RequestParseError::PayloadTooLarge);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+
+        read_chunk_end(reader)?;
+    }
+
+    read_trailers(reader, header_budget)?;
+    Ok(body)
+}
+
+/// Read a chunk-size line, returning the size of the chunk following it.
+fn read_chunk_size<R: BufRead>(reader: &mut R) -> Result<usize, RequestParseError> {
+    let mut line_budget = MAX_CHUNK_LINE_BYTES;
+    let line = read_line(reader, &mut line_budget, false).map_err(|err| match err {
+        RequestParseError::HeadersTooLarge | RequestParseError::MalformedHeader => {
+            RequestParseError::MalformedChunk
+        }
+        err => err,
+    })?;
+    let size = line.split(';').next().unwrap_or_default().trim();
+    if size.is_empty() || size.len() > 16 || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(RequestParseError::MalformedChunk);
+    }
+    usize::from_str_radix(size, 16).map_err(|_| RequestParseError::MalformedChunk)
+}
+
+/// Read the line terminator following a chunk's data.
+fn read_chunk_end<R: BufRead>(reader: &mut R) -> Result<(), RequestParseError> {
+    let mut line_budget = 2;
+    if !read_line(reader, &mut line_budget, false)
+        .map_err(|err| match err {
+            RequestParseError::RequestTimeout => err,
+            _ => RequestParseError::MalformedChunk,
+        })?
+        .is_empty()
+    {
+        return Err(RequestParseError::MalformedChunk);
+    }
+    Ok(())
+}
+
+/// Read the trailer section following the last chunk, discarding its fields.
+fn read_trailers<R: BufRead>(reader: &mut R, header_budget: &mut usize) -> Result<(), RequestParseError> {
+    loop {
+        let line = read_line(reader, header_budget, false)?;
+        if line.is_empty() {
+            return Ok(());
+        }
+        parse_header_line(&line)?;
+    }
+}
+
+/// The body of a [`Request`], read from the connection as the handler asks for it,
+/// rather than into `Request::body` beforehand; `Request::multipart` reads from it
+/// so that uploads are bound by `UploadLimits` alone.
+///
+/// The body's framing is decoded, so reading stops at its end, leaving whatever
+/// follows on the connection, i.e. the next request, unread. What the handler leaves
+/// of the body is read past by the server, within `RequestLimits::max_body_bytes`,
+/// failing which the connection is closed after the response.
+///
+/// Clones read from the same body, on from where any of them stopped.
+#[derive(Clone)]
+pub struct BodyStream {
+    source: Arc<Mutex<dyn BufRead + Send>>,
+    state: Arc<Mutex<BodyState>>,
+}
+
+/// How much of a [`BodyStream`] is left to read.
+#[derive(Debug)]
+enum BodyState {
+    /// This many bytes of a body with a `Content-Length`.
+    Length(usize),
+    /// This many bytes of the current chunk of a `chunked` body, or `None` before
+    /// the first one, followed by chunks whose trailer fields may take `header_budget`.
+    Chunk { left: Option<usize>, header_budget: usize },
+    /// Nothing, the whole body was read.
+    Done,
+}
+
+impl BodyStream {
+    /// A stream of the body framed as `framing` at the start of `source`.
+    pub(crate) fn new(source: Arc<Mutex<dyn BufRead + Send>>, framing: Framing) -> BodyStream {
+        let state = match framing {
+            Framing::Length(content_length) => BodyState::Length(content_length),
+            Framing::Chunked { header_budget } => BodyState::Chunk { left: None, header_budget },
+        };
+        BodyStream { source, state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// Whether the body was read to its end, so that the next request on the
+    /// connection can be read.
+    pub fn is_finished(&self) -> bool {
+        matches!(*lock(&self.state), BodyState::Length(0) | BodyState::Done)
+    }
+}
+
+/// A malformed chunk fails reads with `io::ErrorKind::InvalidData`, and a timeout
+/// with `io::ErrorKind::TimedOut`.
+impl Read for BodyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Reading nothing mustn't wait for the source to have something.
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut state = lock(&self.state);
+        let mut guard = lock(&self.source);
+        let mut source: &mut dyn BufRead = &mut *guard;
+        loop {
+            let left = match &mut *state {
+                BodyState::Length(0) | BodyState::Done => return Ok(0),
+                BodyState::Length(left) | BodyState::Chunk { left: Some(left @ 1..), .. } => left,
+                BodyState::Chunk { left, header_budget } => {
+                    if left.is_some() {
+                        read_chunk_end(&mut source).map_err(into_io_error)?;
+                    }
+                    let size = read_chunk_size(&mut source).map_err(into_io_error)?;
+                    if size == 0 {
+                        read_trailers(&mut source, header_budget).map_err(into_io_error)?;
+                        *state = BodyState::Done;
+                    } else {
+                        *left = Some(size);
+                    }
+                    continue;
+                }
+            };
+            let max = buf.len().min(*left);
+            let read = source.read(&mut buf[..max])?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            *left -= read;
+            return Ok(read);
+        }
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyStream")
+            .field("state", &*lock(&self.state))
+            .finish_non_exhaustive()
+    }
+}
+
+/// The error a [`BodyStream`] fails a read with, when `err` is how the body is malformed.
+fn into_io_error(err: RequestParseError) -> io::Error {
+    match err {
+        RequestParseError::Io(err) => err,
+        RequestParseError::RequestTimeout => io::ErrorKind::TimedOut.into(),
+        err => io::Error::new(io::ErrorKind::InvalidData, format!("{err:?}")),
+    }
+}
+
+fn content_length(headers: &Headers) -> Result<Option<usize>, RequestParseError> {
+    let mut length = None;
+    for value in headers.get_all("Content-Length") {
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(RequestParseError::InvalidContentLength);
+        }
+        let parsed = value
+            .parse()
+            .map_err(|_| RequestParseError::InvalidContentLength)?;
+        match length {
+            Some(previous) if previous != parsed => {
+                return Err(RequestParseError::InvalidContentLength)
+            }
+            _ => length = Some(parsed),
+        }
+    }
+
+    Ok(length)
+}
+
+/// An HTTP response status code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StatusCode(pub u16);
+
+impl StatusCode {
+    pub const OK: StatusCode = StatusCode(200);
+    pub const ACCEPTED: StatusCode = StatusCode(202);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const PARTIAL_CONTENT: StatusCode = StatusCode(206);
+    pub const MOVED_PERMANENTLY: StatusCode = StatusCode(301);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const FORBIDDEN: StatusCode = StatusCode(403);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const REQUEST_TIMEOUT: StatusCode = StatusCode(408);
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const UNSUPPORTED_MEDIA_TYPE: StatusCode = StatusCode(415);
+    pub const RANGE_NOT_SATISFIABLE: StatusCode = StatusCode(416);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const NOT_IMPLEMENTED: StatusCode = StatusCode(501);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+    pub const HTTP_VERSION_NOT_SUPPORTED: StatusCode = StatusCode(505);
+
+    /// The numeric code, e.g. `404`.
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    /// Whether responses with this status may have a body, which all but
+    /// `204 No Content` and `304 Not Modified` may, among those the server sends.
+    pub fn allows_body(&self) -> bool {
+        *self != StatusCode::NO_CONTENT && *self != StatusCode::NOT_MODIFIED
+    }
+
+    /// The reason phrase sent along with the code in a status line.
+    pub fn reason(&self) -> &'static str {
+        match self.0 {
+            200 => "OK",
+            202 => "Accepted",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            304 => "Not Modified",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            503 => "Service Unavailable",
+            505 => "HTTP Version Not Supported",
+            _ => "Unknown",
+        }
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.0, self.reason())
+    }
+}
+
+/// A function producing a body of unknown length, by writing it piece by piece.
+pub type BodyWriter = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static>;
+
+/// The body of a [`Response`].
+pub enum Body {
+    /// A body held entirely in memory.
+    Bytes(Vec<u8>),
+    /// A body of known length, copied from `reader` as the response is written,
+    /// so that e.g. large files never have to be loaded into memory.
+    Reader {
+        reader: Box<dyn Read + Send>,
+        len: u64,
+    },
+    /// A body whose length isn't known up front, sent with `Transfer-Encoding: chunked`.
+    ///
+    /// The function is called once the head of the response has been written, and
+    /// every `write` it makes is sent to the client as it goes, after some buffering
+    /// to avoid tiny chunks.
+    Chunked(BodyWriter),
+}
+
+impl Body {
+    /// Length of the body in bytes, as sent in `Content-Length`, or `None` for
+    /// a `Body::Chunked`.
+    pub fn content_length(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { len, .. } => Some(*len),
+            Body::Chunked(_) => None,
+        }
+    }
+
+    /// The body's contents, if they are held in memory.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Reader { .. } | Body::Chunked(_) => None,
+        }
+    }
+
+    /// Collect the body's contents into memory, reading or producing them if necessary.
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            Body::Bytes(bytes) => Ok(bytes),
+            Body::Reader { reader, len } => {
+                let mut bytes = Vec::new();
+                reader.take(len).read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+            Body::Chunked(write_body) => {
+                let mut bytes = Vec::new();
+                write_body(&mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Write the whole body into `writer`, chunk-encoding it if it's a `Body::Chunked`.
+    ///
+    /// # Errors
+    ///
+    /// Besides errors from either side of the copy, an `io::ErrorKind::UnexpectedEof`
+    /// error is returned if a `Body::Reader` runs out before `len` bytes, since the
+    /// `Content-Length` already sent can then no longer be honored.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Body::Chunked(write_body) => {
+                {
+                    let mut encoder = io::BufWriter::with_capacity(
+                        CHUNK_BUFFER_BYTES,
+                        ChunkedEncoder { inner: &mut *writer },
+                    );
+                    write_body(&mut encoder)?;
+                    encoder.flush()?;
+                }
+                writer.write_all(b"0\r\n\r\n")
+            }
+            body => body.copy_to(writer),
+        }
+    }
+
+    /// Write the body's contents into `writer` as they are, i.e. without chunk-encoding
+    /// a `Body::Chunked`, e.g. to compress them.
+    ///
+    /// # Errors
+    ///
+    /// The same as `Body::write_to`'s.
+    pub fn copy_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Body::Bytes(bytes) => writer.write_all(&bytes),
+            Body::Reader { reader, len } => {
+                let copied = io::copy(&mut reader.take(len), writer)?;
+                if copied < len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(())
+            }
+            Body::Chunked(write_body) => write_body(writer),
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Body::Reader { len, .. } => f.debug_struct("Reader").field("len", len).finish(),
+            Body::Chunked(_) => f.write_str("Chunked"),
+        }
+    }
+}
+
+/// Amount of a `Body::Chunked` buffered before it is sent as a chunk.
+const CHUNK_BUFFER_BYTES: usize = 8 * 1024;
+
+/// Wraps a writer, sending every `write` to it as a single chunk.
+///
+/// The terminating zero-length chunk is not written on drop, as a body that failed
+/// halfway must not look complete to the client.
+struct ChunkedEncoder<'a, W: Write> {
+    inner: &'a mut W,
+}
+
+impl<W: Write> Write for ChunkedEncoder<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A zero-length chunk would terminate the body.
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+/// An HTTP response, to be written to a client.
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Body,
+}
+
+impl Response {
+    /// Create a response with the given status, no headers, and an empty body.
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    /// Create a response whose body is the status line's text, e.g. `404 Not Found`.
+    ///
+    /// Used when there is nothing more meaningful to tell the client.
+    pub fn plain(status: StatusCode) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(format!("{status}\n"))
+    }
+
+    /// Set a header field, replacing any existing one with the same name.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Replace the response's body with one held in memory.
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Replace the response's body with one produced by `write_body` as the response
+    /// is written, and sent with `Transfer-Encoding: chunked`.
+    pub fn with_chunked<F>(mut self, write_body: F) -> Response
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    {
+        self.body = Body::Chunked(Box::new(write_body));
+        self
+    }
+
+    /// Replace the response's body with `len` bytes read from `reader` when the
+    /// response is written.
+    pub fn with_reader<R: Read + Send + 'static>(mut self, reader: R, len: u64) -> Response {
+        self.body = Body::Reader {
+            reader: Box::new(reader),
+            len,
+        };
+        self
+    }
+
+    /// Serialize the response into `writer`.
+    ///
+    /// A `Content-Length` field is computed from the body, or `Transfer-Encoding: chunked`
+    /// is used if its length is unknown, overriding any set by the caller, since
+    /// wrong ones would desynchronize the connection.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        self.write_head_to(writer)?;
+        self.body.write_to(writer)
+    }
+
+    /// Serialize only the status line and header fields into `writer`, as is done
+    /// when answering a `HEAD` request.
+    ///
+    /// `Content-Length` is still that of the body, as if it were being sent. Responses
+    /// that never have a body, i.e. `204 No Content` and `304 Not Modified`, have
+    /// neither `Content-Length` nor `Transfer-Encoding`.
+    pub fn write_head_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut head = format!("{} {}\r\n", Version::Http11.as_str(), self.status);
+        for (name, value) in self.headers.iter() {
+            if !name.eq_ignore_ascii_case("Content-Length")
+                && !name.eq_ignore_ascii_case("Transfer-Encoding")
+            {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+        }
+        match self.body.content_length() {
+            _ if !self.status.allows_body() => head.push_str("\r\n"),
+            Some(len) => head.push_str(&format!("Content-Length: {len}\r\n\r\n")),
+            None => head.push_str("Transfer-Encoding: chunked\r\n\r\n"),
+        }
+
+        writer.write_all(head.as_bytes())
+    }
+}
@@ -0,0 +1,108 @@
+//! This module contains [`JobHandle`], through which the result of a job submitted
+//! with `ThreadPool::submit` is obtained.
+//!
+//! Unlike `ThreadPool::execute`, whose jobs' outcomes are only logged by the worker
+//! running them, `submit` sends the value returned by the job - or the message of
+//! the panic it raised - back through a dedicated `mpsc::channel`, whose receiving
+//! end is held by the handle.
+
+use std::{panic, sync::mpsc, time::Duration};
+
+/// Enum representing the ways in which a [`JobHandle`] can fail to provide its job's result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobError {
+    /// The job panicked; the panic's message is kept, and the panic itself was
+    /// logged by the worker running the job.
+    JobPanicError(String),
+
+    /// The job was dropped without ever running, so it will never produce a result.
+    /// This is also what is reported once a handle's result has already been taken.
+    JobCancelledError,
+
+    /// `JobHandle::join_timeout`'s timeout expired before the job finished. The handle
+    /// can still be used to wait for it.
+    JobTimeoutError,
+}
+
+/// The outcome of a job, as sent back to its [`JobHandle`].
+type JobOutcome<T> = Result<T, String>;
+
+/// A handle on a job submitted with `ThreadPool::submit`, from which its result can
+/// be obtained.
+///
+/// Dropping the handle does not cancel the job; its result is then discarded.
+#[derive(Debug)]
+pub struct JobHandle<T> {
+    result_receiver: mpsc::Receiver<JobOutcome<T>>,
+}
+
+impl<T: Send + 'static> JobHandle<T> {
+    /// Wrap `f` into a [`Job`](crate::Job) that sends `f`'s result to the returned handle.
+    ///
+    /// Should `f` panic, the panic's message is sent, and then the panic resumed, so
+    /// that the worker running the job still catches and logs it.
+    pub(crate) fn wrap<F>(f: F) -> (crate::Job, JobHandle<T>)
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (result_sender, result_receiver) = mpsc::channel();
+
+        let job: crate::Job = Box::new(move || {
+            match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+                Ok(value) => {
+                    // The handle having been dropped just means no one wants the result.
+                    let _ = result_sender.send(Ok(value));
+                    Ok(())
+                }
+                Err(payload) => {
+                    let msg = crate::panic_message(payload.as_ref()).to_string();
+                    let _ = result_sender.send(Err(msg));
+                    panic::resume_unwind(payload)
+                }
+            }
+        });
+
+        (job, JobHandle { result_receiver })
+    }
+
+    /// Block until the job finishes, and return its result.
+    ///
+    /// # Errors
+    ///
+    /// * `JobPanicError` if the job panicked;
+    /// * `JobCancelledError` if it was dropped without running.
+    pub fn join(self) -> Result<T, JobError> {
+        match self.result_receiver.recv() {
+            Ok(outcome) => outcome.map_err(JobError::JobPanicError),
+            Err(mpsc::RecvError) => Err(JobError::JobCancelledError),
+        }
+    }
+
+    /// Like `JobHandle::join`, but wait at most `timeout` for the job to finish.
+    ///
+    /// # Errors
+    ///
+    /// The same as `JobHandle::join`'s, as well as `JobTimeoutError` if the job didn't
+    /// finish in time, in which case the handle may be used again.
+    pub fn join_timeout(&self, timeout: Duration) -> Result<T, JobError> {
+        match self.result_receiver.recv_timeout(timeout) {
+            Ok(outcome) => outcome.map_err(JobError::JobPanicError),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(JobError::JobTimeoutError),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(JobError::JobCancelledError),
+        }
+    }
+
+    /// Return the job's result if it has finished, or `None` if it is still queued
+    /// or running. Never blocks.
+    ///
+    /// # Errors
+    ///
+    /// The same as `JobHandle::join`'s.
+    pub fn try_get(&self) -> Option<Result<T, JobError>> {
+        match self.result_receiver.try_recv() {
+            Ok(outcome) => Some(outcome.map_err(JobError::JobPanicError)),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(JobError::JobCancelledError)),
+        }
+    }
+}
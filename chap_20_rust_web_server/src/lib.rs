@@ -7,13 +7,46 @@
 //! and shared ownership in a (thread) concurrent setting.
 
 use std::{
-    io,
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    cell::Cell,
+    fmt, io, panic,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, MutexGuard, PoisonError, Weak,
+    },
     thread,
+    time::{Duration, Instant},
 };
-use simplelog;
 
+pub mod access_log;
+pub mod body;
+pub mod cache;
+pub mod cancellation;
+pub mod compression;
+pub mod config;
+pub mod date;
+pub mod event_loop;
+pub mod http;
+pub mod job;
+pub mod log_rotation;
+pub mod metrics;
+pub mod priority;
+pub mod range;
+pub mod router;
+pub mod service;
+pub mod shutdown;
+pub mod static_files;
+pub mod template;
+pub mod timer;
+pub mod tls;
 pub mod util;
+pub mod work_stealing;
+
+use job::JobHandle;
+use metrics::{PoolMetrics, PoolStats, StatsHandle};
+use priority::{Dispatcher, JobOptions};
+use timer::{ScheduledHandle, Task, Timer};
+use work_stealing::{LocalQueue, Pop, PushError, WorkStealingQueue, WorkStealingSender};
 
 /// A [`ThreadPool`]'s individual worker.
 ///
@@ -35,37 +68,365 @@ pub enum WorkerBuildError {
     ThreadCreationError(io::Error),
 }
 
+impl fmt::Display for WorkerBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkerBuildError::ThreadCreationError(err) => {
+                write!(f, "could not spawn a worker thread: {err}")
+            }
+        }
+    }
+}
+
+/// Lock `mutex`, even if a thread panicked while holding it.
+///
+/// The data behind the pool's mutexes - the job channel's receiving end, the list
+/// of workers, and their count - is never left half-updated, so a poisoned lock is safe to use.
+/// Failing on it instead would take down every other worker along with the first.
+pub(crate) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+thread_local! {
+    /// ID of the [`Worker`] running on this thread, if any.
+    static WORKER_ID: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// ID of the [`Worker`] whose thread this is called from, e.g. from within a [`Job`],
+/// or `None` if called from any other thread.
+pub fn current_worker_id() -> Option<usize> {
+    WORKER_ID.with(Cell::get)
+}
+
+/// Run `job` on the current worker's thread, catching and logging any panic.
+pub(crate) fn run_job(job: impl FnOnce() -> io::Result<()>) {
+    let id = current_worker_id().unwrap_or_default();
+    simplelog::info!("<cyan>Worker {id}</> got a job; executing");
+    // A job is consumed by running it, so nothing it may have left in an
+    // inconsistent state is observed afterwards.
+    match panic::catch_unwind(panic::AssertUnwindSafe(job)) {
+        Err(payload) => simplelog::error!(
+            "<red>Worker {id}</> caught a panic in a job: {}",
+            panic_message(payload.as_ref())
+        ),
+        Ok(Err(err)) => simplelog::warn!("<red>Worker {id}</> failed a job with error: {:?}", err),
+        Ok(Ok(_)) => simplelog::info!("<cyan>Worker {id}</> successfully completed a job."),
+    }
+}
+
+/// Extract the message from a panic's payload, as given to `panic!`.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
+/// State shared between a [`ThreadPool`] and the threads of its [`Worker`]s.
+pub(crate) struct PoolShared {
+    /// Where workers get their jobs from, as chosen by the pool's [`Scheduler`].
+    queue: JobQueue,
+    /// Where jobs submitted with [`JobOptions`] wait for their ticket to come up.
+    dispatcher: Arc<Dispatcher>,
+    /// The pool's workers. A worker whose thread dies replaces itself here.
+    workers: Mutex<Vec<Worker>>,
+    /// ID of the next worker to be built, so that respawned workers get fresh IDs.
+    next_worker_id: AtomicUsize,
+    /// How many workers there are, and how many there may be.
+    sizing: Mutex<Sizing>,
+    /// Number of workers waiting for a job, rather than running one.
+    idle_workers: AtomicUsize,
+    /// Number of jobs sent, but not yet received by any worker.
+    queued_jobs: AtomicUsize,
+    /// How long a worker may go without a job before it is retired.
+    idle_timeout: Duration,
+    /// Counters and histograms describing the jobs run, as snapshotted by `ThreadPool::stats`.
+    metrics: Arc<PoolMetrics>,
+}
+
+/// The receiving end of a [`ThreadPool`]'s job queue, for each [`Scheduler`].
+enum JobQueue {
+    /// Reading end of the job channel, whose writing end resides in [`ThreadPool`].
+    /// As every worker thread must have access to it, it is behind a `Mutex`.
+    Channel(Mutex<mpsc::Receiver<Job>>),
+    /// Queue whose [`WorkStealingSender`] resides in [`ThreadPool`].
+    WorkStealing(Arc<WorkStealingQueue>),
+}
+
+/// What a [`Worker`] is to do next, as decided by `PoolShared::next_job`.
+enum Next {
+    Job(Job),
+    /// No job came up for a while; check again.
+    Idle,
+    Retire,
+    /// The job queue is closed, and empty.
+    Disconnected,
+}
+
+/// The number of [`Worker`]s in a [`ThreadPool`], and its bounds.
+#[derive(Debug)]
+struct Sizing {
+    /// Workers that are running, and haven't been asked to retire.
+    size: usize,
+    /// Idle workers are only retired while there are more than this many.
+    min_size: usize,
+    /// The pool only grows while there are fewer workers than this.
+    max_size: usize,
+    /// Workers asked to retire by `ThreadPool::resize`, which haven't done so yet.
+    to_retire: usize,
+}
+
+impl PoolShared {
+    /// Decide whether the calling worker, without a job since `idle_since`, should
+    /// retire, accounting for it if so.
+    fn should_retire(&self, idle_since: Instant) -> bool {
+        let mut sizing = lock(&self.sizing);
+        if sizing.to_retire > 0 {
+            sizing.to_retire -= 1;
+            return true;
+        }
+        if idle_since.elapsed() >= self.idle_timeout && sizing.size > sizing.min_size {
+            sizing.size -= 1;
+            return true;
+        }
+        false
+    }
+
+    /// Wait for the next job for the worker with the given ID, unless it should retire.
+    ///
+    /// `local` is where that worker keeps its deque under the work-stealing scheduler,
+    /// created on first use.
+    fn next_job(&self, id: usize, local: &mut Option<LocalQueue>, idle_since: Instant) -> Next {
+        match &self.queue {
+            JobQueue::Channel(job_receiver) => {
+                // IMPORTANT
+                // The lock on the receiver must be released as soon as a job is received, or
+                // sequential behavior will be observable.
+                //
+                // This is because there is no `.unlock()` method - the lock is held as long as the
+                // corresponding `MutexGuard`'s lifetim:
+                // * in the below case, it starts and ends in this block, so it is released
+                //   as soon as `.recv_timeout()` returns.
+                // * in the case where `match job_receiver.lock() { ... }` to handle possible `PoisonError`s,
+                //   the lock will be held for much longer than expected! - possibly until after the thread
+                //   has finished running its `job()`.
+                //
+                // Whether to retire is decided with the lock held, as only then is it this
+                // worker's turn to wait for a job.
+                let job_receiver = lock(job_receiver);
+                if self.should_retire(idle_since) {
+                    return Next::Retire;
+                }
+                match job_receiver.recv_timeout(RETIRE_POLL_INTERVAL) {
+                    Ok(job) => Next::Job(job),
+                    Err(mpsc::RecvTimeoutError::Timeout) => Next::Idle,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => Next::Disconnected,
+                }
+            }
+            JobQueue::WorkStealing(queue) => {
+                let local = local.get_or_insert_with(|| queue.register(id));
+                match local.pop() {
+                    Pop::Job(job) => Next::Job(job),
+                    Pop::Closed => Next::Disconnected,
+                    Pop::Empty if self.should_retire(idle_since) => Next::Retire,
+                    Pop::Empty => {
+                        local.wait(RETIRE_POLL_INTERVAL);
+                        Next::Idle
+                    }
+                }
+            }
+        }
+    }
+
+    /// Build a new worker with a fresh ID, and add it to the pool.
+    fn spawn_worker(self: &Arc<Self>) -> Result<usize, WorkerBuildError> {
+        let id = self.next_worker_id.fetch_add(1, Ordering::SeqCst);
+        let worker = Worker::build(id, Arc::clone(self))?;
+        lock(&self.workers).push(worker);
+        Ok(id)
+    }
+
+    /// Put `job` in the queue through `sender`, as described in `ThreadPool::send_job`.
+    fn send_job(self: &Arc<Self>, sender: &JobSender, job: Job, block: bool) -> Result<(), ThreadPoolError> {
+        let job = self.metrics.instrument(job);
+        self.send_ticket(sender, job, block)
+    }
+
+    /// Keep `job` aside in the dispatcher, and send a ticket for it through `sender` in
+    /// its place, as described in `ThreadPool::execute_with`.
+    fn send_job_with(
+        self: &Arc<Self>,
+        sender: Option<&JobSender>,
+        options: JobOptions,
+        job: Job,
+        block: bool,
+    ) -> Result<(), ThreadPoolError> {
+        let job = self.metrics.instrument(job);
+        let key = self.dispatcher.push(options, job).inspect_err(|err| {
+            self.metrics.record_submit_error(err);
+        })?;
+        let sent = match sender {
+            None => Err(ThreadPoolError::InexistentJobSenderError),
+            Some(sender) => self.send_ticket(sender, self.dispatcher.ticket(), block),
+        };
+        match sent {
+            Ok(()) => Ok(()),
+            // An urgent job may have been run already by a worker; it then succeeded.
+            Err(err) if self.dispatcher.cancel(key) => Err(err),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Like `PoolShared::send_job`, but for a job that isn't instrumented for the pool's
+    /// metrics, e.g. a ticket for one kept aside by the [`Dispatcher`], as that one
+    /// already was.
+    fn send_ticket(self: &Arc<Self>, sender: &JobSender, job: Job, block: bool) -> Result<(), ThreadPoolError> {
+        self.grow_if_backed_up();
+        // Counted before sending, as a worker may receive the job right away.
+        self.queued_jobs.fetch_add(1, Ordering::SeqCst);
+        let res = match sender {
+            JobSender::Unbounded(sender) => sender
+                .send(job)
+                .map_err(ThreadPoolError::JobTransmissionError),
+            JobSender::Bounded(sender) if block => sender
+                .send(job)
+                .map_err(ThreadPoolError::JobTransmissionError),
+            JobSender::Bounded(sender) => sender.try_send(job).map_err(|err| match err {
+                mpsc::TrySendError::Full(_) => ThreadPoolError::JobQueueFullError,
+                mpsc::TrySendError::Disconnected(job) => {
+                    ThreadPoolError::JobTransmissionError(mpsc::SendError(job))
+                }
+            }),
+            JobSender::WorkStealing(sender) => sender.push(job, block).map_err(|err| match err {
+                PushError::Full(_) => ThreadPoolError::JobQueueFullError,
+                PushError::Closed(job) => ThreadPoolError::JobTransmissionError(mpsc::SendError(job)),
+            }),
+        };
+        match &res {
+            Ok(()) => self.metrics.record_submitted(),
+            Err(err) => {
+                self.queued_jobs.fetch_sub(1, Ordering::SeqCst);
+                self.metrics.record_submit_error(err);
+            }
+        }
+        res
+    }
+
+    /// Add a worker if the pool may still grow, and the job about to be sent would
+    /// otherwise have to wait for a busy one.
+    fn grow_if_backed_up(self: &Arc<Self>) {
+        let idle = self.idle_workers.load(Ordering::SeqCst);
+        if self.queued_jobs.load(Ordering::SeqCst) < idle {
+            return;
+        }
+
+        // The worker's slot is reserved under the lock, so that concurrent submissions
+        // can't grow the pool past `max_size`, but its thread is spawned after releasing
+        // it, so that neither they nor retiring workers wait for the OS.
+        let size = {
+            let mut sizing = lock(&self.sizing);
+            if sizing.size >= sizing.max_size {
+                return;
+            }
+            sizing.size += 1;
+            sizing.size
+        };
+        match self.spawn_worker() {
+            Ok(id) => simplelog::info!("Job queue backed up; added <cyan>Worker {id}</>, for {size} workers"),
+            // Not fatal: the existing workers will get to the job eventually.
+            Err(err) => {
+                lock(&self.sizing).size -= 1;
+                simplelog::error!("could not grow ThreadPool: {:?}", err);
+            }
+        }
+    }
+}
+
+/// How often a waiting [`Worker`] checks whether it should retire.
+const RETIRE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Respawns a [`Worker`] whose thread is unwinding, when `drop`ped as part of it.
+///
+/// Each [`Job`] already runs inside `panic::catch_unwind`, so this is the last line
+/// of defense, for panics outside of any job - e.g. in the `Drop` implementation of
+/// a panic's payload.
+struct Respawner {
+    id: usize,
+    shared: Arc<PoolShared>,
+}
+
+impl Drop for Respawner {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+
+        let id = self.id;
+        let new_id = self.shared.next_worker_id.fetch_add(1, Ordering::SeqCst);
+        simplelog::error!("<red>Worker {id}</> died; respawning it as worker {new_id}");
+        match Worker::build(new_id, Arc::clone(&self.shared)) {
+            Ok(worker) => {
+                let mut workers = lock(&self.shared.workers);
+                // This thread's own handle is dropped, detaching it; it is about to exit.
+                workers.retain(|w| w.id != id);
+                workers.push(worker);
+            }
+            Err(err) => simplelog::error!("could not respawn worker {id}: {:?}", err),
+        }
+    }
+}
+
 /// Function with which each worker thread is `spawn`ed.
 ///
 /// Having so much code inline makes it hard to understand what is part of
 /// `Worker::build`, and what is the thread's spawning closure, so it was moved out.
-pub fn worker_func(id: usize, job_receiver: Arc<Mutex<mpsc::Receiver<Job>>>) {
+///
+/// A panicking [`Job`] is caught and logged, and the worker carries on with the next
+/// one. Should the thread die regardless, a new [`Worker`] takes its place.
+pub(crate) fn worker_func(id: usize, shared: Arc<PoolShared>) {
+    WORKER_ID.with(|worker_id| worker_id.set(Some(id)));
+    let _metrics = shared.metrics.enter_worker(id);
+    let _respawner = Respawner {
+        id,
+        shared: Arc::clone(&shared),
+    };
+    // Declared after `_respawner`, so that it is dropped first, and any jobs left in
+    // it are handed back before a replacement worker starts.
+    let mut local = None;
+
+    let mut idle_since = Instant::now();
+    // The worker counts as idle from here until it gets a job, and again once done.
+    shared.idle_workers.fetch_add(1, Ordering::SeqCst);
     loop {
-        // IMPORTANT
-        // The `.lock()` must be immediately followed by `.unwrap()`, or sequential behavior will
-        // be observable.
-        //
-        // This is because there is no `.unlock()` method - the lock is held as long as the
-        // corresponding `MutexGuard`'s lifetim:
-        // * in the below case, it starts and ends in this line, so it is released
-        //   as soon as the line is executed.
-        // * in the case where `match job_receiver.lock() { ... }` to handle possible `PoisonError`s,
-        //   the lock will be held for much longer than expected! - possibly until after the thread
-        //   has finished running its `job()`.
-        let msg = job_receiver.lock().unwrap().recv();
-        match msg {
-            Err(_) => {
+        let next = shared.next_job(id, &mut local, idle_since);
+        if !matches!(next, Next::Idle) {
+            shared.idle_workers.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        match next {
+            Next::Retire => {
+                simplelog::info!("<cyan>Worker {id}</> retiring");
+                // This thread's own handle is dropped, detaching it; it is about to exit.
+                lock(&shared.workers).retain(|w| w.id != id);
+                break;
+            }
+            Next::Idle => continue,
+            Next::Disconnected => {
                 simplelog::warn!(
                     "<yellow>Worker {id}</> disconnected due to closure of job queue; shutting down.",
                 );
                 break;
             }
-            Ok(job) => {
-                simplelog::info!("<cyan>Worker {id}</> got a job; executing");
-                match job() {
-                    Err(err) => simplelog::warn!("<red>Worker {id}</> failed a job with error: {:?}", err),
-                    Ok(_) => simplelog::info!("<cyan>Worker {id}</> successfully completed a job."),
-                }
+            Next::Job(job) => {
+                shared.queued_jobs.fetch_sub(1, Ordering::SeqCst);
+                // High priority jobs go ahead of any other; see the `priority` module.
+                shared.dispatcher.run_urgent();
+                run_job(job);
+                idle_since = Instant::now();
+                shared.idle_workers.fetch_add(1, Ordering::SeqCst);
             }
         }
     }
@@ -80,11 +441,10 @@ impl Worker {
     /// # Arguments
     ///
     /// * `id: usize`: ID of the worker being built. That it is unique must be enforced
-    ///    by the caller e.g. in this case, `ThreadPool::build`.
-    /// * `job_receiver: Arc<Mutex<mpsc::Receiver<Job>>>`: reading end of a channel, whose
-    ///    writing end resides in [`ThreadPool`]. As all other worker threads must also have
-    ///    access to it, it must be wrapped in an `Arc<Mutex<_>>`. It is through this channel
-    ///    that each channel will receive [`Job`]s.
+    ///   by the caller e.g. in this case, `ThreadPool::build`.
+    /// * `shared: Arc<PoolShared>`: state shared with the [`ThreadPool`] and all other
+    ///   workers, among which the reading end of the channel through which each worker
+    ///   will receive [`Job`]s.
     ///
     /// # Errors
     ///
@@ -95,14 +455,14 @@ impl Worker {
     /// # Panics
     ///
     /// This function doesn't panic.
-    pub fn build(
+    pub(crate) fn build(
         id: usize,
-        job_receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+        shared: Arc<PoolShared>,
     ) -> Result<Worker, WorkerBuildError> {
         let builder = thread::Builder::new().name(format!("Worker-{}", id));
 
         let thread_res = builder
-            .spawn(move || worker_func(id, job_receiver));
+            .spawn(move || worker_func(id, shared));
 
         match thread_res {
             Ok(thread_handle) => {
@@ -140,6 +500,32 @@ pub enum ThreadPoolBuildError {
     /// fails with that variant, `ThreadPool::build` will fail with this one,
     /// because the OS being unable to spawn a thread is a serious problem.
     WorkerError(WorkerBuildError),
+
+    /// A [`ThreadPoolConfig`]'s `max_size` must be at least its `size`, or the pool
+    /// would start out larger than it is allowed to be.
+    InvalidMaxSizeThreadPoolError,
+
+    /// A [`ThreadPoolConfig`]'s `queue_capacity` can't be `0`, or no [`Job`] could ever
+    /// wait for a worker: `ThreadPool::execute` would block forever, and
+    /// `ThreadPool::try_execute` always fail.
+    ZeroQueueCapacityThreadPoolError,
+}
+
+impl fmt::Display for ThreadPoolBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThreadPoolBuildError::ZeroThreadThreadPoolError => {
+                f.write_str("a thread pool needs at least one thread, but its size is 0")
+            }
+            ThreadPoolBuildError::WorkerError(err) => err.fmt(f),
+            ThreadPoolBuildError::InvalidMaxSizeThreadPoolError => {
+                f.write_str("a thread pool's maximum size can't be less than its size")
+            }
+            ThreadPoolBuildError::ZeroQueueCapacityThreadPoolError => {
+                f.write_str("a thread pool's queue capacity can't be 0")
+            }
+        }
+    }
 }
 
 /// Enum representing errors that can happen in `ThreadPool::execute`, in the course of a
@@ -153,6 +539,123 @@ pub enum ThreadPoolError {
     /// This variant occurs if when attempting to insert a [`Job`] into the `mpsc::channel`,
     /// the `.send` method fails.
     JobTransmissionError(mpsc::SendError<Job>),
+
+    /// This variant is returned by `ThreadPool::try_execute` when the pool was built
+    /// with a `queue_capacity`, and that many [`Job`]s are already waiting for a
+    /// [`Worker`]. The job is dropped without being run.
+    JobQueueFullError,
+
+    /// This variant occurs when a job is submitted into a queue, by name, that wasn't
+    /// registered with `ThreadPool::add_queue`.
+    UnknownQueueError(String),
+
+    /// This variant occurs when a job is scheduled with `ThreadPool::execute_after` or
+    /// `ThreadPool::execute_every`, and the pool's timer thread could not be spawned.
+    TimerThreadCreationError(io::Error),
+}
+
+/// Configuration with which to `ThreadPool::build_with` a [`ThreadPool`].
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadPoolConfig {
+    /// Number of [`Worker`]s the pool starts with, and never shrinks below on its own.
+    pub size: usize,
+    /// Number of workers the pool may grow to, or `None` for a pool whose size only
+    /// changes through `ThreadPool::resize`.
+    ///
+    /// When a job is submitted and there is no idle worker to take it, a worker is
+    /// added, up to this many. Workers beyond `size` are retired once they have gone
+    /// `idle_timeout` without a job.
+    pub max_size: Option<usize>,
+    /// How long a worker beyond the pool's minimum size may go without a job.
+    pub idle_timeout: Duration,
+    /// Maximum number of [`Job`]s waiting for a worker, or `None` for no limit.
+    ///
+    /// Without a limit, a flood of jobs arriving faster than the workers can run
+    /// them accumulates in memory for as long as the flood lasts. With one,
+    /// `ThreadPool::execute` blocks until there is room in the queue, and
+    /// `ThreadPool::try_execute` fails instead, so the caller can shed the load.
+    /// The limit can't be `0`, or no job would ever be queued.
+    pub queue_capacity: Option<usize>,
+    /// How jobs are handed out to the workers.
+    pub scheduler: Scheduler,
+}
+
+/// The ways in which a [`ThreadPool`] can hand out [`Job`]s to its [`Worker`]s.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Scheduler {
+    /// Every worker receives jobs from the same `mpsc::channel`, taking turns
+    /// to lock its receiving end. Jobs are run in the order they were submitted.
+    #[default]
+    Channel,
+    /// Workers take jobs in batches from a global queue, into deques of their own,
+    /// and steal from each other's when they run out; see [`work_stealing`]. This
+    /// avoids contention on a single lock when there are many short jobs, at the
+    /// cost of jobs not always starting in the order they were submitted.
+    WorkStealing,
+}
+
+impl ThreadPoolConfig {
+    /// Configuration for a pool of `size` workers with an unbounded queue, as
+    /// built by `ThreadPool::build`.
+    pub fn new(size: usize) -> ThreadPoolConfig {
+        ThreadPoolConfig {
+            size,
+            max_size: None,
+            idle_timeout: Duration::from_secs(60),
+            queue_capacity: None,
+            scheduler: Scheduler::Channel,
+        }
+    }
+
+    /// Check the configuration describes a pool that can be built, as done by
+    /// `ThreadPool::build_with`, e.g. to report a bad configuration before doing
+    /// anything else.
+    ///
+    /// # Errors
+    ///
+    /// * `ZeroThreadThreadPoolError` if `size` is `0`;
+    /// * `InvalidMaxSizeThreadPoolError` if `max_size` is less than `size`;
+    /// * `ZeroQueueCapacityThreadPoolError` if `queue_capacity` is `Some(0)`.
+    pub fn validate(&self) -> Result<(), ThreadPoolBuildError> {
+        if self.size == 0 {
+            return Err(ThreadPoolBuildError::ZeroThreadThreadPoolError);
+        }
+        if self.max_size.is_some_and(|max_size| max_size < self.size) {
+            return Err(ThreadPoolBuildError::InvalidMaxSizeThreadPoolError);
+        }
+        if self.queue_capacity == Some(0) {
+            return Err(ThreadPoolBuildError::ZeroQueueCapacityThreadPoolError);
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of `ThreadPool::shutdown`, identifying [`Worker`]s by their IDs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Workers that finished every job, and were joined, within the drain timeout.
+    pub finished: Vec<usize>,
+    /// Workers still running a job when the drain timeout expired. Their threads
+    /// were detached rather than joined, and will exit once they finish.
+    pub busy: Vec<usize>,
+}
+
+/// The writing end of a [`ThreadPool`]'s job queue.
+///
+/// `mpsc::channel` and `mpsc::sync_channel` have different sending ends, but the
+/// same receiving end, so [`Worker`]s need not know which one the pool uses.
+/// The work-stealing scheduler has a queue of its own, bounded or not.
+///
+/// Every kind may be cloned, e.g. for the timer thread running scheduled jobs, and
+/// workers are only told the queue is closed once every clone is dropped.
+#[derive(Clone)]
+pub enum JobSender {
+    /// Sending end of an `mpsc::channel`, for pools without a queue capacity.
+    Unbounded(mpsc::Sender<Job>),
+    /// Sending end of an `mpsc::sync_channel`, whose bound is the queue capacity.
+    Bounded(mpsc::SyncSender<Job>),
+    /// Sending end of a [`WorkStealingQueue`], under `Scheduler::WorkStealing`.
+    WorkStealing(WorkStealingSender),
 }
 
 /// A thread pool used to concurrently execute requests of the same type.
@@ -160,18 +663,30 @@ pub enum ThreadPoolError {
 /// A request is represented by [`Job`], seen also in this module.
 ///
 /// A thread pool consists of two parts:
-/// * the workers, each containing a thread used to run a request
-/// * the sending end of an `mpsc::channel`, taking into account that
-///   the writing end, wrapped in `Arc<Mutex<_>>`, is passed into the closure each
-///   worker thread is spawned with.
+/// * the workers, each containing a thread used to run a request, kept in the
+///   `PoolShared` state passed into the closure each worker thread is spawned
+///   with, alongside the reading end of the `mpsc::channel`
+/// * the sending end of that `mpsc::channel`
+///
+/// A [`Job`] that panics doesn't take its worker down with it; should a worker's
+/// thread die nonetheless, it is replaced by a new worker, with a fresh ID, so the
+/// pool keeps its size.
 pub struct ThreadPool {
-    /// Vector for workers, whose length is an argument to `ThreadPool::build`
-    pub workers: Vec<Worker>,
+    /// State shared with the workers, among which the workers themselves, whose
+    /// number is an argument to `ThreadPool::build`.
+    shared: Arc<PoolShared>,
     /// Sending end of a channel. It is wrapped in an `Option`, so that when
     /// the thread pool is `drop`ped, this end of the channel is `Option::take`n,
     /// signaling to the worker threads via the subsequent `mpsc::RecvError` that
     /// they must also shut down.
-    pub job_sender: Option<mpsc::Sender<Job>>,
+    pub job_sender: Option<JobSender>,
+    /// Thread putting jobs scheduled with `ThreadPool::execute_after` and
+    /// `ThreadPool::execute_every` in the queue, started along with the first of them.
+    /// It holds a clone of `job_sender`, so it is stopped before that is dropped.
+    timer: Mutex<Option<Timer>>,
+    /// Clone of `job_sender` for [`SubmitHandle`]s to submit jobs through. They only
+    /// hold weak references to it, so it is dropped along with `job_sender`.
+    handle_sender: Option<Arc<JobSender>>,
 }
 
 impl ThreadPool {
@@ -189,27 +704,157 @@ impl ThreadPool {
     ///
     /// This function does not panic.
     pub fn build(size: usize) -> Result<ThreadPool, ThreadPoolBuildError> {
-        if size == 0 {
-            return Err(ThreadPoolBuildError::ZeroThreadThreadPoolError);
-        }
+        ThreadPool::build_with(ThreadPoolConfig::new(size))
+    }
 
-        let (job_sender, job_receiver) = mpsc::channel();
-        let job_receiver = Arc::new(Mutex::new(job_receiver));
-        let mut workers = Vec::with_capacity(size);
+    /// Create a new ThreadPool, as described by `config`.
+    ///
+    /// # Errors
+    ///
+    /// The same as `ThreadPool::build`'s.
+    ///
+    /// # Panics
+    ///
+    /// This function does not panic.
+    pub fn build_with(config: ThreadPoolConfig) -> Result<ThreadPool, ThreadPoolBuildError> {
+        config.validate()?;
+        let size = config.size;
+        let max_size = config.max_size.unwrap_or(size);
+
+        let (job_sender, queue) = match (config.scheduler, config.queue_capacity) {
+            (Scheduler::Channel, None) => {
+                let (sender, receiver) = mpsc::channel();
+                (JobSender::Unbounded(sender), JobQueue::Channel(Mutex::new(receiver)))
+            }
+            (Scheduler::Channel, Some(capacity)) => {
+                let (sender, receiver) = mpsc::sync_channel(capacity);
+                (JobSender::Bounded(sender), JobQueue::Channel(Mutex::new(receiver)))
+            }
+            (Scheduler::WorkStealing, capacity) => {
+                let queue = WorkStealingQueue::new(capacity);
+                (
+                    JobSender::WorkStealing(WorkStealingSender::new(Arc::clone(&queue))),
+                    JobQueue::WorkStealing(queue),
+                )
+            }
+        };
+        let shared = Arc::new(PoolShared {
+            queue,
+            dispatcher: Arc::default(),
+            workers: Mutex::new(Vec::with_capacity(size)),
+            next_worker_id: AtomicUsize::new(size),
+            sizing: Mutex::new(Sizing {
+                size,
+                min_size: size,
+                max_size,
+                to_retire: 0,
+            }),
+            idle_workers: AtomicUsize::new(0),
+            queued_jobs: AtomicUsize::new(0),
+            idle_timeout: config.idle_timeout,
+            metrics: Arc::default(),
+        });
+        // Built now, so that should a worker fail to build, dropping the pool
+        // disconnects and joins those already built.
+        let pool = ThreadPool {
+            shared,
+            handle_sender: Some(Arc::new(job_sender.clone())),
+            job_sender: Some(job_sender),
+            timer: Mutex::new(None),
+        };
 
         for n in 0..size {
-            let worker_res = Worker::build(n, Arc::clone(&job_receiver));
+            let worker_res = Worker::build(n, Arc::clone(&pool.shared));
             // If even one of the workers could not be created, fail and exit early.
             match worker_res {
-                Ok(worker) => workers.push(worker),
+                Ok(worker) => lock(&pool.shared.workers).push(worker),
                 Err(w_err) => return Err(ThreadPoolBuildError::WorkerError(w_err)),
             }
         }
 
-        Ok(ThreadPool {
-            workers,
-            job_sender: Some(job_sender),
-        })
+        Ok(pool)
+    }
+
+    /// IDs of the pool's current [`Worker`]s, in the order they were built.
+    ///
+    /// These are `0..size` at first; a respawned or added worker is given the next
+    /// unused ID. Workers asked to retire are included until they have done so.
+    pub fn worker_ids(&self) -> Vec<usize> {
+        lock(&self.shared.workers).iter().map(|w| w.id).collect()
+    }
+
+    /// Number of [`Worker`]s in the pool, not counting those asked to retire.
+    pub fn size(&self) -> usize {
+        lock(&self.shared.sizing).size
+    }
+
+    /// A snapshot of the pool's metrics: its size, how many jobs are queued and running,
+    /// how they ended, and how long they took; see the [`metrics`] module.
+    pub fn stats(&self) -> PoolStats {
+        PoolStats::of(&self.shared)
+    }
+
+    /// A handle with which to submit jobs to the pool from where the pool itself isn't at
+    /// hand, e.g. from within a job.
+    pub fn submit_handle(&self) -> SubmitHandle {
+        SubmitHandle {
+            shared: Arc::clone(&self.shared),
+            sender: self.handle_sender.as_ref().map_or_else(Weak::new, Arc::downgrade),
+        }
+    }
+
+    /// A handle from which to take snapshots of the pool's metrics, e.g. from a request
+    /// handler serving them.
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Change the number of [`Worker`]s in the pool to `size`.
+    ///
+    /// Workers are added right away; when shrinking, workers retire once they finish
+    /// their current job. The pool's bounds are widened to include `size` if needed,
+    /// and it keeps growing and retiring workers within them afterwards, so a pool
+    /// resized above its minimum size shrinks back to it once idle.
+    ///
+    /// # Errors
+    ///
+    /// * `ZeroThreadThreadPoolError` if `size` is `0`;
+    /// * `WorkerError` if a worker could not be built, in which case the pool keeps
+    ///   those that could.
+    pub fn resize(&self, size: usize) -> Result<(), ThreadPoolBuildError> {
+        if size == 0 {
+            return Err(ThreadPoolBuildError::ZeroThreadThreadPoolError);
+        }
+
+        let mut sizing = lock(&self.shared.sizing);
+        sizing.min_size = sizing.min_size.min(size);
+        sizing.max_size = sizing.max_size.max(size);
+        simplelog::info!("Resizing ThreadPool from {} to {size} workers", sizing.size);
+
+        if size < sizing.size {
+            sizing.to_retire += sizing.size - size;
+            sizing.size = size;
+            return Ok(());
+        }
+
+        // Workers not yet retired are kept rather than replaced.
+        let kept = sizing.to_retire.min(size - sizing.size);
+        sizing.to_retire -= kept;
+        sizing.size += kept;
+        // As in `PoolShared::grow_if_backed_up`, the new workers' slots are reserved,
+        // and their threads spawned after releasing the lock.
+        let missing = size - sizing.size;
+        sizing.size = size;
+        drop(sizing);
+        for spawned in 0..missing {
+            if let Err(err) = self.shared.spawn_worker() {
+                lock(&self.shared.sizing).size -= missing - spawned;
+                return Err(ThreadPoolBuildError::WorkerError(err));
+            }
+        }
+        Ok(())
     }
 
     /*
@@ -229,21 +874,272 @@ impl ThreadPool {
     /// We can be further confident that `FnOnce` is the trait we want to use in the job's type
     /// because the thread running a request will only execute that request’s closure one time,
     /// which matches the `Once` in `FnOnce`.
+    ///
+    /// If the pool was built with a `queue_capacity` and the queue is full, this blocks
+    /// until a [`Worker`] takes a job off it; see `ThreadPool::try_execute` for an
+    /// alternative that doesn't.
     pub fn execute<F>(&self, f: F) -> Result<(), ThreadPoolError>
     where
         // We still use the () after FnOnce because this FnOnce represents a
         // closure that takes no parameters and returns the unit type ()
         F: FnOnce() -> io::Result<()> + Send + 'static,
     {
-        let job = Box::new(f);
+        self.send_job(Box::new(f), true)
+    }
+
+    /// Put `job` in the queue. If it is bounded and full, block until there is room
+    /// if `block` is set, and fail with `JobQueueFullError` otherwise.
+    fn send_job(&self, job: Job, block: bool) -> Result<(), ThreadPoolError> {
+        match self.job_sender.as_ref() {
+            None => Err(ThreadPoolError::InexistentJobSenderError),
+            Some(sender) => self.shared.send_job(sender, job, block),
+        }
+    }
 
-        let sender = match self.job_sender.as_ref() {
-            None => return Err(ThreadPoolError::InexistentJobSenderError),
-            Some(s) => s,
+    /// Like `ThreadPool::execute`, but for a job returning any value, which, along
+    /// with whether the job panicked, can be obtained from the returned [`JobHandle`].
+    ///
+    /// # Errors
+    ///
+    /// The same as `ThreadPool::execute`'s.
+    pub fn submit<F, T>(&self, f: F) -> Result<JobHandle<T>, ThreadPoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = JobHandle::wrap(f);
+        self.send_job(job, true)?;
+        Ok(handle)
+    }
+
+    /// Like `ThreadPool::execute`, but rather than blocking when the job queue is
+    /// full, fail with `ThreadPoolError::JobQueueFullError`.
+    ///
+    /// For pools without a `queue_capacity`, this is the same as `execute`.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ThreadPoolError>
+    where
+        F: FnOnce() -> io::Result<()> + Send + 'static,
+    {
+        self.send_job(Box::new(f), false)
+    }
+
+    /// Register a queue named `name`, whose jobs may only occupy `quota` workers at
+    /// once, or change the quota of an existing one.
+    ///
+    /// Jobs are put in the queue by submitting them with [`JobOptions`] naming it, and
+    /// are then started in order of priority, as described in the [`priority`] module.
+    /// This keeps e.g. slow requests from occupying every worker.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `quota` is `0`, as the queue's jobs would never run.
+    pub fn add_queue(&self, name: &str, quota: usize) {
+        assert!(quota > 0, "queue \"{name}\" must have a quota of at least one worker");
+        self.shared.dispatcher.add_queue(name, quota);
+    }
+
+    /// Like `ThreadPool::execute`, but with a priority, and possibly into a named queue,
+    /// as given by `options`.
+    ///
+    /// # Errors
+    ///
+    /// The same as `ThreadPool::execute`'s, as well as `UnknownQueueError` if the
+    /// queue named in `options` wasn't registered with `ThreadPool::add_queue`.
+    pub fn execute_with<F>(&self, options: JobOptions, f: F) -> Result<(), ThreadPoolError>
+    where
+        F: FnOnce() -> io::Result<()> + Send + 'static,
+    {
+        self.send_job_with(options, Box::new(f), true)
+    }
+
+    /// Like `ThreadPool::try_execute`, but with `options`, as for `ThreadPool::execute_with`.
+    ///
+    /// # Errors
+    ///
+    /// The same as `ThreadPool::execute_with`'s, as well as `JobQueueFullError`.
+    pub fn try_execute_with<F>(&self, options: JobOptions, f: F) -> Result<(), ThreadPoolError>
+    where
+        F: FnOnce() -> io::Result<()> + Send + 'static,
+    {
+        self.send_job_with(options, Box::new(f), false)
+    }
+
+    /// Like `ThreadPool::submit`, but with `options`, as for `ThreadPool::execute_with`.
+    ///
+    /// # Errors
+    ///
+    /// The same as `ThreadPool::execute_with`'s.
+    pub fn submit_with<F, T>(&self, options: JobOptions, f: F) -> Result<JobHandle<T>, ThreadPoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = JobHandle::wrap(f);
+        self.send_job_with(options, job, true)?;
+        Ok(handle)
+    }
+
+    /// Keep `job` aside in the dispatcher, and send a ticket for it in its place.
+    fn send_job_with(&self, options: JobOptions, job: Job, block: bool) -> Result<(), ThreadPoolError> {
+        self.shared.send_job_with(self.job_sender.as_ref(), options, job, block)
+    }
+
+    /// Run `f` on one of the pool's [`Worker`]s once `delay` has passed, unless the
+    /// returned [`ScheduledHandle`] is used to cancel it first.
+    ///
+    /// The job is put in the queue when it is due, and so may start later than that
+    /// if every worker is busy. If the pool is dropped or shut down before then, the
+    /// job is discarded without running.
+    ///
+    /// # Errors
+    ///
+    /// * `TimerThreadCreationError` if this is the first job scheduled, and the
+    ///   pool's timer thread could not be spawned;
+    /// * `InexistentJobSenderError` as for `ThreadPool::execute`.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> Result<ScheduledHandle, ThreadPoolError>
+    where
+        F: FnOnce() -> io::Result<()> + Send + 'static,
+    {
+        self.schedule(delay, Task::Once(Box::new(f)))
+    }
+
+    /// Run `f` on one of the pool's [`Worker`]s every `interval`, starting `interval`
+    /// from now, until the returned [`ScheduledHandle`] is used to cancel it, or the
+    /// pool is dropped or shut down.
+    ///
+    /// Runs are due at a fixed rate, rather than `interval` after the previous one
+    /// finished. Should a run still be queued or running when the next is due, that
+    /// next one is skipped, so that runs of `f` never overlap or pile up.
+    ///
+    /// # Errors
+    ///
+    /// The same as `ThreadPool::execute_after`'s.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `interval` is zero.
+    pub fn execute_every<F>(&self, interval: Duration, f: F) -> Result<ScheduledHandle, ThreadPoolError>
+    where
+        F: Fn() -> io::Result<()> + Send + Sync + 'static,
+    {
+        assert!(!interval.is_zero(), "a job can't be run every 0 seconds");
+        self.schedule(interval, Task::Every(interval, Arc::new(f)))
+    }
+
+    /// Hand `task` to the timer, starting it if this is the first.
+    fn schedule(&self, delay: Duration, task: Task) -> Result<ScheduledHandle, ThreadPoolError> {
+        let mut slot = lock(&self.timer);
+        let timer = match slot.take() {
+            Some(timer) => timer,
+            None => {
+                let sender = self
+                    .job_sender
+                    .clone()
+                    .ok_or(ThreadPoolError::InexistentJobSenderError)?;
+                Timer::start(Arc::clone(&self.shared), sender)
+                    .map_err(ThreadPoolError::TimerThreadCreationError)?
+            }
         };
-        sender
-            .send(job)
-            .map_err(ThreadPoolError::JobTransmissionError)
+        let handle = timer.schedule(delay, task);
+        *slot = Some(timer);
+        Ok(handle)
+    }
+
+    /// Stop the timer, if started, discarding scheduled jobs not yet due, and wait for
+    /// it to drop its clone of the job sender.
+    fn stop_timer(&self) {
+        std::mem::drop(lock(&self.timer).take());
+    }
+
+    /// Shut the pool down, giving its [`Worker`]s at most `drain_timeout` to finish
+    /// the jobs already submitted, queued ones included.
+    ///
+    /// As in the `Drop` implementation, the timer is stopped and the sending end of
+    /// the job channel is dropped first, so that each worker exits once the queue is empty. Unlike it, workers
+    /// still busy once the timeout expires are not waited for, but detached, and
+    /// reported as such.
+    pub fn shutdown(mut self, drain_timeout: Duration) -> ShutdownReport {
+        simplelog::debug!("Shutting down ThreadPool, draining for at most {:?}", drain_timeout);
+        self.stop_timer();
+        std::mem::drop(self.handle_sender.take());
+        std::mem::drop(self.job_sender.take());
+
+        let deadline = Instant::now() + drain_timeout;
+        let mut workers = Vec::new();
+        // `JoinHandle` has no timed `join`, so instead poll until every thread has
+        // finished, or the deadline has passed. Workers respawned in the meantime
+        // are collected along the way.
+        loop {
+            workers.append(&mut lock(&self.shared.workers));
+            let busy = workers
+                .iter()
+                .any(|w| w.handle.as_ref().is_some_and(|h| !h.is_finished()));
+            if !busy || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut report = ShutdownReport::default();
+        for worker in &mut workers {
+            match worker.handle.take() {
+                Some(handle) if handle.is_finished() => {
+                    if let Err(err) = handle.join() {
+                        simplelog::error!("worker {} panicked: {:?}", worker.id, err);
+                    }
+                    report.finished.push(worker.id);
+                }
+                Some(_) => {
+                    simplelog::warn!("worker {} still busy after {:?}; detaching it", worker.id, drain_timeout);
+                    report.busy.push(worker.id);
+                }
+                None => {}
+            }
+        }
+
+        report
+    }
+}
+
+/// A cloneable handle with which to submit jobs to a [`ThreadPool`], as obtained from
+/// `ThreadPool::submit_handle`, e.g. for the jobs of `util::accept_connections` to hand
+/// their connection off to a job submitted with the [`JobOptions`] of a request.
+///
+/// It doesn't keep the pool's queue open: once the pool is shut down or dropped, jobs
+/// submitted through it are rejected with `InexistentJobSenderError`.
+#[derive(Clone)]
+pub struct SubmitHandle {
+    shared: Arc<PoolShared>,
+    sender: Weak<JobSender>,
+}
+
+impl SubmitHandle {
+    /// See `ThreadPool::try_execute`.
+    ///
+    /// # Errors
+    ///
+    /// The same as `ThreadPool::try_execute`'s.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ThreadPoolError>
+    where
+        F: FnOnce() -> io::Result<()> + Send + 'static,
+    {
+        match self.sender.upgrade() {
+            None => Err(ThreadPoolError::InexistentJobSenderError),
+            Some(sender) => self.shared.send_job(&sender, Box::new(f), false),
+        }
+    }
+
+    /// See `ThreadPool::try_execute_with`.
+    ///
+    /// # Errors
+    ///
+    /// The same as `ThreadPool::try_execute_with`'s.
+    pub fn try_execute_with<F>(&self, options: JobOptions, f: F) -> Result<(), ThreadPoolError>
+    where
+        F: FnOnce() -> io::Result<()> + Send + 'static,
+    {
+        let sender = self.sender.upgrade();
+        self.shared.send_job_with(sender.as_deref(), options, Box::new(f), false)
     }
 }
 
@@ -253,14 +1149,31 @@ impl ThreadPool {
 /// The sending half of the [`ThreadPool`]'s `mpsc::channel`, owned by the main thread,
 /// is dropped, in order to signal the worker threads that when they read from their
 /// end of the channel and get a `ReceiveError`, it is time to shut themselves down.
+///
+/// Before that, the timer thread, if any, is stopped, as it holds a clone of that
+/// sending half; jobs scheduled on it that aren't due yet are discarded.
+///
+/// This waits for as long as the workers take; see `ThreadPool::shutdown` for a
+/// way to bound that.
 impl Drop for ThreadPool {
     fn drop(&mut self) {
         simplelog::debug!("Running impl Drop for ThreadPool");
 
+        self.stop_timer();
+        std::mem::drop(self.handle_sender.take());
         std::mem::drop(self.job_sender.take());
 
-        for worker in &mut self.workers {
-            if let Some(handle) = worker.handle.take() {
+        // Joining a dying worker may respawn it, so repeat until none are left;
+        // the lock must not be held while joining, or that worker couldn't finish.
+        loop {
+            let workers = std::mem::take(&mut *lock(&self.shared.workers));
+            if workers.is_empty() {
+                break;
+            }
+            for mut worker in workers {
+                let Some(handle) = worker.handle.take() else {
+                    continue;
+                };
                 let this_id = thread::current().id();
                 let thread_id = handle.thread().id();
                 match handle.join() {
@@ -0,0 +1,220 @@
+//! This module contains [`RotatingFile`], the log file written to by
+//! `util::init_logging_infrastructure_with`, which is rotated according to a
+//! [`LogRotation`] policy rather than growing forever.
+//!
+//! Rotation works as with `logrotate`: the file at `path` is renamed to `path.1`, the
+//! previous `path.1` to `path.2`, and so on, the oldest being deleted once there are
+//! more than `LogRotation::retention` of them. Rotated files are optionally compressed
+//! with gzip, becoming `path.1.gz`, etc.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use flate2::{write::GzEncoder, Compression};
+
+/// When a [`RotatingFile`] is rotated, and what is kept of its rotated files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRotation {
+    /// Rotate once the file would grow past this many bytes, if set.
+    pub max_size: Option<u64>,
+    /// Rotate once the file is this old, e.g. a day for daily logs, if set. A file
+    /// appended to after a restart is as old as when it was created.
+    pub max_age: Option<Duration>,
+    /// Number of rotated files kept, older ones being deleted. With `0`, the file is
+    /// simply started over.
+    pub retention: usize,
+    /// Whether rotated files are compressed with gzip.
+    pub compress: bool,
+}
+
+impl Default for LogRotation {
+    /// Rotate at 10 MiB, keeping 5 uncompressed rotated files.
+    fn default() -> LogRotation {
+        LogRotation {
+            max_size: Some(10 * 1024 * 1024),
+            max_age: None,
+            retention: 5,
+            compress: false,
+        }
+    }
+}
+
+impl LogRotation {
+    /// A policy that never rotates, so that the file is only ever appended to.
+    pub fn never() -> LogRotation {
+        LogRotation {
+            max_size: None,
+            max_age: None,
+            retention: 0,
+            compress: false,
+        }
+    }
+}
+
+/// A file opened for appending, which rotates itself as its [`LogRotation`] says
+/// while being written to.
+///
+/// Rotation only happens between lines, i.e. before a write following one that ended
+/// with `\n`, so that a log record written in several pieces is never split across
+/// files, and a file may grow past `LogRotation::max_size` by a line.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    file: fs::File,
+    /// Size of the current file, in bytes.
+    size: u64,
+    /// When the current file was created.
+    created: SystemTime,
+    /// Whether the last write ended a line, so that the file may be rotated.
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    /// Open the file at `path` for appending, creating it if it doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// If the file can't be opened, or its metadata read.
+    pub fn open(path: impl AsRef<Path>, rotation: LogRotation) -> io::Result<RotatingFile> {
+        let path = path.as_ref().to_path_buf();
+        let file = open_append(&path)?;
+        let metadata = file.metadata()?;
+        Ok(RotatingFile {
+            path,
+            rotation,
+            file,
+            size: metadata.len(),
+            created: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            at_line_start: true,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn rotation(&self) -> &LogRotation {
+        &self.rotation
+    }
+
+    /// Path of the `n`th most recent rotated file, counting from `1`, as named when
+    /// compressed or not according to the file's [`LogRotation`].
+    pub fn rotated_path(&self, n: usize) -> PathBuf {
+        rotated_path(&self.path, n, self.rotation.compress)
+    }
+
+    /// Whether writing `len` more bytes calls for a rotation first.
+    fn should_rotate(&self, len: usize) -> bool {
+        if !self.at_line_start || self.size == 0 {
+            return false;
+        }
+        let too_big = self
+            .rotation
+            .max_size
+            .is_some_and(|max_size| self.size + len as u64 > max_size);
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|max_age| self.created.elapsed().is_ok_and(|age| age >= max_age));
+        too_big || too_old
+    }
+
+    /// Rotate the file now, whatever its size and age.
+    ///
+    /// # Errors
+    ///
+    /// If a rotated file can't be renamed, deleted or compressed, or a new file
+    /// created. The file keeps being written to as it was, rotated or not.
+    pub fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let res = self.shift_rotated_files();
+        // A new file if it was moved away, or the same one if not; either way, size and
+        // age are counted from now, so that a failed rotation is only retried once the
+        // file is due for another, rather than on every line.
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.created = SystemTime::now();
+        res
+    }
+
+    /// Make room for, and then move the current file into, `path.1`.
+    fn shift_rotated_files(&self) -> io::Result<()> {
+        let retention = self.rotation.retention;
+        if retention == 0 {
+            return fs::remove_file(&self.path);
+        }
+
+        // Files may be compressed or not, depending on the policy they were
+        // rotated with.
+        for compressed in [false, true] {
+            remove_if_exists(&rotated_path(&self.path, retention, compressed))?;
+        }
+        for n in (1..retention).rev() {
+            for compressed in [false, true] {
+                let from = rotated_path(&self.path, n, compressed);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, n + 1, compressed))?;
+                }
+            }
+        }
+
+        let rotated = rotated_path(&self.path, 1, false);
+        fs::rename(&self.path, &rotated)?;
+        if self.rotation.compress {
+            compress(&rotated, &rotated_path(&self.path, 1, true))?;
+            fs::remove_file(&rotated)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            if let Err(err) = self.rotate() {
+                // Not through `log`, as this is likely called while logging.
+                eprintln!("Could not rotate log file {:?}! Error: {:?}", self.path, err);
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        self.at_line_start = buf[..written].ends_with(b"\n");
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, n: usize, compressed: bool) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{n}"));
+    if compressed {
+        rotated.push(".gz");
+    }
+    PathBuf::from(rotated)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Compress the file at `from` with gzip into a new file at `to`.
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(fs::File::create(to)?, Compression::default());
+    io::copy(&mut fs::File::open(from)?, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
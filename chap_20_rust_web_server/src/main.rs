@@ -1,22 +1,56 @@
-use chap_20_rust_web_server::{ThreadPool, util};
+use chap_20_rust_web_server::{
+    access_log::AccessLog,
+    cache::{self, FileCache},
+    config::{self, CommandLine, IoMode, ServerConfig},
+    event_loop, http::Method, metrics::ServerMetrics, service::Service, shutdown::ShutdownHandle,
+    static_files::StaticFiles, template::Templates, tls::TlsAcceptor,
+    ThreadPool, util,
+};
 
-use std::{net::TcpListener, process};
+use std::{env, net::TcpListener, process, sync::Arc, thread};
 
 fn main() {
-    // Setup logging infra
-    let log_file_name = Some("rust_web_server.log");
-    util::init_logging_infrastructure(log_file_name, log::LevelFilter::Trace)
+    // Settings come from `server.toml`, environment variables and flags, in that
+    // order; see `config`. They're checked before anything is started.
+    let program = env::args().next().unwrap_or_else(|| "rust_web_server".to_string());
+    let command_line = CommandLine::parse(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        eprint!("{}", config::usage(&program));
+        process::exit(2);
+    });
+    if command_line.help {
+        print!("{}", config::usage(&program));
+        return;
+    }
+    let config = ServerConfig::load(&command_line, |name| env::var(name).ok())
         .unwrap_or_else(|err| {
-            eprintln!("Could not init logging infrastructure! Error: {:?}", err);
+            eprintln!("Invalid configuration! Error: {err}");
             eprintln!("Exiting");
-            std::process::exit(1);
+            process::exit(1);
         });
+    if command_line.print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+
+    // Setup logging infra
+    let log_file_name = config.log_file.as_ref().map(|path| path.to_string_lossy());
+    util::init_logging_infrastructure_with(
+        log_file_name.as_deref(),
+        config.log_level,
+        config.log_rotation.clone(),
+    )
+    .unwrap_or_else(|err| {
+        eprintln!("Could not init logging infrastructure! Error: {:?}", err);
+        eprintln!("Exiting");
+        std::process::exit(1);
+    });
 
     //
     // The function is called bind because, in networking, connecting to a port
     // to listen to is known as “binding to a port.”
     //
-    let addr = "127.0.0.1:7878";
+    let addr = &config.addr;
     let listener = TcpListener::bind(addr).unwrap_or_else(|err| {
         simplelog::error!(
             "Problem creating TCP listener on address \"{addr}\". Error: {:?}",
@@ -26,7 +60,29 @@ fn main() {
         process::exit(1);
     });
 
-    let thread_pool_size = 4;
+    // HTTPS is served on a second listener, with the same routes, if configured.
+    let tls_listener = config.tls().map(|(tls_addr, cert_file, key_file)| {
+        let acceptor = TlsAcceptor::from_pem_files(cert_file, key_file).unwrap_or_else(|err| {
+            simplelog::error!("Problem setting up TLS: {err}");
+            simplelog::error!("Exiting");
+            process::exit(1);
+        });
+        let listener = TcpListener::bind(tls_addr).unwrap_or_else(|err| {
+            simplelog::error!(
+                "Problem creating TLS listener on address \"{tls_addr}\". Error: {:?}",
+                err
+            );
+            simplelog::error!("Exiting");
+            process::exit(1);
+        });
+        (listener, acceptor)
+    });
+
+    // Shutdown is requested with Ctrl-C, `SIGTERM`, or `POST /admin/shutdown`.
+    let shutdown = ShutdownHandle::new();
+    shutdown.install_signal_handler().unwrap_or_else(|err| {
+        simplelog::warn!("Could not install signal handler; only the admin endpoint can stop the server. Error: {:?}", err);
+    });
 
     // The below was left inside the `for` loop by mistake, which caused
     // many problems.
@@ -34,26 +90,105 @@ fn main() {
     // Ideally we don't create a thread pool with every new request,
     // causing the old one to dropped with the every iteration of the loop below,
     // and then have to fix cryptic `Recv/PoisonError` problems :)
-    let pool = ThreadPool::build(thread_pool_size)
+    //
+    // Under bursts of connections, the pool grows up to `max_pool_size` workers, and
+    // connections arriving while `queue_capacity` are already waiting for a worker are
+    // turned away with `503 Service Unavailable`, rather than piling up in memory.
+    let pool = ThreadPool::build_with(config.thread_pool_config())
         .unwrap_or_else(|err| {
-            simplelog::error!("Problem creating the server's threadpool: {:?}", err);
+            simplelog::error!("Problem creating the server's threadpool: {err}");
             simplelog::error!("Exiting");
             process::exit(1);
         });
 
-    // The `take(3)` is to simulated a server being shutdown while it is
-    // serving requests, to test graceful termination. Remove it if unneeded.
-    for stream in listener.incoming().take(3) {
-        let stream = stream.unwrap_or_else(|err| {
-            simplelog::error!("Could not read from TCP stream. Error: {:?}", err);
-            simplelog::error!("Exiting");
-            process::exit(1);
-        });
+    // Files under this directory are served at `/static/...`, if it exists.
+    let static_root = &config.static_root;
+    // Pages are rendered from templates, parsed again once modified if reloading.
+    let templates = Templates::new(&config.templates_dir).with_reload(config.template_reload);
+    let mut router = util::default_router_with(templates, config.page_cache_control.as_deref());
+    // Compressed variants of static files are cached, rather than compressed each time.
+    let compression = config.compress_responses.then(|| config.compression.clone());
+    match StaticFiles::build(static_root) {
+        Ok(mut files) => {
+            if let Some(compression) = &compression {
+                files = files.with_compression(compression.clone());
+            }
+            // Small files are kept in memory, until modified.
+            files = files.with_file_cache(FileCache::default());
+            simplelog::info!("Serving static files from {:?}", files.root());
+            match &config.static_cache_control {
+                Some(cache_control) => router.route(
+                    Method::Get,
+                    "/static/*path",
+                    cache::with_cache_control(cache_control.clone(), files.into_handler()),
+                ),
+                None => router.route(Method::Get, "/static/*path", files.into_handler()),
+            };
+        }
+        Err(err) => {
+            simplelog::info!("Not serving static files from {static_root:?}: {:?}", err);
+        }
+    }
+    util::add_admin_routes(&mut router, shutdown.clone());
+    // Pool and server metrics are served at `/metrics`, for Prometheus to scrape.
+    let metrics = ServerMetrics::new();
+    util::add_metrics_route(&mut router, pool.stats_handle(), metrics.clone());
+
+    // Slow requests may only occupy half the pool's workers, and administrative ones
+    // and metrics go ahead of the others, whichever the I/O mode.
+    pool.add_queue(util::SLOW_QUEUE, (config.pool_size / 2).max(1));
 
-        let execution_res = pool.execute(|| util::handle_connection(stream));
+    // Every worker needs the router and connection settings, so they're shared
+    // rather than rebuilt per connection.
+    let mut service = Service::new(router);
+    service.job_policy = Box::new(util::default_job_options);
+    service.shutdown = shutdown;
+    service.metrics = metrics;
+    // Bodies are read into memory, up to this size, apart from uploads, which are
+    // streamed to disk by their handler.
+    service.limits.max_body_bytes = config.max_body_size;
+    // Responses are compressed as negotiated with `Accept-Encoding`.
+    service.compression = compression;
+    // Every request is recorded there, apart from the diagnostic log.
+    if let Some(access_log_file_name) = &config.access_log {
+        match AccessLog::open(access_log_file_name, config.access_log_format) {
+            Ok(access_log) => service.access_log = Some(access_log),
+            Err(err) => {
+                simplelog::warn!(
+                    "Could not open access log {access_log_file_name:?}; not logging requests. Error: {:?}",
+                    err
+                );
+            }
+        }
+    }
+    let service = Arc::new(service);
 
-        execution_res.unwrap_or_else(|err| {
-            simplelog::warn!("problem sending job to pool; {:?}", err)
+    // Each listener has a thread of its own accepting connections, until shutdown.
+    thread::scope(|scope| {
+        if let Some((tls_listener, acceptor)) = &tls_listener {
+            let (pool, service) = (&pool, &service);
+            scope.spawn(move || {
+                util::accept_tls_connections(tls_listener, pool, service, acceptor)
+                    .unwrap_or_else(|err| {
+                        simplelog::error!("Problem accepting TLS connections. Error: {:?}", err);
+                    });
+            });
+        }
+        // With the event loop, workers are only busy once a request has fully arrived,
+        // so that slow or idle clients can't take them all.
+        let accept_res = match config.io_mode {
+            IoMode::Blocking => util::accept_connections(&listener, &pool, &service),
+            IoMode::EventLoop => event_loop::accept_connections(&listener, &pool, &service),
+        };
+        accept_res.unwrap_or_else(|err| {
+            simplelog::error!("Problem accepting connections. Error: {:?}", err);
         });
+    });
+
+    let report = pool.shutdown(config.drain_timeout);
+    if report.busy.is_empty() {
+        simplelog::info!("All workers finished; exiting");
+    } else {
+        simplelog::warn!("Exiting with workers {:?} still busy", report.busy);
     }
-}
\ No newline at end of file
+}
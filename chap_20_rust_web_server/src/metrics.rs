@@ -0,0 +1,532 @@
+//! This module contains the counters, gauges and histograms kept by a
+//! [`ThreadPool`](crate::ThreadPool) and by the server, and their rendering in the
+//! Prometheus text exposition format, as served at `/metrics`.
+//!
+//! There's:
+//! * [`PoolStats`], a snapshot of a pool's metrics, as returned by `ThreadPool::stats`,
+//!   or by a [`StatsHandle`] where the pool itself isn't at hand, e.g. in a handler;
+//! * [`ServerMetrics`], a cloneable handle on the server's own metrics, i.e. connections
+//!   accepted and responses sent by status code, and the [`ServerStats`] snapshot of them.
+//!
+//! Every job submitted to the pool is wrapped so that, when run, it records how long
+//! it waited in the queue, how long it ran, and how it ended, without the workers
+//! having to know; jobs submitted with [`JobOptions`](crate::priority::JobOptions) are
+//! wrapped before being kept aside, so their tickets aren't counted twice.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{http::StatusCode, lock, Job, PoolShared, ThreadPoolError};
+
+/// Upper bounds of the buckets of job wait and run time histograms.
+const LATENCY_BUCKETS: [Duration; 10] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(1000),
+    Duration::from_millis(5000),
+    Duration::from_millis(10000),
+];
+
+/// A histogram of durations, with the fixed `LATENCY_BUCKETS`.
+#[derive(Debug)]
+struct Histogram {
+    /// Observations in each bucket, not counting those in smaller buckets. The last
+    /// one is for those above every bound.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| duration <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut count = 0;
+        let mut buckets = Vec::with_capacity(LATENCY_BUCKETS.len());
+        for (bound, observations) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            count += observations.load(Ordering::Relaxed);
+            buckets.push((*bound, count));
+        }
+        count += self.buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+
+        HistogramSnapshot {
+            buckets,
+            count,
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A snapshot of a histogram of durations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Upper bounds of the buckets, in increasing order, each along with the number of
+    /// observations no greater than it, as in Prometheus.
+    pub buckets: Vec<(Duration, u64)>,
+    /// Number of observations, those above every bucket's bound included.
+    pub count: u64,
+    /// Sum of every observation.
+    pub sum: Duration,
+}
+
+/// Counters of a single [`Worker`](crate::Worker), reachable from the jobs it runs.
+#[derive(Debug, Default)]
+pub(crate) struct WorkerMetrics {
+    in_flight: AtomicUsize,
+    jobs_run: AtomicU64,
+}
+
+thread_local! {
+    /// Counters of the [`Worker`](crate::Worker) running on this thread, if any.
+    static WORKER_METRICS: RefCell<Option<Arc<WorkerMetrics>>> = const { RefCell::new(None) };
+}
+
+/// The metrics a [`ThreadPool`](crate::ThreadPool) keeps, as part of the state shared
+/// with its workers.
+#[derive(Debug, Default)]
+pub(crate) struct PoolMetrics {
+    jobs_submitted: AtomicU64,
+    jobs_succeeded: AtomicU64,
+    jobs_panicked: AtomicU64,
+    /// Jobs that returned an error, by `io::ErrorKind`.
+    job_errors: Mutex<BTreeMap<String, u64>>,
+    /// Failures to submit a job, by [`ThreadPoolError`] variant.
+    submit_errors: Mutex<BTreeMap<String, u64>>,
+    wait_time: Histogram,
+    run_time: Histogram,
+    /// Counters of every running worker, by ID.
+    workers: Mutex<BTreeMap<usize, Arc<WorkerMetrics>>>,
+}
+
+impl PoolMetrics {
+    /// Wrap `job` so that running it records its metrics. It counts as waiting in the
+    /// queue from now on.
+    pub(crate) fn instrument(self: &Arc<Self>, job: Job) -> Job {
+        let metrics = Arc::clone(self);
+        let queued_at = Instant::now();
+        Box::new(move || {
+            let started = Instant::now();
+            metrics.wait_time.observe(started - queued_at);
+            let worker = WORKER_METRICS.with(|current| current.borrow().clone());
+            if let Some(worker) = &worker {
+                worker.in_flight.fetch_add(1, Ordering::Relaxed);
+            }
+            let mut run = JobRun {
+                metrics,
+                worker,
+                started,
+                outcome: None,
+            };
+
+            let res = job();
+            run.outcome = Some(res.as_ref().map(|_| ()).map_err(io::Error::kind));
+            res
+        })
+    }
+
+    pub(crate) fn record_submitted(&self) {
+        self.jobs_submitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_submit_error(&self, err: &ThreadPoolError) {
+        let name = match err {
+            ThreadPoolError::InexistentJobSenderError => "InexistentJobSenderError",
+            ThreadPoolError::JobTransmissionError(_) => "JobTransmissionError",
+            ThreadPoolError::JobQueueFullError => "JobQueueFullError",
+            ThreadPoolError::UnknownQueueError(_) => "UnknownQueueError",
+            ThreadPoolError::TimerThreadCreationError(_) => "TimerThreadCreationError",
+        };
+        *lock(&self.submit_errors).entry(name.to_string()).or_default() += 1;
+    }
+
+    /// Start keeping the counters of the worker with the given ID, which is running on
+    /// the calling thread, until the returned guard is dropped.
+    pub(crate) fn enter_worker(self: &Arc<Self>, id: usize) -> WorkerMetricsGuard {
+        let worker = Arc::new(WorkerMetrics::default());
+        lock(&self.workers).insert(id, Arc::clone(&worker));
+        WORKER_METRICS.with(|current| *current.borrow_mut() = Some(worker));
+        WorkerMetricsGuard {
+            id,
+            metrics: Arc::clone(self),
+        }
+    }
+}
+
+/// Records how a job's run ended when dropped, so that a panicking job is accounted
+/// for as well.
+struct JobRun {
+    metrics: Arc<PoolMetrics>,
+    worker: Option<Arc<WorkerMetrics>>,
+    started: Instant,
+    /// Set once the job returns.
+    outcome: Option<Result<(), io::ErrorKind>>,
+}
+
+impl Drop for JobRun {
+    fn drop(&mut self) {
+        self.metrics.run_time.observe(self.started.elapsed());
+        match self.outcome {
+            None => {
+                self.metrics.jobs_panicked.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(Ok(())) => {
+                self.metrics.jobs_succeeded.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(Err(kind)) => {
+                *lock(&self.metrics.job_errors).entry(format!("{kind:?}")).or_default() += 1;
+            }
+        }
+        if let Some(worker) = &self.worker {
+            worker.in_flight.fetch_sub(1, Ordering::Relaxed);
+            worker.jobs_run.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returned by `PoolMetrics::enter_worker`, to stop keeping the worker's counters once
+/// it exits, whether by retiring or by dying.
+pub(crate) struct WorkerMetricsGuard {
+    id: usize,
+    metrics: Arc<PoolMetrics>,
+}
+
+impl Drop for WorkerMetricsGuard {
+    fn drop(&mut self) {
+        lock(&self.metrics.workers).remove(&self.id);
+        WORKER_METRICS.with(|current| current.borrow_mut().take());
+    }
+}
+
+/// A snapshot of a single [`Worker`](crate::Worker)'s metrics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorkerStats {
+    pub id: usize,
+    /// Jobs the worker is running, i.e. `0` or `1`, unless a job it runs runs others.
+    pub in_flight: usize,
+    /// Jobs the worker has run, successfully or not.
+    pub jobs_run: u64,
+}
+
+/// A snapshot of a [`ThreadPool`](crate::ThreadPool)'s metrics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Number of workers, not counting those asked to retire.
+    pub size: usize,
+    /// Number of workers the pool may grow to.
+    pub max_size: usize,
+    /// Number of workers waiting for a job.
+    pub idle_workers: usize,
+    /// Number of jobs waiting for a worker.
+    pub queued_jobs: usize,
+    /// Jobs successfully submitted, scheduled ones included once they come due.
+    pub jobs_submitted: u64,
+    /// Jobs that returned `Ok`.
+    pub jobs_succeeded: u64,
+    /// Jobs that panicked.
+    pub jobs_panicked: u64,
+    /// Jobs that returned `Err`, by the `io::ErrorKind` of the error, e.g. `"TimedOut"`.
+    pub job_errors: BTreeMap<String, u64>,
+    /// Failures to submit a job, by [`ThreadPoolError`] variant, e.g. `"JobQueueFullError"`.
+    pub submit_errors: BTreeMap<String, u64>,
+    /// How long jobs waited for a worker.
+    pub wait_time: HistogramSnapshot,
+    /// How long jobs ran for.
+    pub run_time: HistogramSnapshot,
+    /// Every running worker's metrics, by increasing ID.
+    pub workers: Vec<WorkerStats>,
+}
+
+impl PoolStats {
+    /// Take a snapshot of the metrics of the pool whose shared state is `shared`.
+    pub(crate) fn of(shared: &PoolShared) -> PoolStats {
+        let metrics = &shared.metrics;
+        let sizing = lock(&shared.sizing);
+        PoolStats {
+            size: sizing.size,
+            max_size: sizing.max_size,
+            idle_workers: shared.idle_workers.load(Ordering::SeqCst),
+            queued_jobs: shared.queued_jobs.load(Ordering::SeqCst),
+            jobs_submitted: metrics.jobs_submitted.load(Ordering::Relaxed),
+            jobs_succeeded: metrics.jobs_succeeded.load(Ordering::Relaxed),
+            jobs_panicked: metrics.jobs_panicked.load(Ordering::Relaxed),
+            job_errors: lock(&metrics.job_errors).clone(),
+            submit_errors: lock(&metrics.submit_errors).clone(),
+            wait_time: metrics.wait_time.snapshot(),
+            run_time: metrics.run_time.snapshot(),
+            workers: lock(&metrics.workers)
+                .iter()
+                .map(|(&id, worker)| WorkerStats {
+                    id,
+                    in_flight: worker.in_flight.load(Ordering::Relaxed),
+                    jobs_run: worker.jobs_run.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+
+    /// Number of jobs that returned `Err`, whatever the error.
+    pub fn jobs_failed(&self) -> u64 {
+        self.job_errors.values().sum()
+    }
+
+    /// Render the snapshot in the Prometheus text exposition format, with metric
+    /// names prefixed by `threadpool_`.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        gauge(&mut out, "threadpool_workers", "Number of workers.", &[("", self.size as u64)]);
+        gauge(
+            &mut out,
+            "threadpool_max_workers",
+            "Number of workers the pool may grow to.",
+            &[("", self.max_size as u64)],
+        );
+        gauge(
+            &mut out,
+            "threadpool_idle_workers",
+            "Number of workers waiting for a job.",
+            &[("", self.idle_workers as u64)],
+        );
+        gauge(
+            &mut out,
+            "threadpool_queued_jobs",
+            "Number of jobs waiting for a worker.",
+            &[("", self.queued_jobs as u64)],
+        );
+        counter(
+            &mut out,
+            "threadpool_jobs_submitted_total",
+            "Jobs submitted to the pool.",
+            &[("", self.jobs_submitted)],
+        );
+        let completed = vec![
+            ("outcome=\"ok\"".to_string(), self.jobs_succeeded),
+            ("outcome=\"error\"".to_string(), self.jobs_failed()),
+            ("outcome=\"panic\"".to_string(), self.jobs_panicked),
+        ];
+        counter(
+            &mut out,
+            "threadpool_jobs_completed_total",
+            "Jobs run to completion, by outcome.",
+            &labelled(&completed),
+        );
+        let job_errors = labels("kind", &self.job_errors);
+        counter(
+            &mut out,
+            "threadpool_job_errors_total",
+            "Jobs that returned an error, by io::ErrorKind.",
+            &labelled(&job_errors),
+        );
+        let submit_errors = labels("error", &self.submit_errors);
+        counter(
+            &mut out,
+            "threadpool_submit_errors_total",
+            "Failures to submit a job, by ThreadPoolError variant.",
+            &labelled(&submit_errors),
+        );
+        let in_flight: Vec<_> = self
+            .workers
+            .iter()
+            .map(|w| (format!("worker=\"{}\"", w.id), w.in_flight as u64))
+            .collect();
+        gauge(
+            &mut out,
+            "threadpool_worker_jobs_in_flight",
+            "Jobs each worker is running.",
+            &labelled(&in_flight),
+        );
+        let jobs_run: Vec<_> = self
+            .workers
+            .iter()
+            .map(|w| (format!("worker=\"{}\"", w.id), w.jobs_run))
+            .collect();
+        counter(
+            &mut out,
+            "threadpool_worker_jobs_total",
+            "Jobs each worker has run.",
+            &labelled(&jobs_run),
+        );
+        histogram(
+            &mut out,
+            "threadpool_job_wait_seconds",
+            "Time jobs spent waiting for a worker.",
+            &self.wait_time,
+        );
+        histogram(
+            &mut out,
+            "threadpool_job_run_seconds",
+            "Time jobs spent running.",
+            &self.run_time,
+        );
+        out
+    }
+}
+
+/// A handle from which snapshots of a [`ThreadPool`](crate::ThreadPool)'s metrics can be
+/// taken without the pool itself, as returned by `ThreadPool::stats_handle`.
+#[derive(Clone)]
+pub struct StatsHandle {
+    pub(crate) shared: Arc<PoolShared>,
+}
+
+impl StatsHandle {
+    /// See `ThreadPool::stats`.
+    pub fn stats(&self) -> PoolStats {
+        PoolStats::of(&self.shared)
+    }
+}
+
+/// Shared state behind every clone of a [`ServerMetrics`].
+#[derive(Debug, Default)]
+struct ServerState {
+    connections: AtomicU64,
+    responses: Mutex<BTreeMap<u16, u64>>,
+}
+
+/// A cloneable handle on the server's metrics, kept by `util::handle_connection` and
+/// `util::accept_connections`.
+#[derive(Debug, Clone, Default)]
+pub struct ServerMetrics {
+    state: Arc<ServerState>,
+}
+
+/// A snapshot of the server's metrics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Connections accepted, rejected ones included.
+    pub connections: u64,
+    /// Responses sent, by status code.
+    pub responses: BTreeMap<u16, u64>,
+}
+
+impl ServerMetrics {
+    /// Create a handle on metrics starting from zero.
+    pub fn new() -> ServerMetrics {
+        ServerMetrics::default()
+    }
+
+    pub fn record_connection(&self) {
+        self.state.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_response(&self, status: StatusCode) {
+        *lock(&self.state.responses).entry(status.as_u16()).or_default() += 1;
+    }
+
+    /// Take a snapshot of the metrics.
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            connections: self.state.connections.load(Ordering::Relaxed),
+            responses: lock(&self.state.responses).clone(),
+        }
+    }
+}
+
+impl ServerStats {
+    /// Render the snapshot in the Prometheus text exposition format, with metric
+    /// names prefixed by `http_`.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "http_connections_total",
+            "Connections accepted.",
+            &[("", self.connections)],
+        );
+        let responses: Vec<_> = self
+            .responses
+            .iter()
+            .map(|(status, &count)| (format!("status=\"{status}\""), count))
+            .collect();
+        counter(
+            &mut out,
+            "http_responses_total",
+            "Responses sent, by status code.",
+            &labelled(&responses),
+        );
+        out
+    }
+}
+
+/// The content type of the Prometheus text exposition format.
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Label sets with a single label `name`, one per key of `counts`.
+fn labels(name: &str, counts: &BTreeMap<String, u64>) -> Vec<(String, u64)> {
+    counts
+        .iter()
+        .map(|(value, &count)| (format!("{name}=\"{}\"", escape_label(value)), count))
+        .collect()
+}
+
+/// Borrow the label sets in `samples`, as taken by `counter` and `gauge`.
+fn labelled(samples: &[(String, u64)]) -> Vec<(&str, u64)> {
+    samples.iter().map(|(labels, value)| (labels.as_str(), *value)).collect()
+}
+
+/// Escape a label value, as required by the exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn counter(out: &mut String, name: &str, help: &str, samples: &[(&str, u64)]) {
+    metric(out, name, "counter", help, samples);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, samples: &[(&str, u64)]) {
+    metric(out, name, "gauge", help, samples);
+}
+
+/// Write a metric's `HELP` and `TYPE` lines, and a sample for each of the given label
+/// sets, as in `worker="3"`, an empty one meaning no labels.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+    out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            out.push_str(&format!("{name} {value}\n"));
+        } else {
+            out.push_str(&format!("{name}{{{labels}}} {value}\n"));
+        }
+    }
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, snapshot: &HistogramSnapshot) {
+    out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} histogram\n"));
+    for (bound, count) in &snapshot.buckets {
+        out.push_str(&format!("{name}_bucket{{le=\"{}\"}} {count}\n", bound.as_secs_f64()));
+    }
+    out.push_str(&format!("{name}_bucket{{le=\"+Inf\"}} {}\n", snapshot.count));
+    out.push_str(&format!("{name}_sum {}\n", snapshot.sum.as_secs_f64()));
+    out.push_str(&format!("{name}_count {}\n", snapshot.count));
+}
@@ -0,0 +1,264 @@
+//! This module contains [`Priority`] and [`JobOptions`], with which jobs are submitted
+//! to a [`ThreadPool`](crate::ThreadPool) ahead of others, or into named queues that
+//! may only occupy so many of its workers at once.
+//!
+//! Such jobs aren't put in the pool's queue directly, but kept aside by a `Dispatcher`.
+//! What is queued in their place is a ticket: a job that, when run, runs whichever of
+//! the jobs kept aside should go first. So, as far as jobs submitted with options go:
+//! * the one with the highest priority is started first, and among those with the same
+//!   priority, the one submitted first;
+//! * jobs in a named queue whose quota of workers is used up are skipped, until one of
+//!   its running jobs finishes.
+//!
+//! Jobs submitted with `ThreadPool::execute` or `ThreadPool::submit` go straight into the
+//! pool's queue, and count as [`Priority::Normal`]. To still run `Priority::High` jobs
+//! ahead of them, a worker first runs every pending `High` job before starting any
+//! other. There is no such check for `Priority::Low` jobs, which are only guaranteed
+//! to start after every pending job submitted with a higher priority.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    io, panic,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{lock, run_job, Job, ThreadPoolError};
+
+/// How urgently a job submitted with [`JobOptions`] should start.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// For background jobs, which can wait for any others.
+    Low,
+    /// The priority of jobs submitted without options.
+    #[default]
+    Normal,
+    /// For cheap jobs that shouldn't wait for others, e.g. health checks.
+    High,
+}
+
+/// How to submit a job, with e.g. `ThreadPool::execute_with`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JobOptions {
+    /// Jobs with a higher priority are started first.
+    pub priority: Priority,
+    /// Name of the queue the job belongs to, as registered with `ThreadPool::add_queue`,
+    /// or `None` for a job whose number of workers isn't limited.
+    pub queue: Option<String>,
+}
+
+impl JobOptions {
+    /// Set the job's priority.
+    pub fn with_priority(mut self, priority: Priority) -> JobOptions {
+        self.priority = priority;
+        self
+    }
+
+    /// Put the job in the named queue.
+    pub fn with_queue(mut self, queue: impl Into<String>) -> JobOptions {
+        self.queue = Some(queue.into());
+        self
+    }
+}
+
+/// A job kept aside until a worker is free to run it.
+struct Pending {
+    job: Job,
+    priority: Priority,
+    queue: Option<String>,
+}
+
+/// A queue registered with `ThreadPool::add_queue`.
+#[derive(Debug)]
+struct NamedQueue {
+    /// Maximum number of its jobs running at once.
+    quota: usize,
+    running: usize,
+}
+
+/// Identifies a job kept aside: the order in which such jobs are started, highest
+/// priority first, and then first submitted first.
+type PendingKey = (Reverse<Priority>, u64);
+
+#[derive(Default)]
+struct DispatchState {
+    pending: BTreeMap<PendingKey, Pending>,
+    next_seq: u64,
+    queues: HashMap<String, NamedQueue>,
+    /// Tickets sent to the pool's queue, and not run yet.
+    tickets: usize,
+    /// Tickets that were run when every pending job's queue was at its quota, and so
+    /// are owed to those jobs. They are made good on by the workers finishing a job
+    /// from a named queue, which then run a pending job in its place.
+    deferred: usize,
+}
+
+impl DispatchState {
+    /// Take the first pending job whose queue isn't at its quota, and whose priority
+    /// is above `above`, if given, accounting for it as running.
+    fn take(&mut self, above: Option<Priority>, urgent: &AtomicUsize) -> Option<Pending> {
+        let queues = &self.queues;
+        let key = *self
+            .pending
+            .iter()
+            .take_while(|(_, p)| above.is_none_or(|above| p.priority > above))
+            .find(|(_, p)| match &p.queue {
+                None => true,
+                Some(name) => queues.get(name).is_none_or(|q| q.running < q.quota),
+            })?
+            .0;
+
+        let pending = self.pending.remove(&key)?;
+        if let Some(queue) = pending.queue.as_ref().and_then(|name| self.queues.get_mut(name)) {
+            queue.running += 1;
+        }
+        if pending.priority > Priority::Normal {
+            urgent.fetch_sub(1, Ordering::SeqCst);
+        }
+        Some(pending)
+    }
+}
+
+/// Keeps jobs submitted with [`JobOptions`] aside, and hands them out to workers in
+/// order of priority, within the quotas of their queues.
+#[derive(Default)]
+pub(crate) struct Dispatcher {
+    state: Mutex<DispatchState>,
+    /// Number of pending jobs above `Priority::Normal`, so that workers can tell
+    /// whether there are any without taking the lock.
+    urgent: AtomicUsize,
+}
+
+impl Dispatcher {
+    /// Register a queue whose jobs may only occupy `quota` workers at once, or change
+    /// the quota of an existing one.
+    pub(crate) fn add_queue(&self, name: &str, quota: usize) {
+        let mut state = lock(&self.state);
+        state
+            .queues
+            .entry(name.to_string())
+            .and_modify(|q| q.quota = quota)
+            .or_insert(NamedQueue { quota, running: 0 });
+    }
+
+    /// Keep `job` aside, counting on the caller to then send a ticket for it.
+    ///
+    /// # Errors
+    ///
+    /// `UnknownQueueError` if the options name a queue that wasn't registered.
+    pub(crate) fn push(&self, options: JobOptions, job: Job) -> Result<PendingKey, ThreadPoolError> {
+        let mut state = lock(&self.state);
+        if let Some(name) = &options.queue {
+            if !state.queues.contains_key(name) {
+                return Err(ThreadPoolError::UnknownQueueError(name.clone()));
+            }
+        }
+
+        let key = (Reverse(options.priority), state.next_seq);
+        state.next_seq += 1;
+        state.tickets += 1;
+        state.pending.insert(
+            key,
+            Pending {
+                job,
+                priority: options.priority,
+                queue: options.queue,
+            },
+        );
+        if options.priority > Priority::Normal {
+            self.urgent.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(key)
+    }
+
+    /// Undo `Dispatcher::push`, after failing to send the job's ticket.
+    ///
+    /// Returns whether the job was still pending: a worker may have run it already,
+    /// if it was urgent.
+    pub(crate) fn cancel(&self, key: PendingKey) -> bool {
+        let mut state = lock(&self.state);
+        state.tickets -= 1;
+        match state.pending.remove(&key) {
+            None => false,
+            Some(pending) => {
+                if pending.priority > Priority::Normal {
+                    self.urgent.fetch_sub(1, Ordering::SeqCst);
+                }
+                true
+            }
+        }
+    }
+
+    /// The ticket to send to the pool's queue for a job just pushed.
+    pub(crate) fn ticket(self: &Arc<Self>) -> Job {
+        let dispatcher = Arc::clone(self);
+        Box::new(move || dispatcher.run_ticket())
+    }
+
+    /// Run the first pending job, if any can be run, as the ticket's own: its outcome
+    /// is the ticket's, for the worker running the ticket to log.
+    fn run_ticket(&self) -> io::Result<()> {
+        let next = {
+            let mut state = lock(&self.state);
+            state.tickets -= 1;
+            let next = state.take(None, &self.urgent);
+            // The jobs a ticket is meant for may have been run already, by workers
+            // looking for urgent ones; only owe it to those still pending.
+            if next.is_none() && state.pending.len() > state.tickets + state.deferred {
+                state.deferred += 1;
+            }
+            next
+        };
+        match next {
+            Some(pending) => self.run(pending),
+            None => Ok(()),
+        }
+    }
+
+    /// Run every pending job above `Priority::Normal`, as workers do before starting
+    /// any other job.
+    pub(crate) fn run_urgent(&self) {
+        while self.urgent.load(Ordering::SeqCst) > 0 {
+            let next = lock(&self.state).take(Some(Priority::Normal), &self.urgent);
+            match next {
+                Some(pending) => run_job(|| self.run(pending)),
+                // Those left are in queues at their quota.
+                None => break,
+            }
+        }
+    }
+
+    /// Run `pending`, and then any job a deferred ticket is owed to, returning the
+    /// outcome of `pending`.
+    ///
+    /// `pending` is run as part of the caller's `run_job`, which logs its outcome, and
+    /// catches its panic. A panic is only caught here for as long as it takes to free
+    /// the job's slot in its queue, and to run the deferred jobs, and then resumed.
+    fn run(&self, pending: Pending) -> io::Result<()> {
+        let outcome = panic::catch_unwind(panic::AssertUnwindSafe(pending.job));
+
+        let mut queue = pending.queue;
+        loop {
+            let mut state = lock(&self.state);
+            if let Some(queue) = queue.as_ref().and_then(|name| state.queues.get_mut(name)) {
+                queue.running -= 1;
+            }
+            if state.deferred == 0 {
+                break;
+            }
+            let Some(next) = state.take(None, &self.urgent) else {
+                break;
+            };
+            state.deferred -= 1;
+            drop(state);
+
+            run_job(next.job);
+            queue = next.queue;
+        }
+
+        outcome.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+}
@@ -0,0 +1,299 @@
+//! This module contains what the server needs to answer range requests, as described
+//! in RFC 9110, section 14, with which clients fetch parts of a file, e.g. to resume
+//! an interrupted download:
+//! * [`RangeRequest`], the byte ranges a request's `Range` header field asks for, and
+//! * `serve_ranges`, which answers with `206 Partial Content` accordingly, the parts
+//!   of a `multipart/byteranges` body if there are several, or with
+//!   `416 Range Not Satisfiable` if none of the ranges is within the file.
+//!
+//! Responses that may be requested in parts advertise so with `Accept-Ranges: bytes`.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    date::DateTime,
+    http::{Method, Request, Response, StatusCode},
+};
+
+/// Most ranges served in one response, once overlapping ones are merged. Requests for
+/// more are answered with the whole file, as many tiny ranges cost more to send than
+/// they save.
+pub const MAX_RANGES: usize = 32;
+
+/// A range of bytes of a file, both ends included, as in `Range: bytes=0-499`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// Number of bytes in the range.
+    #[allow(clippy::len_without_is_empty, reason = "both ends are included, so a range is never empty")]
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// The value of the `Content-Range` header field of this range of a file `complete`
+    /// bytes long, e.g. `bytes 0-499/1234`.
+    pub fn content_range(&self, complete: u64) -> String {
+        format!("bytes {}-{}/{complete}", self.start, self.end)
+    }
+}
+
+/// What a request's `Range` header field asks of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// The whole file: there's no `Range`, or it is to be ignored, being malformed, in
+    /// another unit than bytes, or asking for more than [`MAX_RANGES`] ranges.
+    Full,
+    /// Those ranges of the file, in ascending order and without overlaps.
+    Partial(Vec<ByteRange>),
+    /// None of the ranges asked for starts within the file.
+    Unsatisfiable,
+}
+
+impl RangeRequest {
+    /// The ranges the value `range` of a `Range` header field asks of a file `len`
+    /// bytes long, e.g. `bytes=0-499`, `bytes=500-`, or `bytes=-500` for the last
+    /// 500 bytes.
+    ///
+    /// Ranges ending past the file are cut short, and those overlapping or adjacent
+    /// to each other are merged.
+    pub fn parse(range: &str, len: u64) -> RangeRequest {
+        let Some((unit, ranges)) = range.split_once('=') else {
+            return RangeRequest::Full;
+        };
+        if !unit.trim().eq_ignore_ascii_case("bytes") {
+            return RangeRequest::Full;
+        }
+
+        let number = |digits: &str| {
+            let digits = digits.trim();
+            if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+                return None;
+            }
+            digits.parse::<u64>().ok()
+        };
+        let mut satisfiable = Vec::new();
+        let mut any = false;
+        for spec in ranges.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+            let Some((first, last)) = spec.split_once('-') else {
+                return RangeRequest::Full;
+            };
+            any = true;
+            let range = match (first.trim(), last.trim()) {
+                ("", suffix) => {
+                    let Some(suffix) = number(suffix) else {
+                        return RangeRequest::Full;
+                    };
+                    (suffix > 0 && len > 0).then(|| ByteRange {
+                        start: len.saturating_sub(suffix),
+                        end: len - 1,
+                    })
+                }
+                (first, "") => {
+                    let Some(start) = number(first) else {
+                        return RangeRequest::Full;
+                    };
+                    (start < len).then(|| ByteRange { start, end: len - 1 })
+                }
+                (first, last) => match (number(first), number(last)) {
+                    (Some(start), Some(end)) if start <= end => {
+                        (start < len).then(|| ByteRange { start, end: end.min(len - 1) })
+                    }
+                    _ => return RangeRequest::Full,
+                },
+            };
+            satisfiable.extend(range);
+        }
+        if !any {
+            return RangeRequest::Full;
+        }
+        if satisfiable.is_empty() {
+            return RangeRequest::Unsatisfiable;
+        }
+
+        satisfiable.sort_by_key(|range| range.start);
+        let mut merged: Vec<ByteRange> = Vec::with_capacity(satisfiable.len());
+        for range in satisfiable {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end.saturating_add(1) => {
+                    last.end = last.end.max(range.end)
+                }
+                _ => merged.push(range),
+            }
+        }
+        if merged.len() > MAX_RANGES {
+            return RangeRequest::Full;
+        }
+        RangeRequest::Partial(merged)
+    }
+
+    /// The ranges `request` asks of the body of `response`, `len` bytes long, which is
+    /// the whole of it unless `request` is a `GET` with a `Range` header field.
+    ///
+    /// If the request has an `If-Range` header field, its ranges only apply if the
+    /// response has the entity tag, strongly compared, or the exact `Last-Modified`
+    /// date it names; the client's partial copy is otherwise stale, and it gets the
+    /// whole file instead.
+    pub fn from_request(request: &Request, response: &Response, len: u64) -> RangeRequest {
+        let Some(range) = request.headers.get("Range") else {
+            return RangeRequest::Full;
+        };
+        if request.method != Method::Get {
+            return RangeRequest::Full;
+        }
+        if let Some(if_range) = request.headers.get("If-Range") {
+            let if_range = if_range.trim();
+            let current = if if_range.starts_with('"') {
+                response.headers.get("ETag").is_some_and(|etag| etag == if_range)
+            } else {
+                let last_modified = response.headers.get("Last-Modified");
+                DateTime::parse_http_date(if_range).is_some_and(|date| {
+                    last_modified.and_then(DateTime::parse_http_date) == Some(date)
+                })
+            };
+            if !current {
+                return RangeRequest::Full;
+            }
+        }
+        RangeRequest::parse(range, len)
+    }
+}
+
+/// Answer `request` with the parts of the body, `len` bytes read from `reader`, it
+/// asks for, `response` being the `200 OK` response that would send all of it.
+///
+/// * A single range is sent as the body of a `206 Partial Content`, with a
+///   `Content-Range` header field.
+/// * Several are sent as the parts of a `multipart/byteranges` body, each with the
+///   `Content-Type` of the response, and its `Content-Range`.
+/// * If none is satisfiable, the response is a `416 Range Not Satisfiable`, whose
+///   `Content-Range` gives the length of the body.
+/// * Otherwise, `response` sends the whole body.
+///
+/// Either way, the response carries `Accept-Ranges: bytes`.
+///
+/// # Errors
+///
+/// If seeking `reader` to the start of a single range fails.
+pub fn serve_ranges<R>(request: &Request, response: Response, mut reader: R, len: u64) -> io::Result<Response>
+where
+    R: Read + Seek + Send + 'static,
+{
+    let response = response.with_header("Accept-Ranges", "bytes");
+    let mut ranges = match RangeRequest::from_request(request, &response, len) {
+        RangeRequest::Full => return Ok(response.with_reader(reader, len)),
+        RangeRequest::Unsatisfiable => {
+            return Ok(Response::plain(StatusCode::RANGE_NOT_SATISFIABLE)
+                .with_header("Accept-Ranges", "bytes")
+                .with_header("Content-Range", format!("bytes */{len}")))
+        }
+        RangeRequest::Partial(ranges) => ranges,
+    };
+
+    let mut partial = response;
+    partial.status = StatusCode::PARTIAL_CONTENT;
+    if ranges.len() == 1 {
+        let range = ranges.remove(0);
+        reader.seek(SeekFrom::Start(range.start))?;
+        return Ok(partial
+            .with_header("Content-Range", range.content_range(len))
+            .with_reader(reader.take(range.len()), range.len()));
+    }
+
+    let boundary = boundary();
+    let content_type = partial.headers.get("Content-Type").map(str::to_string);
+    let mut parts = VecDeque::new();
+    let mut body_len = 0;
+    for range in ranges {
+        let mut head = format!("\r\n--{boundary}\r\n");
+        if let Some(content_type) = &content_type {
+            head.push_str(&format!("Content-Type: {content_type}\r\n"));
+        }
+        head.push_str(&format!("Content-Range: {}\r\n\r\n", range.content_range(len)));
+        body_len += head.len() as u64 + range.len();
+        parts.push_back(Part::Text(Cursor::new(head.into_bytes())));
+        parts.push_back(Part::Range(range));
+    }
+    let tail = format!("\r\n--{boundary}--\r\n");
+    body_len += tail.len() as u64;
+    parts.push_back(Part::Text(Cursor::new(tail.into_bytes())));
+
+    Ok(partial
+        .with_header("Content-Type", format!("multipart/byteranges; boundary={boundary}"))
+        .with_reader(MultipartReader { reader, position: None, parts }, body_len))
+}
+
+/// A boundary delimiting the parts of a `multipart/byteranges` body, unlikely to be
+/// found in the parts themselves.
+fn boundary() -> String {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.subsec_nanos() as u64);
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    format!("byteranges_{:016x}", nanos.rotate_left(32) ^ count.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+}
+
+/// A piece of a `multipart/byteranges` body.
+enum Part {
+    /// The delimiter and header fields of a part, or the final delimiter.
+    Text(Cursor<Vec<u8>>),
+    /// The bytes of a part still to be read from the file.
+    Range(ByteRange),
+}
+
+/// Reads a `multipart/byteranges` body, the ranges being read from `reader` as they
+/// come, so the file is never held in memory.
+struct MultipartReader<R> {
+    reader: R,
+    /// Where `reader` is at, if known, to spare seeking it while reading a range.
+    position: Option<u64>,
+    parts: VecDeque<Part>,
+}
+
+impl<R: Read + Seek> Read for MultipartReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let Some(part) = self.parts.front_mut() else {
+                return Ok(0);
+            };
+            let n = match part {
+                Part::Text(text) => text.read(buf)?,
+                Part::Range(range) => {
+                    if self.position != Some(range.start) {
+                        self.reader.seek(SeekFrom::Start(range.start))?;
+                    }
+                    let max = range.len().min(buf.len() as u64) as usize;
+                    let n = self.reader.read(&mut buf[..max])?;
+                    if n == 0 {
+                        // The file shrank since its length was taken.
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                    }
+                    self.position = Some(range.start + n as u64);
+                    if n as u64 == range.len() {
+                        self.parts.pop_front();
+                    } else {
+                        range.start += n as u64;
+                    }
+                    return Ok(n);
+                }
+            };
+            if n == 0 {
+                self.parts.pop_front();
+            } else {
+                return Ok(n);
+            }
+        }
+    }
+}
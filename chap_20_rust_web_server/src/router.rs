@@ -0,0 +1,227 @@
+//! This module contains the [`Router`], which decides which handler serves a request.
+//!
+//! Handlers are registered per [`Method`] and path pattern. A pattern is a `/`-separated
+//! list of segments, each of which is one of:
+//! * a literal, e.g. `users`, matching only itself;
+//! * a parameter, e.g. `:id`, matching any single segment, whose percent-decoded
+//!   value is then available through `Request::param("id")`;
+//! * a wildcard, e.g. `*path`, which must be the last segment, and matches the rest
+//!   of the path, possibly empty.
+//!
+//! So `GET /users/:id` matches `GET /users/7`, and `GET /static/*path` matches
+//! `GET /static/css/site.css` with `path` being `css/site.css`.
+
+use std::{collections::HashMap, io};
+
+use crate::http::{self, Method, Request, Response, StatusCode};
+
+/// A request handler, i.e. what the [`Router`] calls once it has matched a request.
+///
+/// As with [`crate::Job`], the `io::Result` is there so that handlers can use `?`
+/// on e.g. file system operations; an `Err` aborts the connection.
+///
+/// It must be `Send + Sync` because the router is shared among every worker thread.
+pub type Handler = Box<dyn Fn(&Request) -> io::Result<Response> + Send + Sync + 'static>;
+
+/// A single segment of a route's path pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+/// A path pattern, parsed from its textual form in `Router::route`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    /// Parse a pattern such as `/users/:id` or `/static/*path`.
+    fn parse(pattern: &str) -> Option<Pattern> {
+        if !pattern.starts_with('/') {
+            return None;
+        }
+
+        let parts: Vec<&str> = split_path(pattern).collect();
+        let mut segments = Vec::with_capacity(parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                // A wildcard swallows the rest of the path, so nothing can follow it.
+                if i != parts.len() - 1 {
+                    return None;
+                }
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Literal(part.to_string())
+            };
+            if let Segment::Param(name) | Segment::Wildcard(name) = &segment {
+                if name.is_empty() {
+                    return None;
+                }
+            }
+            segments.push(segment);
+        }
+
+        Some(Pattern { segments })
+    }
+
+    /// Match `path` against the pattern, returning the captured parameters if it matches.
+    ///
+    /// A path whose captured values cannot be percent-decoded does not match.
+    fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let parts: Vec<&str> = split_path(path).collect();
+        let mut params = HashMap::new();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.get(i)? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.clone(), http::percent_decode(parts.get(i)?)?);
+                }
+                Segment::Wildcard(name) => {
+                    let rest = parts.get(i..).unwrap_or_default().join("/");
+                    params.insert(name.clone(), http::percent_decode(&rest)?);
+                    return Some(params);
+                }
+            }
+        }
+
+        if parts.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+/// Split a path into its non-empty segments, so that `/a//b/` is the same as `/a/b`.
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+/// A registered route: a method, a path pattern, and the handler serving them.
+struct Route {
+    method: Method,
+    pattern: Pattern,
+    handler: Handler,
+}
+
+/// Dispatches requests to the handlers registered for their method and path.
+///
+/// Routes are tried in the order they were registered, and the first one whose
+/// method and pattern both match is used. Then:
+/// * if some route's pattern matches the path, but none for the request's method,
+///   `405 Method Not Allowed` is returned, with an `Allow` field listing the
+///   methods that would have matched;
+/// * if no pattern matches, the fallback handler is used, which by default
+///   returns a plain `404 Not Found`.
+///
+/// `HEAD` requests are served by the matching `GET` route if no `HEAD` route was
+/// registered; it is up to the caller to not send the body.
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Handler,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+impl Router {
+    /// Create a router with no routes, and a plain `404 Not Found` fallback.
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            fallback: Box::new(|_| Ok(Response::plain(StatusCode::NOT_FOUND))),
+        }
+    }
+
+    /// Register `handler` to serve requests with the given method, whose path
+    /// matches `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `pattern` does not start with `/`, has an unnamed
+    /// `:` or `*` segment, or has segments after a wildcard, as that is a
+    /// programming error rather than something to recover from at runtime.
+    pub fn route<H>(&mut self, method: Method, pattern: &str, handler: H) -> &mut Router
+    where
+        H: Fn(&Request) -> io::Result<Response> + Send + Sync + 'static,
+    {
+        let parsed = Pattern::parse(pattern)
+            .unwrap_or_else(|| panic!("invalid route pattern \"{pattern}\""));
+        self.routes.push(Route {
+            method,
+            pattern: parsed,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Replace the handler used when no route's pattern matches the request's path.
+    pub fn fallback<H>(&mut self, handler: H) -> &mut Router
+    where
+        H: Fn(&Request) -> io::Result<Response> + Send + Sync + 'static,
+    {
+        self.fallback = Box::new(handler);
+        self
+    }
+
+    /// Find the route for `request`, fill in its path parameters, and call its handler.
+    ///
+    /// # Errors
+    ///
+    /// Only the handler's own errors are returned; failing to match a route results
+    /// in a `404` or `405` response, as described in [`Router`]'s documentation.
+    pub fn handle(&self, mut request: Request) -> io::Result<Response> {
+        let mut allowed: Vec<Method> = Vec::new();
+        let mut head_fallback = None;
+
+        for route in &self.routes {
+            let params = match route.pattern.matches(request.path()) {
+                None => continue,
+                Some(params) => params,
+            };
+
+            if route.method == request.method {
+                request.params = params;
+                return (route.handler)(&request);
+            }
+            if request.method == Method::Head && route.method == Method::Get && head_fallback.is_none() {
+                head_fallback = Some((route, params));
+            }
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method);
+            }
+        }
+
+        if let Some((route, params)) = head_fallback {
+            request.params = params;
+            return (route.handler)(&request);
+        }
+
+        if allowed.is_empty() {
+            return (self.fallback)(&request);
+        }
+
+        if allowed.contains(&Method::Get) && !allowed.contains(&Method::Head) {
+            allowed.push(Method::Head);
+        }
+        let allow = allowed
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        Ok(Response::plain(StatusCode::METHOD_NOT_ALLOWED).with_header("Allow", allow))
+    }
+}
@@ -0,0 +1,125 @@
+//! This module contains [`Service`], everything a worker needs to serve a connection.
+//!
+//! A single `Service` is built by the binary crate, wrapped in an `Arc`, and shared
+//! by every [`Job`](crate::Job) passed to `util::handle_connection`.
+
+use std::time::Duration;
+
+use crate::{
+    access_log::AccessLog,
+    compression::Compression,
+    http::{Request, RequestLimits},
+    metrics::ServerMetrics,
+    priority::JobOptions,
+    router::Router,
+    shutdown::ShutdownHandle,
+};
+
+/// Decides how the job answering a request is submitted to the pool, e.g.
+/// `util::default_job_options`.
+pub type JobPolicy = Box<dyn Fn(&Request) -> JobOptions + Send + Sync + 'static>;
+
+/// Settings for persistent connections, i.e. HTTP keep-alive.
+///
+/// A worker keeps serving requests on the same connection until one of the
+/// following happens:
+/// * the client asks for the connection to be closed, see `Request::wants_keep_alive`;
+/// * no new request starts within `idle_timeout` of the previous response;
+/// * `max_requests` requests have been served on the connection;
+/// * the server is shutting down.
+///
+/// While a connection is kept open, the worker serving it can't serve any other,
+/// which is why both limits exist.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    /// How long to wait for the next request on an open connection.
+    pub idle_timeout: Duration,
+    /// Number of requests after which the connection is closed. `1` disables
+    /// persistent connections altogether.
+    pub max_requests: usize,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive {
+            idle_timeout: Duration::from_secs(5),
+            max_requests: 100,
+        }
+    }
+}
+
+/// Limits on how long serving a single request may take, so that neither a slow
+/// client nor a slow handler can tie up a worker indefinitely.
+///
+/// Waiting for a request to start on a persistent connection is covered by
+/// `KeepAlive::idle_timeout` instead.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// How long the client has to send a whole request, from its first byte. A client
+    /// too slow to do so is answered with `408 Request Timeout`.
+    pub read: Duration,
+    /// How long a single write of the response may block, e.g. on a client that
+    /// stopped reading, before the connection is dropped.
+    pub write: Duration,
+    /// How long a handler has to produce its response, after which the request's
+    /// [`CancellationToken`](crate::cancellation::CancellationToken) is cancelled.
+    /// Handlers giving up because of it have the client answered with
+    /// `503 Service Unavailable`.
+    pub handler: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            read: Duration::from_secs(10),
+            write: Duration::from_secs(10),
+            handler: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The state shared by every connection the server handles.
+pub struct Service {
+    /// Decides which handler serves each request.
+    pub router: Router,
+    /// Limits on the size of each request.
+    pub limits: RequestLimits,
+    /// Settings for persistent connections.
+    pub keep_alive: KeepAlive,
+    /// Limits on the time spent on each request.
+    pub timeouts: Timeouts,
+    /// Value of `Retry-After` when a connection is rejected for lack of capacity.
+    pub retry_after: Duration,
+    /// Checked by the thread accepting connections, and by workers before keeping
+    /// a connection open for another request.
+    pub shutdown: ShutdownHandle,
+    /// Where connections accepted and responses sent are counted.
+    pub metrics: ServerMetrics,
+    /// Where every request answered is recorded, if anywhere.
+    pub access_log: Option<AccessLog>,
+    /// How responses are compressed, if at all.
+    pub compression: Option<Compression>,
+    /// With which priority, and in which named queue, each request is answered, those
+    /// queues having to be registered with the pool. The event loop submits a job per
+    /// request with them, and in blocking mode, a connection is handed off to a job
+    /// submitted with them, as described in `util::accept_connections`.
+    pub job_policy: JobPolicy,
+}
+
+impl Service {
+    /// Create a service routing requests with `router`, and default settings otherwise.
+    pub fn new(router: Router) -> Service {
+        Service {
+            router,
+            limits: RequestLimits::default(),
+            keep_alive: KeepAlive::default(),
+            timeouts: Timeouts::default(),
+            retry_after: Duration::from_secs(5),
+            shutdown: ShutdownHandle::new(),
+            metrics: ServerMetrics::new(),
+            access_log: None,
+            compression: None,
+            job_policy: Box::new(|_| JobOptions::default()),
+        }
+    }
+}
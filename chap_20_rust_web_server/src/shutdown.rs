@@ -0,0 +1,92 @@
+//! This module contains [`ShutdownHandle`], used to ask the server to stop.
+//!
+//! Shutting down is done in two stages:
+//! * the threads accepting connections, running `util::accept_connections`,
+//!   `util::accept_tls_connections` or `event_loop::accept_connections`, notice the
+//!   handle was triggered and stop accepting, and then
+//! * the [`ThreadPool`](crate::ThreadPool) is given some time to finish the jobs it has
+//!   already accepted, via `ThreadPool::shutdown`.
+//!
+//! A handle can be triggered by `SIGINT` or `SIGTERM` once `install_signal_handler` is
+//! called, or from a request handler, as done for `POST /admin/shutdown` in `util`.
+
+use std::{
+    net,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::lock;
+
+/// Shared state behind every clone of a [`ShutdownHandle`].
+#[derive(Debug, Default)]
+struct ShutdownState {
+    triggered: AtomicBool,
+    /// Addresses of the listeners to wake up when the handle is triggered, e.g. one
+    /// for HTTP and one for HTTPS.
+    listener_addrs: Mutex<Vec<net::SocketAddr>>,
+}
+
+/// A cloneable handle through which shutdown of the server is requested, and checked.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+impl ShutdownHandle {
+    /// Create a handle that hasn't been triggered.
+    pub fn new() -> ShutdownHandle {
+        ShutdownHandle::default()
+    }
+
+    /// Request shutdown of the server.
+    ///
+    /// Each thread accepting connections is blocked in `TcpListener::accept`, or
+    /// waiting for events on its listener, and would only notice with the next
+    /// connection, so one is made to wake it up.
+    /// Triggering a handle more than once has no further effect.
+    pub fn trigger(&self) {
+        if self.state.triggered.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        simplelog::info!("Shutdown requested");
+
+        let listener_addrs = lock(&self.state.listener_addrs).clone();
+        for mut addr in listener_addrs {
+            // A listener bound to e.g. `0.0.0.0` can't be connected to at that address.
+            if addr.ip().is_unspecified() {
+                addr.set_ip(net::Ipv4Addr::LOCALHOST.into());
+            }
+            if let Err(err) = net::TcpStream::connect(addr) {
+                simplelog::warn!("Could not wake up listener at {addr} for shutdown: {:?}", err);
+            }
+        }
+    }
+
+    /// Whether shutdown has been requested.
+    pub fn is_triggered(&self) -> bool {
+        self.state.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Register the address of a listener to wake up in `ShutdownHandle::trigger`.
+    pub fn watch_listener(&self, addr: net::SocketAddr) {
+        let mut listener_addrs = lock(&self.state.listener_addrs);
+        if !listener_addrs.contains(&addr) {
+            listener_addrs.push(addr);
+        }
+    }
+
+    /// Trigger this handle when the process receives `SIGINT` (i.e. Ctrl-C) or
+    /// `SIGTERM`, or their equivalents on Windows.
+    ///
+    /// # Errors
+    ///
+    /// Only one such handler may be installed per process; trying to install a
+    /// second one, or the OS refusing to install it, results in `ctrlc::Error`.
+    pub fn install_signal_handler(&self) -> Result<(), ctrlc::Error> {
+        let handle = self.clone();
+        ctrlc::set_handler(move || handle.trigger())
+    }
+}
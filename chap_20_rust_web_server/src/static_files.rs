@@ -0,0 +1,333 @@
+//! This module contains [`StaticFiles`], a handler serving the files under a directory.
+//!
+//! It is meant to be registered on a [`Router`](crate::router::Router) with a wildcard
+//! pattern, e.g. `GET /static/*path`, the `path` parameter then being resolved
+//! relative to the directory.
+//!
+//! Files may be served compressed, as negotiated with the client, in which case their
+//! compressed variants are cached, so that each is only compressed once, and at the
+//! highest level, for as long as the file isn't modified.
+//!
+//! Every file is served with an `ETag` and a `Last-Modified`, so that clients can
+//! revalidate their copy with a conditional request, answered with `304 Not Modified`
+//! if the file is unchanged. They may also be requested in parts, with the `Range`
+//! header field, e.g. to resume a download; see [`range`].
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Cursor},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::{
+    cache::{self, FileCache, Validators},
+    compression::{self, Compression, Encoding, Level},
+    http::{Request, Response, StatusCode},
+    lock, range,
+};
+
+/// Name of the route parameter holding the path of the file to serve.
+pub const PATH_PARAM: &str = "path";
+
+/// Name of the file served when a directory is requested.
+pub const INDEX_FILE: &str = "index.html";
+
+/// Largest file whose compressed variants are cached. Larger ones are left for the
+/// service to compress as they are sent, if it does.
+pub const MAX_CACHED_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Largest total size of the compressed variants cached, past which no more are.
+pub const MAX_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Serves files from under a root directory.
+///
+/// Files are streamed rather than read into memory, so they may be arbitrarily
+/// large, and of any type; their `Content-Type` is guessed from their extension
+/// by [`mime_type`].
+///
+/// Requests are confined to the root directory:
+/// * paths with `..` segments are answered with `403 Forbidden`, and
+/// * so are paths that only escape the root through a symbolic link, which is
+///   detected by canonicalizing the path and checking it is still under the
+///   (also canonicalized) root.
+///
+/// Clones share the cache of compressed variants, if compression is enabled with
+/// `StaticFiles::with_compression`, and the [`FileCache`] given to
+/// `StaticFiles::with_file_cache`, if any.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    variants: Option<Arc<CompressedVariants>>,
+    files: Option<FileCache>,
+}
+
+impl StaticFiles {
+    /// Create a handler serving files under `root`, with the path to serve taken
+    /// from the route's `path` parameter, e.g. as in `GET /static/*path`.
+    ///
+    /// # Errors
+    ///
+    /// `root` is canonicalized right away, which fails if it does not exist, or
+    /// can't be accessed.
+    pub fn build(root: impl AsRef<Path>) -> io::Result<StaticFiles> {
+        Ok(StaticFiles {
+            root: fs::canonicalize(root)?,
+            variants: None,
+            files: None,
+        })
+    }
+
+    /// Serve files compressed according to `compression`, when the client accepts it,
+    /// caching their compressed variants.
+    ///
+    /// Files larger than [`MAX_CACHED_FILE_BYTES`] are served as they are, for
+    /// `Service::compression` to compress on the fly, if it is set.
+    pub fn with_compression(mut self, compression: Compression) -> StaticFiles {
+        self.variants = Some(Arc::new(CompressedVariants {
+            compression,
+            cache: Mutex::new(VariantCache::default()),
+        }));
+        self
+    }
+
+    /// Serve files small enough for `files` from memory, for as long as they aren't
+    /// modified, rather than from disk.
+    pub fn with_file_cache(mut self, files: FileCache) -> StaticFiles {
+        self.files = Some(files);
+        self
+    }
+
+    /// The canonical path of the directory files are served from.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Turn this into a handler for `Router::route`.
+    pub fn into_handler(self) -> impl Fn(&Request) -> io::Result<Response> + Send + Sync + 'static {
+        move |request| self.serve(request)
+    }
+
+    /// Answer `request` with the file its route parameter names.
+    ///
+    /// # Errors
+    ///
+    /// Missing files and permission problems are answered with `404` and `403`
+    /// respectively; only other I/O errors are returned.
+    pub fn serve(&self, request: &Request) -> io::Result<Response> {
+        let relative = request.param(PATH_PARAM).unwrap_or_default();
+        let mut path = match self.resolve(relative) {
+            Err(err) => return error_response(err),
+            Ok(None) => return Ok(Response::plain(StatusCode::FORBIDDEN)),
+            Ok(Some(path)) => path,
+        };
+
+        if path.is_dir() {
+            // Without the trailing slash, relative links in the index page would
+            // resolve against the parent directory.
+            if !request.path().ends_with('/') {
+                return Ok(Response::plain(StatusCode::MOVED_PERMANENTLY)
+                    .with_header("Location", format!("{}/", request.path())));
+            }
+            // The index file could itself be a symbolic link out of the root.
+            path = match fs::canonicalize(path.join(INDEX_FILE)) {
+                Err(err) => return error_response(err),
+                Ok(index) if !index.starts_with(&self.root) => {
+                    return Ok(Response::plain(StatusCode::FORBIDDEN))
+                }
+                Ok(index) => index,
+            };
+        }
+
+        let file = match fs::File::open(&path) {
+            Err(err) => return error_response(err),
+            Ok(file) => file,
+        };
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Ok(Response::plain(StatusCode::NOT_FOUND));
+        }
+
+        let content_type = mime_type(&path);
+        let validators = Validators::from_metadata(&metadata);
+        let mut response =
+            validators.apply(Response::new(StatusCode::OK).with_header("Content-Type", content_type));
+        if let Some(variants) = &self.variants {
+            if variants.applies_to(content_type, metadata.len()) {
+                compression::add_vary(&mut response);
+                let accept_encoding = request.headers.get("Accept-Encoding");
+                if let Some((encoding, bytes)) = variants.get(accept_encoding, &path, &metadata)? {
+                    // Ranges then apply to the compressed variant, the representation sent.
+                    let len = bytes.len() as u64;
+                    let response = response
+                        .with_header("ETag", cache::variant_etag(&validators.etag, encoding.as_str()))
+                        .with_header("Content-Encoding", encoding.as_str());
+                    return range::serve_ranges(request, response, Cursor::new(bytes), len);
+                }
+            }
+        }
+        if let Some(files) = &self.files {
+            if metadata.len() <= files.max_file_bytes() {
+                let cached = files.read(&path)?;
+                let len = cached.contents.len() as u64;
+                let response = cached.validators.apply(response);
+                return range::serve_ranges(request, response, Cursor::new(cached.contents), len);
+            }
+        }
+        range::serve_ranges(request, response, file, metadata.len())
+    }
+
+    /// Map a `/`-separated path relative to the root onto the file system.
+    ///
+    /// Returns `Ok(None)` if the path would escape the root directory.
+    fn resolve(&self, relative: &str) -> io::Result<Option<PathBuf>> {
+        let mut path = self.root.clone();
+        for part in relative.split('/').filter(|p| !p.is_empty()) {
+            // Only plain names are allowed; this rejects `..`, but also `.`, and
+            // anything `Path` would interpret as a root or drive prefix.
+            let mut components = Path::new(part).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) if !part.contains('\\') => path.push(part),
+                _ => return Ok(None),
+            }
+        }
+
+        let canonical = fs::canonicalize(&path)?;
+        if canonical.starts_with(&self.root) {
+            Ok(Some(canonical))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// The compressed variants of files, and how to compress them.
+#[derive(Debug)]
+struct CompressedVariants {
+    compression: Compression,
+    cache: Mutex<VariantCache>,
+}
+
+#[derive(Debug, Default)]
+struct VariantCache {
+    variants: HashMap<(PathBuf, Encoding), CachedVariant>,
+    /// Total size of the compressed variants, in bytes.
+    size: usize,
+}
+
+/// A file compressed with an encoding, as it was when last modified at `modified`.
+#[derive(Debug)]
+struct CachedVariant {
+    modified: SystemTime,
+    len: u64,
+    /// The compressed file, or `None` if compressing didn't make it smaller.
+    bytes: Option<Arc<[u8]>>,
+}
+
+impl CompressedVariants {
+    /// Whether files of type `content_type`, `len` bytes long, are compressed here.
+    fn applies_to(&self, content_type: &str, len: u64) -> bool {
+        self.compression.is_compressible(content_type)
+            && self.compression.is_large_enough(Some(len))
+            && len <= MAX_CACHED_FILE_BYTES
+    }
+
+    /// The variant of the file at `path` to send to a client whose `Accept-Encoding`
+    /// header field is `accept_encoding`, from the cache if it is up to date with the
+    /// file's `metadata`, or `None` if the file is to be sent as it is.
+    fn get(
+        &self,
+        accept_encoding: Option<&str>,
+        path: &Path,
+        metadata: &fs::Metadata,
+    ) -> io::Result<Option<(Encoding, Arc<[u8]>)>> {
+        let Some(encoding) = self.compression.negotiate(accept_encoding) else {
+            return Ok(None);
+        };
+        // Without modification times, there's no telling whether a variant is stale.
+        let Ok(modified) = metadata.modified() else {
+            return Ok(None);
+        };
+        let key = (path.to_path_buf(), encoding);
+        if let Some(cached) = lock(&self.cache).variants.get(&key) {
+            if cached.modified == modified && cached.len == metadata.len() {
+                return Ok(cached.bytes.clone().map(|bytes| (encoding, bytes)));
+            }
+        }
+
+        // Compressed without holding the lock, as it may take a while; two requests for
+        // the same file may then both compress it, which is harmless.
+        let original = fs::read(path)?;
+        if original.len() as u64 != metadata.len() {
+            // Modified since its metadata was read; better luck next time.
+            return Ok(None);
+        }
+        let compressed = encoding.encode(&original, Level::Best)?;
+        let bytes: Option<Arc<[u8]>> = (compressed.len() < original.len()).then(|| compressed.into());
+
+        let mut cache = lock(&self.cache);
+        let size = bytes.as_ref().map_or(0, |bytes| bytes.len());
+        let replaced = cache.variants.get(&key).and_then(|cached| cached.bytes.as_ref());
+        let new_size = cache.size - replaced.map_or(0, |bytes| bytes.len()) + size;
+        if new_size <= MAX_CACHE_BYTES {
+            cache.size = new_size;
+            let variant = CachedVariant { modified, len: metadata.len(), bytes: bytes.clone() };
+            cache.variants.insert(key, variant);
+        }
+        Ok(bytes.map(|bytes| (encoding, bytes)))
+    }
+}
+
+/// Answer file system errors caused by the request itself, propagating the rest.
+fn error_response(err: io::Error) -> io::Result<Response> {
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => {
+            Ok(Response::plain(StatusCode::NOT_FOUND))
+        }
+        io::ErrorKind::PermissionDenied => Ok(Response::plain(StatusCode::FORBIDDEN)),
+        _ => Err(err),
+    }
+}
+
+/// Guess a file's MIME type from its extension, defaulting to `application/octet-stream`.
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "xml" => "application/xml",
+        "json" => "application/json",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "ogg" => "audio/ogg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
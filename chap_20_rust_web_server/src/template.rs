@@ -0,0 +1,557 @@
+//! This module contains [`Templates`], a small template engine with which routes
+//! render HTML pages from request data, rather than serve fixed files.
+//!
+//! Templates are text, usually HTML, with:
+//! * `{{ user.name }}`, replaced by the value at that path in the context, HTML-escaped
+//!   unless followed by `| safe`; missing values render as nothing;
+//! * `{% if user.admin %}...{% else %}...{% endif %}`, rendering one branch or the
+//!   other depending on whether the value is truthy, or `{% if not ... %}`;
+//! * `{% for item in items %}...{% else %}...{% endfor %}`, rendering its body once
+//!   per element of an array, with `loop.index`, `loop.first` and `loop.last` set,
+//!   or the `else` branch if there are none;
+//! * `{% include "header.html" %}`, rendering another template in place;
+//! * `{% extends "layout.html" %}`, rendering the layout instead, with the
+//!   `{% block name %}...{% endblock %}`s of the template replacing the layout's; and
+//! * `{# comments #}`, rendering as nothing.
+//!
+//! The context is any value implementing `serde::Serialize`, e.g. a struct deriving
+//! it, or `serde_json::json!({ "path": path })`.
+//!
+//! Templates are parsed once, and cached; in development, `Templates::with_reload`
+//! has them parsed again whenever their file is modified.
+
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::lock;
+
+/// Directory templates are loaded from by default, relative to the working directory.
+pub const TEMPLATES_DIR: &str = "templates";
+
+/// How deeply templates may include or extend each other, which catches cycles.
+pub const MAX_DEPTH: usize = 16;
+
+/// Enum representing the ways loading or rendering a template can fail.
+#[derive(Debug)]
+pub enum TemplateError {
+    /// The template's name isn't a relative path without `..`, so it could be outside
+    /// the templates directory.
+    InvalidNameError(String),
+    /// The template's file could not be read.
+    ReadError(String, io::Error),
+    /// The template isn't well-formed, where, and why.
+    SyntaxError { name: String, line: usize, message: String },
+    /// Templates include or extend each other more than [`MAX_DEPTH`] deep, likely in
+    /// a cycle, starting with the one named.
+    RecursionError(String),
+    /// The context could not be serialized.
+    ContextError(serde_json::Error),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::InvalidNameError(name) => write!(f, "invalid template name {name:?}"),
+            TemplateError::ReadError(name, err) => write!(f, "could not read template {name:?}: {err}"),
+            TemplateError::SyntaxError { name, line, message } => {
+                write!(f, "syntax error in template {name:?}, line {line}: {message}")
+            }
+            TemplateError::RecursionError(name) => {
+                write!(f, "templates nested more than {MAX_DEPTH} deep from {name:?}")
+            }
+            TemplateError::ContextError(err) => write!(f, "could not serialize template context: {err}"),
+        }
+    }
+}
+
+/// Escape `text` to be included in HTML, as text or as an attribute's value.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A parsed template.
+#[derive(Debug)]
+pub struct Template {
+    /// The layout it extends, if any.
+    parent: Option<String>,
+    nodes: Vec<Node>,
+}
+
+/// Path to a value in the context, e.g. `["user", "name"]` for `user.name`.
+type ValuePath = Vec<String>;
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Value { path: ValuePath, escape: bool },
+    If { negated: bool, path: ValuePath, then: Vec<Node>, otherwise: Vec<Node> },
+    For { variable: String, path: ValuePath, body: Vec<Node>, otherwise: Vec<Node> },
+    Include(String),
+    Block { name: String, body: Vec<Node> },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Token<'a> {
+    Text(&'a str),
+    /// The inside of `{{ ... }}`.
+    Value(&'a str),
+    /// The inside of `{% ... %}`.
+    Tag(&'a str),
+}
+
+impl Template {
+    /// Parse the template `name`, whose text is `source`.
+    ///
+    /// # Errors
+    ///
+    /// `SyntaxError` if a tag isn't closed, is unknown, or is malformed, or a block
+    /// tag isn't matched by its end tag.
+    pub fn parse(name: &str, source: &str) -> Result<Template, TemplateError> {
+        let mut parser = Parser {
+            name,
+            tokens: tokenize(name, source)?,
+            pos: 0,
+            parent: None,
+        };
+        let (nodes, _) = parser.parse_until(&[])?;
+        Ok(Template { parent: parser.parent, nodes })
+    }
+}
+
+/// Split `source` into text, values and tags, each with the line it starts on.
+fn tokenize<'a>(name: &str, source: &'a str) -> Result<Vec<(Token<'a>, usize)>, TemplateError> {
+    let mut tokens = Vec::new();
+    let line_at = |index: usize| source[..index].matches('\n').count() + 1;
+    let (mut pos, mut text_start) = (0, 0);
+    while let Some(offset) = source[pos..].find('{') {
+        let start = pos + offset;
+        let close = match source[start + 1..].chars().next() {
+            Some('{') => "}}",
+            Some('%') => "%}",
+            Some('#') => "#}",
+            _ => {
+                pos = start + 1;
+                continue;
+            }
+        };
+        if text_start < start {
+            tokens.push((Token::Text(&source[text_start..start]), line_at(text_start)));
+        }
+        let Some(len) = source[start + 2..].find(close) else {
+            return Err(TemplateError::SyntaxError {
+                name: name.to_string(),
+                line: line_at(start),
+                message: format!("unclosed {}", &source[start..start + 2]),
+            });
+        };
+        let inner = source[start + 2..start + 2 + len].trim();
+        match close {
+            "}}" => tokens.push((Token::Value(inner), line_at(start))),
+            "%}" => tokens.push((Token::Tag(inner), line_at(start))),
+            _ => {}
+        }
+        pos = start + 2 + len + 2;
+        text_start = pos;
+    }
+    if text_start < source.len() {
+        tokens.push((Token::Text(&source[text_start..]), line_at(text_start)));
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    name: &'a str,
+    tokens: Vec<(Token<'a>, usize)>,
+    pos: usize,
+    parent: Option<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, line: usize, message: impl Into<String>) -> TemplateError {
+        TemplateError::SyntaxError {
+            name: self.name.to_string(),
+            line,
+            message: message.into(),
+        }
+    }
+
+    /// Parse nodes until one of the tags `ends`, which is consumed and returned, or
+    /// until the end of the template if `ends` is empty.
+    fn parse_until(&mut self, ends: &[&str]) -> Result<(Vec<Node>, &'a str), TemplateError> {
+        let mut nodes = Vec::new();
+        loop {
+            let Some(&(token, line)) = self.tokens.get(self.pos) else {
+                if ends.is_empty() {
+                    return Ok((nodes, ""));
+                }
+                let line = self.tokens.last().map_or(1, |(_, line)| *line);
+                return Err(self.error(line, format!("missing {{% {} %}}", ends.join(" %} or {% "))));
+            };
+            self.pos += 1;
+            match token {
+                Token::Text(text) => nodes.push(Node::Text(text.to_string())),
+                Token::Value(inner) => {
+                    let mut parts = inner.split('|').map(str::trim);
+                    let path = self.parse_path(parts.next().unwrap_or_default(), line)?;
+                    let mut escape = true;
+                    for filter in parts {
+                        match filter {
+                            "safe" => escape = false,
+                            filter => return Err(self.error(line, format!("unknown filter {filter:?}"))),
+                        }
+                    }
+                    nodes.push(Node::Value { path, escape });
+                }
+                Token::Tag(inner) => {
+                    let (keyword, args) = inner
+                        .split_once(char::is_whitespace)
+                        .map_or((inner, ""), |(keyword, args)| (keyword, args.trim()));
+                    if ends.contains(&keyword) {
+                        return Ok((nodes, keyword));
+                    }
+                    nodes.push(self.parse_tag(keyword, args, line)?);
+                }
+            }
+        }
+    }
+
+    /// Parse the tag `{% keyword args %}`, and its body if it has one.
+    fn parse_tag(&mut self, keyword: &str, args: &str, line: usize) -> Result<Node, TemplateError> {
+        match keyword {
+            "if" => {
+                let (negated, path) = match args.strip_prefix("not ") {
+                    Some(path) => (true, path.trim()),
+                    None => (false, args),
+                };
+                let path = self.parse_path(path, line)?;
+                let (then, end) = self.parse_until(&["else", "endif"])?;
+                let otherwise = match end {
+                    "else" => self.parse_until(&["endif"])?.0,
+                    _ => Vec::new(),
+                };
+                Ok(Node::If { negated, path, then, otherwise })
+            }
+            "for" => {
+                let Some((variable, path)) = args.split_once(" in ") else {
+                    return Err(self.error(line, "expected {% for item in items %}"));
+                };
+                let variable = variable.trim();
+                if !is_identifier(variable) {
+                    return Err(self.error(line, format!("invalid loop variable {variable:?}")));
+                }
+                let path = self.parse_path(path, line)?;
+                let (body, end) = self.parse_until(&["else", "endfor"])?;
+                let otherwise = match end {
+                    "else" => self.parse_until(&["endfor"])?.0,
+                    _ => Vec::new(),
+                };
+                Ok(Node::For { variable: variable.to_string(), path, body, otherwise })
+            }
+            "include" => Ok(Node::Include(self.parse_string(args, line)?)),
+            "extends" => {
+                if self.parent.is_some() {
+                    return Err(self.error(line, "a template can only extend one other"));
+                }
+                self.parent = Some(self.parse_string(args, line)?);
+                // Only its blocks matter, which are found wherever they are.
+                Ok(Node::Text(String::new()))
+            }
+            "block" => {
+                if !is_identifier(args) {
+                    return Err(self.error(line, format!("invalid block name {args:?}")));
+                }
+                let (body, _) = self.parse_until(&["endblock"])?;
+                Ok(Node::Block { name: args.to_string(), body })
+            }
+            keyword => Err(self.error(line, format!("unexpected {{% {keyword} %}}"))),
+        }
+    }
+
+    /// Parse a path such as `user.name`, or `items.0` for the first element.
+    fn parse_path(&self, path: &str, line: usize) -> Result<ValuePath, TemplateError> {
+        let segments: ValuePath = path.split('.').map(str::to_string).collect();
+        if !segments.iter().all(|segment| is_identifier(segment)) {
+            return Err(self.error(line, format!("invalid value {path:?}")));
+        }
+        Ok(segments)
+    }
+
+    /// Parse a double-quoted string, such as the name of an included template.
+    fn parse_string(&self, string: &str, line: usize) -> Result<String, TemplateError> {
+        string
+            .strip_prefix('"')
+            .and_then(|string| string.strip_suffix('"'))
+            .filter(|string| !string.contains('"'))
+            .map(str::to_string)
+            .ok_or_else(|| self.error(line, format!("expected a quoted name, found {string:?}")))
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Loads templates from a directory, caching them once parsed.
+///
+/// It is cheap to clone, every clone sharing the same cache.
+#[derive(Debug, Clone)]
+pub struct Templates {
+    dir: PathBuf,
+    reload: bool,
+    cache: Arc<Mutex<HashMap<String, CachedTemplate>>>,
+}
+
+#[derive(Debug)]
+struct CachedTemplate {
+    template: Arc<Template>,
+    /// When the file was modified, as it was parsed.
+    modified: Option<SystemTime>,
+}
+
+impl Templates {
+    /// Load templates from under `dir`, each parsed only once.
+    pub fn new(dir: impl Into<PathBuf>) -> Templates {
+        Templates {
+            dir: dir.into(),
+            reload: false,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Check, if `reload` is true, whether a template's file was modified each time it
+    /// is used, and parse it again if so, so that changes show up without restarting
+    /// the server, as is convenient in development.
+    pub fn with_reload(mut self, reload: bool) -> Templates {
+        self.reload = reload;
+        self
+    }
+
+    /// The directory templates are loaded from.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The template `name`, a `/`-separated path relative to the templates directory,
+    /// from the cache if it's there, and, when reloading, still up to date.
+    ///
+    /// # Errors
+    ///
+    /// `InvalidNameError` if `name` could be outside the templates directory,
+    /// `ReadError` if the template's file can't be read, and `SyntaxError` if it
+    /// can't be parsed.
+    pub fn get(&self, name: &str) -> Result<Arc<Template>, TemplateError> {
+        let mut path = self.dir.clone();
+        for part in name.split('/') {
+            match Path::new(part).components().collect::<Vec<_>>()[..] {
+                [Component::Normal(_)] if !part.contains('\\') => path.push(part),
+                _ => return Err(TemplateError::InvalidNameError(name.to_string())),
+            }
+        }
+        let read_error = |err| TemplateError::ReadError(name.to_string(), err);
+
+        let modified = if self.reload {
+            let metadata = fs::metadata(&path).map_err(read_error)?;
+            metadata.modified().ok()
+        } else {
+            None
+        };
+        if let Some(cached) = lock(&self.cache).get(name) {
+            if !self.reload || (modified.is_some() && cached.modified == modified) {
+                return Ok(cached.template.clone());
+            }
+        }
+
+        let source = fs::read_to_string(&path).map_err(read_error)?;
+        let template = Arc::new(Template::parse(name, &source)?);
+        let cached = CachedTemplate { template: template.clone(), modified };
+        lock(&self.cache).insert(name.to_string(), cached);
+        Ok(template)
+    }
+
+    /// Render the template `name` with `context`.
+    ///
+    /// # Errors
+    ///
+    /// Those of `Templates::get`, for the template and those it includes or extends,
+    /// `RecursionError` if they are nested too deeply, and `ContextError` if `context`
+    /// can't be serialized, e.g. a map with keys that aren't strings.
+    pub fn render(&self, name: &str, context: &impl Serialize) -> Result<String, TemplateError> {
+        let context = serde_json::to_value(context).map_err(TemplateError::ContextError)?;
+        let mut output = String::new();
+        self.render_named(name, &context, &mut Vec::new(), &mut output, 0)?;
+        Ok(output)
+    }
+
+    /// Render the template `name`, or the layouts it extends, into `output`.
+    fn render_named(
+        &self,
+        name: &str,
+        context: &Value,
+        scopes: &mut Vec<(String, Value)>,
+        output: &mut String,
+        depth: usize,
+    ) -> Result<(), TemplateError> {
+        let mut chain = vec![self.get(name)?];
+        while let Some(parent) = &chain[chain.len() - 1].parent {
+            if depth + chain.len() > MAX_DEPTH {
+                return Err(TemplateError::RecursionError(name.to_string()));
+            }
+            let parent = self.get(parent)?;
+            chain.push(parent);
+        }
+        // The blocks of templates extending others win over those of their layouts.
+        let mut blocks = HashMap::new();
+        for template in &chain {
+            collect_blocks(&template.nodes, &mut blocks);
+        }
+        let root = &chain[chain.len() - 1];
+        let renderer = Renderer { templates: self, context, blocks, depth: depth + chain.len() };
+        renderer.render(&root.nodes, scopes, output)
+    }
+}
+
+/// Add the blocks among `nodes` to `blocks`, unless there already.
+fn collect_blocks<'a>(nodes: &'a [Node], blocks: &mut HashMap<&'a str, &'a [Node]>) {
+    for node in nodes {
+        match node {
+            Node::Block { name, body } => {
+                blocks.entry(name.as_str()).or_insert(body.as_slice());
+                collect_blocks(body, blocks);
+            }
+            Node::If { then, otherwise, .. } => {
+                collect_blocks(then, blocks);
+                collect_blocks(otherwise, blocks);
+            }
+            Node::For { body, otherwise, .. } => {
+                collect_blocks(body, blocks);
+                collect_blocks(otherwise, blocks);
+            }
+            _ => {}
+        }
+    }
+}
+
+struct Renderer<'a> {
+    templates: &'a Templates,
+    context: &'a Value,
+    blocks: HashMap<&'a str, &'a [Node]>,
+    depth: usize,
+}
+
+impl Renderer<'_> {
+    /// Render `nodes` into `output`, with the loop variables in `scopes` shadowing the
+    /// context.
+    fn render(&self, nodes: &[Node], scopes: &mut Vec<(String, Value)>, output: &mut String) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => output.push_str(text),
+                Node::Value { path, escape } => {
+                    let text = to_text(self.lookup(path, scopes));
+                    output.push_str(&if *escape { escape_html(&text) } else { text });
+                }
+                Node::If { negated, path, then, otherwise } => {
+                    let branch = if is_truthy(self.lookup(path, scopes)) != *negated { then } else { otherwise };
+                    self.render(branch, scopes, output)?;
+                }
+                Node::For { variable, path, body, otherwise } => {
+                    let items = match self.lookup(path, scopes) {
+                        Some(Value::Array(items)) => items.clone(),
+                        Some(Value::Object(entries)) => entries
+                            .iter()
+                            .map(|(key, value)| serde_json::json!({ "key": key, "value": value }))
+                            .collect(),
+                        _ => Vec::new(),
+                    };
+                    if items.is_empty() {
+                        self.render(otherwise, scopes, output)?;
+                    }
+                    let length = items.len();
+                    for (index, item) in items.into_iter().enumerate() {
+                        let status = serde_json::json!({
+                            "index": index + 1,
+                            "index0": index,
+                            "first": index == 0,
+                            "last": index + 1 == length,
+                            "length": length,
+                        });
+                        scopes.push(("loop".to_string(), status));
+                        scopes.push((variable.clone(), item));
+                        let rendered = self.render(body, scopes, output);
+                        scopes.truncate(scopes.len() - 2);
+                        rendered?;
+                    }
+                }
+                Node::Include(name) => {
+                    if self.depth >= MAX_DEPTH {
+                        return Err(TemplateError::RecursionError(name.clone()));
+                    }
+                    self.templates.render_named(name, self.context, scopes, output, self.depth)?;
+                }
+                Node::Block { name, body } => {
+                    let body = self.blocks.get(name.as_str()).copied().unwrap_or(body.as_slice());
+                    self.render(body, scopes, output)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The value at `path`, in the innermost loop variable it starts with, or else in
+    /// the context.
+    fn lookup<'v>(&'v self, path: &[String], scopes: &'v [(String, Value)]) -> Option<&'v Value> {
+        let (first, rest) = path.split_first()?;
+        let mut value = match scopes.iter().rev().find(|(name, _)| name == first) {
+            Some((_, value)) => value,
+            None => self.context.get(first)?,
+        };
+        for segment in rest {
+            value = match value {
+                Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+                value => value.get(segment)?,
+            };
+        }
+        Some(value)
+    }
+}
+
+/// How a value is rendered: strings as they are, nothing for `null`, and arrays and
+/// objects as JSON.
+fn to_text(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(string)) => string.clone(),
+        Some(value) => value.to_string(),
+    }
+}
+
+/// Whether a value counts as true in `{% if %}`: anything but `null`, `false`, `0`,
+/// and empty strings, arrays and objects.
+fn is_truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(b)) => *b,
+        Some(Value::Number(n)) => n.as_f64() != Some(0.0),
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(items)) => !items.is_empty(),
+        Some(Value::Object(entries)) => !entries.is_empty(),
+    }
+}
@@ -0,0 +1,282 @@
+//! This module contains the timer behind `ThreadPool::execute_after` and
+//! `ThreadPool::execute_every`, and the [`ScheduledHandle`]s they return.
+//!
+//! A pool's timer is a thread of its own, started along with the first scheduled job.
+//! It sleeps until the earliest job is due, and then puts it in the pool's queue like
+//! any other, so the job runs on one of the pool's [`Worker`](crate::Worker)s; the
+//! timer thread itself never runs jobs.
+//!
+//! The timer is stopped first thing when the pool is dropped or shut down, so that it
+//! doesn't keep the job queue open. Scheduled jobs not yet due by then are discarded.
+
+use std::{
+    cmp::{Ordering as CmpOrdering, Reverse},
+    collections::BinaryHeap,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{lock, Job, JobSender, PoolShared};
+
+/// A job to be run every so often, as given to `ThreadPool::execute_every`.
+pub(crate) type RepeatedJob = Arc<dyn Fn() -> io::Result<()> + Send + Sync + 'static>;
+
+/// The job to run when a [`Scheduled`] comes due.
+pub(crate) enum Task {
+    Once(Job),
+    Every(Duration, RepeatedJob),
+}
+
+/// States of a [`ScheduledHandle`].
+const PENDING: u8 = 0;
+const FIRED: u8 = 1;
+const CANCELLED: u8 = 2;
+
+/// A handle on a job scheduled with `ThreadPool::execute_after` or
+/// `ThreadPool::execute_every`, through which it can be cancelled.
+///
+/// Dropping the handle does not cancel the job.
+#[derive(Debug, Clone)]
+pub struct ScheduledHandle {
+    state: Arc<AtomicU8>,
+}
+
+impl ScheduledHandle {
+    /// Cancel the job, so that it isn't run again, and return whether this prevented
+    /// any run: it doesn't for a job that was cancelled already, nor for a job from
+    /// `ThreadPool::execute_after` that was already put in the pool's queue.
+    ///
+    /// A run already in progress isn't interrupted.
+    pub fn cancel(&self) -> bool {
+        self.state
+            .compare_exchange(PENDING, CANCELLED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Whether `ScheduledHandle::cancel` was called, successfully.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == CANCELLED
+    }
+}
+
+/// A job waiting in the timer for its time to come.
+struct Scheduled {
+    due: Instant,
+    /// Breaks ties between jobs due at the same time, in the order they were scheduled.
+    seq: u64,
+    task: Task,
+    state: Arc<AtomicU8>,
+    /// Whether a run of a repeated job is still queued or running, in which case the
+    /// next one is skipped rather than piling up behind it.
+    running: Arc<AtomicBool>,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+#[derive(Default)]
+struct TimerState {
+    /// Earliest due first.
+    scheduled: BinaryHeap<Reverse<Scheduled>>,
+    next_seq: u64,
+    stopped: bool,
+}
+
+/// State shared between a [`Timer`] and its thread.
+#[derive(Default)]
+struct TimerShared {
+    state: Mutex<TimerState>,
+    /// Notified when a job is scheduled, or the timer stopped.
+    wakeup: Condvar,
+}
+
+/// A pool's timer thread, and the means to schedule jobs on it.
+pub(crate) struct Timer {
+    shared: Arc<TimerShared>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Timer {
+    /// Start the timer thread, which puts jobs in the pool's queue through `sender`.
+    ///
+    /// # Errors
+    ///
+    /// If the thread could not be spawned.
+    pub(crate) fn start(pool: Arc<PoolShared>, sender: JobSender) -> io::Result<Timer> {
+        let shared = Arc::new(TimerShared::default());
+        let thread_shared = Arc::clone(&shared);
+        let handle = thread::Builder::new()
+            .name("Timer".to_string())
+            .spawn(move || timer_func(&thread_shared, &pool, &sender))?;
+
+        Ok(Timer {
+            shared,
+            handle: Some(handle),
+        })
+    }
+
+    /// Schedule `task` to be put in the pool's queue after `delay`.
+    pub(crate) fn schedule(&self, delay: Duration, task: Task) -> ScheduledHandle {
+        let handle = ScheduledHandle {
+            state: Arc::new(AtomicU8::new(PENDING)),
+        };
+
+        let mut state = lock(&self.shared.state);
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.scheduled.push(Reverse(Scheduled {
+            due: Instant::now() + delay,
+            seq,
+            task,
+            state: Arc::clone(&handle.state),
+            running: Arc::default(),
+        }));
+        // The new job may be due before the one the timer is sleeping until.
+        self.shared.wakeup.notify_one();
+
+        handle
+    }
+}
+
+/// Stopping the timer discards the jobs not yet due, and waits for its thread to
+/// exit, which drops its [`JobSender`].
+impl Drop for Timer {
+    fn drop(&mut self) {
+        lock(&self.shared.state).stopped = true;
+        self.shared.wakeup.notify_one();
+
+        if let Some(handle) = self.handle.take() {
+            if let Err(err) = handle.join() {
+                simplelog::error!("timer thread panicked: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Function with which the timer thread is spawned.
+fn timer_func(shared: &TimerShared, pool: &Arc<PoolShared>, sender: &JobSender) {
+    let mut state = lock(&shared.state);
+    while !state.stopped {
+        let now = Instant::now();
+        let due = state.scheduled.peek().map(|Reverse(next)| next.due);
+        match due {
+            None => {
+                state = shared
+                    .wakeup
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            Some(due) if due > now => {
+                state = shared
+                    .wakeup
+                    .wait_timeout(state, due - now)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+            Some(_) => {
+                let Some(Reverse(scheduled)) = state.scheduled.pop() else {
+                    continue;
+                };
+                // Sending may block, if the queue is bounded and full; that mustn't
+                // keep other jobs from being scheduled meanwhile.
+                drop(state);
+                let next = fire(scheduled, pool, sender, now);
+                state = lock(&shared.state);
+                if let Some(next) = next {
+                    state.scheduled.push(Reverse(next));
+                }
+            }
+        }
+    }
+}
+
+/// Put a job that came due in the pool's queue, returning it if it is to be run
+/// again, rescheduled.
+fn fire(
+    mut scheduled: Scheduled,
+    pool: &Arc<PoolShared>,
+    sender: &JobSender,
+    now: Instant,
+) -> Option<Scheduled> {
+    match scheduled.task {
+        Task::Once(job) => {
+            if scheduled
+                .state
+                .compare_exchange(PENDING, FIRED, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                if let Err(err) = pool.send_job(sender, job, true) {
+                    simplelog::error!("could not queue scheduled job: {:?}", err);
+                }
+            }
+            None
+        }
+        Task::Every(interval, ref job) => {
+            if scheduled.state.load(Ordering::SeqCst) == CANCELLED {
+                return None;
+            }
+
+            if scheduled.running.swap(true, Ordering::SeqCst) {
+                simplelog::warn!(
+                    "previous run of a repeated job not finished after {:?}; skipping this one",
+                    interval
+                );
+            } else {
+                let job = Arc::clone(job);
+                let state = Arc::clone(&scheduled.state);
+                let running = RunningGuard(Arc::clone(&scheduled.running));
+                let run: Job = Box::new(move || {
+                    let _running = running;
+                    // It may have been cancelled while queued.
+                    if state.load(Ordering::SeqCst) == CANCELLED {
+                        return Ok(());
+                    }
+                    job()
+                });
+                if let Err(err) = pool.send_job(sender, run, true) {
+                    simplelog::error!("could not queue repeated job: {:?}", err);
+                }
+            }
+
+            // Runs missed while the timer was held up are skipped, rather than
+            // caught up on all at once.
+            scheduled.due += interval;
+            if scheduled.due <= now {
+                scheduled.due = now + interval;
+            }
+            Some(scheduled)
+        }
+    }
+}
+
+/// Marks a run of a repeated job as finished when dropped, whether it returned,
+/// panicked, or was dropped without running at all.
+struct RunningGuard(Arc<AtomicBool>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
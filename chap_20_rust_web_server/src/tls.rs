@@ -0,0 +1,190 @@
+//! This module contains [`TlsAcceptor`], with which the server serves HTTPS.
+//!
+//! Connections accepted by `util::accept_tls_connections` are handed to a worker as
+//! they are, and the TLS handshake is done by the worker's job, in
+//! `util::handle_tls_connection`, so that a slow or malicious client can't hold up
+//! the thread accepting connections. Once the handshake is done, requests are served
+//! over the resulting [`TlsStream`] exactly as over a plain `net::TcpStream`, with
+//! the same routes.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig, ServerConnection, StreamOwned,
+};
+
+use crate::util::Connection;
+
+/// A connection over which HTTPS is served, once `TlsAcceptor::accept` did the
+/// handshake.
+pub type TlsStream = StreamOwned<ServerConnection, net::TcpStream>;
+
+/// Enum representing errors that can occur while loading a certificate chain and
+/// private key in `TlsAcceptor::from_pem_files`.
+#[derive(Debug)]
+pub enum TlsError {
+    /// The certificate chain file could not be read, or isn't valid PEM.
+    CertificateFileError(PathBuf, rustls::pki_types::pem::Error),
+    /// The certificate chain file holds no certificate.
+    NoCertificateError(PathBuf),
+    /// The private key file could not be read, isn't valid PEM, or holds no key.
+    PrivateKeyFileError(PathBuf, rustls::pki_types::pem::Error),
+    /// `rustls` rejected the certificate chain or private key, e.g. because they
+    /// don't match.
+    ConfigError(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::CertificateFileError(path, err) => {
+                write!(f, "could not load certificate chain from {path:?}: {err}")
+            }
+            TlsError::NoCertificateError(path) => {
+                write!(f, "no certificate found in {path:?}")
+            }
+            TlsError::PrivateKeyFileError(path, err) => {
+                write!(f, "could not load private key from {path:?}: {err}")
+            }
+            TlsError::ConfigError(err) => write!(f, "invalid certificate or key: {err}"),
+        }
+    }
+}
+
+/// Performs the server side of TLS handshakes, with a certificate chain and its
+/// private key.
+///
+/// It is cheap to clone, every clone sharing the same `rustls::ServerConfig`.
+#[derive(Debug, Clone)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    /// Create an acceptor from a `rustls` configuration, e.g. to require client
+    /// certificates.
+    pub fn new(config: Arc<ServerConfig>) -> TlsAcceptor {
+        TlsAcceptor { config }
+    }
+
+    /// Create an acceptor presenting the certificate chain in the PEM file at
+    /// `cert_chain`, leaf certificate first, signed with the private key in the PEM
+    /// file at `private_key`, in PKCS#1, PKCS#8 or SEC1 format.
+    ///
+    /// Only HTTP/1.1 is offered to clients negotiating a protocol with ALPN.
+    ///
+    /// # Errors
+    ///
+    /// Any of [`TlsError`]'s variants, as described there.
+    pub fn from_pem_files(
+        cert_chain: impl AsRef<Path>,
+        private_key: impl AsRef<Path>,
+    ) -> Result<TlsAcceptor, TlsError> {
+        let cert_path = cert_chain.as_ref();
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| TlsError::CertificateFileError(cert_path.to_path_buf(), err))?;
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificateError(cert_path.to_path_buf()));
+        }
+
+        let key_path = private_key.as_ref();
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|err| TlsError::PrivateKeyFileError(key_path.to_path_buf(), err))?;
+
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(TlsError::ConfigError)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::new(Arc::new(config)))
+    }
+
+    pub fn config(&self) -> &Arc<ServerConfig> {
+        &self.config
+    }
+
+    /// Do the server side of the TLS handshake on `stream`, which must be done within
+    /// `timeout`.
+    ///
+    /// # Errors
+    ///
+    /// * An `io::ErrorKind::TimedOut` error if the handshake takes longer than
+    ///   `timeout`;
+    /// * an `io::ErrorKind::InvalidData` error if the client doesn't speak TLS, or
+    ///   the handshake fails, e.g. because the client doesn't trust the certificate;
+    /// * any other error reading from, or writing to, `stream`.
+    pub fn accept(&self, mut stream: net::TcpStream, timeout: Duration) -> io::Result<TlsStream> {
+        let mut connection = ServerConnection::new(Arc::clone(&self.config))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let mut handshake_io = HandshakeIo {
+            stream: &mut stream,
+            deadline: Instant::now() + timeout,
+        };
+        match connection.complete_io(&mut handshake_io) {
+            Ok(_) => {}
+            // What a read timing out looks like on some platforms.
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"));
+            }
+            Err(err) => return Err(err),
+        }
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
+
+/// The connection a TLS handshake is done over, which must be done by `deadline`.
+///
+/// The handshake takes several reads, so a timeout on the socket wouldn't do, as it
+/// applies to every read separately.
+struct HandshakeIo<'a> {
+    stream: &'a mut net::TcpStream,
+    deadline: Instant,
+}
+
+impl Read for HandshakeIo<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        // `set_read_timeout` rejects a zero timeout.
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for HandshakeIo<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Connection for TlsStream {
+    fn tcp_stream(&self) -> &net::TcpStream {
+        &self.sock
+    }
+
+    /// Tell the client no more data is coming, so that it can tell the connection
+    /// was closed on purpose rather than cut.
+    fn close(&mut self) -> io::Result<()> {
+        self.conn.send_close_notify();
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        Ok(())
+    }
+}
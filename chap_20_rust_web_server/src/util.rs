@@ -2,9 +2,34 @@
 //!
 //! There's:
 //! * a function to configure and initialize logging infrastructure, and
-//! * another to handle each client's HTTP request, and respond appropriately.
+//! * others to accept connections, and handle each client's HTTP requests, and respond
+//!   appropriately, and
+//! * the [`Router`] with the server's endpoints.
 
-use std::{fs, io::{self, prelude::*}, net, thread, time};
+use std::{io::{self, prelude::*}, net, sync::{Arc, Mutex}, time};
+
+use crate::{
+    access_log::AccessLogEntry,
+    body::Form,
+    cache::{Conditions, Validators},
+    cancellation::CancellationToken,
+    current_worker_id,
+    date::DateTime,
+    http::{
+        percent_decode, Body, BodyStream, Method, Request, RequestParseError, Response, StatusCode,
+        Version,
+    },
+    lock,
+    log_rotation::{LogRotation, RotatingFile},
+    metrics::{self, ServerMetrics, StatsHandle},
+    priority::{JobOptions, Priority},
+    router::Router,
+    service::{KeepAlive, Service, Timeouts},
+    shutdown::ShutdownHandle,
+    template::{Templates, TEMPLATES_DIR},
+    tls::{TlsAcceptor, TlsStream},
+    SubmitHandle, ThreadPool, ThreadPoolError,
+};
 
 use log::SetLoggerError;
 use simplelog::{
@@ -21,10 +46,23 @@ use simplelog::{
 ///
 /// The default logging configuration is used, which is then modified to allow
 /// source-code information on every log message, not just errors.
+///
+/// The log file, if any, is appended to rather than truncated, and rotated as
+/// `LogRotation::default` says; see `init_logging_infrastructure_with` to choose how.
 pub fn init_logging_infrastructure(
     opt_log_file_name : Option<&str>,
     log_level: LevelFilter
     ) -> Result<(), SetLoggerError> {
+    init_logging_infrastructure_with(opt_log_file_name, log_level, LogRotation::default())
+}
+
+/// Like `init_logging_infrastructure`, but with the log file rotated according to
+/// `rotation`, e.g. daily, or never with `LogRotation::never`.
+pub fn init_logging_infrastructure_with(
+    opt_log_file_name: Option<&str>,
+    log_level: LevelFilter,
+    rotation: LogRotation,
+) -> Result<(), SetLoggerError> {
     let config = ConfigBuilder::new()
         // This enables source-code location in logging message of any level
         .set_location_level(LevelFilter::Error)
@@ -47,10 +85,10 @@ pub fn init_logging_infrastructure(
             println!("Terminal-only logging will be done instead.");
         }
         Some(log_file_name) => {
-            let log_file = fs::File::create(log_file_name);
+            let log_file = RotatingFile::open(log_file_name, rotation);
             match log_file {
                 Err(err) => {
-                    eprintln!("Could not create logging file! Error: {:?}", err);
+                    eprintln!("Could not open logging file! Error: {:?}", err);
                     eprintln!("Terminal-only logging will be attempted.");
                 }
                 Ok(file) => {
@@ -68,38 +106,736 @@ pub fn init_logging_infrastructure(
     CombinedLogger::init(logger_vec)
 }
 
+/// A connection `handle_connection` serves requests on: a `net::TcpStream`, or a
+/// [`TlsStream`] over one.
+pub trait Connection: Read + Write {
+    /// The TCP stream the connection is over, e.g. to set its timeouts.
+    fn tcp_stream(&self) -> &net::TcpStream;
+
+    /// Close the connection once the last response was written; only the TCP stream
+    /// being dropped is needed by default.
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for net::TcpStream {
+    fn tcp_stream(&self) -> &net::TcpStream {
+        self
+    }
+}
+
 /// This function is passed to each worker thread's closure, so that they may concurrently
-/// serve the various requests made by clients in the `net::TcpStream` passed via the closure.
-///
-/// It is responsible for
-/// * parsing the request to see which endpoint was requested,
-/// * building the appropriate HTTP response, and
-/// * writing it into the socket.
-pub fn handle_connection(mut stream: net::TcpStream) -> io::Result<()> {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer)?;
-
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
-
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(time::Duration::from_secs(5));
-        ("HTTP/1.1 200 OK", "hello.html")
+/// serve the various requests made by clients in the `net::TcpStream` passed via the closure,
+/// or the [`TlsStream`] it becomes in `handle_tls_connection`.
+///
+/// For every request on the connection, it is responsible for
+/// * parsing the request,
+/// * having the service's router find and run the handler for the requested endpoint, and
+/// * writing the handler's response into the socket.
+///
+/// Connections are persistent: requests keep being served until the client asks
+/// for the connection to be closed, one of the limits in [`KeepAlive`] is hit, or
+/// the server starts shutting down.
+/// The response to the last request carries `Connection: close` so the client knows.
+///
+/// The body of a `multipart/form-data` request isn't read beforehand, but left for
+/// its handler to read from the connection, through the request's [`BodyStream`],
+/// so that uploads are bound by the [`UploadLimits`](crate::body::UploadLimits) they
+/// are parsed with rather than by `RequestLimits::max_body_bytes`. What the handler
+/// leaves of the body is read past, and the connection is closed after the response
+/// if that's more than `max_body_bytes`.
+///
+/// Requests the server cannot parse are answered with the status code their
+/// [`RequestParseError`] maps to, e.g. `400 Bad Request` or
+/// `431 Request Header Fields Too Large`, instead of being routed, after which the
+/// connection is closed, as there's no telling where the next request would start.
+///
+/// Time spent on each request is bounded by the service's [`Timeouts`]: a client
+/// that takes too long to send its request is answered with `408 Request Timeout`,
+/// and a handler that gives up once its request's [`CancellationToken`] expires has
+/// the client answered with `503 Service Unavailable`. Either way, the connection
+/// is then closed. A body streamed to its handler has the read timeout apply to
+/// each read of it, rather than to the whole of it.
+///
+/// Responses are compressed as negotiated with the client, if the service has a
+/// [`Compression`](crate::compression::Compression) policy. Conditional `GET` and
+/// `HEAD` requests are then answered with `304 Not Modified` when the client's copy
+/// of the response is still fresh, as told by the [`Conditions`] of the request.
+///
+/// Each response sent is recorded in the service's [`AccessLog`](crate::access_log::AccessLog),
+/// if it has one.
+pub fn handle_connection(stream: impl Connection + Send + 'static, service: &Service) -> io::Result<()> {
+    serve(ServedConnection::new(stream, service)?, service, None)
+}
+
+/// A connection served by `handle_connection`, with what is kept of it between
+/// requests, and between the jobs it may be handed off to.
+struct ServedConnection<C: Connection> {
+    /// The reader must outlive each request, as it may have buffered the start of the
+    /// next. It is shared with the body stream of a request whose body is left to its
+    /// handler. Responses are written through it too, as a TLS stream can't be split
+    /// in two.
+    reader: Arc<Mutex<io::BufReader<RequestReader<C>>>>,
+    peer_addr: Option<net::SocketAddr>,
+    /// Number of requests served on the connection so far.
+    served: usize,
+    /// A request read, but not answered yet, and when it was, as for `respond`.
+    pending: Option<(Result<Request, RequestParseError>, time::Instant)>,
+}
+
+impl<C: Connection> ServedConnection<C> {
+    /// Get ready to serve requests on `stream` with `service`'s timeouts.
+    fn new(stream: C, service: &Service) -> io::Result<ServedConnection<C>> {
+        let idle_timeout = service.keep_alive.idle_timeout;
+        let Timeouts { read: read_timeout, write: write_timeout, .. } = service.timeouts;
+        stream.tcp_stream().set_write_timeout(Some(write_timeout))?;
+        let peer_addr = stream.tcp_stream().peer_addr().ok();
+
+        let reader = Arc::new(Mutex::new(io::BufReader::new(RequestReader {
+            stream,
+            idle_timeout,
+            read_timeout,
+            deadline: None,
+            streaming: false,
+        })));
+        Ok(ServedConnection { reader, peer_addr, served: 0, pending: None })
+    }
+}
+
+/// What a job of `accept_connections` needs to hand its connection off to another job.
+struct Handoff {
+    service: Arc<Service>,
+    pool: SubmitHandle,
+    /// The options the job at hand was submitted with.
+    options: JobOptions,
+}
+
+/// Serve requests on `connection`, as described in `handle_connection`.
+///
+/// With `handoff`, a request the service's `job_policy` gives other options than those
+/// of the job at hand has the connection handed off to a job submitted with them, unless
+/// the pool has no room for it, in which case it is answered here all the same.
+fn serve<C: Connection + Send + 'static>(
+    mut connection: ServedConnection<C>,
+    service: &Service,
+    handoff: Option<&Handoff>,
+) -> io::Result<()> {
+    let mut writer = SharedWriter(Arc::clone(&connection.reader));
+    let mut handed_back = false;
+
+    loop {
+        let (parsed, received_at) = match connection.pending.take() {
+            Some(pending) => pending,
+            None => {
+                let reader = &connection.reader;
+                {
+                    let mut reader = lock(reader);
+                    let started = !reader.buffer().is_empty();
+                    reader.get_mut().next_request(started);
+                }
+
+                let parsed = Request::read_streaming_from(reader, &service.limits);
+                if let Ok(Request { body_stream: Some(_), .. }) = &parsed {
+                    lock(reader).get_mut().streaming = true;
+                }
+                (parsed, time::Instant::now())
+            }
+        };
+
+        if let (Some(handoff), Ok(request), false) = (handoff, &parsed, std::mem::take(&mut handed_back)) {
+            let options = (service.job_policy)(request);
+            if options != handoff.options {
+                connection.pending = Some((parsed, received_at));
+                match hand_off(connection, handoff, options) {
+                    None => return Ok(()),
+                    Some(returned) => {
+                        connection = returned;
+                        handed_back = true;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        let ServedConnection { reader, peer_addr, served, .. } = &mut connection;
+        match respond(parsed, received_at, served, *peer_addr, &mut writer, service)? {
+            Outcome::KeepAlive => {}
+            Outcome::Close => return lock(reader).get_mut().stream.close(),
+            Outcome::Gone => return Ok(()),
+        }
+    }
+}
+
+/// Submit a job serving `connection` from its pending request on, with `options`, as
+/// described in `serve`, handing `connection` back if the job couldn't be submitted.
+fn hand_off<C: Connection + Send + 'static>(
+    connection: ServedConnection<C>,
+    handoff: &Handoff,
+    options: JobOptions,
+) -> Option<ServedConnection<C>> {
+    // The job is dropped without being run if it can't be submitted, so the connection
+    // is left where it can be taken back from.
+    let slot = Arc::new(Mutex::new(Some(connection)));
+    let job_slot = Arc::clone(&slot);
+    let next = Handoff {
+        service: Arc::clone(&handoff.service),
+        pool: handoff.pool.clone(),
+        options: options.clone(),
+    };
+    let job = move || {
+        let connection = lock(&job_slot).take();
+        connection.map_or(Ok(()), |connection| serve(connection, &next.service, Some(&next)))
+    };
+
+    let submitted = if options == JobOptions::default() {
+        handoff.pool.try_execute(job)
     } else {
-        ("HTTP/1.1 404 NOT FOUND", "404.html")
+        handoff.pool.try_execute_with(options, job)
     };
+    match submitted {
+        Ok(()) => None,
+        Err(err) => {
+            simplelog::warn!("could not hand connection off; answering it as is: {:?}", err);
+            lock(&slot).take()
+        }
+    }
+}
 
-    let contents = fs::read_to_string(filename)?;
+/// Writes into the connection shared by `handle_connection`'s reader, locking it for
+/// each write only, so that the handler can read the request's body meanwhile.
+struct SharedWriter<C: Connection>(Arc<Mutex<io::BufReader<RequestReader<C>>>>);
 
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status_line,
-        contents.len(),
-        contents
-    );
+impl<C: Connection> Write for SharedWriter<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        lock(&self.0).get_mut().stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        lock(&self.0).get_mut().stream.flush()
+    }
+}
+
+/// What is to become of a connection once `respond` is done with a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// A response was written, and the connection is kept open for another request.
+    KeepAlive,
+    /// A response was written, the last one, after which the connection is closed.
+    Close,
+    /// The client closed the connection, or left it idle, so nothing was written,
+    /// and the connection is just dropped.
+    Gone,
+}
+
+/// Answer the request `parsed` from a connection with `peer_addr`, as described in
+/// `handle_connection`, by routing it with `service` and writing the response into
+/// `writer`, or by writing the error response a request that couldn't be parsed
+/// calls for.
+///
+/// `served` counts the requests served on the connection so far, including this one
+/// once it is routed, and `received_at` is when the request was fully read, or its
+/// header section, if its body is left in its `body_stream`. The connection is kept
+/// alive only if that body is read to its end, by the handler or by `skip_body`.
+///
+/// # Errors
+///
+/// If the connection fails while reading the request, or writing the response, or
+/// if the handler fails without having been cancelled.
+pub(crate) fn respond<W: Write>(
+    parsed: Result<Request, RequestParseError>,
+    received_at: time::Instant,
+    served: &mut usize,
+    peer_addr: Option<net::SocketAddr>,
+    writer: &mut W,
+    service: &Service,
+) -> io::Result<Outcome> {
+    let KeepAlive { idle_timeout, max_requests } = service.keep_alive;
+    let handler_timeout = service.timeouts.handler;
+    // What the access log records about the request, if there is one; the rest is
+    // filled in once the response is sent.
+    let mut entry = service.access_log.as_ref().map(|_| AccessLogEntry {
+        peer_addr,
+        time: DateTime::now(),
+        request_line: None,
+        status: StatusCode::OK,
+        bytes: 0,
+        duration: time::Duration::ZERO,
+        worker_id: current_worker_id(),
+        referer: None,
+        user_agent: None,
+    });
+
+    let (response, is_head, keep_alive) = match parsed {
+        Ok(mut request) => {
+            *served += 1;
+            if let Some(entry) = &mut entry {
+                entry.request_line =
+                    Some((request.method, request.target.clone(), request.version));
+                entry.referer = request.headers.get("Referer").map(str::to_string);
+                entry.user_agent = request.headers.get("User-Agent").map(str::to_string);
+            }
+            request.peer_addr = peer_addr;
+            request.cancellation = CancellationToken::with_timeout(handler_timeout);
+            let cancellation = request.cancellation.clone();
+            let is_head = request.method == Method::Head;
+            let wants_keep_alive = request.wants_keep_alive();
+            let is_http_1_0 = request.version == Version::Http10;
+            let accept_encoding = request.headers.get("Accept-Encoding").map(str::to_string);
+            let conditions = Conditions::from_request(&request);
+            let body_stream = request.body_stream.clone();
+
+            match service.router.handle(request) {
+                Ok(mut response) => {
+                    if let Some(compression) = &service.compression {
+                        response = compression.apply(accept_encoding.as_deref(), response)?;
+                    }
+                    // After compression, which changes the entity tag.
+                    response = conditions.apply(response);
+                    // HTTP/1.0 clients don't understand chunked responses.
+                    if is_http_1_0 && response.body.content_length().is_none() {
+                        response.body = Body::Bytes(response.body.into_bytes()?);
+                    }
+                    // Shutdown may have been requested while the handler ran, and there's
+                    // no telling where the next request starts in a body left unread.
+                    let keep_alive = wants_keep_alive
+                        && *served < max_requests
+                        && !service.shutdown.is_triggered()
+                        && body_stream.is_none_or(|body_stream| {
+                            skip_body(body_stream, service.limits.max_body_bytes)
+                        });
+                    (response, is_head, keep_alive)
+                }
+                Err(err) if cancellation.is_cancelled() => {
+                    simplelog::warn!(
+                        "Handler gave up on request after its {:?} timeout: {:?}",
+                        handler_timeout,
+                        err
+                    );
+                    (Response::plain(StatusCode::SERVICE_UNAVAILABLE), is_head, false)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(err) => match err.status() {
+            None => {
+                return match err {
+                    RequestParseError::Io(io_err) => Err(io_err),
+                    _ => Ok(Outcome::Gone),
+                }
+            }
+            Some(status) => {
+                simplelog::warn!("Rejecting unparseable request with {status}: {:?}", err);
+                (Response::plain(status), false, false)
+            }
+        },
+    };
+
+    let response = if keep_alive {
+        response.with_header("Connection", "keep-alive").with_header(
+            "Keep-Alive",
+            format!("timeout={}, max={}", idle_timeout.as_secs(), max_requests - *served),
+        )
+    } else {
+        response.with_header("Connection", "close")
+    };
+
+    let status = response.status;
+    response.write_head_to(writer)?;
+    let bytes = if is_head || !status.allows_body() {
+        0
+    } else {
+        let mut body_writer = CountingWriter { inner: &mut *writer, count: 0 };
+        response.body.write_to(&mut body_writer)?;
+        body_writer.count
+    };
+    writer.flush()?;
+    service.metrics.record_response(status);
+    if let (Some(access_log), Some(mut entry)) = (&service.access_log, entry) {
+        entry.status = status;
+        entry.bytes = bytes;
+        entry.duration = received_at.elapsed();
+        access_log.log(&entry);
+    }
+
+    Ok(if keep_alive { Outcome::KeepAlive } else { Outcome::Close })
+}
+
+/// Read what the handler left of a streamed body, unless there's more of it than
+/// `max_len` bytes, i.e. than would have been read had the body not been streamed,
+/// returning whether the body was read to its end.
+///
+/// Closing the connection on a body left unread could have the client miss the
+/// response, as unread data has the OS reset the connection.
+fn skip_body(mut body_stream: BodyStream, max_len: usize) -> bool {
+    let _ = io::copy(&mut (&mut body_stream).take(max_len as u64), &mut io::sink());
+    body_stream.is_finished()
+}
+
+/// Do the TLS handshake on `stream` with `acceptor`, and then serve requests on it as
+/// `handle_connection` does.
+///
+/// The client has the service's read timeout to complete the handshake, as it does
+/// to send a request.
+///
+/// # Errors
+///
+/// Those of `TlsAcceptor::accept`, e.g. if the client doesn't speak TLS, or doesn't
+/// trust the server's certificate, and then those of `handle_connection`.
+pub fn handle_tls_connection(
+    stream: net::TcpStream,
+    acceptor: &TlsAcceptor,
+    service: &Service,
+) -> io::Result<()> {
+    handle_connection(accept_tls(stream, acceptor, service)?, service)
+}
+
+/// The TLS handshake of `handle_tls_connection`.
+fn accept_tls(stream: net::TcpStream, acceptor: &TlsAcceptor, service: &Service) -> io::Result<TlsStream> {
+    stream.set_write_timeout(Some(service.timeouts.write))?;
+    acceptor.accept(stream, service.timeouts.read)
+}
+
+/// Counts the bytes written through it, e.g. for the access log to record the size of
+/// a response's body, which isn't known up front if it is chunked.
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The reading end of a connection, as used by `handle_connection`, which waits at
+/// most the keep-alive idle timeout for a request to start, and then at most the
+/// read timeout for the rest of it to arrive.
+///
+/// A single timeout on the socket wouldn't do for the latter, as it applies to every
+/// `read` separately, so that a client sending a byte every so often never hits it.
+/// It does for a body streamed to its handler, though, whose size is only bound by
+/// what the handler is willing to read.
+struct RequestReader<C: Connection> {
+    stream: C,
+    idle_timeout: time::Duration,
+    read_timeout: time::Duration,
+    /// When the request being read must have arrived by, once it has started.
+    deadline: Option<time::Instant>,
+    /// Whether the request's header section was read, and its body is being
+    /// streamed to its handler, each read of which has the read timeout.
+    streaming: bool,
+}
+
+impl<C: Connection> RequestReader<C> {
+    /// Get ready to read the next request; `started` is whether some of it was
+    /// already read, along with the previous one.
+    fn next_request(&mut self, started: bool) {
+        self.deadline = started.then(|| time::Instant::now() + self.read_timeout);
+        self.streaming = false;
+    }
+}
+
+impl<C: Connection> Read for RequestReader<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline {
+            _ if self.streaming => self.read_timeout,
+            None => self.idle_timeout,
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(time::Instant::now());
+                // `set_read_timeout` rejects a zero timeout.
+                if remaining.is_zero() {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                remaining
+            }
+        };
+        self.stream.tcp_stream().set_read_timeout(Some(timeout))?;
+
+        let read = self.stream.read(buf)?;
+        if read > 0 && self.deadline.is_none() {
+            self.next_request(true);
+        }
+        Ok(read)
+    }
+}
+
+/// Accept connections on `listener` and submit a [`Job`](crate::Job) handling each
+/// one to `pool`, until `service.shutdown` is triggered.
+///
+/// Each job serves its connection as `handle_connection` does, until a request is read
+/// that the service's `job_policy` gives other [`JobOptions`] than those it was
+/// submitted with: the connection is then handed off to a new job submitted with those,
+/// e.g. into a named queue, and the worker freed.
+///
+/// Connections the pool has no room for, i.e. when `ThreadPool::try_execute` fails
+/// with `ThreadPoolError::JobQueueFullError`, are answered by `reject_connection`.
+///
+/// Once this returns, no more connections are accepted, but the pool may still be
+/// serving some; `ThreadPool::shutdown` is how to wait for those.
+///
+/// # Errors
+///
+/// Only failing to get the listener's address is an error: failures to accept
+/// connections, which may be transient (e.g. running out of file descriptors),
+/// are logged, and accepting continues.
+pub fn accept_connections(
+    listener: &net::TcpListener,
+    pool: &ThreadPool,
+    service: &Arc<Service>,
+) -> io::Result<()> {
+    accept_with(listener, pool, service, None)
+}
+
+/// Like `accept_connections`, but for HTTPS: each job does the TLS handshake with
+/// `acceptor` before serving requests, in `handle_tls_connection`.
+///
+/// Connections the pool has no room for are closed right away instead, as they
+/// can't be answered before a handshake.
+///
+/// # Errors
+///
+/// The same as `accept_connections`'.
+pub fn accept_tls_connections(
+    listener: &net::TcpListener,
+    pool: &ThreadPool,
+    service: &Arc<Service>,
+    acceptor: &TlsAcceptor,
+) -> io::Result<()> {
+    accept_with(listener, pool, service, Some(acceptor))
+}
+
+/// The loop behind `accept_connections` and `accept_tls_connections`.
+fn accept_with(
+    listener: &net::TcpListener,
+    pool: &ThreadPool,
+    service: &Arc<Service>,
+    acceptor: Option<&TlsAcceptor>,
+) -> io::Result<()> {
+    service.shutdown.watch_listener(listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        if service.shutdown.is_triggered() {
+            simplelog::info!("No longer accepting connections");
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                simplelog::warn!("Could not accept connection. Error: {:?}", err);
+                continue;
+            }
+        };
+        service.metrics.record_connection();
+
+        // The job takes ownership of the stream, so a handle to it is kept to be
+        // able to answer the client if the job is rejected.
+        let rejection_stream = stream.try_clone();
+        let handoff = Handoff {
+            service: Arc::clone(service),
+            pool: pool.submit_handle(),
+            options: JobOptions::default(),
+        };
+        let execution_res = match acceptor {
+            None => pool.try_execute(move || {
+                let connection = ServedConnection::new(stream, &handoff.service)?;
+                serve(connection, &handoff.service, Some(&handoff))
+            }),
+            Some(acceptor) => {
+                let acceptor = acceptor.clone();
+                pool.try_execute(move || {
+                    let stream = accept_tls(stream, &acceptor, &handoff.service)?;
+                    let connection = ServedConnection::new(stream, &handoff.service)?;
+                    serve(connection, &handoff.service, Some(&handoff))
+                })
+            }
+        };
+
+        match execution_res {
+            Ok(()) => {}
+            Err(ThreadPoolError::JobQueueFullError) if acceptor.is_some() => {
+                simplelog::warn!("job queue is full; closing TLS connection");
+            }
+            Err(ThreadPoolError::JobQueueFullError) => {
+                simplelog::warn!("job queue is full; rejecting connection");
+                let rejection_res = rejection_stream
+                    .and_then(|s| reject_connection(s, service.retry_after));
+                match rejection_res {
+                    Ok(()) => service.metrics.record_response(StatusCode::SERVICE_UNAVAILABLE),
+                    Err(err) => simplelog::warn!("problem rejecting connection; {:?}", err),
+                }
+            }
+            Err(err) => simplelog::warn!("problem sending job to pool; {:?}", err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Answer a connection the server has no capacity to serve, e.g. because its
+/// [`crate::ThreadPool`]'s job queue is full, with `503 Service Unavailable`.
+///
+/// `Retry-After` tells the client how many seconds to wait before trying again.
+///
+/// This is meant to be called from the thread accepting connections, so it never
+/// waits on the client: the request is not read, and whatever part of it has
+/// already arrived is discarded before closing, since closing a socket with unread
+/// data makes the OS reset the connection, and the client could miss the response.
+pub fn reject_connection(stream: net::TcpStream, retry_after: time::Duration) -> io::Result<()> {
+    stream.set_nonblocking(true)?;
+
+    let mut writer = &stream;
+    Response::plain(StatusCode::SERVICE_UNAVAILABLE)
+        .with_header("Retry-After", retry_after.as_secs().to_string())
+        .with_header("Connection", "close")
+        .write_to(&mut writer)?;
+    stream.shutdown(net::Shutdown::Write)?;
+
+    let mut reader = &stream;
+    let mut discard = [0; 1024];
+    loop {
+        match reader.read(&mut discard) {
+            Ok(0) => return Ok(()),
+            Ok(_) => continue,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(err) => return Err(err),
+        }
+    }
+}
 
-    stream.write_all(response.as_bytes())?;
-    stream.flush()
+/// Build the [`Router`] serving the endpoints from the Rust book's chapter 20, its
+/// pages rendered from the templates in [`TEMPLATES_DIR`]:
+/// * `GET /` returns `hello.html`, greeting whoever the `name` query parameter names,
+/// * `GET /sleep` returns `hello.html` as well, but only after 5 seconds, unless
+///   the handler timeout expires first, and
+/// * any other path returns `404.html` with status `404 Not Found`, naming the path.
+///
+/// Pages are served with `Cache-Control: no-cache`, so that clients revalidate them on
+/// every use; see `default_router_with` to change that.
+pub fn default_router() -> Router {
+    default_router_with(Templates::new(TEMPLATES_DIR), Some("no-cache"))
+}
+
+/// Build the [`Router`] described in `default_router`, with the pages rendered from
+/// `templates`, and served with `page_cache_control` as their `Cache-Control`, if given.
+///
+/// Pages carry an `ETag` computed from their contents, for clients to revalidate their
+/// copy with.
+pub fn default_router_with(templates: Templates, page_cache_control: Option<&str>) -> Router {
+    let cache_control = page_cache_control.map(str::to_string);
+    let (sleep_templates, sleep_cache_control) = (templates.clone(), cache_control.clone());
+    let fallback_templates = templates.clone();
+    let mut router = Router::new();
+    router
+        .route(Method::Get, "/", move |request| {
+            let context = greeting(request);
+            Ok(page(&templates, StatusCode::OK, "hello.html", &context, cache_control.as_deref()))
+        })
+        .route(Method::Get, "/sleep", move |request| {
+            request.cancellation.sleep(time::Duration::from_secs(5));
+            request.cancellation.check()?;
+            let context = greeting(request);
+            Ok(page(&sleep_templates, StatusCode::OK, "hello.html", &context, sleep_cache_control.as_deref()))
+        })
+        .fallback(move |request| {
+            let path = request.path();
+            let context = serde_json::json!({ "path": percent_decode(path).as_deref().unwrap_or(path) });
+            Ok(page(&fallback_templates, StatusCode::NOT_FOUND, "404.html", &context, None))
+        });
+    router
+}
+
+/// The context of `hello.html`: the `name` query parameter of `request`, if any.
+fn greeting(request: &Request) -> serde_json::Value {
+    let query = Form::parse(request.query().unwrap_or_default()).unwrap_or_default();
+    serde_json::json!({ "name": query.get("name") })
+}
+
+/// Register the server's administrative endpoints on `router`:
+/// * `POST /admin/shutdown` triggers `shutdown`, and answers `202 Accepted`.
+///
+/// As there is no authentication, these are only served to clients connecting
+/// from a loopback address; others get `403 Forbidden`.
+pub fn add_admin_routes(router: &mut Router, shutdown: ShutdownHandle) {
+    router.route(Method::Post, "/admin/shutdown", move |request| {
+        if !request.peer_addr.is_some_and(|addr| addr.ip().is_loopback()) {
+            return Ok(Response::plain(StatusCode::FORBIDDEN));
+        }
+        shutdown.trigger();
+        Ok(Response::plain(StatusCode::ACCEPTED))
+    });
+}
+
+/// Register `GET /metrics` on `router`, serving the metrics of the pool `stats` was
+/// taken from, and those in `server`, in the Prometheus text exposition format.
+///
+/// Unlike the administrative endpoints, this is served to any client, as metrics are
+/// usually scraped from another host.
+pub fn add_metrics_route(router: &mut Router, stats: StatsHandle, server: ServerMetrics) {
+    router.route(Method::Get, "/metrics", move |_| {
+        let mut body = stats.stats().to_prometheus();
+        body.push_str(&server.stats().to_prometheus());
+        Ok(Response::new(StatusCode::OK)
+            .with_header("Content-Type", metrics::PROMETHEUS_CONTENT_TYPE)
+            .with_body(body))
+    });
+}
+
+/// Name of the queue in which `default_job_options` puts `/sleep` requests, to be
+/// registered with `ThreadPool::add_queue`.
+pub const SLOW_QUEUE: &str = "slow";
+
+/// How the requests of the server's routes are submitted to the pool; see
+/// `Service::job_policy`:
+/// * `/admin/...` and `/metrics` with `Priority::High`, so that they are answered even
+///   while the pool is busy, and
+/// * `/sleep` in [`SLOW_QUEUE`], so that slow requests can only occupy as many workers
+///   as the queue's quota.
+///
+/// Other requests are submitted without options.
+pub fn default_job_options(request: &Request) -> JobOptions {
+    match request.path() {
+        "/metrics" => JobOptions::default().with_priority(Priority::High),
+        path if path.starts_with("/admin/") => JobOptions::default().with_priority(Priority::High),
+        "/sleep" => JobOptions::default().with_queue(SLOW_QUEUE),
+        _ => JobOptions::default(),
+    }
+}
+
+/// Build a response with the given status, whose body is the template `name` rendered
+/// with `context`.
+///
+/// `200 OK` responses carry validators computed from the page, and `cache_control`, if
+/// given. If the template can't be rendered, the error is logged, and the response is a
+/// `500 Internal Server Error` instead.
+fn page(
+    templates: &Templates,
+    status: StatusCode,
+    name: &str,
+    context: &serde_json::Value,
+    cache_control: Option<&str>,
+) -> Response {
+    let contents = match templates.render(name, context) {
+        Ok(contents) => contents,
+        Err(err) => {
+            simplelog::error!("Could not render template {name}: {err}");
+            return Response::plain(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut response = Response::new(status).with_header("Content-Type", "text/html; charset=utf-8");
+    if status == StatusCode::OK {
+        response = Validators::from_contents(contents.as_bytes()).apply(response);
+        if let Some(cache_control) = cache_control {
+            response = response.with_header("Cache-Control", cache_control);
+        }
+    }
+    response.with_body(contents)
 }
@@ -0,0 +1,322 @@
+//! This module contains the work-stealing scheduler, an alternative to the single
+//! `mpsc::channel` from which every [`Worker`](crate::Worker) of a
+//! [`ThreadPool`](crate::ThreadPool) takes its jobs, in turns, behind a `Mutex`.
+//!
+//! With this scheduler, jobs submitted to the pool are pushed into a global
+//! [`Injector`]. Each worker takes jobs from it in batches, into a deque of its own,
+//! from which it then pops them without contending with the other workers. A worker
+//! whose deque is empty steals from the injector first, and from the other workers'
+//! deques next; finding no job anywhere, it waits until one is pushed.
+//!
+//! It is selected with `Scheduler::WorkStealing` in a [`ThreadPoolConfig`](crate::ThreadPoolConfig).
+
+use std::{
+    iter,
+    sync::{
+        atomic::{self, AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, PoisonError, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use crossbeam_deque::{Injector, Steal, Stealer};
+
+use crate::{lock, Job};
+
+/// How many times a worker without jobs yields, before going to sleep until one is pushed.
+const SPIN_ROUNDS: usize = 64;
+
+/// How long a blocked `push` waits before checking again whether there is room.
+const FULL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The queue shared by a [`ThreadPool`](crate::ThreadPool)'s workers under the
+/// work-stealing scheduler.
+pub struct WorkStealingQueue {
+    /// Where submitted jobs go, before some worker takes them.
+    injector: Injector<Job>,
+    /// Stealing ends of every worker's deque, along with the worker's ID.
+    stealers: RwLock<Vec<(usize, Stealer<Job>)>>,
+    /// Number of jobs pushed that no worker has started yet.
+    len: AtomicUsize,
+    /// Maximum number of jobs not yet started, if any.
+    capacity: Option<usize>,
+    /// Set once the pool stops accepting jobs, i.e. once its [`WorkStealingSender`] is dropped.
+    closed: AtomicBool,
+    /// Number of workers waiting for `job_pushed`.
+    sleepers: AtomicUsize,
+    /// Held while checking whether to wait on either `Condvar`, so that a
+    /// notification can't be missed in between.
+    doorbell: Mutex<()>,
+    job_pushed: Condvar,
+    job_popped: Condvar,
+}
+
+/// Reasons `WorkStealingQueue::push` may fail, handing back the job.
+pub enum PushError {
+    /// The queue holds `capacity` jobs already.
+    Full(Job),
+    /// The pool no longer accepts jobs.
+    Closed(Job),
+}
+
+/// Outcome of `LocalQueue::pop`.
+pub enum Pop {
+    /// A job to run.
+    Job(Job),
+    /// There was no job to be found, for now.
+    Empty,
+    /// There are no jobs left, nor will there ever be.
+    Closed,
+}
+
+impl WorkStealingQueue {
+    /// Create an empty queue, holding at most `capacity` jobs not yet started, if given.
+    pub fn new(capacity: Option<usize>) -> Arc<WorkStealingQueue> {
+        Arc::new(WorkStealingQueue {
+            injector: Injector::new(),
+            stealers: RwLock::new(Vec::new()),
+            len: AtomicUsize::new(0),
+            capacity,
+            closed: AtomicBool::new(false),
+            sleepers: AtomicUsize::new(0),
+            doorbell: Mutex::new(()),
+            job_pushed: Condvar::new(),
+            job_popped: Condvar::new(),
+        })
+    }
+
+    /// Number of jobs pushed that no worker has started yet.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    /// Whether every job pushed has been started.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Create the deque of the worker with the given ID, from which other workers
+    /// can then steal.
+    pub fn register(self: &Arc<Self>, id: usize) -> LocalQueue {
+        let deque = crossbeam_deque::Worker::new_fifo();
+        self.stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((id, deque.stealer()));
+        LocalQueue {
+            id,
+            deque,
+            queue: Arc::clone(self),
+        }
+    }
+
+    /// Push `job` for some worker to run.
+    ///
+    /// If the queue is full, this blocks until there is room if `block` is set, and
+    /// fails otherwise.
+    fn push(&self, job: Job, block: bool) -> Result<(), PushError> {
+        // Reserve room for the job first, so the queue never holds more than `capacity`.
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(PushError::Closed(job));
+            }
+            let len = self.len.load(Ordering::SeqCst);
+            match self.capacity {
+                None => {
+                    self.len.fetch_add(1, Ordering::SeqCst);
+                    break;
+                }
+                Some(capacity) if len >= capacity => {
+                    if !block {
+                        return Err(PushError::Full(job));
+                    }
+                    // Workers don't take the lock to notify, so don't wait for too long.
+                    let doorbell = lock(&self.doorbell);
+                    let _ = self.job_popped.wait_timeout(doorbell, FULL_POLL_INTERVAL);
+                }
+                _ => {
+                    if self
+                        .len
+                        .compare_exchange(len, len + 1, Ordering::SeqCst, Ordering::SeqCst)
+                        .is_ok()
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+
+        self.injector.push(job);
+        self.wake_sleeper();
+        Ok(())
+    }
+
+    /// Wake up a worker waiting in `LocalQueue::wait`, if any, once a job was made
+    /// available to it.
+    fn wake_sleeper(&self) {
+        // Pairs with the fence in `LocalQueue::wait`: either the worker going to sleep
+        // sees the job, or this sees the worker and wakes it up.
+        atomic::fence(Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _doorbell = lock(&self.doorbell);
+            self.job_pushed.notify_one();
+        }
+    }
+
+    /// Stop accepting jobs, and wake up every waiting worker so it finds out.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _doorbell = lock(&self.doorbell);
+        self.job_pushed.notify_all();
+        self.job_popped.notify_all();
+    }
+}
+
+/// The sending end of a [`WorkStealingQueue`], as held by a [`ThreadPool`](crate::ThreadPool).
+///
+/// As with the sending end of an `mpsc::channel`, dropping it, and every clone of it,
+/// tells the workers there won't be any more jobs once the queue is empty.
+#[derive(Clone)]
+pub struct WorkStealingSender {
+    inner: Arc<SenderInner>,
+}
+
+/// Shared by every clone of a [`WorkStealingSender`], closing the queue once dropped.
+struct SenderInner {
+    queue: Arc<WorkStealingQueue>,
+}
+
+impl Drop for SenderInner {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+impl WorkStealingSender {
+    /// Create the sending end of `queue`.
+    pub fn new(queue: Arc<WorkStealingQueue>) -> WorkStealingSender {
+        WorkStealingSender {
+            inner: Arc::new(SenderInner { queue }),
+        }
+    }
+
+    /// See `WorkStealingQueue::push`.
+    ///
+    /// # Errors
+    ///
+    /// `PushError::Full` if the queue is full and `block` isn't set, and
+    /// `PushError::Closed` if the pool no longer accepts jobs.
+    pub fn push(&self, job: Job, block: bool) -> Result<(), PushError> {
+        self.inner.queue.push(job, block)
+    }
+}
+
+/// A worker's own deque of jobs, taken from the [`WorkStealingQueue`] in batches.
+///
+/// When dropped, i.e. when its worker exits, whether by retiring or by dying, the jobs
+/// left in it are handed back to the queue, so that other workers run them instead.
+pub struct LocalQueue {
+    id: usize,
+    deque: crossbeam_deque::Worker<Job>,
+    queue: Arc<WorkStealingQueue>,
+}
+
+impl LocalQueue {
+    /// Find a job to run: in this worker's deque, then in the global injector, and
+    /// then in other workers' deques.
+    pub fn pop(&self) -> Pop {
+        // Read before looking for jobs: no more are pushed once `closed` is set, so
+        // finding none afterwards means none are left.
+        let closed = self.queue.closed.load(Ordering::SeqCst);
+
+        match self.deque.pop().or_else(|| self.steal()) {
+            Some(job) => {
+                self.queue.len.fetch_sub(1, Ordering::SeqCst);
+                if self.queue.capacity.is_some() {
+                    self.queue.job_popped.notify_one();
+                }
+                // The rest of a batch taken from the injector waits in this deque while
+                // the job runs, however long; idle workers are to steal it meanwhile.
+                if !self.deque.is_empty() {
+                    self.queue.wake_sleeper();
+                }
+                Pop::Job(job)
+            }
+            None if closed => Pop::Closed,
+            None => Pop::Empty,
+        }
+    }
+
+    /// Steal a batch of jobs from the injector, or a single one from another worker.
+    fn steal(&self) -> Option<Job> {
+        // As done in `crossbeam_deque`'s documentation, retrying as long as any of
+        // the steals was interrupted by a concurrent one.
+        iter::repeat_with(|| {
+            self.queue.injector.steal_batch_and_pop(&self.deque).or_else(|| {
+                self.queue
+                    .stealers
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .iter()
+                    .filter(|(id, _)| *id != self.id)
+                    .map(|(_, stealer)| stealer.steal())
+                    .collect::<Steal<Job>>()
+            })
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(Steal::success)
+    }
+
+    /// Wait at most `timeout` for a job to be pushed, if there is none to be taken from
+    /// the injector or other workers' deques.
+    pub fn wait(&self, timeout: Duration) {
+        // Jobs often come in bursts, in which case the next one is bound to be pushed
+        // shortly; yielding for a while is cheaper than sleeping, and being woken up.
+        for _ in 0..SPIN_ROUNDS {
+            if self.has_jobs_to_take() || self.queue.closed.load(Ordering::SeqCst) {
+                return;
+            }
+            thread::yield_now();
+        }
+
+        let doorbell = lock(&self.queue.doorbell);
+        self.queue.sleepers.fetch_add(1, Ordering::SeqCst);
+        // Pairs with the fence in `WorkStealingQueue::wake_sleeper`.
+        atomic::fence(Ordering::SeqCst);
+        if !self.has_jobs_to_take() && !self.queue.closed.load(Ordering::SeqCst) {
+            let _ = self.queue.job_pushed.wait_timeout(doorbell, timeout);
+        }
+        self.queue.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Whether there are jobs in the injector, or in another worker's deque.
+    ///
+    /// These are counted rather than looked for, so that spinning workers don't take
+    /// the `stealers` lock: jobs not yet started are either in this worker's deque, or
+    /// to be taken. As `len` is only decremented once a job is taken, this may tell of
+    /// one just taken by another worker, but never misses one pushed.
+    fn has_jobs_to_take(&self) -> bool {
+        self.queue.len() > self.deque.len()
+    }
+}
+
+impl Drop for LocalQueue {
+    fn drop(&mut self) {
+        self.queue
+            .stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(id, _)| *id != self.id);
+
+        let mut handed_back = false;
+        while let Some(job) = self.deque.pop() {
+            self.queue.injector.push(job);
+            handed_back = true;
+        }
+        if handed_back {
+            let _doorbell = lock(&self.queue.doorbell);
+            self.queue.job_pushed.notify_all();
+        }
+    }
+}
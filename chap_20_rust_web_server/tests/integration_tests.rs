@@ -1,7 +1,10 @@
 use chap_20_rust_web_server as server;
-use chap_20_rust_web_server::util as util;
+use chap_20_rust_web_server::{
+    access_log, body, cache, cancellation, compression, config, date, event_loop, http, job, log_rotation,
+    metrics, priority, range, router, service, shutdown, static_files, template, tls, util,
+};
 
-use std::{fs, io::{self, Write}, time, thread};
+use std::{fs, io::{self, Read, Write}, net, time, thread};
 
 fn message(filename: &str) -> String {
     format!("Hello, {filename}!\n")
@@ -95,4 +98,3052 @@ fn thread_pool_concurrency_1_round() {
 #[test]
 fn thread_pool_concurrency_2_round() {
     thread_pool_concurrency(5, 2)
-}
\ No newline at end of file
+}
+
+/// Build a `GET` request for `path` carrying enough padding headers to push it
+/// past `padding` bytes, which the server's original fixed 1024-byte buffer could not hold.
+fn padded_request(path: &str, padding: usize) -> String {
+    let mut request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n");
+    let mut n = 0;
+    while request.len() < padding {
+        request.push_str(&format!("X-Padding-{n}: {}\r\n", "x".repeat(64)));
+        n += 1;
+    }
+    request.push_str("\r\n");
+    request
+}
+
+/// Accept a single connection on an OS-assigned port, and serve it with `handler`
+/// on a separate thread.
+///
+/// Returns the address to connect to, and the serving thread's handle.
+fn serve_one_connection<F>(handler: F) -> (net::SocketAddr, thread::JoinHandle<io::Result<()>>)
+where
+    F: FnOnce(net::TcpStream) -> io::Result<()> + Send + 'static,
+{
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept()?;
+        handler(stream)
+    });
+
+    (addr, handle)
+}
+
+/// Send raw bytes to `addr`, and read back everything the server writes until it
+/// closes the connection.
+///
+/// The client's writing half is shut down after sending, so the server sees no
+/// further requests are coming, rather than waiting for its idle timeout.
+fn raw_exchange(addr: net::SocketAddr, request: &[u8]) -> String {
+    let mut stream = net::TcpStream::connect(addr).unwrap();
+    stream.write_all(request).unwrap();
+    stream.shutdown(net::Shutdown::Write).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn request_parser_reads_past_1_kib() {
+    let mut raw = padded_request("/search?q=rust", 4096).into_bytes();
+    // Replace the terminating empty line with a body, and follow the request
+    // with a second, pipelined one.
+    raw.truncate(raw.len() - 2);
+    raw.extend_from_slice(b"content-length: 5\r\n\r\nhello");
+    raw.extend_from_slice(b"DELETE /users/7 HTTP/1.0\r\n\r\n");
+
+    let mut reader = io::BufReader::new(raw.as_slice());
+    let limits = http::RequestLimits::default();
+
+    let first = http::Request::read_from(&mut reader, &limits).unwrap();
+    assert_eq!(first.method, http::Method::Get);
+    assert_eq!(first.path(), "/search");
+    assert_eq!(first.query(), Some("q=rust"));
+    assert_eq!(first.version, http::Version::Http11);
+    assert_eq!(first.headers.get("HOST"), Some("localhost"));
+    assert_eq!(first.headers.get("Content-Length"), Some("5"));
+    assert_eq!(first.body, b"hello");
+
+    let second = http::Request::read_from(&mut reader, &limits).unwrap();
+    assert_eq!(second.method, http::Method::Delete);
+    assert_eq!(second.target, "/users/7");
+    assert_eq!(second.version, http::Version::Http10);
+    assert!(second.body.is_empty());
+
+    assert!(matches!(
+        http::Request::read_from(&mut reader, &limits),
+        Err(http::RequestParseError::ConnectionClosed)
+    ));
+}
+
+#[test]
+fn request_parse_errors_map_to_status_codes() {
+    let limits = http::RequestLimits {
+        max_header_bytes: 1024,
+        max_body_bytes: 16,
+    };
+    let cases: Vec<(Vec<u8>, Option<u16>)> = vec![
+        (b"GET /\r\n\r\n".to_vec(), Some(400)),
+        (b"FETCH / HTTP/1.1\r\n\r\n".to_vec(), Some(400)),
+        (b"GET / HTTP/2.0\r\n\r\n".to_vec(), Some(505)),
+        (b"GET / HTTP/1.1\r\nno colon here\r\n\r\n".to_vec(), Some(400)),
+        (b"GET / HTTP/1.1\r\nContent-Length: ten\r\n\r\n".to_vec(), Some(400)),
+        (padded_request("/", 2048).into_bytes(), Some(431)),
+        (b"POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n".to_vec(), Some(413)),
+        (b"GET / HTTP/1.1\r\nHost: loc".to_vec(), None),
+    ];
+
+    for (raw, expected) in cases {
+        let err = http::Request::read_from(&mut io::BufReader::new(raw.as_slice()), &limits)
+            .unwrap_err();
+        assert_eq!(err.status().map(|s| s.as_u16()), expected, "{:?}", err);
+    }
+}
+
+/// The page served at `/`, to whoever isn't named, rendered from its template.
+fn hello_page() -> String {
+    template::Templates::new(template::TEMPLATES_DIR)
+        .render("hello.html", &serde_json::json!({}))
+        .unwrap()
+}
+
+#[test]
+fn handle_connection_serves_requests_longer_than_1_kib() {
+    let (addr, server) = serve_one_connection(|stream| {
+        util::handle_connection(stream, &service::Service::new(util::default_router()))
+    });
+
+    let response = raw_exchange(addr, padded_request("/", 4096).as_bytes());
+    server.join().unwrap().unwrap();
+
+    let hello = hello_page();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains(&format!("Content-Length: {}\r\n", hello.len())));
+    assert!(response.ends_with(&hello));
+}
+
+#[test]
+fn handle_connection_rejects_malformed_requests_with_400() {
+    let (addr, server) = serve_one_connection(|stream| {
+        util::handle_connection(stream, &service::Service::new(util::default_router()))
+    });
+
+    let response = raw_exchange(addr, b"this is not http\r\n\r\n");
+    server.join().unwrap().unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+}
+
+/// A handler answering with a plain-text body, for router tests.
+fn text(body: String) -> io::Result<http::Response> {
+    Ok(http::Response::new(http::StatusCode::OK).with_body(body))
+}
+
+fn body_of(response: &http::Response) -> &str {
+    std::str::from_utf8(response.body.as_bytes().unwrap()).unwrap()
+}
+
+#[test]
+fn router_matches_methods_parameters_and_wildcards() {
+    let mut router = router::Router::new();
+    router
+        .route(http::Method::Get, "/users/:id", |req| {
+            text(format!("get user {}", req.param("id").unwrap()))
+        })
+        .route(http::Method::Delete, "/users/:id", |req| {
+            text(format!("delete user {}", req.param("id").unwrap()))
+        })
+        .route(http::Method::Get, "/users/:id/posts/:post", |req| {
+            text(format!("post {} of {}", req.param("post").unwrap(), req.param("id").unwrap()))
+        })
+        .route(http::Method::Get, "/static/*path", |req| {
+            text(format!("file {}", req.param("path").unwrap()))
+        });
+
+    let cases = [
+        (http::Method::Get, "/users/7", "get user 7"),
+        (http::Method::Delete, "/users/7?force=true", "delete user 7"),
+        (http::Method::Get, "/users/ada%20lovelace", "get user ada lovelace"),
+        (http::Method::Get, "/users/7/posts/12", "post 12 of 7"),
+        (http::Method::Get, "/static/css/site.css", "file css/site.css"),
+        (http::Method::Get, "/static/", "file "),
+        (http::Method::Head, "/users/7", "get user 7"),
+    ];
+    for (method, target, expected) in cases {
+        let response = router.handle(http::Request::new(method, target)).unwrap();
+        assert_eq!(response.status, http::StatusCode::OK, "{method} {target}");
+        assert_eq!(body_of(&response), expected, "{method} {target}");
+    }
+
+    for target in ["/users", "/users/7/posts", "/nothing/here"] {
+        let response = router
+            .handle(http::Request::new(http::Method::Get, target))
+            .unwrap();
+        assert_eq!(response.status, http::StatusCode::NOT_FOUND, "{target}");
+    }
+}
+
+#[test]
+fn router_answers_405_with_allow_header() {
+    let mut router = router::Router::new();
+    router
+        .route(http::Method::Get, "/items/:id", |_| text(String::new()))
+        .route(http::Method::Put, "/items/:id", |_| text(String::new()));
+
+    let response = router
+        .handle(http::Request::new(http::Method::Post, "/items/3"))
+        .unwrap();
+    assert_eq!(response.status, http::StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers.get("allow"), Some("GET, PUT, HEAD"));
+}
+
+#[test]
+fn default_router_serves_chapter_20_pages() {
+    let router = util::default_router();
+    let hello = hello_page();
+
+    let response = router.handle(http::Request::new(http::Method::Get, "/")).unwrap();
+    assert_eq!(response.status, http::StatusCode::OK);
+    assert_eq!(response.body.as_bytes(), Some(hello.as_bytes()));
+    assert!(body_of(&response).contains("<h1>Hello!</h1>"));
+
+    let response = router
+        .handle(http::Request::new(http::Method::Get, "/?name=Ferris%20%26%20co"))
+        .unwrap();
+    assert!(body_of(&response).contains("<h1>Hello, Ferris &amp; co!</h1>"));
+
+    let response = router
+        .handle(http::Request::new(http::Method::Get, "/does/not/exist"))
+        .unwrap();
+    assert_eq!(response.status, http::StatusCode::NOT_FOUND);
+    assert!(body_of(&response).contains("<title>Not Found</title>"));
+    assert!(body_of(&response).contains("<code>/does/not/exist</code>"));
+
+    // The requested path is echoed, escaped.
+    let response = router
+        .handle(http::Request::new(http::Method::Get, "/%3Cscript%3Ealert(1)%3C/script%3E"))
+        .unwrap();
+    assert_eq!(response.status, http::StatusCode::NOT_FOUND);
+    assert!(body_of(&response).contains("<code>/&lt;script&gt;alert(1)&lt;/script&gt;</code>"));
+    assert!(!body_of(&response).contains("<script>"));
+
+    let response = router.handle(http::Request::new(http::Method::Post, "/")).unwrap();
+    assert_eq!(response.status, http::StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers.get("Allow"), Some("GET, HEAD"));
+}
+
+#[test]
+fn default_router_pages_carry_validators_and_cache_control() {
+    let response = get(&util::default_router(), "/");
+    assert_eq!(response.headers.get("Cache-Control"), Some("no-cache"));
+    assert!(response.headers.get("ETag").is_some_and(|etag| etag.starts_with('"')));
+    let etag = response.headers.get("ETag").unwrap().to_string();
+    // Rendered pages have no modification time to go by.
+    assert_eq!(response.headers.get("Last-Modified"), None);
+    assert_eq!(get(&util::default_router(), "/").headers.get("ETag"), Some(etag.as_str()));
+    assert_ne!(get(&util::default_router(), "/?name=Ferris").headers.get("ETag"), Some(etag.as_str()));
+
+    let templates = || template::Templates::new(template::TEMPLATES_DIR);
+    let response = get(&util::default_router_with(templates(), Some("max-age=60")), "/");
+    assert_eq!(response.headers.get("Cache-Control"), Some("max-age=60"));
+    let response = get(&util::default_router_with(templates(), None), "/");
+    assert_eq!(response.headers.get("Cache-Control"), None);
+
+    // Error pages aren't to be cached.
+    let response = get(&util::default_router(), "/does/not/exist");
+    assert_eq!(response.headers.get("Cache-Control"), None);
+    assert_eq!(response.headers.get("ETag"), None);
+}
+
+/// Create an empty directory for a test to write files in, under the OS's temporary
+/// directory, so that tests don't leave files behind in the package.
+fn scratch_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust_web_server_{}_{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Lay out a directory to be served by `StaticFiles`, plus a secret file beside it.
+///
+/// Returns the directory to serve, and the contents of its binary file.
+fn static_site(name: &str) -> (std::path::PathBuf, Vec<u8>) {
+    let dir = scratch_dir(name);
+    let root = dir.join("public");
+    fs::create_dir_all(root.join("docs")).unwrap();
+    fs::write(dir.join("secret.txt"), "top secret").unwrap();
+    fs::write(root.join("style.css"), "body { color: red; }").unwrap();
+    fs::write(root.join("docs").join("index.html"), "<h1>Docs</h1>").unwrap();
+
+    // Larger than the server's original 1024-byte buffer, and not valid UTF-8.
+    let binary: Vec<u8> = (0..5000u32).map(|n| (n * 7 % 256) as u8).collect();
+    fs::write(root.join("logo.png"), &binary).unwrap();
+
+    (root, binary)
+}
+
+fn static_router(root: &std::path::Path) -> router::Router {
+    let mut router = router::Router::new();
+    router.route(
+        http::Method::Get,
+        "/static/*path",
+        static_files::StaticFiles::build(root).unwrap().into_handler(),
+    );
+    router
+}
+
+fn get(router: &router::Router, target: &str) -> http::Response {
+    router
+        .handle(http::Request::new(http::Method::Get, target))
+        .unwrap()
+}
+
+#[test]
+fn static_files_serves_any_file_type_and_directory_indexes() {
+    let (root, binary) = static_site("static_types");
+    let router = static_router(&root);
+
+    let response = get(&router, "/static/logo.png");
+    assert_eq!(response.status, http::StatusCode::OK);
+    assert_eq!(response.headers.get("Content-Type"), Some("image/png"));
+    assert_eq!(response.body.content_length(), Some(binary.len() as u64));
+    assert_eq!(response.body.into_bytes().unwrap(), binary);
+
+    let response = get(&router, "/static/style.css");
+    assert_eq!(response.headers.get("Content-Type"), Some("text/css; charset=utf-8"));
+
+    let response = get(&router, "/static/docs/");
+    assert_eq!(response.status, http::StatusCode::OK);
+    assert_eq!(response.headers.get("Content-Type"), Some("text/html; charset=utf-8"));
+    assert_eq!(response.body.into_bytes().unwrap(), b"<h1>Docs</h1>");
+
+    let response = get(&router, "/static/docs");
+    assert_eq!(response.status, http::StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(response.headers.get("Location"), Some("/static/docs/"));
+
+    let response = get(&router, "/static/missing.js");
+    assert_eq!(response.status, http::StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn static_files_rejects_path_traversal() {
+    let (root, _) = static_site("static_traversal");
+    let router = static_router(&root);
+
+    for target in ["/static/../secret.txt", "/static/docs/../../secret.txt", "/static/..%2Fsecret.txt"] {
+        let response = get(&router, target);
+        assert_eq!(response.status, http::StatusCode::FORBIDDEN, "{target}");
+    }
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(root.parent().unwrap().join("secret.txt"), root.join("link.txt"))
+            .unwrap();
+        let response = get(&router, "/static/link.txt");
+        assert_eq!(response.status, http::StatusCode::FORBIDDEN);
+    }
+}
+
+#[test]
+fn static_files_are_streamed_over_the_socket() {
+    let (root, binary) = static_site("static_socket");
+    let (addr, server) = serve_one_connection(move |stream| {
+        util::handle_connection(stream, &service::Service::new(static_router(&root)))
+    });
+
+    let mut stream = net::TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /static/logo.png HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    server.join().unwrap().unwrap();
+
+    let head_len = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+    let head = std::str::from_utf8(&response[..head_len]).unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\nContent-Type: image/png\r\n"), "{head}");
+    assert!(head.contains(&format!("\r\nContent-Length: {}\r\n", binary.len())), "{head}");
+    assert!(head.contains("\r\nConnection: close\r\n"), "{head}");
+    assert_eq!(&response[head_len..], binary.as_slice());
+}
+
+/// Run a server the way `main` does, i.e. submitting each accepted connection to a
+/// `ThreadPool` as a `Job`, on an OS-assigned port.
+///
+/// The accepting thread, and with it the pool, live until the test process exits.
+fn spawn_server(service: service::Service, pool_size: usize) -> net::SocketAddr {
+    spawn_server_with(service, server::ThreadPoolConfig::new(pool_size))
+}
+
+/// Like `spawn_server`, but with control over the pool's configuration. As in `main`,
+/// connections the pool has no room for are rejected with `503 Service Unavailable`.
+fn spawn_server_with(service: service::Service, config: server::ThreadPoolConfig) -> net::SocketAddr {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = std::sync::Arc::new(service);
+    thread::spawn(move || {
+        let pool = server::ThreadPool::build_with(config).unwrap();
+        util::accept_connections(&listener, &pool, &service).unwrap();
+    });
+
+    addr
+}
+
+/// A response as read by a test client: the status code, header fields, and body.
+struct ClientResponse {
+    status: u16,
+    headers: http::Headers,
+    body: Vec<u8>,
+}
+
+/// Read one response from `reader`, or `None` if the server closed the connection
+/// instead.
+///
+/// Bodies are delimited by `Content-Length`, or decoded if they are chunked.
+fn read_response<R: io::BufRead>(reader: &mut R) -> Option<ClientResponse> {
+    let mut status_line = String::new();
+    if reader.read_line(&mut status_line).unwrap() == 0 {
+        return None;
+    }
+    let status: u16 = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+
+    let mut headers = http::Headers::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').unwrap();
+        headers.append(name, value.trim());
+    }
+
+    let body = if status == 204 || status == 304 {
+        Vec::new()
+    } else if headers.get("Transfer-Encoding") == Some("chunked") {
+        let mut body = Vec::new();
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size).unwrap();
+            let size = usize::from_str_radix(size.trim_end(), 16).unwrap();
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).unwrap();
+            assert!(chunk.ends_with(b"\r\n"));
+            if size == 0 {
+                break body;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else {
+        let length = headers.get("Content-Length").unwrap().parse().unwrap();
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        body
+    };
+
+    Some(ClientResponse { status, headers, body })
+}
+
+#[test]
+fn keep_alive_serves_several_requests_over_one_connection() {
+    let addr = spawn_server(service::Service::new(util::default_router()), 2);
+    let hello = hello_page().into_bytes();
+
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+
+    for n in 0..3 {
+        writer.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let response = read_response(&mut reader).unwrap();
+        assert_eq!(response.status, 200, "request {n}");
+        assert_eq!(response.headers.get("Connection"), Some("keep-alive"));
+        assert_eq!(response.body, hello);
+    }
+
+    // Pipelined requests are answered in order, and `Connection: close` ends the connection.
+    writer
+        .write_all(b"GET /missing HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    assert_eq!(read_response(&mut reader).unwrap().status, 404);
+    let last = read_response(&mut reader).unwrap();
+    assert_eq!(last.status, 200);
+    assert_eq!(last.headers.get("Connection"), Some("close"));
+    assert!(read_response(&mut reader).is_none());
+}
+
+#[test]
+fn keep_alive_closes_after_request_cap_and_idle_timeout() {
+    let mut service = service::Service::new(util::default_router());
+    service.keep_alive = service::KeepAlive {
+        idle_timeout: time::Duration::from_millis(300),
+        max_requests: 2,
+    };
+    let addr = spawn_server(service, 2);
+
+    // The cap is reached on the second request.
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader).unwrap().headers.get("Connection"), Some("keep-alive"));
+    assert_eq!(read_response(&mut reader).unwrap().headers.get("Connection"), Some("close"));
+    assert!(read_response(&mut reader).is_none());
+
+    // An idle connection is closed once the timeout expires, freeing its worker.
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader).unwrap().status, 200);
+    let start = time::Instant::now();
+    assert!(read_response(&mut reader).is_none());
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+}
+
+#[test]
+fn http_1_0_connections_close_unless_kept_alive() {
+    let addr = spawn_server(service::Service::new(util::default_router()), 1);
+
+    let response = raw_exchange(addr, b"GET / HTTP/1.0\r\n\r\n");
+    assert!(response.contains("Connection: close\r\n"));
+
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer
+        .write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nHEAD / HTTP/1.0\r\n\r\n")
+        .unwrap();
+    assert_eq!(read_response(&mut reader).unwrap().headers.get("Connection"), Some("keep-alive"));
+
+    // A `HEAD` response has a `Content-Length`, but no body, so read it by hand.
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert!(rest.starts_with("HTTP/1.1 200 OK\r\n"));
+    let hello = hello_page();
+    assert!(rest.ends_with(&format!("Connection: close\r\nContent-Length: {}\r\n\r\n", hello.len())));
+}
+
+#[test]
+fn chunked_request_bodies_are_decoded() {
+    let raw = b"POST /upload HTTP/1.1\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n\
+        5;name=value\r\nhello\r\n\
+        7\r\n, world\r\n\
+        0\r\n\
+        Expires: never\r\n\
+        \r\n\
+        GET / HTTP/1.1\r\n\r\n";
+    let mut reader = io::BufReader::new(raw.as_slice());
+    let limits = http::RequestLimits::default();
+
+    let request = http::Request::read_from(&mut reader, &limits).unwrap();
+    assert_eq!(request.body, b"hello, world");
+    // The trailer is consumed, so the next request starts where expected.
+    let next = http::Request::read_from(&mut reader, &limits).unwrap();
+    assert_eq!(next.target, "/");
+
+    let limits = http::RequestLimits {
+        max_header_bytes: 1024,
+        max_body_bytes: 8,
+    };
+    let cases: [(&[u8], u16); 5] = [
+        (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n", 413),
+        (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nhello\r\n0\r\n\r\n", 400),
+        (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhello\r\n0\r\n\r\n", 400),
+        (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n", 400),
+        (b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n", 501),
+    ];
+    for (raw, expected) in cases {
+        let err = http::Request::read_from(&mut io::BufReader::new(raw), &limits).unwrap_err();
+        assert_eq!(err.status().map(|s| s.as_u16()), Some(expected), "{:?}", err);
+    }
+}
+
+/// A router whose `/count/:n` route streams the numbers from `0` to `n`, one per line,
+/// without ever holding the whole body in memory.
+fn counting_router() -> router::Router {
+    let mut router = router::Router::new();
+    router.route(http::Method::Get, "/count/:n", |req| {
+        let n: usize = req.param("n").unwrap().parse().unwrap();
+        Ok(http::Response::new(http::StatusCode::OK).with_chunked(move |w| {
+            for i in 0..n {
+                writeln!(w, "{i}")?;
+            }
+            Ok(())
+        }))
+    });
+    router
+}
+
+fn expected_count(n: usize) -> Vec<u8> {
+    (0..n).map(|i| format!("{i}\n")).collect::<String>().into_bytes()
+}
+
+#[test]
+fn chunked_responses_are_streamed_over_keep_alive_connections() {
+    let addr = spawn_server(service::Service::new(counting_router()), 1);
+
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+
+    for n in [0, 3, 20_000] {
+        writer
+            .write_all(format!("GET /count/{n} HTTP/1.1\r\n\r\n").as_bytes())
+            .unwrap();
+        let response = read_response(&mut reader).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("Transfer-Encoding"), Some("chunked"));
+        assert!(!response.headers.contains("Content-Length"));
+        assert_eq!(response.body, expected_count(n));
+    }
+
+    // The same body is sent with a `Content-Length` to HTTP/1.0 clients.
+    writer.write_all(b"GET /count/50 HTTP/1.0\r\n\r\n").unwrap();
+    let response = read_response(&mut reader).unwrap();
+    assert!(!response.headers.contains("Transfer-Encoding"));
+    assert_eq!(response.body, expected_count(50));
+}
+
+#[test]
+fn bounded_queue_rejects_jobs_when_full() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build_with(server::ThreadPoolConfig {
+        queue_capacity: Some(1),
+        ..server::ThreadPoolConfig::new(1)
+    })
+    .unwrap();
+
+    // Occupy the only worker until `release` is sent on.
+    let (started, started_rx) = std::sync::mpsc::channel();
+    let (release, release_rx) = std::sync::mpsc::channel::<()>();
+    pool.execute(move || {
+        started.send(()).unwrap();
+        release_rx.recv().unwrap();
+        Ok(())
+    })
+    .unwrap();
+    started_rx.recv().unwrap();
+
+    // One job fits in the queue, the next doesn't.
+    let (done, done_rx) = std::sync::mpsc::channel();
+    let queued_done = done.clone();
+    pool.try_execute(move || {
+        queued_done.send("queued").unwrap();
+        Ok(())
+    })
+    .unwrap();
+    assert!(matches!(
+        pool.try_execute(|| Ok(())),
+        Err(server::ThreadPoolError::JobQueueFullError)
+    ));
+
+    // Once the worker is free, the queue drains and accepts jobs again.
+    release.send(()).unwrap();
+    assert_eq!(done_rx.recv().unwrap(), "queued");
+    pool.execute(move || {
+        done.send("after").unwrap();
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(done_rx.recv().unwrap(), "after");
+}
+
+#[test]
+fn server_sheds_load_with_503_when_queue_is_full() {
+    let mut service = service::Service::new(util::default_router());
+    service.keep_alive.idle_timeout = time::Duration::from_secs(2);
+    service.retry_after = time::Duration::from_secs(3);
+    let addr = spawn_server_with(
+        service,
+        server::ThreadPoolConfig {
+            queue_capacity: Some(1),
+            ..server::ThreadPoolConfig::new(1)
+        },
+    );
+
+    // The first connection is kept alive, occupying the only worker...
+    let busy = net::TcpStream::connect(addr).unwrap();
+    let mut busy_reader = io::BufReader::new(busy.try_clone().unwrap());
+    (&busy).write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut busy_reader).unwrap().status, 200);
+
+    // ...the second one waits in the queue...
+    let queued = net::TcpStream::connect(addr).unwrap();
+    (&queued).write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+    thread::sleep(time::Duration::from_millis(200));
+
+    // ...and the third one is turned away right away, without the server waiting
+    // for its request.
+    let mut rejected = net::TcpStream::connect(addr).unwrap();
+    let mut response = String::new();
+    rejected.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{response}");
+    assert!(response.contains("Retry-After: 3\r\n"));
+
+    // The queued connection is still served once the worker frees up.
+    drop(busy);
+    let mut queued_reader = io::BufReader::new(queued);
+    assert_eq!(read_response(&mut queued_reader).unwrap().status, 200);
+}
+
+#[test]
+fn thread_pool_shutdown_reports_busy_workers() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build(2).unwrap();
+
+    let (started, started_rx) = std::sync::mpsc::channel();
+    pool.execute(move || {
+        started.send(()).unwrap();
+        thread::sleep(time::Duration::from_secs(2));
+        Ok(())
+    })
+    .unwrap();
+    started_rx.recv().unwrap();
+    pool.execute(|| Ok(())).unwrap();
+
+    let start = time::Instant::now();
+    let report = pool.shutdown(time::Duration::from_millis(300));
+    let elapsed = start.elapsed();
+
+    assert!(elapsed >= time::Duration::from_millis(300));
+    assert!(elapsed < time::Duration::from_secs(1), "{:?}", elapsed);
+    assert_eq!(report.busy.len(), 1);
+    assert_eq!(report.finished.len(), 1);
+    assert_ne!(report.busy, report.finished);
+}
+
+#[test]
+fn admin_endpoint_shuts_server_down_after_draining() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+
+    let handle = shutdown::ShutdownHandle::new();
+    let mut router = router::Router::new();
+    router.route(http::Method::Get, "/slow", |_| {
+        thread::sleep(time::Duration::from_millis(500));
+        text("done".to_string())
+    });
+    util::add_admin_routes(&mut router, handle.clone());
+    let mut service = service::Service::new(router);
+    service.shutdown = handle.clone();
+    let service = std::sync::Arc::new(service);
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let pool = server::ThreadPool::build(2).unwrap();
+        util::accept_connections(&listener, &pool, &service).unwrap();
+        pool.shutdown(time::Duration::from_secs(5))
+    });
+
+    // A request is in flight when shutdown is requested...
+    let slow = net::TcpStream::connect(addr).unwrap();
+    (&slow).write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+    thread::sleep(time::Duration::from_millis(100));
+
+    let response = raw_exchange(addr, b"POST /admin/shutdown HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 202 Accepted\r\n"), "{response}");
+    assert!(handle.is_triggered());
+
+    // ...and still completes, but the connection isn't kept alive afterwards.
+    let report = server.join().unwrap();
+    assert!(report.busy.is_empty());
+    assert_eq!(report.finished.len(), 2);
+
+    let response = read_response(&mut io::BufReader::new(slow)).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"done");
+    assert_eq!(response.headers.get("Connection"), Some("close"));
+
+    assert!(net::TcpStream::connect(addr).is_err());
+}
+
+/// Submit one job per worker, each of which only completes once all of them are
+/// running, i.e. once every worker is alive and taking jobs.
+fn assert_all_workers_alive(pool: &server::ThreadPool, size: usize) {
+    let barrier = std::sync::Arc::new(std::sync::Barrier::new(size));
+    let (done, done_rx) = std::sync::mpsc::channel();
+    for _ in 0..size {
+        let barrier = barrier.clone();
+        let done = done.clone();
+        pool.execute(move || {
+            barrier.wait();
+            done.send(()).unwrap();
+            Ok(())
+        })
+        .unwrap();
+    }
+    for _ in 0..size {
+        done_rx.recv_timeout(time::Duration::from_secs(5)).unwrap();
+    }
+}
+
+#[test]
+fn thread_pool_survives_panicking_jobs() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build(3).unwrap();
+
+    for i in 0..10 {
+        pool.execute(move || panic!("job {i} panicked")).unwrap();
+    }
+    assert_all_workers_alive(&pool, 3);
+
+    // The panics were contained in the jobs, so no worker had to be replaced.
+    assert_eq!(pool.worker_ids(), vec![0, 1, 2]);
+    let report = pool.shutdown(time::Duration::from_secs(5));
+    assert_eq!(report.finished.len(), 3);
+}
+
+/// A panic payload whose `Drop` panics as well, so that the worker catching the
+/// first panic dies anyway when disposing of it.
+struct PanicOnDrop;
+
+impl Drop for PanicOnDrop {
+    fn drop(&mut self) {
+        panic!("panic payload dropped");
+    }
+}
+
+#[test]
+fn thread_pool_respawns_dead_workers() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build(2).unwrap();
+
+    pool.execute(|| std::panic::panic_any(PanicOnDrop)).unwrap();
+    pool.execute(|| std::panic::panic_any(PanicOnDrop)).unwrap();
+
+    let start = time::Instant::now();
+    while pool.worker_ids().iter().any(|&id| id < 2) {
+        assert!(start.elapsed() < time::Duration::from_secs(5), "{:?}", pool.worker_ids());
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    let mut ids = pool.worker_ids();
+    ids.sort();
+    assert_eq!(ids, vec![2, 3]);
+
+    assert_all_workers_alive(&pool, 2);
+    let report = pool.shutdown(time::Duration::from_secs(5));
+    assert_eq!(report.finished.len(), 2);
+}
+
+#[test]
+fn thread_pool_submit_returns_job_results() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build(2).unwrap();
+
+    let handles: Vec<job::JobHandle<u64>> = (1..=10)
+        .map(|n| pool.submit(move || (1..=n).product()).unwrap())
+        .collect();
+    let results: Vec<u64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(results[0], 1);
+    assert_eq!(results[9], 3_628_800);
+
+    // A job that is still running can be polled and waited on with a timeout...
+    let (release, release_rx) = std::sync::mpsc::channel::<()>();
+    let slow = pool
+        .submit(move || {
+            release_rx.recv().unwrap();
+            "done".to_string()
+        })
+        .unwrap();
+    assert!(slow.try_get().is_none());
+    assert_eq!(
+        slow.join_timeout(time::Duration::from_millis(50)),
+        Err(job::JobError::JobTimeoutError)
+    );
+    release.send(()).unwrap();
+    assert_eq!(slow.join_timeout(time::Duration::from_secs(5)).unwrap(), "done");
+
+    // ...and once finished, its result is available without blocking.
+    let quick = pool.submit(|| 7).unwrap();
+    let start = time::Instant::now();
+    let result = loop {
+        if let Some(result) = quick.try_get() {
+            break result;
+        }
+        assert!(start.elapsed() < time::Duration::from_secs(5));
+        thread::sleep(time::Duration::from_millis(10));
+    };
+    assert_eq!(result, Ok(7));
+
+    // Panics are reported through the handle, and don't cost the pool its workers.
+    let panicking = pool.submit(|| -> u8 { panic!("no result for you") }).unwrap();
+    assert_eq!(
+        panicking.join(),
+        Err(job::JobError::JobPanicError("no result for you".to_string()))
+    );
+    assert_all_workers_alive(&pool, 2);
+    assert_eq!(pool.worker_ids(), vec![0, 1]);
+}
+
+/// Poll `condition` until it holds, failing the test if it doesn't within 5 seconds.
+fn wait_until(mut condition: impl FnMut() -> bool) {
+    let start = time::Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < time::Duration::from_secs(5), "condition never held");
+        thread::sleep(time::Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn thread_pool_grows_under_load_and_retires_idle_workers() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build_with(server::ThreadPoolConfig {
+        max_size: Some(4),
+        idle_timeout: time::Duration::from_millis(200),
+        ..server::ThreadPoolConfig::new(1)
+    })
+    .unwrap();
+    assert_eq!(pool.size(), 1);
+
+    // Four jobs that can only complete together need four workers.
+    assert_all_workers_alive(&pool, 4);
+    assert_eq!(pool.size(), 4);
+
+    // The extra workers are retired once idle, but not the last one.
+    wait_until(|| pool.size() == 1 && pool.worker_ids().len() == 1);
+    thread::sleep(time::Duration::from_millis(400));
+    assert_eq!(pool.size(), 1);
+    assert_eq!(pool.submit(|| 42).unwrap().join(), Ok(42));
+
+    assert!(matches!(
+        server::ThreadPool::build_with(server::ThreadPoolConfig {
+            max_size: Some(1),
+            ..server::ThreadPoolConfig::new(2)
+        }),
+        Err(server::ThreadPoolBuildError::InvalidMaxSizeThreadPoolError)
+    ));
+}
+
+#[test]
+fn thread_pool_resize() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build(1).unwrap();
+
+    // Shrinking while a worker is busy, then growing again before it is done.
+    let (release, release_rx) = std::sync::mpsc::channel::<()>();
+    let busy = pool.submit(move || release_rx.recv().unwrap()).unwrap();
+    pool.resize(3).unwrap();
+    assert_eq!(pool.worker_ids(), vec![0, 1, 2]);
+    pool.resize(1).unwrap();
+    pool.resize(2).unwrap();
+    release.send(()).unwrap();
+    busy.join().unwrap();
+    wait_until(|| pool.worker_ids().len() == 2);
+    assert_eq!(pool.size(), 2);
+
+    pool.resize(5).unwrap();
+    assert_eq!(pool.size(), 5);
+    assert_eq!(pool.worker_ids().len(), 5);
+    assert_all_workers_alive(&pool, 5);
+
+    pool.resize(1).unwrap();
+    assert_eq!(pool.size(), 1);
+    wait_until(|| pool.worker_ids().len() == 1);
+    assert_all_workers_alive(&pool, 1);
+
+    assert!(matches!(
+        pool.resize(0),
+        Err(server::ThreadPoolBuildError::ZeroThreadThreadPoolError)
+    ));
+    let report = pool.shutdown(time::Duration::from_secs(5));
+    assert!(!report.finished.is_empty());
+    assert!(report.busy.is_empty());
+}
+
+#[test]
+fn thread_pool_work_stealing_scheduler() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let work_stealing = |size| server::ThreadPoolConfig {
+        scheduler: server::Scheduler::WorkStealing,
+        ..server::ThreadPoolConfig::new(size)
+    };
+
+    let pool = server::ThreadPool::build_with(work_stealing(4)).unwrap();
+    let handles: Vec<_> = (0..10_000u64).map(|n| pool.submit(move || n).unwrap()).collect();
+    let sum: u64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    assert_eq!(sum, 10_000 * 9_999 / 2);
+
+    for i in 0..10 {
+        pool.execute(move || panic!("job {i} panicked")).unwrap();
+    }
+    assert_all_workers_alive(&pool, 4);
+    assert_eq!(pool.worker_ids(), vec![0, 1, 2, 3]);
+
+    // Jobs still queued when the pool shuts down are run before it does.
+    let ran = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    for _ in 0..100 {
+        let ran = ran.clone();
+        pool.execute(move || {
+            thread::sleep(time::Duration::from_millis(1));
+            ran.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        })
+        .unwrap();
+    }
+    let report = pool.shutdown(time::Duration::from_secs(5));
+    assert_eq!(report.finished.len(), 4);
+    assert_eq!(ran.load(std::sync::atomic::Ordering::SeqCst), 100);
+
+    // A bounded queue fills up just as the channel does.
+    let pool = server::ThreadPool::build_with(server::ThreadPoolConfig {
+        queue_capacity: Some(1),
+        ..work_stealing(1)
+    })
+    .unwrap();
+    let (started, started_rx) = std::sync::mpsc::channel();
+    let (release, release_rx) = std::sync::mpsc::channel::<()>();
+    pool.execute(move || {
+        started.send(()).unwrap();
+        release_rx.recv().unwrap();
+        Ok(())
+    })
+    .unwrap();
+    started_rx.recv().unwrap();
+    let queued = pool.submit(|| "queued").unwrap();
+    assert!(matches!(
+        pool.try_execute(|| Ok(())),
+        Err(server::ThreadPoolError::JobQueueFullError)
+    ));
+    release.send(()).unwrap();
+    assert_eq!(queued.join(), Ok("queued"));
+}
+
+#[test]
+fn idle_workers_steal_jobs_left_in_busy_workers_deques() {
+    let queue = server::work_stealing::WorkStealingQueue::new(None);
+    let sender = server::work_stealing::WorkStealingSender::new(queue.clone());
+    let (busy, other, idle) = (queue.register(0), queue.register(1), queue.register(2));
+    for _ in 0..3 {
+        let job: server::Job = Box::new(|| Ok(()));
+        assert!(sender.push(job, false).is_ok());
+    }
+    // Taking the first job moves the second into `busy`'s deque, and `other` takes the
+    // third, leaving the injector empty while `busy` runs its job.
+    assert!(matches!(busy.pop(), server::work_stealing::Pop::Job(_)));
+    assert!(matches!(other.pop(), server::work_stealing::Pop::Job(_)));
+    assert_eq!(queue.len(), 1);
+
+    let start = time::Instant::now();
+    idle.wait(time::Duration::from_secs(5));
+    assert!(start.elapsed() < time::Duration::from_secs(1));
+    assert!(matches!(idle.pop(), server::work_stealing::Pop::Job(_)));
+    assert!(queue.is_empty());
+}
+
+/// Occupy a pool's only worker until the returned sender is used or dropped.
+fn block_only_worker(pool: &server::ThreadPool) -> std::sync::mpsc::Sender<()> {
+    let (started, started_rx) = std::sync::mpsc::channel();
+    let (release, release_rx) = std::sync::mpsc::channel::<()>();
+    pool.execute(move || {
+        started.send(()).unwrap();
+        let _ = release_rx.recv();
+        Ok(())
+    })
+    .unwrap();
+    started_rx.recv().unwrap();
+    release
+}
+
+#[test]
+fn thread_pool_runs_jobs_in_order_of_priority() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build(1).unwrap();
+    let order = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let record = |name: &'static str| {
+        let order = order.clone();
+        move || {
+            order.lock().unwrap().push(name);
+            Ok(())
+        }
+    };
+    let with = |priority| priority::JobOptions::default().with_priority(priority);
+
+    let release = block_only_worker(&pool);
+    pool.execute_with(with(priority::Priority::Low), record("low")).unwrap();
+    pool.execute_with(with(priority::Priority::Normal), record("normal 1")).unwrap();
+    pool.execute_with(with(priority::Priority::High), record("high 1")).unwrap();
+    pool.execute(record("plain")).unwrap();
+    pool.execute_with(with(priority::Priority::Normal), record("normal 2")).unwrap();
+    pool.execute_with(with(priority::Priority::High), record("high 2")).unwrap();
+    release.send(()).unwrap();
+
+    let report = pool.shutdown(time::Duration::from_secs(5));
+    assert!(report.busy.is_empty());
+    // High priority jobs go ahead of everything, then the rest submitted with a
+    // priority are run in order of it, as their tickets come up.
+    assert_eq!(
+        *order.lock().unwrap(),
+        vec!["high 1", "high 2", "normal 1", "normal 2", "low", "plain"]
+    );
+}
+
+#[test]
+fn thread_pool_named_queues_respect_their_quotas() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build(3).unwrap();
+    pool.add_queue("slow", 1);
+    let slow = priority::JobOptions::default().with_queue("slow");
+
+    let running = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let max_running = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let slow_handles: Vec<_> = (0..3)
+        .map(|_| {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            pool.submit_with(slow.clone(), move || {
+                let now = running.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, std::sync::atomic::Ordering::SeqCst);
+                thread::sleep(time::Duration::from_millis(200));
+                running.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                time::Instant::now()
+            })
+            .unwrap()
+        })
+        .collect();
+
+    // Quick jobs submitted afterwards aren't stuck behind the slow ones, as those
+    // only ever occupy one of the three workers.
+    let quick_handles: Vec<_> = (0..10)
+        .map(|_| pool.submit_with(Default::default(), time::Instant::now).unwrap())
+        .collect();
+    let quick_done = quick_handles.into_iter().map(|h| h.join().unwrap()).max().unwrap();
+    let slow_done: Vec<_> = slow_handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+    assert!(slow_done.iter().all(|&done| quick_done < done));
+    assert_eq!(max_running.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    // A job that panics frees its slot in the queue, and no worker has to be replaced
+    // for running it through a ticket.
+    let panicked = pool.submit_with(slow.clone(), || -> u8 { panic!("slow and broken") }).unwrap();
+    assert!(panicked.join().is_err());
+    assert_eq!(pool.submit_with(slow, || "after").unwrap().join(), Ok("after"));
+    assert_eq!(pool.worker_ids(), vec![0, 1, 2]);
+
+    assert!(matches!(
+        pool.execute_with(priority::JobOptions::default().with_queue("fast"), || Ok(())),
+        Err(server::ThreadPoolError::UnknownQueueError(name)) if name == "fast"
+    ));
+}
+
+#[test]
+fn thread_pool_runs_delayed_jobs() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build(2).unwrap();
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    let start = time::Instant::now();
+    let later = sender.clone();
+    pool.execute_after(time::Duration::from_millis(300), move || {
+        later.send("later").unwrap();
+        Ok(())
+    })
+    .unwrap();
+    let sooner = sender.clone();
+    let fired = pool
+        .execute_after(time::Duration::from_millis(100), move || {
+            sooner.send("sooner").unwrap();
+            Ok(())
+        })
+        .unwrap();
+    let cancelled = pool
+        .execute_after(time::Duration::from_millis(200), move || {
+            sender.send("cancelled").unwrap();
+            Ok(())
+        })
+        .unwrap();
+    assert!(cancelled.cancel());
+    assert!(cancelled.is_cancelled());
+
+    assert_eq!(receiver.recv_timeout(time::Duration::from_secs(5)), Ok("sooner"));
+    assert!(start.elapsed() >= time::Duration::from_millis(100));
+    assert_eq!(receiver.recv_timeout(time::Duration::from_secs(5)), Ok("later"));
+    assert!(start.elapsed() >= time::Duration::from_millis(300));
+    // Every sender was dropped, the cancelled job's included, without it running.
+    assert!(receiver.recv_timeout(time::Duration::from_secs(5)).is_err());
+
+    // Too late to cancel a job already run.
+    assert!(!fired.cancel());
+    assert!(!fired.is_cancelled());
+}
+
+#[test]
+fn thread_pool_runs_periodic_jobs_until_cancelled() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build(2).unwrap();
+    let runs = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    let counter = runs.clone();
+    let handle = pool
+        .execute_every(time::Duration::from_millis(20), move || {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        })
+        .unwrap();
+    wait_until(|| runs.load(std::sync::atomic::Ordering::SeqCst) >= 3);
+
+    assert!(handle.cancel());
+    // A run may have been underway when cancelling.
+    thread::sleep(time::Duration::from_millis(50));
+    let after_cancel = runs.load(std::sync::atomic::Ordering::SeqCst);
+    thread::sleep(time::Duration::from_millis(200));
+    assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), after_cancel);
+}
+
+#[test]
+fn thread_pool_skips_periodic_runs_while_one_is_running() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build(4).unwrap();
+    let running = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let max_running = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    let (running_clone, max_clone) = (running.clone(), max_running.clone());
+    let handle = pool
+        .execute_every(time::Duration::from_millis(10), move || {
+            let now = running_clone.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            max_clone.fetch_max(now, std::sync::atomic::Ordering::SeqCst);
+            thread::sleep(time::Duration::from_millis(50));
+            running_clone.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        })
+        .unwrap();
+    thread::sleep(time::Duration::from_millis(300));
+    handle.cancel();
+
+    assert_eq!(max_running.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn thread_pool_drop_discards_pending_scheduled_jobs() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build(2).unwrap();
+    let ran = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    let flag = ran.clone();
+    let handle = pool
+        .execute_after(time::Duration::from_secs(60), move || {
+            flag.store(true, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        })
+        .unwrap();
+    pool.execute_every(time::Duration::from_secs(60), || Ok(())).unwrap();
+
+    let start = time::Instant::now();
+    drop(pool);
+    assert!(start.elapsed() < time::Duration::from_secs(5));
+    assert!(!ran.load(std::sync::atomic::Ordering::SeqCst));
+    // The job never fired, so cancelling still reports success, though it's moot.
+    assert!(handle.cancel());
+}
+
+/// A service answering with the default router, whose `read` and `handler` timeouts
+/// are short enough for tests.
+fn impatient_service() -> service::Service {
+    let mut service = service::Service::new(util::default_router());
+    service.timeouts = service::Timeouts {
+        read: time::Duration::from_millis(300),
+        write: time::Duration::from_secs(5),
+        handler: time::Duration::from_millis(300),
+    };
+    service
+}
+
+#[test]
+fn slow_requests_are_answered_with_408() {
+    let addr = spawn_server(impatient_service(), 2);
+
+    // A client stalling halfway through its headers.
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+    let start = time::Instant::now();
+    let response = read_response(&mut reader).unwrap();
+    assert_eq!(response.status, 408);
+    assert_eq!(response.headers.get("Connection"), Some("close"));
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+    assert!(read_response(&mut reader).is_none());
+
+    // A client trickling its request a byte at a time is cut off all the same, even
+    // though no single read takes longer than the timeout.
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let start = time::Instant::now();
+    for byte in b"GET / HTTP/1.1\r\nX-Slow: ".iter().cycle() {
+        if writer.write_all(&[*byte]).is_err() || start.elapsed() > time::Duration::from_secs(2) {
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(50));
+    }
+    assert_eq!(read_response(&mut reader).unwrap().status, 408);
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+}
+
+#[test]
+fn idle_timeout_applies_before_a_request_starts() {
+    let mut service = impatient_service();
+    service.keep_alive.idle_timeout = time::Duration::from_secs(1);
+    let addr = spawn_server(service, 2);
+
+    // Waiting longer than the read timeout, but not the idle timeout, before sending
+    // a request is fine.
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    thread::sleep(time::Duration::from_millis(500));
+    writer.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader).unwrap().status, 200);
+    thread::sleep(time::Duration::from_millis(500));
+    writer.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader).unwrap().status, 200);
+}
+
+#[test]
+fn handlers_are_cancelled_after_the_handler_timeout() {
+    let addr = spawn_server(impatient_service(), 2);
+
+    let start = time::Instant::now();
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(b"GET /sleep HTTP/1.1\r\n\r\n").unwrap();
+    let response = read_response(&mut reader).unwrap();
+    assert_eq!(response.status, 503);
+    assert_eq!(response.headers.get("Connection"), Some("close"));
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+}
+
+#[test]
+fn cancellation_tokens_wake_up_sleeping_handlers() {
+    let token = cancellation::CancellationToken::new();
+    assert!(!token.is_cancelled());
+    assert!(token.check().is_ok());
+    assert_eq!(token.deadline(), None);
+
+    let canceller = token.clone();
+    let start = time::Instant::now();
+    thread::spawn(move || {
+        thread::sleep(time::Duration::from_millis(100));
+        canceller.cancel();
+    });
+    assert!(!token.sleep(time::Duration::from_secs(10)));
+    assert!(start.elapsed() < time::Duration::from_secs(5));
+    assert!(token.is_cancelled());
+    assert_eq!(token.check().unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+    let token = cancellation::CancellationToken::with_timeout(time::Duration::from_millis(100));
+    assert!(token.sleep(time::Duration::from_millis(10)));
+    assert!(!token.sleep(time::Duration::from_secs(10)));
+    assert!(token.is_cancelled());
+    assert_eq!(token.remaining(), Some(time::Duration::ZERO));
+}
+
+#[test]
+fn thread_pool_stats_count_jobs_by_outcome() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build_with(server::ThreadPoolConfig {
+        queue_capacity: Some(1),
+        ..server::ThreadPoolConfig::new(1)
+    })
+    .unwrap();
+    pool.add_queue("slow", 1);
+
+    let release = block_only_worker(&pool);
+    let stats = pool.stats();
+    assert_eq!((stats.size, stats.max_size), (1, 1));
+    assert_eq!(stats.workers.len(), 1);
+    wait_until(|| pool.stats().workers[0].in_flight == 1);
+
+    pool.execute(|| Err(io::ErrorKind::TimedOut.into())).unwrap();
+    assert!(matches!(
+        pool.try_execute(|| Ok(())),
+        Err(server::ThreadPoolError::JobQueueFullError)
+    ));
+    assert!(pool
+        .execute_with(priority::JobOptions::default().with_queue("fast"), || Ok(()))
+        .is_err());
+    assert_eq!(pool.stats().queued_jobs, 1);
+    thread::sleep(time::Duration::from_millis(20));
+    release.send(()).unwrap();
+
+    pool.execute_with(priority::JobOptions::default().with_queue("slow"), || {
+        Err(io::ErrorKind::NotFound.into())
+    })
+    .unwrap();
+    let panicked = pool.submit(|| panic!("metrics")).unwrap();
+    assert!(panicked.join().is_err());
+    pool.submit(|| ()).unwrap().join().unwrap();
+
+    // The last job's run is recorded right after its result is sent.
+    wait_until(|| pool.stats().workers[0].jobs_run == 5);
+    let stats = pool.stats();
+    assert_eq!(stats.jobs_submitted, 5);
+    assert_eq!(stats.jobs_succeeded, 2);
+    assert_eq!(stats.jobs_panicked, 1);
+    assert_eq!(stats.jobs_failed(), 2);
+    assert_eq!(stats.job_errors.get("TimedOut"), Some(&1));
+    assert_eq!(stats.job_errors.get("NotFound"), Some(&1));
+    assert_eq!(stats.submit_errors.get("JobQueueFullError"), Some(&1));
+    assert_eq!(stats.submit_errors.get("UnknownQueueError"), Some(&1));
+    assert_eq!(stats.wait_time.count, 5);
+    assert_eq!(stats.run_time.count, 5);
+    // The job blocking the worker ran for as long as it took to release it.
+    assert!(stats.run_time.buckets[0].1 < 5);
+    assert_eq!(stats.workers[0].in_flight, 0);
+    assert_eq!(stats.queued_jobs, 0);
+}
+
+#[test]
+fn metrics_endpoint_serves_prometheus_text() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let pool = server::ThreadPool::build(2).unwrap();
+    let server_metrics = metrics::ServerMetrics::new();
+    let mut router = util::default_router();
+    util::add_metrics_route(&mut router, pool.stats_handle(), server_metrics.clone());
+    let mut service = service::Service::new(router);
+    service.metrics = server_metrics;
+    let service = std::sync::Arc::new(service);
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || util::accept_connections(&listener, &pool, &service).unwrap());
+
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(b"GET / HTTP/1.1\r\n\r\nGET /missing HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader).unwrap().status, 200);
+    assert_eq!(read_response(&mut reader).unwrap().status, 404);
+    writer.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    let response = read_response(&mut reader).unwrap();
+    assert_eq!(response.status, 200);
+    assert!(response.headers.get("Content-Type").unwrap().starts_with("text/plain; version=0.0.4"));
+
+    let body = String::from_utf8(response.body).unwrap();
+    for expected in [
+        "# TYPE threadpool_workers gauge\nthreadpool_workers 2\n",
+        "# TYPE threadpool_jobs_submitted_total counter\nthreadpool_jobs_submitted_total 1\n",
+        "threadpool_jobs_completed_total{outcome=\"panic\"} 0\n",
+        "threadpool_worker_jobs_in_flight{worker=\"0\"} ",
+        "threadpool_job_wait_seconds_bucket{le=\"0.001\"} ",
+        "threadpool_job_run_seconds_bucket{le=\"+Inf\"} 0\n",
+        "http_connections_total 1\n",
+        "http_responses_total{status=\"200\"} 1\nhttp_responses_total{status=\"404\"} 1\n",
+    ] {
+        assert!(body.contains(expected), "{expected:?} not in:\n{body}");
+    }
+    // The connection being served is still running, on one of the workers.
+    assert!(body.contains("threadpool_worker_jobs_in_flight{worker=\"0\"} 1\n")
+        || body.contains("threadpool_worker_jobs_in_flight{worker=\"1\"} 1\n"));
+}
+
+#[test]
+fn dates_convert_to_and_from_unix_time() {
+    let cases = [
+        (0, "1970-01-01T00:00:00Z", "01/Jan/1970:00:00:00 +0000"),
+        (
+            971_186_136,
+            "2000-10-10T13:55:36Z",
+            "10/Oct/2000:13:55:36 +0000",
+        ),
+        (
+            951_782_400,
+            "2000-02-29T00:00:00Z",
+            "29/Feb/2000:00:00:00 +0000",
+        ),
+        (-1, "1969-12-31T23:59:59Z", "31/Dec/1969:23:59:59 +0000"),
+        (
+            4_107_542_399,
+            "2100-02-28T23:59:59Z",
+            "28/Feb/2100:23:59:59 +0000",
+        ),
+    ];
+    for (secs, rfc3339, clf) in cases {
+        let date = date::DateTime::from_unix_seconds(secs);
+        assert_eq!(date.to_rfc3339(), rfc3339);
+        assert_eq!(date.to_clf(), clf);
+        assert_eq!(date.unix_seconds(), secs);
+        assert_eq!(
+            date::DateTime::from_system_time(date.to_system_time()),
+            date
+        );
+    }
+}
+
+#[test]
+fn access_log_entries_are_formatted() {
+    let mut entry = access_log::AccessLogEntry {
+        peer_addr: Some("127.0.0.1:5000".parse().unwrap()),
+        time: date::DateTime::from_unix_seconds(971_186_136),
+        request_line: Some((
+            http::Method::Get,
+            "/a\"b".to_string(),
+            http::Version::Http11,
+        )),
+        status: http::StatusCode::OK,
+        bytes: 2326,
+        duration: time::Duration::from_micros(1500),
+        worker_id: Some(3),
+        referer: None,
+        user_agent: Some("curl/8.0".to_string()),
+    };
+
+    assert_eq!(
+        entry.format(access_log::AccessLogFormat::Common),
+        r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /a\"b HTTP/1.1" 200 2326"#
+    );
+    assert_eq!(
+        entry.format(access_log::AccessLogFormat::Combined),
+        r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /a\"b HTTP/1.1" 200 2326 "-" "curl/8.0""#
+    );
+    assert_eq!(
+        entry.format(access_log::AccessLogFormat::Json),
+        r#"{"time":"2000-10-10T13:55:36Z","remote_addr":"127.0.0.1","method":"GET","target":"/a\"b","protocol":"HTTP/1.1","status":200,"bytes":2326,"duration_ms":1.500,"worker":3,"referer":null,"user_agent":"curl/8.0"}"#
+    );
+
+    // An unparseable request, answered with an empty body.
+    entry.request_line = None;
+    entry.status = http::StatusCode::BAD_REQUEST;
+    entry.bytes = 0;
+    entry.user_agent = Some("evil\n127.0.0.1 - - fake line".to_string());
+    assert_eq!(
+        entry.format(access_log::AccessLogFormat::Combined),
+        r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "-" 400 - "-" "evil\x0a127.0.0.1 - - fake line""#
+    );
+}
+
+/// A writer appending into a buffer that can be inspected while it is in use elsewhere.
+#[derive(Clone, Default)]
+struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SharedBuffer {
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+#[test]
+fn access_log_records_every_response() {
+    let buffer = SharedBuffer::default();
+    let mut service = service::Service::new(counting_router());
+    service.access_log = Some(access_log::AccessLog::new(
+        buffer.clone(),
+        access_log::AccessLogFormat::Json,
+    ));
+    let addr = spawn_server(service, 1);
+
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer
+        .write_all(b"GET /count/3 HTTP/1.1\r\nUser-Agent: test\r\n\r\nHEAD /missing HTTP/1.1\r\n\r\nnot http\r\n\r\n")
+        .unwrap();
+    let counted = read_response(&mut reader).unwrap();
+    assert_eq!(counted.status, 200);
+    let mut head = String::new();
+    while io::BufRead::read_line(&mut reader, &mut head).unwrap() > 2 {
+        head.clear();
+    }
+    assert_eq!(read_response(&mut reader).unwrap().status, 400);
+
+    wait_until(|| buffer.lines().len() == 3);
+    let lines = buffer.lines();
+    assert!(lines[0]
+        .contains(r#""method":"GET","target":"/count/3","protocol":"HTTP/1.1","status":200,"#));
+    // The chunked body is counted as sent, i.e. "0\n1\n2\n" in a single chunk and the last one.
+    assert!(lines[0].contains(&format!(
+        r#""bytes":{},"#,
+        "6\r\n".len() + counted.body.len() + "\r\n0\r\n\r\n".len()
+    )));
+    assert!(lines[0].contains(r#""worker":0,"#));
+    assert!(lines[0].ends_with(r#""referer":null,"user_agent":"test"}"#));
+    assert!(lines[1].contains(
+        r#""method":"HEAD","target":"/missing","protocol":"HTTP/1.1","status":404,"bytes":0,"#
+    ));
+    assert!(lines[2].contains(r#""method":null,"target":null,"protocol":null,"status":400,"#));
+    assert!(lines
+        .iter()
+        .all(|line| line.contains(r#""remote_addr":"127.0.0.1""#)));
+}
+
+#[test]
+fn log_file_is_appended_to_and_rotated_by_size() {
+    let dir = scratch_dir("log_rotation_size");
+    let path = dir.join("server.log");
+    fs::write(&path, "from a previous run\n").unwrap();
+    let rotation = log_rotation::LogRotation {
+        max_size: Some(40),
+        max_age: None,
+        retention: 2,
+        compress: false,
+    };
+
+    let mut file = log_rotation::RotatingFile::open(&path, rotation).unwrap();
+    // Lines written in pieces are never split across files, and two of them fit.
+    for i in 0..6 {
+        write!(file, "line {i}, ").unwrap();
+        writeln!(file, "in two pieces").unwrap();
+    }
+    file.flush().unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "line 5, in two pieces\n");
+    assert_eq!(
+        fs::read_to_string(file.rotated_path(1)).unwrap(),
+        "line 3, in two pieces\nline 4, in two pieces\n"
+    );
+    assert_eq!(
+        fs::read_to_string(file.rotated_path(2)).unwrap(),
+        "line 1, in two pieces\nline 2, in two pieces\n"
+    );
+    // The oldest file, with the line from before the restart, is past retention.
+    assert!(!file.rotated_path(3).exists());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn log_file_is_rotated_by_age_and_compressed() {
+    let dir = scratch_dir("log_rotation_age");
+    let path = dir.join("server.log");
+    let rotation = log_rotation::LogRotation {
+        max_size: None,
+        max_age: Some(time::Duration::from_millis(100)),
+        retention: 3,
+        compress: true,
+    };
+
+    let mut file = log_rotation::RotatingFile::open(&path, rotation).unwrap();
+    writeln!(file, "first").unwrap();
+    writeln!(file, "still first").unwrap();
+    thread::sleep(time::Duration::from_millis(150));
+    writeln!(file, "second").unwrap();
+    file.flush().unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+    let rotated = file.rotated_path(1);
+    assert!(rotated.to_str().unwrap().ends_with("server.log.1.gz"));
+    let mut decompressed = String::new();
+    flate2::read::GzDecoder::new(fs::File::open(rotated).unwrap())
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, "first\nstill first\n");
+    assert!(!file.rotated_path(2).exists());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn log_file_with_no_retention_starts_over() {
+    let dir = scratch_dir("log_rotation_none");
+    let path = dir.join("server.log");
+    let rotation = log_rotation::LogRotation {
+        max_size: Some(10),
+        retention: 0,
+        ..log_rotation::LogRotation::never()
+    };
+
+    let mut file = log_rotation::RotatingFile::open(&path, rotation).unwrap();
+    writeln!(file, "0123456789").unwrap();
+    writeln!(file, "abc").unwrap();
+    file.rotate().unwrap();
+    writeln!(file, "def").unwrap();
+    file.flush().unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "def\n");
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn command_line_is_parsed() {
+    let command_line = config::CommandLine::parse(args(&[
+        "--config",
+        "other.toml",
+        "--pool-size",
+        "8",
+        "--log-level=debug",
+        "--print-config",
+    ]))
+    .unwrap();
+    assert_eq!(command_line.config_file, Some("other.toml".into()));
+    assert_eq!(
+        command_line.settings,
+        [("pool_size".to_string(), "8".to_string()), ("log_level".to_string(), "debug".to_string())]
+    );
+    assert!(command_line.print_config);
+    assert!(!command_line.help);
+
+    assert!(config::CommandLine::parse(args(&["-h"])).unwrap().help);
+    for (bad, message) in [
+        (&["--pool-sizes", "8"][..], "unknown argument \"--pool-sizes\""),
+        (&["8"][..], "unknown argument \"8\""),
+        (&["--addr"][..], "missing value for \"--addr\""),
+    ] {
+        let err = config::CommandLine::parse(args(bad)).unwrap_err();
+        assert_eq!(err.to_string(), message);
+    }
+}
+
+#[test]
+fn config_file_is_overridden_by_env_vars_then_flags() {
+    let dir = scratch_dir("config_precedence");
+    let path = dir.join("server.toml");
+    fs::write(
+        &path,
+        "addr = \"0.0.0.0:8080\"\npool_size = 2\nmax_pool_size = \"none\"\nlog_level = \"info\"\n\
+         access_log_format = \"json\"\nlog_compress = false\nmax_body_size = 10485760\n\
+         template_reload = true\n",
+    )
+    .unwrap();
+    let mut command_line = config::CommandLine::parse(args(&["--pool-size", "6"])).unwrap();
+    let env = |name: &str| match name {
+        "RUST_WEB_SERVER_CONFIG" => Some(path.to_str().unwrap().to_string()),
+        "RUST_WEB_SERVER_POOL_SIZE" => Some("3".to_string()),
+        "RUST_WEB_SERVER_LOG_FILE" => Some("none".to_string()),
+        "RUST_WEB_SERVER_LOG_LEVEL" => Some("WARN".to_string()),
+        _ => None,
+    };
+
+    let config = config::ServerConfig::load(&command_line, env).unwrap();
+    assert_eq!(config.addr, "0.0.0.0:8080");
+    assert_eq!(config.pool_size, 6);
+    assert_eq!(config.max_pool_size, None);
+    assert_eq!(config.log_file, None);
+    assert_eq!(config.log_level, log::LevelFilter::Warn);
+    assert_eq!(config.access_log_format, access_log::AccessLogFormat::Json);
+    assert!(!config.log_rotation.compress);
+    assert_eq!(config.max_body_size, 10 * 1024 * 1024);
+    assert!(config.template_reload);
+    // Untouched settings keep their defaults.
+    let defaults = config::ServerConfig::default();
+    assert_eq!(config.queue_capacity, defaults.queue_capacity);
+    assert_eq!(config.static_root, defaults.static_root);
+    assert_eq!(config.templates_dir, std::path::PathBuf::from("templates"));
+    assert_eq!(config.page_cache_control.as_deref(), Some("no-cache"));
+
+    // A file given on the command line wins over the environment's.
+    let missing = dir.join("missing.toml");
+    command_line.config_file = Some(missing.clone());
+    let err = config::ServerConfig::load(&command_line, env).unwrap_err();
+    assert!(matches!(err, config::ConfigError::FileReadError(path, _) if path == missing));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn printed_config_loads_back_unchanged() {
+    let dir = scratch_dir("config_round_trip");
+    let mut config = config::ServerConfig {
+        addr: "[::1]:9000".to_string(),
+        io_mode: config::IoMode::EventLoop,
+        queue_capacity: None,
+        access_log: Some(dir.join("with \"quotes\".log")),
+        page_cache_control: None,
+        static_cache_control: Some("public, max-age=86400, immutable".to_string()),
+        templates_dir: dir.join("pages"),
+        ..config::ServerConfig::default()
+    };
+    config.log_rotation.max_age = None;
+    let path = dir.join("printed.toml");
+    fs::write(&path, config.to_toml()).unwrap();
+
+    let command_line = config::CommandLine {
+        config_file: Some(path),
+        ..config::CommandLine::default()
+    };
+    assert_eq!(config::ServerConfig::load(&command_line, |_| None).unwrap(), config);
+    // Every setting is printed, under its own name.
+    let toml = config.to_toml();
+    let keys: Vec<&str> = toml.lines().filter_map(|line| line.split(" = ").next()).collect();
+    assert_eq!(keys, config::SETTINGS);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn invalid_config_is_reported_readably() {
+    let dir = scratch_dir("config_invalid");
+    let load = |toml: &str, flags: &[&str]| {
+        let path = dir.join("server.toml");
+        fs::write(&path, toml).unwrap();
+        let mut command_line = config::CommandLine::parse(args(flags)).unwrap();
+        command_line.config_file = Some(path);
+        config::ServerConfig::load(&command_line, |_| None)
+            .unwrap_err()
+            .to_string()
+    };
+
+    assert_eq!(
+        load("", &["--pool-size", "0"]),
+        "invalid thread pool settings: a thread pool needs at least one thread, but its size is 0"
+    );
+    assert_eq!(
+        load("pool_size = 8\nmax_pool_size = 4\n", &[]),
+        "invalid thread pool settings: a thread pool's maximum size can't be less than its size"
+    );
+    assert_eq!(
+        load("", &["--queue-capacity", "0"]),
+        "invalid value \"0\" for \"queue_capacity\" in command-line flag --queue-capacity; \
+         expected a positive integer, or \"none\""
+    );
+    assert_eq!(
+        load("", &["--log-max-size", "0"]),
+        "invalid value \"0\" for \"log_max_size\" in command-line flag --log-max-size; \
+         expected a positive integer, or \"none\""
+    );
+    assert_eq!(
+        load("", &["--addr", "localhost"]),
+        "invalid value \"localhost\" for \"addr\" in command-line flag --addr; \
+         expected a host and port, e.g. 127.0.0.1:7878"
+    );
+    assert_eq!(
+        load("io_mode = \"epoll\"\n", &[]),
+        format!(
+            "invalid value \"epoll\" for \"io_mode\" in configuration file {:?}; \
+             expected blocking or event-loop",
+            dir.join("server.toml")
+        )
+    );
+    assert_eq!(
+        load("tls_addr = \"127.0.0.1:7879\"\ntls_cert_file = \"cert.pem\"\n", &[]),
+        "\"tls_key_file\" must be set, as \"tls_addr\" is"
+    );
+    assert!(load("pool_sise = 4\n", &[]).starts_with("unknown setting \"pool_sise\" in configuration file"));
+    assert!(load("pool_size = [4]\n", &[]).contains("expected a string, integer or boolean"));
+    assert!(load("pool_size = \n", &[]).contains("is not valid TOML"));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn thread_pool_config_is_validated_before_building() {
+    let mut config = server::ThreadPoolConfig::new(0);
+    assert!(matches!(config.validate(), Err(server::ThreadPoolBuildError::ZeroThreadThreadPoolError)));
+    config.size = 4;
+    config.max_size = Some(2);
+    assert!(matches!(
+        config.validate(),
+        Err(server::ThreadPoolBuildError::InvalidMaxSizeThreadPoolError)
+    ));
+    config.max_size = Some(4);
+    assert!(config.validate().is_ok());
+
+    // No job could ever wait in a queue without room, whatever the scheduler.
+    for scheduler in [server::Scheduler::Channel, server::Scheduler::WorkStealing] {
+        let config = server::ThreadPoolConfig { queue_capacity: Some(0), scheduler, ..config };
+        assert!(matches!(
+            config.validate(),
+            Err(server::ThreadPoolBuildError::ZeroQueueCapacityThreadPoolError)
+        ));
+        assert!(server::ThreadPool::build_with(config).is_err());
+    }
+}
+
+/// Generate a self-signed certificate for `localhost`, and write it and its private key
+/// as PEM files in a scratch directory.
+///
+/// Returns the certificate's and key's files, and the certificate for clients to trust.
+fn self_signed_certificate(
+    name: &str,
+) -> (std::path::PathBuf, std::path::PathBuf, rustls::pki_types::CertificateDer<'static>) {
+    let dir = scratch_dir(name);
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::write(&cert_file, cert.pem()).unwrap();
+    fs::write(&key_file, key_pair.serialize_pem()).unwrap();
+    (cert_file, key_file, cert.der().clone())
+}
+
+/// Connect to the HTTPS server at `addr`, trusting only `cert`.
+///
+/// The handshake is done along with the first request.
+fn tls_client(
+    addr: net::SocketAddr,
+    cert: rustls::pki_types::CertificateDer<'static>,
+) -> rustls::StreamOwned<rustls::ClientConnection, net::TcpStream> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let mut config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let server_name = "localhost".try_into().unwrap();
+    let connection = rustls::ClientConnection::new(std::sync::Arc::new(config), server_name).unwrap();
+    rustls::StreamOwned::new(connection, net::TcpStream::connect(addr).unwrap())
+}
+
+/// Like `spawn_server`, but serving HTTPS with `acceptor`.
+fn spawn_tls_server(service: service::Service, acceptor: tls::TlsAcceptor) -> net::SocketAddr {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = std::sync::Arc::new(service);
+    thread::spawn(move || {
+        let pool = server::ThreadPool::build(2).unwrap();
+        util::accept_tls_connections(&listener, &pool, &service, &acceptor).unwrap();
+    });
+
+    addr
+}
+
+#[test]
+fn https_serves_the_same_routes() {
+    let (cert_file, key_file, cert) = self_signed_certificate("tls_routes");
+    let acceptor = tls::TlsAcceptor::from_pem_files(&cert_file, &key_file).unwrap();
+    let addr = spawn_tls_server(service::Service::new(counting_router()), acceptor);
+
+    let mut client = io::BufReader::new(tls_client(addr, cert));
+    client.get_mut().write_all(b"GET /count/3 HTTP/1.1\r\n\r\n").unwrap();
+    let response = read_response(&mut client).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"0\n1\n2\n");
+    assert_eq!(response.headers.get("Connection"), Some("keep-alive"));
+    assert_eq!(client.get_ref().conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+
+    // Further requests go over the same TLS session.
+    client
+        .get_mut()
+        .write_all(b"GET /count/1 HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let response = read_response(&mut client).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"0\n");
+    assert_eq!(response.headers.get("Connection"), Some("close"));
+    // The server ends the session with a close_notify alert, rather than just closing
+    // the connection, which the client would report as an error.
+    let mut rest = Vec::new();
+    assert_eq!(client.read_to_end(&mut rest).unwrap(), 0);
+    let _ = fs::remove_dir_all(cert_file.parent().unwrap());
+}
+
+#[test]
+fn failed_tls_handshakes_end_the_connection() {
+    let (cert_file, key_file, _) = self_signed_certificate("tls_handshakes");
+    let acceptor = tls::TlsAcceptor::from_pem_files(&cert_file, &key_file).unwrap();
+    let service = std::sync::Arc::new(impatient_service());
+    let serve_tls = || {
+        let (acceptor, service) = (acceptor.clone(), std::sync::Arc::clone(&service));
+        serve_one_connection(move |stream| util::handle_tls_connection(stream, &acceptor, &service))
+    };
+
+    // A client speaking plain HTTP to the HTTPS port.
+    let (addr, handle) = serve_tls();
+    let mut stream = net::TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let err = handle.join().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert!(!response.starts_with(b"HTTP/"));
+
+    // A client that never starts the handshake.
+    let (addr, handle) = serve_tls();
+    let _stream = net::TcpStream::connect(addr).unwrap();
+    let start = time::Instant::now();
+    let err = handle.join().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+    let _ = fs::remove_dir_all(cert_file.parent().unwrap());
+}
+
+#[test]
+fn tls_certificates_and_keys_are_checked_when_loaded() {
+    let (cert_file, key_file, _) = self_signed_certificate("tls_files");
+    let dir = cert_file.parent().unwrap();
+    let load = |cert: &std::path::Path, key: &std::path::Path| {
+        tls::TlsAcceptor::from_pem_files(cert, key).unwrap_err()
+    };
+
+    let missing = dir.join("missing.pem");
+    assert!(matches!(
+        load(&missing, &key_file),
+        tls::TlsError::CertificateFileError(path, _) if path == missing
+    ));
+    assert!(matches!(load(&key_file, &key_file), tls::TlsError::NoCertificateError(_)));
+    assert!(matches!(load(&cert_file, &cert_file), tls::TlsError::PrivateKeyFileError(..)));
+
+    // A key that isn't the certificate's.
+    let other_key = dir.join("other_key.pem");
+    fs::write(&other_key, rcgen::KeyPair::generate().unwrap().serialize_pem()).unwrap();
+    let err = load(&cert_file, &other_key);
+    assert!(matches!(err, tls::TlsError::ConfigError(_)), "{err}");
+    let _ = fs::remove_dir_all(dir);
+}
+
+/// Like `spawn_server`, but waiting on connections with the event loop.
+fn spawn_event_loop_server(service: service::Service, pool_size: usize) -> net::SocketAddr {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = std::sync::Arc::new(service);
+    thread::spawn(move || {
+        let pool = server::ThreadPool::build(pool_size).unwrap();
+        event_loop::accept_connections(&listener, &pool, &service).unwrap();
+    });
+
+    addr
+}
+
+#[test]
+fn metrics_are_answered_while_sleep_requests_saturate_the_pool() {
+    util::init_logging_infrastructure(None, log::LevelFilter::Info).unwrap_or(());
+    let request = |target: &str| http::Request::new(http::Method::Get, target);
+    let options = util::default_job_options(&request("/metrics"));
+    assert_eq!(options.priority, priority::Priority::High);
+    assert_eq!(util::default_job_options(&request("/admin/shutdown")).priority, priority::Priority::High);
+    assert_eq!(util::default_job_options(&request("/sleep")).queue.as_deref(), Some(util::SLOW_QUEUE));
+    assert_eq!(util::default_job_options(&request("/")), priority::JobOptions::default());
+
+    for io_mode in [config::IoMode::Blocking, config::IoMode::EventLoop] {
+        // As in `main`, but with a shorter handler timeout, so `/sleep` lasts 2 seconds.
+        let pool = server::ThreadPool::build(2).unwrap();
+        pool.add_queue(util::SLOW_QUEUE, 1);
+        let mut router = util::default_router();
+        util::add_metrics_route(&mut router, pool.stats_handle(), metrics::ServerMetrics::new());
+        let mut service = service::Service::new(router);
+        service.job_policy = Box::new(util::default_job_options);
+        service.timeouts.handler = time::Duration::from_secs(2);
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = std::sync::Arc::new(service);
+        thread::spawn(move || {
+            match io_mode {
+                config::IoMode::Blocking => util::accept_connections(&listener, &pool, &service),
+                config::IoMode::EventLoop => event_loop::accept_connections(&listener, &pool, &service),
+            }
+            .unwrap()
+        });
+
+        // More slow requests than workers, which without their queue's quota would leave
+        // `/metrics` waiting behind them; in blocking mode, the connections are handed
+        // off to jobs in that queue once their request is read.
+        let sleepers: Vec<_> = (0..4)
+            .map(|_| {
+                let mut stream = net::TcpStream::connect(addr).unwrap();
+                stream.write_all(b"GET /sleep HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+                stream
+            })
+            .collect();
+        thread::sleep(time::Duration::from_millis(300));
+
+        let start = time::Instant::now();
+        let response = raw_exchange(addr, b"GET /metrics HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{io_mode:?}: {response}");
+        assert!(start.elapsed() < time::Duration::from_secs(1), "{io_mode:?}: {:?}", start.elapsed());
+        drop(sleepers);
+    }
+}
+
+#[test]
+fn event_loop_survives_many_concurrent_slow_clients() {
+    const SLOW_CLIENTS: usize = 500;
+    let addr = spawn_event_loop_server(service::Service::new(util::default_router()), 2);
+
+    // Far more clients than workers, each stalling halfway through its request, which
+    // with blocking workers would leave none for anyone else.
+    let mut slow_clients: Vec<_> = (0..SLOW_CLIENTS)
+        .map(|_| {
+            let mut stream = net::TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n").unwrap();
+            stream
+        })
+        .collect();
+
+    // Another client is still served promptly.
+    let start = time::Instant::now();
+    let response = raw_exchange(addr, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+
+    // And so is every slow client, once it is done sending its request.
+    for stream in &mut slow_clients {
+        stream.write_all(b"Connection: close\r\n\r\n").unwrap();
+    }
+    for stream in slow_clients {
+        let mut reader = io::BufReader::new(stream);
+        let response = read_response(&mut reader).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("Connection"), Some("close"));
+    }
+}
+
+#[test]
+fn event_loop_keeps_connections_alive_and_enforces_timeouts() {
+    let mut service = impatient_service();
+    service.keep_alive = service::KeepAlive {
+        idle_timeout: time::Duration::from_millis(300),
+        max_requests: 3,
+    };
+    let addr = spawn_event_loop_server(service, 1);
+
+    // Pipelined requests, and one sent a byte at a time, over one connection, which is
+    // closed once the cap is reached.
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(b"GET / HTTP/1.1\r\n\r\nGET /nope HTTP/1.1\r\n\r\n").unwrap();
+    let response = read_response(&mut reader).unwrap();
+    assert_eq!((response.status, response.headers.get("Connection")), (200, Some("keep-alive")));
+    assert_eq!(response.headers.get("Keep-Alive"), Some("timeout=0, max=2"));
+    assert_eq!(read_response(&mut reader).unwrap().status, 404);
+    for byte in b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi" {
+        writer.write_all(&[*byte]).unwrap();
+        thread::sleep(time::Duration::from_millis(2));
+    }
+    let response = read_response(&mut reader).unwrap();
+    assert_eq!((response.status, response.headers.get("Connection")), (405, Some("close")));
+    assert!(read_response(&mut reader).is_none());
+
+    // A client stalling halfway through its request gets a 408.
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+    let start = time::Instant::now();
+    assert_eq!(read_response(&mut reader).unwrap().status, 408);
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+
+    // An idle connection is just closed.
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream);
+    let start = time::Instant::now();
+    assert!(read_response(&mut reader).is_none());
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+
+    // Unparseable requests are answered as with blocking workers.
+    let response = raw_exchange(addr, b"BREW / HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{response}");
+}
+
+#[test]
+fn accept_encoding_is_negotiated_by_weight_then_server_preference() {
+    use compression::Encoding::{Brotli, Deflate, Gzip};
+    let compression = compression::Compression::default();
+    let negotiate = |accept_encoding| compression.negotiate(accept_encoding);
+
+    assert_eq!(negotiate(None), None);
+    assert_eq!(negotiate(Some("")), None);
+    assert_eq!(negotiate(Some("identity")), None);
+    assert_eq!(negotiate(Some("gzip, deflate, br")), Some(Brotli));
+    assert_eq!(negotiate(Some("deflate, GZIP")), Some(Gzip));
+    assert_eq!(negotiate(Some("x-gzip")), Some(Gzip));
+    assert_eq!(negotiate(Some("br;q=0.5, gzip;q=0.8")), Some(Gzip));
+    assert_eq!(negotiate(Some("br;q=0, *")), Some(Gzip));
+    assert_eq!(negotiate(Some("*;q=0.1, deflate ; q=0.2")), Some(Deflate));
+    assert_eq!(negotiate(Some("*;q=0")), None);
+    assert_eq!(negotiate(Some("gzip;q=high")), None);
+
+    assert!(compression.is_compressible("text/html; charset=utf-8"));
+    assert!(compression.is_compressible("Application/JSON"));
+    assert!(!compression.is_compressible("image/png"));
+    assert!(!compression.is_compressible("textual/plain"));
+}
+
+/// Decompress `body` as its `Content-Encoding`, `encoding`, says, as a client would.
+fn decode(encoding: Option<&str>, body: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    match encoding {
+        None => decoded.extend_from_slice(body),
+        Some("gzip") => {
+            flate2::read::GzDecoder::new(body).read_to_end(&mut decoded).unwrap();
+        }
+        Some("deflate") => {
+            flate2::read::ZlibDecoder::new(body).read_to_end(&mut decoded).unwrap();
+        }
+        Some("br") => {
+            brotli::Decompressor::new(body, 4096).read_to_end(&mut decoded).unwrap();
+        }
+        Some(other) => panic!("unexpected Content-Encoding {other}"),
+    }
+    decoded
+}
+
+#[test]
+fn responses_are_compressed_as_negotiated() {
+    let text = "All work and no play makes Jack a dull boy.\n".repeat(100);
+    let mut router = router::Router::new();
+    let page = text.clone();
+    router.route(http::Method::Get, "/text", move |_| {
+        Ok(http::Response::new(http::StatusCode::OK)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(page.clone()))
+    });
+    router.route(http::Method::Get, "/small", |_| {
+        Ok(http::Response::new(http::StatusCode::OK)
+            .with_header("Content-Type", "text/plain")
+            .with_body("tiny"))
+    });
+    router.route(http::Method::Get, "/image", |_| {
+        Ok(http::Response::new(http::StatusCode::OK)
+            .with_header("Content-Type", "image/png")
+            .with_body(vec![0; 4096]))
+    });
+    let page = text.clone();
+    router.route(http::Method::Get, "/stream", move |_| {
+        let page = page.clone();
+        Ok(http::Response::new(http::StatusCode::OK)
+            .with_header("Content-Type", "application/json")
+            .with_chunked(move |writer| writer.write_all(page.as_bytes())))
+    });
+    let mut service = service::Service::new(router);
+    service.compression = Some(compression::Compression::default());
+    let addr = spawn_server(service, 2);
+
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut fetch = |target: &str, accept_encoding: Option<&str>| {
+        let accept_encoding = accept_encoding
+            .map(|value| format!("Accept-Encoding: {value}\r\n"))
+            .unwrap_or_default();
+        write!(writer, "GET {target} HTTP/1.1\r\n{accept_encoding}\r\n").unwrap();
+        read_response(&mut reader).unwrap()
+    };
+
+    for (accept_encoding, expected) in [
+        (Some("gzip, deflate, br"), Some("br")),
+        (Some("gzip"), Some("gzip")),
+        (Some("deflate"), Some("deflate")),
+        (Some("identity"), None),
+        (None, None),
+    ] {
+        let response = fetch("/text", accept_encoding);
+        let encoding = response.headers.get("Content-Encoding");
+        assert_eq!(encoding, expected);
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        if encoding.is_some() {
+            assert!(response.body.len() < text.len());
+        }
+        assert_eq!(decode(encoding, &response.body), text.as_bytes());
+    }
+
+    // Bodies of unknown length are compressed as they are streamed.
+    let response = fetch("/stream", Some("gzip"));
+    assert_eq!(response.headers.get("Transfer-Encoding"), Some("chunked"));
+    assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+    assert_eq!(decode(Some("gzip"), &response.body), text.as_bytes());
+
+    // Neither small bodies nor images are compressed, nor do they vary.
+    for target in ["/small", "/image"] {
+        let response = fetch(target, Some("gzip"));
+        assert_eq!(response.headers.get("Content-Encoding"), None);
+        assert_eq!(response.headers.get("Vary"), None);
+    }
+    // Nor are error responses.
+    let response = fetch("/missing", Some("gzip"));
+    assert_eq!((response.status, response.headers.get("Content-Encoding")), (404, None));
+}
+
+#[test]
+fn static_files_cache_their_compressed_variants() {
+    let (root, _) = static_site("static_compression");
+    let script = "console.log('hello, world');\n".repeat(200);
+    fs::write(root.join("app.js"), &script).unwrap();
+    let mut router = router::Router::new();
+    let files = static_files::StaticFiles::build(&root)
+        .unwrap()
+        .with_compression(compression::Compression::default());
+    router.route(http::Method::Get, "/static/*path", files.into_handler());
+    let fetch = |target: &str, accept_encoding: &str| {
+        let mut request = http::Request::new(http::Method::Get, target);
+        request.headers.insert("Accept-Encoding", accept_encoding);
+        let response = router.handle(request).unwrap();
+        let encoding = response.headers.get("Content-Encoding").map(str::to_string);
+        let vary = response.headers.get("Vary").map(str::to_string);
+        let body = response.body.into_bytes().unwrap();
+        (encoding, vary, body)
+    };
+
+    let (encoding, vary, body) = fetch("/static/app.js", "br");
+    assert_eq!((encoding.as_deref(), vary.as_deref()), (Some("br"), Some("Accept-Encoding")));
+    assert_eq!(decode(Some("br"), &body), script.as_bytes());
+    assert_eq!(fetch("/static/app.js", "br").2, body);
+    let (encoding, _, body) = fetch("/static/app.js", "gzip");
+    assert_eq!(decode(encoding.as_deref(), &body), script.as_bytes());
+    let (encoding, vary, body) = fetch("/static/app.js", "identity");
+    assert_eq!((encoding, vary.as_deref()), (None, Some("Accept-Encoding")));
+    assert_eq!(body, script.as_bytes());
+
+    // A modified file isn't served from the cache.
+    let script = "console.log('goodbye');\n".repeat(300);
+    fs::write(root.join("app.js"), &script).unwrap();
+    let (encoding, _, body) = fetch("/static/app.js", "br");
+    assert_eq!(decode(encoding.as_deref(), &body), script.as_bytes());
+
+    // Too small, and not compressible, respectively.
+    for target in ["/static/style.css", "/static/logo.png"] {
+        let (encoding, vary, _) = fetch(target, "br");
+        assert_eq!((encoding, vary), (None, None));
+    }
+    let _ = fs::remove_dir_all(root.parent().unwrap());
+}
+
+#[test]
+fn http_dates_are_formatted_and_parsed() {
+    let date = date::DateTime::from_unix_seconds(784_111_777);
+    assert_eq!(date.to_http_date(), "Sun, 06 Nov 1994 08:49:37 GMT");
+
+    // The obsolete RFC 850 and asctime formats are accepted as well.
+    for text in [
+        "Sun, 06 Nov 1994 08:49:37 GMT",
+        "Sunday, 06-Nov-94 08:49:37 GMT",
+        "Sun Nov  6 08:49:37 1994",
+    ] {
+        assert_eq!(date::DateTime::parse_http_date(text), Some(date), "{text}");
+    }
+    for text in [
+        "",
+        "yesterday",
+        "Sun, 06 Nov 1994 08:49:37 UTC",
+        "Sun, 31 Nov 1994 08:49:37 GMT",
+        "Sun, 06 Nov 1994 24:00:00 GMT",
+    ] {
+        assert_eq!(date::DateTime::parse_http_date(text), None, "{text}");
+    }
+}
+
+#[test]
+fn file_cache_rereads_modified_files() {
+    let dir = scratch_dir("file_cache");
+    let path = dir.join("page.html");
+    fs::write(&path, "first").unwrap();
+    let files = cache::FileCache::new(16, 1024);
+
+    let first = files.read(&path).unwrap();
+    assert_eq!(&*first.contents, b"first");
+    let again = files.read(&path).unwrap();
+    assert!(std::sync::Arc::ptr_eq(&first.contents, &again.contents));
+    assert_eq!(first.validators, again.validators);
+
+    // Same size, but a later modification time.
+    fs::write(&path, "again").unwrap();
+    let file = fs::File::options().write(true).open(&path).unwrap();
+    file.set_modified(time::SystemTime::now() + time::Duration::from_secs(10)).unwrap();
+    let modified = files.read(&path).unwrap();
+    assert_eq!(&*modified.contents, b"again");
+    assert_ne!(modified.validators.etag, first.validators.etag);
+
+    // Files larger than the limit are read every time.
+    fs::write(&path, "too large to be cached").unwrap();
+    let large = files.read(&path).unwrap();
+    assert_eq!(&*large.contents, b"too large to be cached");
+    assert!(!std::sync::Arc::ptr_eq(&large.contents, &files.read(&path).unwrap().contents));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn conditional_requests_are_answered_with_304() {
+    let (root, _) = static_site("conditional");
+    let css = "body { color: red; }\n".repeat(100);
+    fs::write(root.join("big.css"), &css).unwrap();
+    let files = static_files::StaticFiles::build(&root)
+        .unwrap()
+        .with_compression(compression::Compression::default())
+        .with_file_cache(cache::FileCache::default());
+    let mut router = router::Router::new();
+    router.route(
+        http::Method::Get,
+        "/static/*path",
+        cache::with_cache_control("public, max-age=300", files.into_handler()),
+    );
+    let addr = spawn_server(service::Service::new(router), 2);
+
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut fetch = |method: &str, target: &str, headers: &str| {
+        write!(writer, "{method} {target} HTTP/1.1\r\n{headers}\r\n").unwrap();
+        read_response(&mut reader).unwrap()
+    };
+
+    let response = fetch("GET", "/static/style.css", "");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.headers.get("Cache-Control"), Some("public, max-age=300"));
+    let etag = response.headers.get("ETag").unwrap().to_string();
+    let last_modified = response.headers.get("Last-Modified").unwrap().to_string();
+
+    for headers in [
+        format!("If-None-Match: {etag}\r\n"),
+        format!("If-None-Match: \"other\", W/{etag}\r\n"),
+        "If-None-Match: *\r\n".to_string(),
+        format!("If-Modified-Since: {last_modified}\r\n"),
+    ] {
+        // Served over the same connection, so a 304 must not leave a body behind.
+        let response = fetch("GET", "/static/style.css", &headers);
+        assert_eq!(response.status, 304, "{headers}");
+        assert_eq!(response.headers.get("ETag"), Some(etag.as_str()));
+        assert_eq!(response.headers.get("Cache-Control"), Some("public, max-age=300"));
+        assert_eq!(response.headers.get("Content-Length"), None);
+        assert_eq!(response.headers.get("Content-Type"), None);
+    }
+    let response = fetch("HEAD", "/static/style.css", &format!("If-None-Match: {etag}\r\n"));
+    assert_eq!(response.status, 304);
+
+    // If-None-Match wins over If-Modified-Since.
+    let headers = format!("If-None-Match: \"other\"\r\nIf-Modified-Since: {last_modified}\r\n");
+    let response = fetch("GET", "/static/style.css", &headers);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"body { color: red; }");
+    let an_hour_earlier = date::DateTime::parse_http_date(&last_modified).unwrap().unix_seconds() - 3600;
+    let an_hour_earlier = date::DateTime::from_unix_seconds(an_hour_earlier).to_http_date();
+    let response = fetch("GET", "/static/style.css", &format!("If-Modified-Since: {an_hour_earlier}\r\n"));
+    assert_eq!(response.status, 200);
+
+    // Compressed variants have entity tags of their own.
+    let response = fetch("GET", "/static/big.css", "Accept-Encoding: gzip\r\n");
+    assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+    let gzip_etag = response.headers.get("ETag").unwrap().to_string();
+    assert!(gzip_etag.ends_with("-gzip\""));
+    let response = fetch("GET", "/static/big.css", &format!("If-None-Match: {gzip_etag}\r\n"));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, css.as_bytes());
+    let headers = format!("Accept-Encoding: gzip\r\nIf-None-Match: {gzip_etag}\r\n");
+    let response = fetch("GET", "/static/big.css", &headers);
+    assert_eq!(response.status, 304);
+    assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+
+    // Missing files are neither cached nor validated.
+    let response = fetch("GET", "/static/missing.css", "If-None-Match: *\r\n");
+    assert_eq!(response.status, 404);
+    assert_eq!(response.headers.get("Cache-Control"), None);
+}
+
+#[test]
+fn range_headers_are_parsed() {
+    use range::{ByteRange, RangeRequest};
+    let partial = |ranges: &[(u64, u64)]| {
+        RangeRequest::Partial(ranges.iter().map(|&(start, end)| ByteRange { start, end }).collect())
+    };
+
+    for (range, expected) in [
+        ("bytes=0-499", partial(&[(0, 499)])),
+        ("bytes=500-", partial(&[(500, 9999)])),
+        ("bytes=-500", partial(&[(9500, 9999)])),
+        ("bytes=-20000", partial(&[(0, 9999)])),
+        ("bytes=9000-20000", partial(&[(9000, 9999)])),
+        ("Bytes = 0-0 , -1", partial(&[(0, 0), (9999, 9999)])),
+        // Sorted, and merged when overlapping or adjacent.
+        ("bytes=500-999,0-99,100-199,150-300", partial(&[(0, 300), (500, 999)])),
+        ("bytes=20000-,0-9", partial(&[(0, 9)])),
+        ("bytes=10000-", RangeRequest::Unsatisfiable),
+        ("bytes=-0", RangeRequest::Unsatisfiable),
+        // Malformed, or in another unit: ignored.
+        ("bytes=500-100", RangeRequest::Full),
+        ("bytes=abc-", RangeRequest::Full),
+        ("bytes=0-1-2", RangeRequest::Full),
+        ("bytes=", RangeRequest::Full),
+        ("items=0-5", RangeRequest::Full),
+        ("0-499", RangeRequest::Full),
+    ] {
+        assert_eq!(RangeRequest::parse(range, 10_000), expected, "{range}");
+    }
+    assert_eq!(RangeRequest::parse("bytes=-5", 0), RangeRequest::Unsatisfiable);
+
+    let many: Vec<String> = (0..=range::MAX_RANGES).map(|n| format!("{}-{}", n * 10, n * 10)).collect();
+    assert_eq!(RangeRequest::parse(&format!("bytes={}", many.join(",")), 10_000), RangeRequest::Full);
+}
+
+/// Split a `multipart/byteranges` body delimited by `boundary` into the header fields
+/// and contents of its parts.
+fn byteranges(body: &[u8], boundary: &str) -> Vec<(String, Vec<u8>)> {
+    let delimiter = format!("\r\n--{boundary}");
+    let mut parts = Vec::new();
+    let mut rest = body;
+    loop {
+        assert!(rest.starts_with(delimiter.as_bytes()));
+        rest = &rest[delimiter.len()..];
+        if rest == b"--\r\n" {
+            return parts;
+        }
+        let head_len = rest.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(rest[2..head_len].to_vec()).unwrap();
+        rest = &rest[head_len + 4..];
+        let len = rest.windows(delimiter.len()).position(|window| window == delimiter.as_bytes()).unwrap();
+        parts.push((head, rest[..len].to_vec()));
+        rest = &rest[len..];
+    }
+}
+
+#[test]
+fn static_files_serve_ranges_of_large_files() {
+    let (root, _) = static_site("ranges");
+    // Far larger than the server's original 1024-byte buffer.
+    let video: Vec<u8> = (0..100_000u32).map(|n| (n * 31 % 251) as u8).collect();
+    fs::write(root.join("video.mp4"), &video).unwrap();
+    let files = static_files::StaticFiles::build(&root).unwrap();
+    let mut router = router::Router::new();
+    router.route(http::Method::Get, "/static/*path", files.clone().into_handler());
+    let cached = files.with_file_cache(cache::FileCache::new(200_000, 1024 * 1024));
+    router.route(http::Method::Get, "/cached/*path", cached.into_handler());
+    let addr = spawn_server(service::Service::new(router), 2);
+
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut fetch = |target: &str, headers: &str| {
+        write!(writer, "GET {target} HTTP/1.1\r\n{headers}\r\n").unwrap();
+        read_response(&mut reader).unwrap()
+    };
+
+    // Served from disk, then from memory.
+    for target in ["/static/video.mp4", "/cached/video.mp4"] {
+        let response = fetch(target, "");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("Accept-Ranges"), Some("bytes"));
+        assert_eq!(response.body, video);
+        let etag = response.headers.get("ETag").unwrap().to_string();
+
+        // An interrupted download, resumed.
+        let response = fetch(target, "Range: bytes=0-39999\r\n");
+        assert_eq!(response.status, 206);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 0-39999/100000"));
+        assert_eq!(response.headers.get("Content-Type"), Some("video/mp4"));
+        let mut resumed = response.body;
+        let response = fetch(target, &format!("Range: bytes=40000-\r\nIf-Range: {etag}\r\n"));
+        assert_eq!(response.status, 206);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 40000-99999/100000"));
+        resumed.extend(response.body);
+        assert_eq!(resumed, video);
+
+        let response = fetch(target, "Range: bytes=-1500\r\n");
+        assert_eq!(response.status, 206);
+        assert_eq!(response.body, &video[98_500..]);
+
+        // A stale partial copy gets the whole file.
+        let response = fetch(target, "Range: bytes=40000-\r\nIf-Range: \"stale\"\r\n");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body.len(), video.len());
+
+        let response = fetch(target, "Range: bytes=99000-99999,1000-2999,2500-4999\r\n");
+        assert_eq!(response.status, 206);
+        let content_type = response.headers.get("Content-Type").unwrap();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let parts = byteranges(&response.body, boundary);
+        assert_eq!(parts.len(), 2);
+        for ((head, contents), (start, end)) in parts.iter().zip([(1000, 4999), (99_000, 99_999)]) {
+            assert_eq!(
+                head,
+                &format!("Content-Type: video/mp4\r\nContent-Range: bytes {start}-{end}/100000")
+            );
+            assert_eq!(contents.as_slice(), &video[start..=end]);
+        }
+
+        let response = fetch(target, "Range: bytes=100000-\r\n");
+        assert_eq!(response.status, 416);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes */100000"));
+    }
+}
+
+/// A request with `body` of type `content_type`, as handlers get it.
+fn request_with_body(content_type: &str, body: impl Into<Vec<u8>>) -> http::Request {
+    let mut request = http::Request::new(http::Method::Post, "/");
+    request.headers.insert("Content-Type", content_type);
+    request.body = body.into();
+    request
+}
+
+#[test]
+fn form_bodies_are_decoded() {
+    let form = body::Form::parse("name=Ferris&likes=rust+%26+crabs&likes=%F0%9F%A6%80&flag&=").unwrap();
+    assert_eq!(form.get("name"), Some("Ferris"));
+    assert_eq!(form.get_all("likes").collect::<Vec<_>>(), ["rust & crabs", "🦀"]);
+    assert_eq!(form.get("flag"), Some(""));
+    assert_eq!(form.len(), 5);
+    assert!(body::Form::parse("a=%2").is_err());
+    assert!(body::Form::parse("a=%FF").is_err());
+
+    let request = request_with_body("application/x-www-form-urlencoded; charset=utf-8", "a=1+2&b=%3D");
+    let form = request.form().unwrap();
+    assert_eq!(form.iter().collect::<Vec<_>>(), [("a", "1 2"), ("b", "=")]);
+
+    let err = request_with_body("text/plain", "a=1").form().unwrap_err();
+    assert_eq!(err.status(), http::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let err = http::Request::new(http::Method::Post, "/").form().unwrap_err();
+    assert!(matches!(err, body::BodyError::UnsupportedMediaTypeError(None)));
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize)]
+struct NewUser {
+    name: String,
+    age: u32,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[test]
+fn json_bodies_are_deserialized() {
+    let request = request_with_body("application/json", r#"{"name": "Ferris", "age": 9, "tags": ["crab"]}"#);
+    assert_eq!(
+        request.json::<NewUser>().unwrap(),
+        NewUser { name: "Ferris".to_string(), age: 9, tags: vec!["crab".to_string()] }
+    );
+    let value: serde_json::Value = request_with_body("application/merge-patch+json", r#"{"age": 10}"#)
+        .json()
+        .unwrap();
+    assert_eq!(value["age"], 10);
+
+    for (content_type, json, status) in [
+        ("application/json", r#"{"name": "Ferris""#, http::StatusCode::BAD_REQUEST),
+        ("application/json", r#"{"name": "Ferris", "age": -1}"#, http::StatusCode::BAD_REQUEST),
+        ("text/plain", r#"{"name": "Ferris", "age": 9}"#, http::StatusCode::UNSUPPORTED_MEDIA_TYPE),
+    ] {
+        let err = request_with_body(content_type, json).json::<NewUser>().unwrap_err();
+        assert_eq!(err.status(), status, "{err}");
+    }
+}
+
+/// A reader handing out at most `chunk` bytes per read, as a slow client would.
+struct Trickle<'a> {
+    bytes: &'a [u8],
+    chunk: usize,
+}
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.chunk.min(buf.len()).min(self.bytes.len());
+        buf[..n].copy_from_slice(&self.bytes[..n]);
+        self.bytes = &self.bytes[n..];
+        Ok(n)
+    }
+}
+
+const BOUNDARY: &str = "----formdata-boundary-7MA4YWxk";
+
+/// A `multipart/form-data` body delimited by `BOUNDARY`, with a `title` field, and
+/// each of `files` as a `file` field.
+fn multipart_body(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut body = b"preamble to ignore\r\n".to_vec();
+    body.extend(format!(
+        "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHoliday \"photos\"; 2024\r\n"
+    ).into_bytes());
+    for (name, contents) in files {
+        body.extend(format!(
+            "--{BOUNDARY}  \r\nContent-Disposition: form-data; name=\"file\"; filename=\"{name}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n"
+        ).into_bytes());
+        body.extend_from_slice(contents);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend(format!("--{BOUNDARY}--\r\nepilogue").into_bytes());
+    body
+}
+
+#[test]
+fn multipart_uploads_are_streamed_to_disk() {
+    let dir = scratch_dir("multipart");
+    let limits = body::UploadLimits { dir: dir.clone(), max_file_bytes: 64 * 1024, ..body::UploadLimits::default() };
+    // Binary, and full of near-delimiters.
+    let mut photo: Vec<u8> = (0..50_000u32).map(|n| (n * 13 % 256) as u8).collect();
+    photo.extend(format!("\r\n--{}\r\n--", &BOUNDARY[..20]).into_bytes());
+    let note = b"see you soon".as_slice();
+    let body = multipart_body(&[("beach.png", &photo), ("../note.txt", note)]);
+
+    for chunk in [1, 7, 100_000] {
+        let reader = Trickle { bytes: &body, chunk };
+        let multipart = body::Multipart::read_from(reader, BOUNDARY, &limits).unwrap();
+        assert_eq!(multipart.fields.get("title"), Some("Holiday \"photos\"; 2024"));
+        assert_eq!(multipart.files.len(), 2);
+        let file = multipart.file("file").unwrap();
+        assert_eq!(file.file_name.as_deref(), Some("beach.png"));
+        assert_eq!(file.content_type.as_deref(), Some("application/octet-stream"));
+        assert_eq!(file.size, photo.len() as u64);
+        assert!(file.path().starts_with(&dir));
+        assert_eq!(fs::read(file.path()).unwrap(), photo);
+        assert_eq!(multipart.files[1].file_name.as_deref(), Some("../note.txt"));
+        assert_eq!(fs::read(multipart.files[1].path()).unwrap(), note);
+    }
+    // Uploads are deleted once dropped, unless persisted.
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    let mut multipart = body::Multipart::read_from(body.as_slice(), BOUNDARY, &limits).unwrap();
+    let kept = dir.join("kept.txt");
+    multipart.files.pop().unwrap().persist(&kept).unwrap();
+    drop(multipart);
+    assert_eq!(fs::read(&kept).unwrap(), note);
+    fs::remove_file(&kept).unwrap();
+
+    let large = vec![b'x'; 64 * 1024 + 1];
+    let err = body::Multipart::read_from(multipart_body(&[("a", note), ("large", &large)]).as_slice(), BOUNDARY, &limits)
+        .unwrap_err();
+    assert!(matches!(&err, body::BodyError::FileTooLargeError(name) if name == "file"), "{err}");
+    assert_eq!(err.status(), http::StatusCode::PAYLOAD_TOO_LARGE);
+    let few_parts = body::UploadLimits { max_parts: 2, ..limits.clone() };
+    let err = body::Multipart::read_from(multipart_body(&[("a", note), ("b", note)]).as_slice(), BOUNDARY, &few_parts)
+        .unwrap_err();
+    assert!(matches!(err, body::BodyError::TooManyPartsError));
+    let truncated = &body[..body.len() / 2];
+    let err = body::Multipart::read_from(truncated, BOUNDARY, &limits).unwrap_err();
+    assert!(matches!(err, body::BodyError::MalformedMultipartError(_)), "{err}");
+    // Nothing is left behind by failed uploads.
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn handlers_receive_uploads_and_json_over_the_socket() {
+    let dir = scratch_dir("uploads_socket");
+    let limits = body::UploadLimits { dir: dir.clone(), ..body::UploadLimits::default() };
+    let mut router = router::Router::new();
+    router.route(http::Method::Post, "/upload", move |request| {
+        let multipart = match request.multipart(&limits) {
+            Ok(multipart) => multipart,
+            Err(err) => return Ok(http::Response::plain(err.status())),
+        };
+        let sizes: Vec<String> = multipart.files.iter().map(|file| file.size.to_string()).collect();
+        text(format!("{} {}", multipart.fields.get("title").unwrap_or_default(), sizes.join(",")))
+    });
+    router.route(http::Method::Post, "/users", |request| match request.json::<NewUser>() {
+        Ok(user) => text(format!("{} is {}", user.name, user.age)),
+        Err(err) => Ok(http::Response::plain(err.status())),
+    });
+    let mut service = service::Service::new(router);
+    service.limits.max_body_bytes = 4 * 1024 * 1024;
+    let addr = spawn_server(service, 2);
+
+    let stream = net::TcpStream::connect(addr).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut post = |target: &str, content_type: &str, body: &[u8]| {
+        write!(
+            writer,
+            "POST {target} HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        writer.write_all(body).unwrap();
+        read_response(&mut reader).unwrap()
+    };
+
+    // Far larger than the server's original 1024-byte buffer.
+    let photo = vec![7; 2 * 1024 * 1024];
+    let body = multipart_body(&[("photo.png", &photo), ("empty.txt", b"")]);
+    let content_type = format!("multipart/form-data; boundary=\"{BOUNDARY}\"");
+    let response = post("/upload", &content_type, &body);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"Holiday \"photos\"; 2024 2097152,0");
+    let response = post("/upload", "multipart/form-data", &body);
+    assert_eq!(response.status, 400);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+    let response = post("/users", "application/json", br#"{"name": "Ferris", "age": 9}"#);
+    assert_eq!(response.body, b"Ferris is 9");
+    let response = post("/users", "application/x-www-form-urlencoded", b"name=Ferris&age=9");
+    assert_eq!(response.status, 415);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// A service whose `/upload` handler answers with the title and file sizes of a
+/// multipart body, storing its files in `dir`, within `max_file_bytes` each.
+fn upload_service(dir: &std::path::Path, max_file_bytes: u64) -> service::Service {
+    let limits = body::UploadLimits { dir: dir.to_path_buf(), max_file_bytes, ..body::UploadLimits::default() };
+    let mut router = router::Router::new();
+    router.route(http::Method::Post, "/upload", move |request| {
+        let multipart = match request.multipart(&limits) {
+            Ok(multipart) => multipart,
+            Err(err) => return Ok(http::Response::plain(err.status())),
+        };
+        let sizes: Vec<String> = multipart.files.iter().map(|file| file.size.to_string()).collect();
+        text(format!("{} {}", multipart.fields.get("title").unwrap_or_default(), sizes.join(",")))
+    });
+    router.route(http::Method::Post, "/ignore", |_| text("ignored".to_string()));
+    service::Service::new(router)
+}
+
+#[test]
+fn uploads_are_bound_by_upload_limits_rather_than_max_body_bytes() {
+    let dir = scratch_dir("uploads_streamed");
+    let make_service = || {
+        let mut service = upload_service(&dir, 256 * 1024);
+        service.limits.max_body_bytes = 16 * 1024;
+        service
+    };
+    let content_type = format!("multipart/form-data; boundary={BOUNDARY}");
+    let photo = vec![7; 200_000];
+    let body = multipart_body(&[("photo.png", &photo)]);
+    let too_large = multipart_body(&[("photo.png", &vec![7; 256 * 1024 + 1])]);
+
+    for addr in [spawn_server(make_service(), 2), spawn_event_loop_server(make_service(), 2)] {
+        let stream = net::TcpStream::connect(addr).unwrap();
+        let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+
+        // Far larger than `max_body_bytes`, with a `Content-Length`, and then chunked.
+        write!(
+            writer,
+            "POST /upload HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        writer.write_all(&body).unwrap();
+        let response = read_response(&mut reader).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"Holiday \"photos\"; 2024 200000");
+
+        write!(
+            writer,
+            "POST /upload HTTP/1.1\r\nContent-Type: {content_type}\r\nTransfer-Encoding: chunked\r\n\r\n"
+        )
+        .unwrap();
+        for chunk in body.chunks(30_000) {
+            write!(writer, "{:x}\r\n", chunk.len()).unwrap();
+            writer.write_all(chunk).unwrap();
+            writer.write_all(b"\r\n").unwrap();
+        }
+        writer.write_all(b"0\r\nTrailer: ignored\r\n\r\n").unwrap();
+        let response = read_response(&mut reader).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"Holiday \"photos\"; 2024 200000");
+
+        // `UploadLimits` are what bounds the upload, and what the handler leaves of a
+        // body is read past, so the connection is kept alive either way.
+        write!(
+            writer,
+            "POST /upload HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
+            too_large.len()
+        )
+        .unwrap();
+        writer.write_all(&too_large).unwrap();
+        let response = read_response(&mut reader).unwrap();
+        assert_eq!(response.status, 413);
+        assert_eq!(response.headers.get("Connection"), Some("keep-alive"));
+
+        let small = multipart_body(&[("note.txt", b"see you soon")]);
+        write!(
+            writer,
+            "POST /ignore HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
+            small.len()
+        )
+        .unwrap();
+        writer.write_all(&small).unwrap();
+        let response = read_response(&mut reader).unwrap();
+        assert_eq!(response.body, b"ignored");
+        assert_eq!(response.headers.get("Connection"), Some("keep-alive"));
+
+        // Other bodies are still bound by `max_body_bytes`.
+        write!(writer, "POST /ignore HTTP/1.1\r\nContent-Length: {}\r\n\r\n", 16 * 1024 + 1).unwrap();
+        let response = read_response(&mut reader).unwrap();
+        assert_eq!(response.status, 413);
+    }
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Lay out a directory of templates for a test, `(name, source)` by `(name, source)`.
+fn template_dir(name: &str, templates: &[(&str, &str)]) -> std::path::PathBuf {
+    let dir = scratch_dir(name);
+    for (name, source) in templates {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, source).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn templates_interpolate_escaped_values() {
+    let dir = template_dir(
+        "template_values",
+        &[
+            ("page.html", "<p title=\"{{ title }}\">{{ user.name }} {{ html | safe }}</p>{# ignored #}"),
+            ("missing.html", "[{{ nothing }}][{{ user.nothing.deeper }}][{{ none }}]"),
+            ("values.html", "{{ count }} {{ ratio }} {{ admin }} {{ tags.1 }} {{ tags }}"),
+        ],
+    );
+    let templates = template::Templates::new(&dir);
+
+    let context = serde_json::json!({
+        "title": "\"quoted\" & 'single'",
+        "user": { "name": "<b>Ferris</b>" },
+        "html": "<em>trusted</em>",
+    });
+    assert_eq!(
+        templates.render("page.html", &context).unwrap(),
+        "<p title=\"&quot;quoted&quot; &amp; &#x27;single&#x27;\">&lt;b&gt;Ferris&lt;/b&gt; <em>trusted</em></p>"
+    );
+    let context = serde_json::json!({ "user": {}, "none": null });
+    assert_eq!(templates.render("missing.html", &context).unwrap(), "[][][]");
+    let context = serde_json::json!({ "count": 3, "ratio": 0.5, "admin": false, "tags": ["a", "b"] });
+    assert_eq!(templates.render("values.html", &context).unwrap(), "3 0.5 false b [&quot;a&quot;,&quot;b&quot;]");
+
+    // Any serializable value will do as a context.
+    #[derive(serde::Serialize)]
+    struct User {
+        name: String,
+    }
+    let context = serde_json::json!({ "user": User { name: "Ada".to_string() } });
+    assert!(templates.render("page.html", &context).unwrap().contains(">Ada "));
+
+    assert_eq!(template::escape_html("a < b && c > \"d\""), "a &lt; b &amp;&amp; c &gt; &quot;d&quot;");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn templates_render_conditionals_and_loops() {
+    let dir = template_dir(
+        "template_control",
+        &[
+            ("if.html", "{% if admin %}admin{% else %}user{% endif %}{% if not items %}, nothing{% endif %}"),
+            (
+                "for.html",
+                "<ul>{% for item in items %}<li>{{ loop.index }}/{{ loop.length }} {{ item.name }}\
+                 {% if loop.first %} first{% endif %}{% if loop.last %} last{% endif %}</li>\
+                 {% else %}<li>none</li>{% endfor %}</ul>",
+            ),
+            ("nested.html", "{% for row in rows %}{% for cell in row %}{{ cell }}{% endfor %};{% endfor %}"),
+            ("map.html", "{% for entry in env %}{{ entry.key }}={{ entry.value }} {% endfor %}"),
+        ],
+    );
+    let templates = template::Templates::new(&dir);
+
+    let render = |name: &str, context: serde_json::Value| templates.render(name, &context).unwrap();
+    assert_eq!(render("if.html", serde_json::json!({ "admin": true, "items": [1] })), "admin");
+    assert_eq!(render("if.html", serde_json::json!({ "admin": 0, "items": [] })), "user, nothing");
+    assert_eq!(render("if.html", serde_json::json!({ "admin": "" })), "user, nothing");
+
+    let items = serde_json::json!({ "items": [{ "name": "a" }, { "name": "<b>" }] });
+    assert_eq!(
+        render("for.html", items),
+        "<ul><li>1/2 a first</li><li>2/2 &lt;b&gt; last</li></ul>"
+    );
+    assert_eq!(render("for.html", serde_json::json!({ "items": [] })), "<ul><li>none</li></ul>");
+    assert_eq!(render("for.html", serde_json::json!({})), "<ul><li>none</li></ul>");
+    assert_eq!(render("nested.html", serde_json::json!({ "rows": [[1, 2], [3]] })), "12;3;");
+    assert_eq!(render("map.html", serde_json::json!({ "env": { "a": 1, "b": "x" } })), "a=1 b=x ");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn templates_include_others_and_extend_layouts() {
+    let dir = template_dir(
+        "template_layouts",
+        &[
+            ("layout.html", "<title>{% block title %}Site{% endblock %}</title>{% include \"partials/nav.html\" %}<main>{% block content %}{% endblock %}</main>"),
+            ("partials/nav.html", "<nav>{{ user }}</nav>"),
+            ("section.html", "{% extends \"layout.html\" %}{% block content %}<section>{% block body %}default{% endblock %}</section>{% endblock %}"),
+            ("page.html", "{% extends \"section.html\" %}ignored{% block title %}Page{% endblock %}{% block body %}{% for n in ns %}{% include \"item.html\" %}{% endfor %}{% endblock %}"),
+            ("item.html", "({{ n }})"),
+        ],
+    );
+    let templates = template::Templates::new(&dir);
+
+    let context = serde_json::json!({ "user": "ada", "ns": [1, 2] });
+    assert_eq!(
+        templates.render("section.html", &context).unwrap(),
+        "<title>Site</title><nav>ada</nav><main><section>default</section></main>"
+    );
+    // Blocks of the template win over those of its layouts, and loop variables are
+    // seen by included templates.
+    assert_eq!(
+        templates.render("page.html", &context).unwrap(),
+        "<title>Page</title><nav>ada</nav><main><section>(1)(2)</section></main>"
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn template_errors_are_reported() {
+    let dir = template_dir(
+        "template_errors",
+        &[
+            ("unclosed.html", "line 1\nline 2 {{ name"),
+            ("unterminated.html", "{% if a %}\n{% for x in xs %}\n{% endfor %}"),
+            ("unknown.html", "\n\n{% while a %}"),
+            ("filter.html", "{{ name | upper }}"),
+            ("cycle.html", "{% include \"cycle.html\" %}"),
+            ("a.html", "{% extends \"b.html\" %}"),
+            ("b.html", "{% extends \"a.html\" %}"),
+            ("include_missing.html", "{% include \"nowhere.html\" %}"),
+        ],
+    );
+    let templates = template::Templates::new(&dir);
+    let render = |name: &str| templates.render(name, &serde_json::json!({})).unwrap_err();
+
+    let err = render("unclosed.html");
+    assert!(
+        matches!(&err, template::TemplateError::SyntaxError { line: 2, message, .. } if message == "unclosed {{"),
+        "{err}"
+    );
+    assert_eq!(err.to_string(), "syntax error in template \"unclosed.html\", line 2: unclosed {{");
+    let err = render("unterminated.html");
+    assert!(matches!(err, template::TemplateError::SyntaxError { line: 3, .. }), "{err}");
+    let err = render("unknown.html");
+    assert!(matches!(err, template::TemplateError::SyntaxError { line: 3, .. }), "{err}");
+    let err = render("filter.html");
+    assert!(matches!(err, template::TemplateError::SyntaxError { line: 1, .. }), "{err}");
+    assert!(matches!(render("cycle.html"), template::TemplateError::RecursionError(_)));
+    assert!(matches!(render("a.html"), template::TemplateError::RecursionError(_)));
+    assert!(matches!(render("include_missing.html"), template::TemplateError::ReadError(name, _) if name == "nowhere.html"));
+    assert!(matches!(render("missing.html"), template::TemplateError::ReadError(..)));
+    for name in ["../secret.html", "/etc/passwd", "a/../b.html", "", "a//b.html", "a\\b.html"] {
+        assert!(matches!(render(name), template::TemplateError::InvalidNameError(_)), "{name}");
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn templates_are_cached_unless_reloading() {
+    let dir = template_dir("template_reload", &[("page.html", "first {{ n }}")]);
+    let cached = template::Templates::new(&dir);
+    let reloading = template::Templates::new(&dir).with_reload(true);
+    let context = serde_json::json!({ "n": 1 });
+    assert_eq!(cached.render("page.html", &context).unwrap(), "first 1");
+    assert_eq!(reloading.render("page.html", &context).unwrap(), "first 1");
+
+    let path = dir.join("page.html");
+    fs::write(&path, "second {{ n }}").unwrap();
+    // Make sure the modification time changes, however coarse the filesystem's clock.
+    let file = fs::File::options().write(true).open(&path).unwrap();
+    file.set_modified(time::SystemTime::now() + time::Duration::from_secs(10)).unwrap();
+    drop(file);
+
+    assert_eq!(cached.render("page.html", &context).unwrap(), "first 1");
+    assert_eq!(cached.clone().render("page.html", &context).unwrap(), "first 1");
+    assert_eq!(reloading.render("page.html", &context).unwrap(), "second 1");
+
+    // A reloaded template that no longer parses is reported, not served stale.
+    fs::write(&path, "{% if n %}").unwrap();
+    let file = fs::File::options().write(true).open(&path).unwrap();
+    file.set_modified(time::SystemTime::now() + time::Duration::from_secs(20)).unwrap();
+    drop(file);
+    assert!(matches!(
+        reloading.render("page.html", &context),
+        Err(template::TemplateError::SyntaxError { .. })
+    ));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn pages_that_cannot_be_rendered_are_answered_with_500() {
+    let dir = template_dir("template_broken_pages", &[("hello.html", "{% if %}")]);
+    let router = util::default_router_with(template::Templates::new(&dir), None);
+    let response = get(&router, "/");
+    assert_eq!(response.status, http::StatusCode::INTERNAL_SERVER_ERROR);
+    // And 404.html is missing altogether.
+    let response = get(&router, "/nowhere");
+    assert_eq!(response.status, http::StatusCode::INTERNAL_SERVER_ERROR);
+    let _ = fs::remove_dir_all(&dir);
+}